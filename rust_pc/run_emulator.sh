@@ -0,0 +1,3 @@
+#!/bin/bash
+
+exec cargo run --features emulator --bin sbemulator -- --address 127.0.0.1:8080
@@ -0,0 +1,85 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use clap::Parser;
+use qexample::emulator::{Emulator, EmulatorConfig};
+use qexample::transport::broker::BrokerConfig;
+use qexample::mazure::aadclient::AADCredentials;
+
+/// Runs a local Service Bus and AAD token endpoint emulator.
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct CommandLineArgs {
+    #[arg(short = 'a', long = "address", default_value = "127.0.0.1:8080", )]
+    address: SocketAddr,
+
+    /// Also serve AMQP 1.0 on this address.
+    #[arg(long = "amqp-address", )]
+    amqp_address: Option<SocketAddr>,
+
+    /// Only accept these credentials at the token endpoint. Any are accepted if not given.
+    #[arg(short = 'c', long = "credentials", )]
+    credentials_file: Option<String>,
+
+    /// Lock duration in seconds.
+    #[arg(long = "lock-duration", default_value = "60", )]
+    lock_duration_secs: i64,
+
+    #[arg(long = "max-delivery-count", default_value = "10", )]
+    max_delivery_count: i32,
+
+    /// Default message time to live in seconds.
+    #[arg(long = "default-ttl", )]
+    default_ttl_secs: Option<i64>,
+
+    /// Dead-letter expired messages instead of dropping them.
+    #[arg(long = "dead-letter-on-expiration", )]
+    dead_letter_on_expiration: bool,
+
+    /// Seconds within which messages repeating a message id are dropped.
+    #[arg(long = "duplicate-detection-window", )]
+    duplicate_detection_window_secs: Option<i64>,
+
+    /// Seconds a receive without a timeout waits for a message.
+    #[arg(long = "receive-timeout", default_value = "60", )]
+    receive_timeout_secs: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = CommandLineArgs::parse();
+
+    let credentials = match &args.credentials_file {
+        Some(path) => Some(AADCredentials::from_file(path)?),
+        None => None,
+    };
+
+    let config = EmulatorConfig {
+        broker: BrokerConfig {
+            lock_duration: chrono::Duration::seconds(args.lock_duration_secs),
+            max_delivery_count: args.max_delivery_count,
+            default_time_to_live: args.default_ttl_secs.map(chrono::Duration::seconds),
+            dead_letter_on_expiration: args.dead_letter_on_expiration,
+            duplicate_detection_window: args.duplicate_detection_window_secs.map(chrono::Duration::seconds),
+        },
+        credentials,
+        default_receive_timeout: Duration::from_secs(args.receive_timeout_secs),
+        ..EmulatorConfig::default()
+    };
+
+    let mut emulator = Emulator::start(args.address, config).await?;
+    println!("Emulator listening on {}", emulator.endpoint());
+    println!("Use --endpoint {0} --oauth-endpoint {0} with the producer and consumer.", emulator.endpoint());
+
+    if let Some(amqp_address) = args.amqp_address {
+        emulator.serve_amqp(amqp_address).await?;
+        println!("Serving AMQP on {0}. Add --transport amqp --amqp-endpoint {0} to use it.", emulator.amqp_endpoint().unwrap_or_default());
+    }
+
+    tokio::signal::ctrl_c().await?;
+    println!("Stopping.");
+    emulator.stop().await?;
+
+    Ok(())
+}
@@ -1,39 +1,132 @@
 use std::error::Error;
+use std::process::ExitCode;
+use std::rc::Rc;
 
+use async_trait::async_trait;
 use chrono::Local;
-use tokio::time::{Duration, sleep};
+use tokio::time::{Duration, Instant, sleep, timeout};
 
-use crate::mazure::sbclient::AzureServiceBusClient;
+use crate::inbox::InboxStore;
+use crate::mazure::sbclient::{BrokerReceiveProperties, Message};
 use crate::messages::LogInfo;
+use crate::middleware::{DedupMiddleware, Pipeline, Processor};
+use crate::poison::{PoisonDecision, PoisonPolicy, ProcessingError, RetryMetadata};
+use crate::shutdown::Shutdown;
+use crate::transport::QueueTransport;
 
-pub async fn run_consumer_loop(sb_client: &AzureServiceBusClient) -> Result<(), Box<dyn Error>> {
-    loop {
+#[derive(Debug, Clone)]
+pub struct ConsumerOptions {
+    /// How long in-flight work is given to finish once shutdown is requested.
+    pub shutdown_grace: Duration,
+
+    /// What happens to messages that fail processing.
+    pub poison_policy: PoisonPolicy,
+
+    /// Ids of processed messages. Redeliveries of them are completed without being
+    /// processed again. Runs as a `DedupMiddleware` around the processing.
+    pub inbox: Option<Rc<dyn InboxStore>>,
+
+    /// How long the simulated work on each message takes.
+    pub processing_time: Duration,
+}
+
+impl Default for ConsumerOptions {
+    fn default() -> Self {
+        ConsumerOptions {
+            shutdown_grace: Duration::from_secs(30),
+            poison_policy: PoisonPolicy::default(),
+            inbox: None,
+            processing_time: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How the consumer left things when it stopped.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum DrainStatus {
+    /// Nothing was outstanding when the consumer stopped.
+    Clean,
+
+    /// In-flight messages did not finish within the grace period and were unlocked.
+    Unlocked,
+
+    /// Something outstanding could not be released, it stays locked until the lock expires.
+    Failed,
+}
+
+impl DrainStatus {
+    pub fn exit_code(self: &Self) -> ExitCode {
+        match self {
+            DrainStatus::Clean => ExitCode::SUCCESS,
+            DrainStatus::Unlocked => ExitCode::from(2),
+            DrainStatus::Failed => ExitCode::from(1),
+        }
+    }
+}
+
+pub async fn run_consumer_loop(transport: &dyn QueueTransport, options: &ConsumerOptions, shutdown: &Shutdown) -> Result<DrainStatus, Box<dyn Error>> {
+    let mut status = DrainStatus::Clean;
+
+    while !shutdown.is_requested() {
         println!("[{}] Waiting for new message.", Local::now());
-        match run_consumer(sb_client).await {
+        match run_consumer(transport, options, shutdown).await {
             Err(e) => {
                 println!("Error processing: {:?}", e);
+                if shutdown.is_requested() {
+                    status = status.max(DrainStatus::Failed);
+                }
             },
-            Ok(_) => {
-                // Do nothing!
+            Ok(s) => {
+                status = status.max(s);
             }
         }
     }
+
+    println!("[{}] Consumer stopped: {:?}", Local::now(), status);
+    Ok(status)
 }
 
-pub async fn process_message(log_info: LogInfo) {
-    let start = Local::now();
-    let end = start + chrono::Duration::seconds(30);
+pub async fn process_message(log_info: LogInfo, attempt: u32, processing_time: Duration) -> Result<(), ProcessingError> {
+    if attempt == 1 {
+        // Fail the first attempt so the poison policy gets exercised.
+        return Err(ProcessingError::transient("Simulated failure on the first attempt."));
+    }
+
+    let end = Instant::now() + processing_time;
 
-    while Local::now() < end {
+    while Instant::now() < end {
         println!("[{}] Still processing.....", Local::now());
-        sleep(Duration::from_secs(5)).await;
+        sleep(Duration::from_secs(5).min(end - Instant::now())).await;
     }
 
     println!("[{}] ok processed: {:?}", Local::now(), &log_info);
+    Ok(())
 }
 
-pub async fn run_consumer(sb_client: &AzureServiceBusClient) -> Result<(), Box<dyn Error>> {
-    match sb_client.peek_lock().await? {
+pub async fn run_consumer(transport: &dyn QueueTransport, options: &ConsumerOptions, shutdown: &Shutdown) -> Result<DrainStatus, Box<dyn Error>> {
+    let receive = transport.receive();
+    tokio::pin!(receive);
+
+    let received = tokio::select! {
+        r = &mut receive => r?,
+        _ = shutdown.requested() => {
+            // The receive is already on the wire, so a message may get locked for us anyway.
+            // Let it finish and hand back anything it returns.
+            match timeout(options.shutdown_grace, &mut receive).await {
+                Err(_) => None,
+                Ok(r) => match r? {
+                    None => None,
+                    Some(msg) => {
+                        println!("Shutting down - unlocking message received during shutdown.");
+                        transport.abandon(&msg.properties).await?;
+                        return Ok(DrainStatus::Unlocked);
+                    }
+                }
+            }
+        }
+    };
+
+    match received {
         None => {
             println!("No message found.");
         },
@@ -41,33 +134,102 @@ pub async fn run_consumer(sb_client: &AzureServiceBusClient) -> Result<(), Box<d
             println!("Recieved message: time={}", Local::now());
             println!("    properties: {:?}", msg.properties);
 
-            let payload: LogInfo = msg.json_into()?;
-            println!("    content: {:?}", payload);
+            let retry = RetryMetadata::from_message(&msg);
+            let processor = LogInfoProcessor { attempt: retry.attempt, processing_time: options.processing_time };
+            let pipeline = pipeline(options);
+            let t = pipeline.process(&msg, &processor);
+            tokio::pin!(t);
+
+            let finished = tokio::select! {
+                r = &mut t => Some(r),
+                _ = shutdown.requested() => {
+                    println!("Shutting down - waiting up to {:?} for processing to finish.", options.shutdown_grace);
+                    timeout(options.shutdown_grace, &mut t).await.ok()
+                }
+            };
 
-            match msg.properties.delivery_count {
+            let result = match finished {
+                Some(r) => r,
                 None => {
-                    println!("No sequence number found");
-                    sb_client.delete_message(&msg.properties).await?;
-                },
-                Some(1) => {
-                    println!("First seen - unlock it.");
-                    sb_client.unlock_message(&msg.properties).await?;
-                },
-                Some(2) => {
-                    println!("2nd time seen - leave the lock.");
-                    sb_client.renew_lock(&msg.properties).await?;
-                },
-                Some(_) => {
-                    println!("Processing now:");
-                    let t = process_message(payload);
-                    t.await;
+                    println!("Processing did not finish in time - unlocking so another consumer can take it.");
+                    transport.abandon(&msg.properties).await?;
+                    return Ok(DrainStatus::Unlocked);
+                }
+            };
 
+            match result {
+                Ok(()) => {
+                    // The inbox has recorded the message by now, so a redelivery after a
+                    // failed complete is caught.
                     println!("Ok its processed now");
-                    sb_client.delete_message(&msg.properties).await?;
+                    transport.complete(&msg).await?;
+                },
+                Err(error) => {
+                    println!("Processing failed: {}", error);
+                    handle_failure(transport, &options.poison_policy, &msg, retry, &error).await?;
                 }
             }
         }
     }
 
+    Ok(DrainStatus::Clean)
+}
+
+// The middleware the consumer runs around `LogInfoProcessor`.
+fn pipeline(options: &ConsumerOptions) -> Pipeline<'static> {
+    let mut pipeline = Pipeline::new();
+    if let Some(inbox) = &options.inbox {
+        pipeline.push(DedupMiddleware::new(inbox.clone()));
+    }
+    pipeline
+}
+
+// Decodes a `LogInfo` and processes it.
+struct LogInfoProcessor {
+    attempt: u32,
+    processing_time: Duration,
+}
+
+#[async_trait(?Send)]
+impl Processor for LogInfoProcessor {
+    async fn process(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), ProcessingError> {
+        let payload = message.decode::<LogInfo>().map_err(|e| ProcessingError::decode(e.to_string()))?;
+        println!("    content: {:?}", payload);
+        println!("Processing now, attempt {}:", self.attempt);
+        process_message(payload, self.attempt, self.processing_time).await
+    }
+}
+
+/// Settles a message that failed processing according to the poison policy.
+pub async fn handle_failure(
+    transport: &dyn QueueTransport,
+    policy: &PoisonPolicy,
+    msg: &Message<BrokerReceiveProperties>,
+    mut retry: RetryMetadata,
+    error: &ProcessingError,
+) -> Result<(), Box<dyn Error>> {
+    let now = chrono::Utc::now();
+    retry.record_failure(error, now);
+
+    match policy.decide(&retry, error, now) {
+        PoisonDecision::DeadLetter { reason, description } => {
+            println!("Dead-lettering: {}: {}", reason, description);
+            transport.dead_letter(msg, &reason, &description).await?;
+        },
+        PoisonDecision::Abandon => {
+            println!("Abandoning for redelivery.");
+            transport.abandon(&msg.properties).await?;
+        },
+        PoisonDecision::Reschedule { at } => {
+            println!("Rescheduling a copy for {}.", at);
+            let mut copy = msg.to_send_message();
+            copy.properties.scheduled_enqueue_time_utc = Some(at);
+            retry.write_to(&mut copy.user_properties);
+
+            transport.send(&copy).await?;
+            transport.complete(msg).await?;
+        }
+    }
+
     Ok(())
 }
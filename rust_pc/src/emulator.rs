@@ -0,0 +1,154 @@
+mod amqp;
+mod server;
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::transport::broker::{Broker, BrokerConfig};
+use crate::emulator::server::EmulatorState;
+use crate::mazure::aadclient::AADCredentials;
+
+#[derive(Debug, Clone)]
+pub struct EmulatorConfig {
+    pub broker: BrokerConfig,
+
+    /// Credentials accepted by the token endpoint. Any credentials are accepted if not set.
+    pub credentials: Option<AADCredentials>,
+
+    pub token_lifetime: Duration,
+
+    /// How long a receive waits for a message when the request has no timeout.
+    pub default_receive_timeout: Duration,
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        EmulatorConfig {
+            broker: BrokerConfig::default(),
+            credentials: None,
+            token_lifetime: Duration::from_secs(3600),
+            default_receive_timeout: Duration::ZERO,
+        }
+    }
+}
+
+/// A local stand-in for a Service Bus namespace and the AAD token endpoint.
+///
+/// It serves the REST subset used by `AzureServiceBusClient` and the client credentials
+/// flow used by `AADClient`, so both can be pointed at it with `with_endpoint` and the
+/// `oauth_endpoint` argument. It can also serve the same broker over AMQP 1.0.
+pub struct Emulator {
+    address: SocketAddr,
+    state: Arc<EmulatorState>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<Result<(), hyper::Error>>>,
+    amqp_address: Option<SocketAddr>,
+    amqp_task: Option<JoinHandle<()>>,
+}
+
+impl Emulator {
+    /// Binds to the address and starts serving in the background. Use port 0 to pick
+    /// a free port.
+    pub async fn start(address: SocketAddr, config: EmulatorConfig) -> Result<Emulator, hyper::Error> {
+        let state = Arc::new(EmulatorState::new(config));
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| server::handle(state.clone(), req)))
+            }
+        });
+
+        let server = hyper::Server::try_bind(&address)?.serve(make_service);
+        let address = server.local_addr();
+
+        let (shutdown, shutdown_receiver) = oneshot::channel::<()>();
+        let task = tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = shutdown_receiver.await;
+        }));
+
+        Ok(Emulator { address, state, shutdown: Some(shutdown), task: Some(task), amqp_address: None, amqp_task: None })
+    }
+
+    pub fn local_addr(self: &Self) -> SocketAddr {
+        self.address
+    }
+
+    /// Base URL for both the Service Bus and the token endpoints.
+    pub fn endpoint(self: &Self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// Also serves the broker over AMQP 1.0 on the address, with SASL ANONYMOUS and
+    /// tokens from the token endpoint put on `$cbs`. Returns the bound address. Calling
+    /// it again drops every AMQP connection.
+    pub async fn serve_amqp(self: &mut Self, address: SocketAddr) -> Result<SocketAddr, std::io::Error> {
+        // Closes the connections to the previous listener and frees its address.
+        if let Some(task) = self.amqp_task.take() {
+            task.abort();
+            let _ = task.await;
+        }
+
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        self.amqp_task = Some(tokio::spawn(amqp::serve(listener, self.state.clone())));
+        self.amqp_address = Some(address);
+        Ok(address)
+    }
+
+    /// Endpoint for `AmqpOptions`, if serving AMQP.
+    pub fn amqp_endpoint(self: &Self) -> Option<String> {
+        self.amqp_address.map(|address| format!("amqp://{}", address))
+    }
+
+    /// Gives direct access to the broker, e.g. to inspect queues or move the clock.
+    pub fn with_broker<R>(self: &Self, f: impl FnOnce(&mut Broker) -> R) -> R {
+        let mut broker = self.state.broker.lock().unwrap();
+        f(&mut broker)
+    }
+
+    /// Invalidates every token handed out so far.
+    pub fn revoke_tokens(self: &Self) {
+        self.state.tokens.lock().unwrap().clear();
+    }
+
+    /// Accepts the next `count` sends but answers them with a 503, as if the response
+    /// had been lost on the way back.
+    pub fn lose_send_responses(self: &Self, count: u32) {
+        self.state.lost_send_responses.store(count, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Stops serving and waits for in-progress requests to finish.
+    pub async fn stop(mut self: Self) -> Result<(), hyper::Error> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(task) = self.amqp_task.take() {
+            task.abort();
+        }
+
+        match self.task.take() {
+            Some(task) => task.await.unwrap_or(Ok(())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(self: &mut Self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(task) = self.amqp_task.take() {
+            task.abort();
+        }
+    }
+}
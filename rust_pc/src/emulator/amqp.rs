@@ -0,0 +1,746 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use uuid::Uuid;
+
+use crate::transport::broker::BrokerError;
+use crate::emulator::server::{EmulatorState, token_is_valid};
+use crate::mazure::amqp::{AmqpError, DEAD_LETTER_CONDITION, LOCK_LOST_CONDITION, NOT_FOUND_CONDITION, UNAUTHORIZED_CONDITION};
+use crate::mazure::amqp::codec::{Value, field};
+use crate::mazure::amqp::frames::*;
+use crate::mazure::amqp::message::{self, AmqpMessage, Body};
+use crate::mazure::sbclient::{DEAD_LETTER_DESCRIPTION_PROPERTY, DEAD_LETTER_REASON_PROPERTY};
+
+const MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+// Credit given to client senders, topped up when half of it is used.
+const SENDER_CREDIT: u32 = 100;
+
+// Longest the connection waits before looking for deliverable messages again, so
+// scheduled messages and expired locks are noticed without a send.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Entity(String),
+    Cbs,
+    // The management node of an entity.
+    Management(String),
+}
+
+impl Node {
+    fn from_address(address: &str) -> Node {
+        if address == "$cbs" {
+            return Node::Cbs;
+        }
+        match address.strip_suffix("/$management") {
+            Some(entity) => Node::Management(entity.to_string()),
+            None => Node::Entity(address.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Link {
+    handle: u32,
+    // The client's role on the link.
+    client_role: bool,
+    node: Node,
+    // Where a management client wants its responses.
+    reply_address: Option<String>,
+    settled: bool,
+    credit: u32,
+    delivery_count: u32,
+    partial: Option<(u32, bool, Vec<u8>)>,
+}
+
+/// Accepts AMQP connections until the task is aborted, which also closes them.
+pub(crate) async fn serve(listener: TcpListener, state: Arc<EmulatorState>) {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                if let Ok((socket, _)) = accepted {
+                    let state = state.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = Connection::run(socket, state).await {
+                            println!("AMQP connection failed: {}", e);
+                        }
+                    });
+                }
+            },
+            Some(_) = connections.join_next() => {},
+        }
+    }
+}
+
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(self: &mut Self) {
+        self.0.abort();
+    }
+}
+
+struct Connection {
+    state: Arc<EmulatorState>,
+    writer: WriteHalf<TcpStream>,
+    max_frame_size: u32,
+    channel: u16,
+    next_handle: u32,
+    next_delivery_id: u32,
+    // Client handle to link.
+    links: HashMap<u32, Link>,
+    // Unsettled peek-lock deliveries: delivery id to entity, message id and lock token.
+    unsettled: HashMap<u32, (String, String, String)>,
+    // Locks of deferred messages received through a management node: lock token to
+    // entity and message id.
+    managed_locks: HashMap<String, (String, String)>,
+    token: Option<String>,
+}
+
+impl Connection {
+    async fn run(socket: TcpStream, state: Arc<EmulatorState>) -> Result<(), AmqpError> {
+        socket.set_nodelay(true)?;
+        let (mut reader, writer) = tokio::io::split(socket);
+        let mut connection = Connection {
+            state,
+            writer,
+            max_frame_size: MIN_MAX_FRAME_SIZE,
+            channel: 0,
+            next_handle: 0,
+            next_delivery_id: 0,
+            links: HashMap::new(),
+            unsettled: HashMap::new(),
+            managed_locks: HashMap::new(),
+            token: None,
+        };
+        connection.handshake(&mut reader).await?;
+
+        let (frame_sender, mut frames) = mpsc::unbounded_channel();
+        let _read_task = AbortOnDrop(tokio::spawn(async move {
+            loop {
+                let frame = read_frame(&mut reader, MAX_FRAME_SIZE).await;
+                let failed = frame.is_err();
+                if frame_sender.send(frame).is_err() || failed {
+                    return;
+                }
+            }
+        }));
+
+        // The reader is stopped with the connection, also when the connection is aborted.
+        connection.serve(&mut frames).await
+    }
+
+    async fn handshake(self: &mut Self, reader: &mut ReadHalf<TcpStream>) -> Result<(), AmqpError> {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header).await?;
+
+        if header == SASL_HEADER {
+            write_header(&mut self.writer, &SASL_HEADER).await?;
+            let mechanisms = Value::Array(vec![Value::symbol("ANONYMOUS"), Value::symbol("MSSBCBS")]);
+            write_frame(&mut self.writer, &Frame::sasl(Performative::new(SASL_MECHANISMS, vec![mechanisms]))).await?;
+
+            let init = read_frame(reader, MAX_FRAME_SIZE).await?;
+            if init.performative.map(|p| p.code) != Some(SASL_INIT) {
+                return Err(AmqpError::ProtocolError("Expected SASL init".into()));
+            }
+            write_frame(&mut self.writer, &Frame::sasl(Performative::new(SASL_OUTCOME, vec![Value::Ubyte(0)]))).await?;
+            expect_header(reader, &AMQP_HEADER).await?;
+        }
+        else if header != AMQP_HEADER {
+            write_header(&mut self.writer, &AMQP_HEADER).await?;
+            return Err(AmqpError::ProtocolError(format!("Unsupported protocol header {:?}", header)));
+        }
+
+        write_header(&mut self.writer, &AMQP_HEADER).await
+    }
+
+    async fn serve(self: &mut Self, frames: &mut mpsc::UnboundedReceiver<Result<Frame, AmqpError>>) -> Result<(), AmqpError> {
+        let state = self.state.clone();
+        loop {
+            // Register interest before looking so a send in between is not missed.
+            let message_sent = state.message_sent.notified();
+            self.deliver().await?;
+
+            tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some(frame) => {
+                        if !self.handle(frame?).await? {
+                            return Ok(());
+                        }
+                    },
+                    None => return Ok(()),
+                },
+                _ = message_sent => {},
+                _ = tokio::time::sleep(POLL_INTERVAL) => {},
+            }
+        }
+    }
+
+    // Handles a frame. Returns false once the connection is closed.
+    async fn handle(self: &mut Self, frame: Frame) -> Result<bool, AmqpError> {
+        let performative = match frame.performative {
+            Some(performative) => performative,
+            None => return Ok(true),
+        };
+
+        match performative.code {
+            OPEN => {
+                self.max_frame_size = performative.field(2).as_u64()
+                    .map(|size| size.clamp(MIN_MAX_FRAME_SIZE as u64, MAX_FRAME_SIZE as u64) as u32)
+                    .unwrap_or(MAX_FRAME_SIZE);
+                self.write(Performative::new(OPEN, vec![
+                    Value::string("sbemulator"),
+                    Value::Null,
+                    Value::Uint(MAX_FRAME_SIZE),
+                    Value::Ushort(0),
+                ])).await?;
+            },
+            BEGIN => {
+                self.channel = frame.channel;
+                self.write(Performative::new(BEGIN, vec![
+                    Value::Ushort(frame.channel),
+                    Value::Uint(0),
+                    Value::Uint(i32::MAX as u32),
+                    Value::Uint(i32::MAX as u32),
+                    Value::Uint(u32::MAX),
+                ])).await?;
+            },
+            ATTACH => self.attach(&performative).await?,
+            FLOW => self.flow(&performative).await?,
+            TRANSFER => self.transfer(&performative, frame.payload).await?,
+            DISPOSITION => self.disposition(&performative).await?,
+            DETACH => {
+                let client_handle = performative.field(0).as_u64().unwrap_or(0) as u32;
+                if let Some(link) = self.links.remove(&client_handle) {
+                    self.write(Performative::new(DETACH, vec![Value::Uint(link.handle), Value::Bool(true)])).await?;
+                }
+            },
+            END => {
+                self.links.clear();
+                self.write(Performative::new(END, vec![])).await?;
+            },
+            CLOSE => {
+                self.write(Performative::new(CLOSE, vec![])).await?;
+                return Ok(false);
+            },
+            _ => {},
+        }
+        Ok(true)
+    }
+
+    async fn attach(self: &mut Self, attach: &Performative) -> Result<(), AmqpError> {
+        let client_handle = attach.field(1).as_u64().unwrap_or(0) as u32;
+        let client_role = attach.field(2).as_bool().unwrap_or(ROLE_SENDER);
+        let source = attach.field(5).clone();
+        let target = attach.field(6).clone();
+
+        // The node is the terminus on our side of the link.
+        let (address, reply_address) = if client_role == ROLE_SENDER {
+            (terminus_address(&target), None)
+        } else {
+            (terminus_address(&source), terminus_address(&target).map(|a| a.to_string()))
+        };
+        let node = address.map(Node::from_address);
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+
+        let refusal = match &node {
+            None => Some((NOT_FOUND_CONDITION, "The link has no address".to_string())),
+            Some(Node::Entity(_)) | Some(Node::Management(_)) if !self.is_authorized() => {
+                Some((UNAUTHORIZED_CONDITION, "Put a valid token on $cbs first".to_string()))
+            },
+            _ => None,
+        };
+
+        let (source, target) = match (&refusal, client_role) {
+            (None, _) => (source, target),
+            (Some(_), ROLE_SENDER) => (source, Value::Null),
+            (Some(_), _) => (Value::Null, target),
+        };
+        let initial_delivery_count = if client_role == ROLE_RECEIVER { Value::Uint(0) } else { Value::Null };
+        self.write(Performative::new(ATTACH, vec![
+            attach.field(0).clone(),
+            Value::Uint(handle),
+            Value::Bool(!client_role),
+            attach.field(3).clone(),
+            Value::Ubyte(RCV_SETTLE_FIRST),
+            source,
+            target,
+            Value::Null,
+            Value::Bool(false),
+            initial_delivery_count,
+        ])).await?;
+
+        if let Some((condition, description)) = refusal {
+            return self.write(Performative::new(DETACH, vec![
+                Value::Uint(handle),
+                Value::Bool(true),
+                error_value(condition, &description, vec![]),
+            ])).await;
+        }
+
+        let link = Link {
+            handle,
+            client_role,
+            node: node.unwrap(),
+            reply_address,
+            settled: attach.field(3).as_u64() == Some(SND_SETTLE_SETTLED as u64),
+            credit: 0,
+            delivery_count: attach.field(9).as_u64().unwrap_or(0) as u32,
+            partial: None,
+        };
+        self.links.insert(client_handle, link);
+
+        if client_role == ROLE_SENDER {
+            self.grant_credit(client_handle).await?;
+        }
+        Ok(())
+    }
+
+    async fn flow(self: &mut Self, flow: &Performative) -> Result<(), AmqpError> {
+        let client_handle = match flow.field(4).as_u64() {
+            Some(handle) => handle as u32,
+            None => return Ok(()),
+        };
+        let link = match self.links.get_mut(&client_handle) {
+            Some(link) if link.client_role == ROLE_RECEIVER => link,
+            _ => return Ok(()),
+        };
+
+        let client_delivery_count = flow.field(5).as_u64().map(|n| n as u32).unwrap_or(0);
+        let link_credit = flow.field(6).as_u64().unwrap_or(0) as u32;
+        link.credit = client_delivery_count.wrapping_add(link_credit).wrapping_sub(link.delivery_count);
+
+        if flow.field(8).as_bool() == Some(true) {
+            // Use up what credit we can, then hand the rest back.
+            self.deliver().await?;
+            let link = self.links.get_mut(&client_handle).unwrap();
+            link.delivery_count = link.delivery_count.wrapping_add(link.credit);
+            link.credit = 0;
+            let (handle, delivery_count) = (link.handle, link.delivery_count);
+            self.write(Performative::new(FLOW, vec![
+                Value::Uint(0),
+                Value::Uint(i32::MAX as u32),
+                Value::Uint(0),
+                Value::Uint(i32::MAX as u32),
+                Value::Uint(handle),
+                Value::Uint(delivery_count),
+                Value::Uint(0),
+                Value::Null,
+                Value::Bool(true),
+            ])).await?;
+        }
+        Ok(())
+    }
+
+    async fn transfer(self: &mut Self, transfer: &Performative, payload: Vec<u8>) -> Result<(), AmqpError> {
+        let client_handle = transfer.field(0).as_u64().unwrap_or(0) as u32;
+        let link = match self.links.get_mut(&client_handle) {
+            Some(link) if link.client_role == ROLE_SENDER => link,
+            _ => return Ok(()),
+        };
+
+        let partial = link.partial.get_or_insert_with(|| {
+            let delivery_id = transfer.field(1).as_u64().unwrap_or(0) as u32;
+            (delivery_id, transfer.field(4).as_bool().unwrap_or(false), vec![])
+        });
+        partial.2.extend_from_slice(&payload);
+        if transfer.field(5).as_bool() == Some(true) {
+            return Ok(());
+        }
+
+        let (delivery_id, settled, payload) = link.partial.take().unwrap();
+        link.credit = link.credit.saturating_sub(1);
+        link.delivery_count = link.delivery_count.wrapping_add(1);
+        let node = link.node.clone();
+        if link.credit < SENDER_CREDIT / 2 {
+            self.grant_credit(client_handle).await?;
+        }
+
+        let state = match node {
+            Node::Entity(entity) => self.enqueue(&entity, &payload),
+            Node::Cbs | Node::Management(_) => {
+                self.management_request(&node, &payload).await?;
+                outcome(ACCEPTED, vec![])
+            },
+        };
+
+        if !settled {
+            self.write(Performative::new(DISPOSITION, vec![
+                Value::Bool(ROLE_RECEIVER),
+                Value::Uint(delivery_id),
+                Value::Null,
+                Value::Bool(true),
+                state,
+            ])).await?;
+        }
+        Ok(())
+    }
+
+    fn enqueue(self: &Self, entity: &str, payload: &[u8]) -> Value {
+        if !self.is_authorized() {
+            return outcome(REJECTED, vec![error_value(UNAUTHORIZED_CONDITION, "The token is no longer valid", vec![])]);
+        }
+        let message = match AmqpMessage::decode(payload) {
+            Ok(message) => message.to_received_message(None),
+            Err(e) => return outcome(REJECTED, vec![error_value("amqp:decode-error", &e.to_string(), vec![])]),
+        };
+
+        self.state.broker.lock().unwrap().send(entity, message);
+        self.state.message_sent.notify_waiters();
+        outcome(ACCEPTED, vec![])
+    }
+
+    async fn management_request(self: &mut Self, node: &Node, payload: &[u8]) -> Result<(), AmqpError> {
+        let request = AmqpMessage::decode(payload)?;
+        let operation = request.application_property("operation").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        let body = match &request.body {
+            Body::Value(value) => value.clone(),
+            Body::Data(_) => Value::Null,
+        };
+
+        let (status, description, response_body) = match (node, operation.as_str()) {
+            (Node::Cbs, "put-token") => {
+                let token = body.as_str().unwrap_or("").to_string();
+                if token_is_valid(&self.state, &token) {
+                    self.token = Some(token);
+                    (200, "OK".to_string(), Value::Null)
+                } else {
+                    (401, "Invalid or expired token".to_string(), Value::Null)
+                }
+            },
+            (Node::Management(_), _) if !self.is_authorized() => (401, "Unauthorized".to_string(), Value::Null),
+            (Node::Management(entity), "com.microsoft:renew-lock") => self.renew_locks(entity, &body),
+            (Node::Management(entity), "com.microsoft:peek-message") => self.peek(entity, &body),
+            (Node::Management(entity), "com.microsoft:receive-by-sequence-number") => self.receive_deferred(entity, &body),
+            (Node::Management(entity), "com.microsoft:update-disposition") => self.update_disposition(entity, &body),
+            (Node::Management(entity), "com.microsoft:schedule-message") => self.schedule(entity, &body),
+            (Node::Management(entity), "com.microsoft:cancel-scheduled-message") => self.cancel_scheduled(entity, &body),
+            _ => (400, format!("Unsupported operation {}", operation), Value::Null),
+        };
+
+        let reply_to = request.property(message::REPLY_TO).as_str().unwrap_or("").to_string();
+        let reply_link = self.links.iter()
+            .find(|(_, link)| link.client_role == ROLE_RECEIVER && link.node == *node && link.reply_address.as_deref() == Some(reply_to.as_str()))
+            .map(|(client_handle, _)| *client_handle);
+        let client_handle = match reply_link {
+            Some(client_handle) => client_handle,
+            None => return Ok(()),
+        };
+
+        let mut response = AmqpMessage {
+            application_properties: vec![
+                (Value::string("status-code"), Value::Int(status)),
+                (Value::string("status-description"), Value::string(description)),
+            ],
+            body: Body::Value(response_body),
+            ..AmqpMessage::default()
+        };
+        response.set_property(message::CORRELATION_ID, request.property(message::MESSAGE_ID).clone());
+
+        let tag = Uuid::new_v4().as_bytes().to_vec();
+        self.send_delivery(client_handle, tag, true, response.encode()).await
+    }
+
+    fn renew_locks(self: &Self, entity: &str, body: &Value) -> (i32, String, Value) {
+        let lock_tokens = body.get("lock-tokens").and_then(|v| v.as_list()).unwrap_or(&[]);
+        let mut broker = self.state.broker.lock().unwrap();
+        let mut expirations = Vec::with_capacity(lock_tokens.len());
+
+        for lock_token in lock_tokens {
+            let lock_token = lock_token.to_text();
+            let renewed = match self.locked_message_id(entity, &lock_token) {
+                Some(message_id) => broker.renew_lock(entity, &message_id, &lock_token),
+                None => Err(BrokerError::LockLost(lock_token.clone())),
+            };
+            match renewed {
+                Ok(locked_until) => expirations.push(Value::Timestamp(locked_until.timestamp_millis())),
+                Err(e) => return (410, e.to_string(), Value::Null),
+            }
+        }
+
+        let body = Value::Map(vec![(Value::string("expirations"), Value::Array(expirations))]);
+        (200, "OK".to_string(), body)
+    }
+
+    fn peek(self: &Self, entity: &str, body: &Value) -> (i32, String, Value) {
+        let from = body.get("from-sequence-number").and_then(|v| v.as_i64()).unwrap_or(0);
+        let count = body.get("message-count").and_then(|v| v.as_i64()).unwrap_or(1);
+        let messages = self.state.broker.lock().unwrap().peek(entity, from, count.max(0) as usize);
+        if messages.is_empty() {
+            return (204, "No messages".to_string(), Value::Null);
+        }
+
+        let entries = messages.iter()
+            .map(|m| Value::Map(vec![(Value::string("message"), Value::Binary(AmqpMessage::from_received_message(m).encode()))]))
+            .collect();
+        (200, "OK".to_string(), Value::Map(vec![(Value::string("messages"), Value::List(entries))]))
+    }
+
+    fn receive_deferred(self: &mut Self, entity: &str, body: &Value) -> (i32, String, Value) {
+        let sequence_numbers = body.get("sequence-numbers").and_then(|v| v.as_list()).unwrap_or(&[]);
+        let mut broker = self.state.broker.lock().unwrap();
+        let mut entries = Vec::with_capacity(sequence_numbers.len());
+
+        for sequence_number in sequence_numbers {
+            let sequence_number = sequence_number.as_i64().unwrap_or(0);
+            let message = match broker.receive_deferred(entity, sequence_number) {
+                Ok(message) => message,
+                Err(e) => return (broker_error_status(&e), e.to_string(), Value::Null),
+            };
+
+            let lock_token = message.properties.lock_token.clone().unwrap_or_default();
+            let message_id = message.properties.message_id.clone().unwrap_or_default();
+            let uuid = Uuid::parse_str(&lock_token).unwrap_or_default();
+            self.managed_locks.insert(lock_token, (entity.to_string(), message_id));
+            entries.push(Value::Map(vec![
+                (Value::string("lock-token"), Value::Uuid(*uuid.as_bytes())),
+                (Value::string("message"), Value::Binary(AmqpMessage::from_received_message(&message).encode())),
+            ]));
+        }
+        (200, "OK".to_string(), Value::Map(vec![(Value::string("messages"), Value::List(entries))]))
+    }
+
+    fn update_disposition(self: &mut Self, entity: &str, body: &Value) -> (i32, String, Value) {
+        let status = body.get("disposition-status").map(|v| v.to_text()).unwrap_or_default();
+        let text = |key: &str| body.get(key).map(|v| v.to_text()).unwrap_or_default();
+        let lock_tokens = body.get("lock-tokens").and_then(|v| v.as_list()).unwrap_or(&[]);
+
+        for lock_token in lock_tokens {
+            let lock_token = lock_token.to_text();
+            let message_id = match self.locked_message_id(entity, &lock_token) {
+                Some(message_id) => message_id,
+                None => return (410, BrokerError::LockLost(lock_token).to_string(), Value::Null),
+            };
+            self.managed_locks.remove(&lock_token);
+
+            let mut broker = self.state.broker.lock().unwrap();
+            let result = match status.as_str() {
+                "completed" => broker.complete(entity, &message_id, &lock_token),
+                "abandoned" => broker.unlock(entity, &message_id, &lock_token),
+                "suspended" => broker.dead_letter(entity, &message_id, &lock_token, &text("deadletter-reason"), &text("deadletter-description")),
+                "defered" => broker.defer(entity, &message_id, &lock_token),
+                other => return (400, format!("Unsupported disposition status {}", other), Value::Null),
+            };
+            if let Err(e) = result {
+                return (broker_error_status(&e), e.to_string(), Value::Null);
+            }
+        }
+        (200, "OK".to_string(), Value::Null)
+    }
+
+    fn schedule(self: &Self, entity: &str, body: &Value) -> (i32, String, Value) {
+        let entries = body.get("messages").and_then(|v| v.as_list()).unwrap_or(&[]);
+        let mut sequence_numbers = Vec::with_capacity(entries.len());
+
+        for entry in entries {
+            let payload = entry.get("message").and_then(|v| v.as_binary()).unwrap_or(&[]);
+            let message = match AmqpMessage::decode(payload) {
+                Ok(message) => message.to_received_message(None),
+                Err(e) => return (400, e.to_string(), Value::Null),
+            };
+            match self.state.broker.lock().unwrap().send(entity, message) {
+                Some(sequence_number) => sequence_numbers.push(Value::Long(sequence_number)),
+                None => return (409, "Duplicate message id".to_string(), Value::Null),
+            }
+        }
+        self.state.message_sent.notify_waiters();
+
+        let body = Value::Map(vec![(Value::string("sequence-numbers"), Value::Array(sequence_numbers))]);
+        (200, "OK".to_string(), body)
+    }
+
+    fn cancel_scheduled(self: &Self, entity: &str, body: &Value) -> (i32, String, Value) {
+        let sequence_numbers = body.get("sequence-numbers").and_then(|v| v.as_list()).unwrap_or(&[]);
+        let mut broker = self.state.broker.lock().unwrap();
+        for sequence_number in sequence_numbers {
+            if let Err(e) = broker.cancel_scheduled(entity, sequence_number.as_i64().unwrap_or(0)) {
+                return (broker_error_status(&e), e.to_string(), Value::Null);
+            }
+        }
+        (200, "OK".to_string(), Value::Null)
+    }
+
+    // The message holding a lock taken on this connection, over a link or a management node.
+    fn locked_message_id(self: &Self, entity: &str, lock_token: &str) -> Option<String> {
+        let on_link = self.unsettled.values()
+            .find(|(e, _, token)| e == entity && token == lock_token)
+            .map(|(_, message_id, _)| message_id.clone());
+        on_link.or_else(|| self.managed_locks.get(lock_token)
+            .filter(|(e, _)| e == entity)
+            .map(|(_, message_id)| message_id.clone()))
+    }
+
+    async fn disposition(self: &mut Self, disposition: &Performative) -> Result<(), AmqpError> {
+        if disposition.field(0).as_bool() != Some(ROLE_RECEIVER) {
+            return Ok(());
+        }
+        let first = disposition.field(1).as_u64().unwrap_or(0) as u32;
+        let last = disposition.field(2).as_u64().map(|n| n as u32).unwrap_or(first);
+        let settled = disposition.field(3).as_bool().unwrap_or(false);
+        let state = disposition.field(4).clone();
+
+        for delivery_id in first..=last {
+            let result = match self.unsettled.remove(&delivery_id) {
+                Some((entity, message_id, lock_token)) => self.apply(&entity, &message_id, &lock_token, &state),
+                None => Err(BrokerError::LockLost(delivery_id.to_string())),
+            };
+
+            if !settled {
+                let state = match result {
+                    Ok(()) => state.clone(),
+                    Err(e) => {
+                        let condition = match e {
+                            BrokerError::MessageNotFound(_) => NOT_FOUND_CONDITION,
+                            BrokerError::LockLost(_) => LOCK_LOST_CONDITION,
+                        };
+                        outcome(REJECTED, vec![error_value(condition, &e.to_string(), vec![])])
+                    },
+                };
+                self.write(Performative::new(DISPOSITION, vec![
+                    Value::Bool(ROLE_SENDER),
+                    Value::Uint(delivery_id),
+                    Value::Null,
+                    Value::Bool(true),
+                    state,
+                ])).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(self: &Self, entity: &str, message_id: &str, lock_token: &str, state: &Value) -> Result<(), BrokerError> {
+        let mut broker = self.state.broker.lock().unwrap();
+        match state.as_described() {
+            Some((ACCEPTED, _)) => broker.complete(entity, message_id, lock_token),
+            Some((REJECTED, fields)) => {
+                let (reason, description) = rejection(fields.as_list().and_then(|f| f.first()).unwrap_or(&Value::Null));
+                broker.dead_letter(entity, message_id, lock_token, &reason, &description)
+            },
+            // Undeliverable here defers the message.
+            Some((MODIFIED, fields)) if fields.as_list().map(|f| field(f, 1).as_bool() == Some(true)).unwrap_or(false) => {
+                broker.defer(entity, message_id, lock_token)
+            },
+            // Released, other modified and unknown outcomes give the message back.
+            _ => broker.unlock(entity, message_id, lock_token),
+        }
+    }
+
+    // Sends available messages on every entity link the client has given credit to.
+    async fn deliver(self: &mut Self) -> Result<(), AmqpError> {
+        let ready: Vec<(u32, String, bool)> = self.links.iter()
+            .filter(|(_, link)| link.client_role == ROLE_RECEIVER && link.credit > 0)
+            .filter_map(|(client_handle, link)| match &link.node {
+                Node::Entity(entity) => Some((*client_handle, entity.clone(), link.settled)),
+                _ => None,
+            })
+            .collect();
+
+        for (client_handle, entity, settled) in ready {
+            while self.links.get(&client_handle).map(|link| link.credit > 0).unwrap_or(false) {
+                let received = {
+                    let mut broker = self.state.broker.lock().unwrap();
+                    if settled { broker.receive_and_delete(&entity) } else { broker.peek_lock(&entity) }
+                };
+                let message = match received {
+                    Some(message) => message,
+                    None => break,
+                };
+
+                let lock_token = message.properties.lock_token.clone().unwrap_or_default();
+                let tag = message::tag_from_lock_token(&lock_token).unwrap_or_else(|| Uuid::new_v4().as_bytes().to_vec());
+                if !settled {
+                    let message_id = message.properties.message_id.clone().unwrap_or_default();
+                    self.unsettled.insert(self.next_delivery_id, (entity.clone(), message_id, lock_token));
+                }
+                self.send_delivery(client_handle, tag, settled, AmqpMessage::from_received_message(&message).encode()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_delivery(self: &mut Self, client_handle: u32, tag: Vec<u8>, settled: bool, payload: Vec<u8>) -> Result<(), AmqpError> {
+        let link = match self.links.get_mut(&client_handle) {
+            Some(link) => link,
+            None => return Ok(()),
+        };
+        link.credit = link.credit.saturating_sub(1);
+        link.delivery_count = link.delivery_count.wrapping_add(1);
+        let handle = link.handle;
+
+        let delivery_id = self.next_delivery_id;
+        self.next_delivery_id = self.next_delivery_id.wrapping_add(1);
+
+        let performative = |more: bool| Performative::new(TRANSFER, vec![
+            Value::Uint(handle),
+            Value::Uint(delivery_id),
+            Value::Binary(tag.clone()),
+            Value::Uint(0),
+            Value::Bool(settled),
+            Value::Bool(more),
+        ]);
+        let overhead = 8 + Frame::amqp(0, performative(true)).encode().len();
+        let chunk_size = (self.max_frame_size as usize).saturating_sub(overhead).max(1);
+
+        let chunks: Vec<&[u8]> = payload.chunks(chunk_size).collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let mut frame = Frame::amqp(self.channel, performative(index + 1 < chunks.len()));
+            frame.payload = chunk.to_vec();
+            self.writer.write_all(&frame.encode()).await?;
+        }
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn grant_credit(self: &mut Self, client_handle: u32) -> Result<(), AmqpError> {
+        let link = self.links.get_mut(&client_handle).unwrap();
+        link.credit = SENDER_CREDIT;
+        let (handle, delivery_count) = (link.handle, link.delivery_count);
+        self.write(Performative::new(FLOW, vec![
+            Value::Uint(0),
+            Value::Uint(i32::MAX as u32),
+            Value::Uint(0),
+            Value::Uint(i32::MAX as u32),
+            Value::Uint(handle),
+            Value::Uint(delivery_count),
+            Value::Uint(SENDER_CREDIT),
+        ])).await
+    }
+
+    fn is_authorized(self: &Self) -> bool {
+        self.token.as_ref().map(|token| token_is_valid(&self.state, token)).unwrap_or(false)
+    }
+
+    async fn write(self: &mut Self, performative: Performative) -> Result<(), AmqpError> {
+        write_frame(&mut self.writer, &Frame::amqp(self.channel, performative)).await
+    }
+}
+
+// The dead-letter reason and description of a rejected outcome's error. Service Bus
+// reads them from the info map of a dead-letter error, and otherwise uses the condition.
+fn rejection(error: &Value) -> (String, String) {
+    let (condition, description) = error_parts(error).unwrap_or(("Rejected".into(), String::new()));
+    if condition != DEAD_LETTER_CONDITION {
+        return (condition, description);
+    }
+    let info = |key: &str| error_info(error, key).map(|v| v.to_text());
+    (
+        info(DEAD_LETTER_REASON_PROPERTY).unwrap_or(condition),
+        info(DEAD_LETTER_DESCRIPTION_PROPERTY).unwrap_or(description),
+    )
+}
+
+fn broker_error_status(error: &BrokerError) -> i32 {
+    match error {
+        BrokerError::MessageNotFound(_) => 404,
+        BrokerError::LockLost(_) => 410,
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::emulator::EmulatorConfig;
+use crate::transport::broker::{Broker, BrokerError};
+use crate::mazure::aadclient::AADCredentials;
+use crate::mazure::sbclient::{
+    BATCH_CONTENT_TYPE, BatchedMessage, BrokerReceiveProperties, Message,
+    decode_user_property_value, encode_user_property_value
+};
+
+// Request headers that are never user properties.
+static STANDARD_REQUEST_HEADERS: &[&str] = &[
+    "accept",
+    "accept-encoding",
+    "authorization",
+    "brokerproperties",
+    "connection",
+    "content-length",
+    "content-type",
+    "expect",
+    "host",
+    "transfer-encoding",
+    "user-agent",
+];
+
+// Longest a waiting receive sleeps before looking again, so scheduled messages and
+// expired locks are noticed without a send to wake it up.
+static POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub(crate) struct EmulatorState {
+    pub(crate) broker: Mutex<Broker>,
+    pub(crate) tokens: Mutex<HashMap<String, u64>>,
+    pub(crate) lost_send_responses: AtomicU32,
+    config: EmulatorConfig,
+    pub(crate) message_sent: Notify,
+}
+
+impl EmulatorState {
+    pub(crate) fn new(config: EmulatorConfig) -> Self {
+        EmulatorState {
+            broker: Mutex::new(Broker::new(config.broker.clone())),
+            tokens: Mutex::new(HashMap::new()),
+            lost_send_responses: AtomicU32::new(0),
+            config,
+            message_sent: Notify::new(),
+        }
+    }
+}
+
+pub(crate) async fn handle(state: Arc<EmulatorState>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match route(&state, req).await {
+        Ok(response) => response,
+        Err(status) => empty_response(status),
+    };
+    Ok(response)
+}
+
+async fn route(state: &EmulatorState, req: Request<Body>) -> Result<Response<Body>, StatusCode> {
+    let segments: Vec<String> = req.uri().path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| urlencoding::decode(segment).map(|s| s.into_owned()))
+        .collect::<Result<_, _>>()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let n = segments.len();
+    if n >= 3 && segments[n - 2] == "oauth2" && segments[n - 1] == "token" {
+        let tenant_id = segments[..n - 2].join("/");
+        return issue_token(state, &tenant_id, req).await;
+    }
+
+    // The entity path may itself contain slashes, e.g. the dead-letter sub-queue.
+    let messages_index = segments.iter()
+        .rposition(|segment| segment == "messages")
+        .filter(|index| *index > 0 && n - index <= 3)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let entity = segments[..messages_index].join("/");
+    let rest = &segments[messages_index + 1..];
+
+    authorize(state, req.headers())?;
+
+    match (req.method(), rest) {
+        (&Method::POST, []) => send(state, &entity, req).await,
+        (&Method::POST, [head]) if head == "head" => receive(state, &entity, &req, true).await,
+        (&Method::DELETE, [head]) if head == "head" => receive(state, &entity, &req, false).await,
+        (method, [message_id, lock_token]) => settle(state, &entity, method, message_id, lock_token),
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+fn authorize(state: &EmulatorState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let token = headers.get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    match token_is_valid(state, token) {
+        true => Ok(()),
+        false => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Whether the token was handed out by the token endpoint and has not expired.
+pub(crate) fn token_is_valid(state: &EmulatorState, token: &str) -> bool {
+    match state.tokens.lock().unwrap().get(token) {
+        Some(expires) => *expires > unix_now(),
+        None => false,
+    }
+}
+
+async fn issue_token(state: &EmulatorState, tenant_id: &str, req: Request<Body>) -> Result<Response<Body>, StatusCode> {
+    // The form may be in the body or the query string.
+    let query = req.uri().query().unwrap_or("").to_string();
+    let body = hyper::body::to_bytes(req.into_body()).await.map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut form = parse_form(&query);
+    form.extend(parse_form(&String::from_utf8_lossy(&body)));
+
+    if form.get("grant_type").map(|s| s.as_str()) != Some("client_credentials") {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if let Some(credentials) = &state.config.credentials {
+        let presented = AADCredentials::new(
+            tenant_id,
+            form.get("client_id").cloned().unwrap_or_default(),
+            form.get("client_secret").cloned().unwrap_or_default());
+
+        if &presented != credentials {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    let access_token = Uuid::new_v4().to_string();
+    let expires_on = unix_now() + state.config.token_lifetime.as_secs();
+    state.tokens.lock().unwrap().insert(access_token.clone(), expires_on);
+
+    let body = serde_json::json!({
+        "token_type": "Bearer",
+        "expires_on": expires_on.to_string(),
+        "access_token": access_token,
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn send(state: &EmulatorState, entity: &str, req: Request<Body>) -> Result<Response<Body>, StatusCode> {
+    let content_type = header_text(req.headers(), "Content-Type").unwrap_or_default();
+
+    let messages = if content_type.starts_with(BATCH_CONTENT_TYPE) {
+        let body = hyper::body::to_bytes(req.into_body()).await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        let batch: Vec<BatchedMessage<BrokerReceiveProperties>> = serde_json::from_slice(&body)
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        batch.into_iter()
+            .map(|batched| Message {
+                properties: batched.broker_properties,
+                content: batched.body.into_bytes(),
+                content_type: "".into(),
+                user_properties: batched.user_properties.into_iter()
+                    .map(|(name, value)| (name.to_ascii_lowercase(), value))
+                    .collect(),
+            })
+            .collect()
+    }
+    else {
+        let properties = match header_text(req.headers(), "BrokerProperties") {
+            None => BrokerReceiveProperties::new_empty(),
+            Some(json) => serde_json::from_str(&json).map_err(|_| StatusCode::BAD_REQUEST)?,
+        };
+        let user_properties = user_properties_from_request(req.headers())?;
+        let body = hyper::body::to_bytes(req.into_body()).await.map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        vec![Message { properties, content: body.to_vec(), content_type, user_properties }]
+    };
+
+    {
+        let mut broker = state.broker.lock().unwrap();
+        for message in messages {
+            broker.send(entity, message);
+        }
+    }
+    state.message_sent.notify_waiters();
+
+    let lose_response = state.lost_send_responses
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok();
+    if lose_response {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    Ok(empty_response(StatusCode::CREATED))
+}
+
+async fn receive(state: &EmulatorState, entity: &str, req: &Request<Body>, peek_lock: bool) -> Result<Response<Body>, StatusCode> {
+    let timeout = req.uri().query()
+        .map(parse_form)
+        .and_then(|query| query.get("timeout").and_then(|t| t.parse::<u64>().ok()))
+        .map(Duration::from_secs)
+        .unwrap_or(state.config.default_receive_timeout);
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        // Register interest before looking so a send in between is not missed.
+        let message_sent = state.message_sent.notified();
+
+        let received = {
+            let mut broker = state.broker.lock().unwrap();
+            if peek_lock { broker.peek_lock(entity) } else { broker.receive_and_delete(entity) }
+        };
+
+        if let Some(message) = received {
+            let status = if peek_lock { StatusCode::CREATED } else { StatusCode::OK };
+            return message_response(status, &message);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(empty_response(StatusCode::NO_CONTENT));
+        }
+
+        let _ = tokio::time::timeout(POLL_INTERVAL.min(deadline - now), message_sent).await;
+    }
+}
+
+fn settle(state: &EmulatorState, entity: &str, method: &Method, message_id: &str, lock_token: &str) -> Result<Response<Body>, StatusCode> {
+    let mut broker = state.broker.lock().unwrap();
+
+    let result = match *method {
+        Method::POST => broker.renew_lock(entity, message_id, lock_token).map(|_| ()),
+        Method::PUT => broker.unlock(entity, message_id, lock_token),
+        Method::DELETE => broker.complete(entity, message_id, lock_token),
+        _ => return Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    match result {
+        Ok(()) => Ok(empty_response(StatusCode::OK)),
+        Err(BrokerError::MessageNotFound(_)) => Err(StatusCode::NOT_FOUND),
+        Err(BrokerError::LockLost(_)) => Err(StatusCode::GONE),
+    }
+}
+
+fn message_response(status: StatusCode, message: &Message<BrokerReceiveProperties>) -> Result<Response<Body>, StatusCode> {
+    let props_json = message.properties.to_json().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut builder = Response::builder()
+        .status(status)
+        .header("BrokerProperties", props_json);
+
+    if !message.content_type.is_empty() {
+        builder = builder.header("Content-Type", &message.content_type);
+    }
+
+    for (name, value) in &message.user_properties {
+        let name = HeaderName::from_bytes(name.as_bytes());
+        let value = encode_user_property_value(value).ok().and_then(|v| HeaderValue::from_str(&v).ok());
+        if let (Ok(name), Some(value)) = (name, value) {
+            builder = builder.header(name, value);
+        }
+    }
+
+    builder
+        .body(Body::from(message.content.clone()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn user_properties_from_request(headers: &HeaderMap) -> Result<BTreeMap<String, String>, StatusCode> {
+    let mut user_properties = BTreeMap::new();
+
+    for (name, value) in headers {
+        if STANDARD_REQUEST_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        let text = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?;
+        user_properties.insert(name.as_str().to_string(), decode_user_property_value(text));
+    }
+
+    Ok(user_properties)
+}
+
+fn header_text(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string())
+}
+
+fn parse_form(text: &str) -> HashMap<String, String> {
+    text.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter_map(|(name, value)| {
+            let name = urlencoding::decode(&name.replace('+', " ")).ok()?.into_owned();
+            let value = urlencoding::decode(&value.replace('+', " ")).ok()?.into_owned();
+            Some((name, value))
+        })
+        .collect()
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
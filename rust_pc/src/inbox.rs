@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum InboxError {
+    #[error("Inbox store error: {0}")]
+    StoreError(String),
+}
+
+impl From<rusqlite::Error> for InboxError {
+    fn from(e: rusqlite::Error) -> Self {
+        InboxError::StoreError(e.to_string())
+    }
+}
+
+/// Remembers the ids of messages that were processed, so redeliveries of them can be
+/// completed without running their side effects again.
+#[async_trait(?Send)]
+pub trait InboxStore {
+    /// Whether the message was recorded as processed within the retention period.
+    async fn contains(&self, message_id: &str) -> Result<bool, InboxError>;
+
+    /// Records the message as processed.
+    async fn record(&self, message_id: &str) -> Result<(), InboxError>;
+}
+
+impl fmt::Debug for dyn InboxStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InboxStore")
+    }
+}
+
+#[derive(Debug)]
+struct LruEntries {
+    // Message id to when it was recorded and its position in `order`.
+    entries: HashMap<String, (DateTime<Utc>, u64)>,
+    order: BTreeMap<u64, String>,
+    next_position: u64,
+}
+
+/// Keeps the most recently processed ids in memory. Only catches redeliveries to the
+/// same process, and forgets everything on restart.
+#[derive(Debug)]
+pub struct MemoryInbox {
+    capacity: usize,
+    retention: Duration,
+    lru: Mutex<LruEntries>,
+}
+
+impl MemoryInbox {
+    pub fn new(capacity: usize, retention: Duration) -> Self {
+        MemoryInbox {
+            capacity: capacity.max(1),
+            retention,
+            lru: Mutex::new(LruEntries { entries: HashMap::new(), order: BTreeMap::new(), next_position: 0 }),
+        }
+    }
+
+    pub fn len(self: &Self) -> usize {
+        self.lru.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait(?Send)]
+impl InboxStore for MemoryInbox {
+    async fn contains(&self, message_id: &str) -> Result<bool, InboxError> {
+        let lru = self.lru.lock().unwrap();
+        match lru.entries.get(message_id) {
+            Some((recorded, _)) => Ok(*recorded + self.retention > Utc::now()),
+            None => Ok(false),
+        }
+    }
+
+    async fn record(&self, message_id: &str) -> Result<(), InboxError> {
+        let mut lru = self.lru.lock().unwrap();
+        let position = lru.next_position;
+        lru.next_position += 1;
+
+        if let Some((_, previous)) = lru.entries.insert(message_id.to_string(), (Utc::now(), position)) {
+            lru.order.remove(&previous);
+        }
+        lru.order.insert(position, message_id.to_string());
+
+        while lru.entries.len() > self.capacity {
+            match lru.order.pop_first() {
+                Some((_, oldest)) => { lru.entries.remove(&oldest); },
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps processed ids in a SQLite database, so redeliveries are caught across restarts
+/// and by every consumer sharing the database file.
+#[derive(Debug)]
+pub struct SqliteInbox {
+    conn: Mutex<Connection>,
+    retention: Duration,
+}
+
+impl SqliteInbox {
+    pub fn open(path: impl AsRef<Path>, retention: Duration) -> Result<Self, InboxError> {
+        SqliteInbox::new(Connection::open(path)?, retention)
+    }
+
+    pub fn new(conn: Connection, retention: Duration) -> Result<Self, InboxError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS inbox (
+                message_id TEXT PRIMARY KEY,
+                processed_at TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS inbox_processed_at ON inbox (processed_at);")?;
+        Ok(SqliteInbox { conn: Mutex::new(conn), retention })
+    }
+
+    /// Deletes ids older than the retention period. Returns the number deleted.
+    pub fn purge(self: &Self) -> Result<usize, InboxError> {
+        let cutoff = Utc::now() - self.retention;
+        let count = self.conn.lock().unwrap().execute("DELETE FROM inbox WHERE processed_at <= ?1", params![cutoff])?;
+        Ok(count)
+    }
+}
+
+#[async_trait(?Send)]
+impl InboxStore for SqliteInbox {
+    async fn contains(&self, message_id: &str) -> Result<bool, InboxError> {
+        let processed_at: Option<DateTime<Utc>> = self.conn.lock().unwrap()
+            .query_row("SELECT processed_at FROM inbox WHERE message_id = ?1", params![message_id], |row| row.get(0))
+            .optional()?;
+        match processed_at {
+            Some(processed_at) => Ok(processed_at + self.retention > Utc::now()),
+            None => Ok(false),
+        }
+    }
+
+    async fn record(&self, message_id: &str) -> Result<(), InboxError> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO inbox (message_id, processed_at) VALUES (?1, ?2)",
+            params![message_id, Utc::now()])?;
+        self.purge()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+// The code base spells out `self: &Self` receivers.
+#![allow(clippy::needless_arbitrary_self_type)]
+
+pub mod mazure;
+pub mod inbox;
+pub mod messages;
+pub mod middleware;
+pub mod producer;
+pub mod received;
+pub mod consumer;
+pub mod outbox;
+pub mod poison;
+pub mod router;
+pub mod shutdown;
+pub mod spool;
+mod stored_message;
+pub mod streaming;
+pub mod transport;
+pub mod typed;
+pub mod verify;
+#[cfg(feature = "emulator")]
+pub mod emulator;
@@ -1,19 +1,186 @@
-mod mazure;
-mod messages;
-mod producer;
-mod consumer;
+// The code base spells out `self: &Self` receivers.
+#![allow(clippy::needless_arbitrary_self_type)]
 
 use std::error::Error;
+use std::process::ExitCode;
+use std::rc::Rc;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::Parser;
-use mazure::aadclient::{AADClient, AADCredentials};
-use mazure::sbclient::{SERVICE_BUS_RESOURCE, AzureServiceBusClient};
+use qexample::{consumer, outbox, producer, verify};
+use qexample::transport::broker::{BrokerConfig, DEAD_LETTER_QUEUE_SUFFIX};
+use qexample::inbox::{InboxStore, MemoryInbox, SqliteInbox};
+use qexample::mazure::aadclient::{AADClient, AADCredentials};
+use qexample::mazure::amqp::AmqpOptions;
+use qexample::mazure::sbclient::{SERVICE_BUS_RESOURCE, AzureServiceBusClient};
+use qexample::consumer::ConsumerOptions;
+use qexample::mazure::claimcheck::{ClaimCheckOptions, LocalBlobStore};
+use qexample::mazure::codec::Format;
+use qexample::mazure::compression::{CompressionAlgorithm, CompressionOptions};
+use qexample::mazure::encryption::LocalKeyProvider;
+use qexample::mazure::limits::{ServiceTier, SizeLimits};
+use qexample::mazure::sending::{MessageIdStrategy, SendRetryPolicy};
+use qexample::mazure::signing::{MessageSigner, SignatureStatus, TrustedKeys};
+use qexample::outbox::{OutboxRelay, OutboxStatus};
+use qexample::poison::{ErrorClass, PoisonPolicy, RetryStrategy};
+use qexample::shutdown::Shutdown;
+use qexample::spool::{SpoolLimits, StoreAndForward};
+use qexample::transport::QueueTransport;
+use qexample::transport::sqlite::SqliteQueue;
+
+// How long a receive from a local queue waits for a message, so an idle consumer does
+// not spin.
+const LOCAL_RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Debug)]
 enum Mode {
     Producer,
-    Consumer
+    Consumer,
+    /// Reports the signature status of the next message without consuming it. Over REST
+    /// this counts as a delivery of the message.
+    Verify,
+    /// Sends the messages in the --outbox database until stopped.
+    Relay,
+    /// Lists the rows in the --outbox database.
+    Outbox,
+    /// Makes failed rows in the --outbox database pending again.
+    Requeue,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Debug)]
+enum Retry {
+    Abandon,
+    Backoff
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Debug)]
+enum MessageIds {
+    Service,
+    Random,
+    Uuid7,
+    ContentHash,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Debug)]
+enum Transport {
+    Rest,
+    Amqp,
+}
+
+/// Body formats, as `--format` spells them.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Debug)]
+enum BodyFormat {
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl From<BodyFormat> for Format {
+    fn from(format: BodyFormat) -> Self {
+        match format {
+            BodyFormat::Json => Format::Json,
+            #[cfg(feature = "msgpack")]
+            BodyFormat::MessagePack => Format::MessagePack,
+            #[cfg(feature = "cbor")]
+            BodyFormat::Cbor => Format::Cbor,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Debug)]
+enum Compression {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl From<Compression> for CompressionAlgorithm {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Gzip => CompressionAlgorithm::Gzip,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => CompressionAlgorithm::Zstd,
+            #[cfg(feature = "brotli")]
+            Compression::Brotli => CompressionAlgorithm::Brotli,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Debug)]
+enum Tier {
+    Standard,
+    Premium,
+}
+
+impl From<Tier> for ServiceTier {
+    fn from(tier: Tier) -> Self {
+        match tier {
+            Tier::Standard => ServiceTier::Standard,
+            Tier::Premium => ServiceTier::Premium,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Debug)]
+enum RowStatus {
+    Pending,
+    Dispatched,
+    Failed,
+}
+
+impl From<RowStatus> for OutboxStatus {
+    fn from(status: RowStatus) -> Self {
+        match status {
+            RowStatus::Pending => OutboxStatus::Pending,
+            RowStatus::Dispatched => OutboxStatus::Dispatched,
+            RowStatus::Failed => OutboxStatus::Failed,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Debug)]
+enum FailureClass {
+    Transient,
+    Permanent,
+    Decode,
+}
+
+impl From<FailureClass> for ErrorClass {
+    fn from(class: FailureClass) -> Self {
+        match class {
+            FailureClass::Transient => ErrorClass::Transient,
+            FailureClass::Permanent => ErrorClass::Permanent,
+            FailureClass::Decode => ErrorClass::Decode,
+        }
+    }
+}
+
+/// Where the queue lives.
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Backend {
+    ServiceBus,
+    /// A SQLite database file, so everything runs locally.
+    Local(String),
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "servicebus" {
+            return Ok(Backend::ServiceBus);
+        }
+        match s.strip_prefix("local:") {
+            Some(path) if !path.is_empty() => Ok(Backend::Local(path.into())),
+            _ => Err(format!("expected `servicebus` or `local:<path>`, got `{}`", s)),
+        }
+    }
 }
 
 #[derive(clap::Parser, Debug)]
@@ -22,43 +189,402 @@ struct CommandLineArgs {
     #[arg(short = 'm', long = "mode", )]
     mode: Mode,
 
+    /// Required with the servicebus backend.
     #[arg(short = 'c', long = "credentials", )]
-    credentials_file: String,
+    credentials_file: Option<String>,
 
+    /// Required with the servicebus backend.
     #[arg(short = 'n', long = "namespace", )]
-    service_bus_namespace: String,
+    service_bus_namespace: Option<String>,
 
     #[arg(short = 'q', long = "queue", )]
     queue: String,
 
+    /// `servicebus`, or `local:<path>` for a queue in a local SQLite database.
+    #[arg(long = "backend", default_value = "servicebus", )]
+    backend: Backend,
+
+    /// Seconds a received message stays locked. Local backend only, Service Bus sets it on the queue.
+    #[arg(long = "lock-duration", default_value = "60", )]
+    lock_duration_secs: i64,
+
+    /// Deliveries after which a message is dead-lettered. Local backend only.
+    #[arg(long = "max-delivery-count", default_value = "10", )]
+    max_delivery_count: i32,
+
     #[arg(long = "count", default_value = "1", )]
     count: u32,
+
+    /// Body format used by the producer. The consumer picks the format from the content type.
+    #[arg(long = "format", default_value = "json", )]
+    format: BodyFormat,
+
+    /// Compress sent bodies. Received bodies are always decompressed as needed.
+    #[arg(long = "compression", )]
+    compression: Option<Compression>,
+
+    /// Bodies smaller than this many bytes are not compressed.
+    #[arg(long = "compression-threshold", default_value = "1024", )]
+    compression_threshold: usize,
+
+    /// JSON file of key-encryption keys. Message bodies are encrypted when set.
+    #[arg(long = "encryption-keys", )]
+    encryption_keys: Option<String>,
+
+    /// Tier of the namespace, which sets the message size limits sends are checked against.
+    #[arg(long = "tier", default_value = "standard", )]
+    tier: Tier,
+
+    /// Largest message size configured on a Premium entity, up to 100 MB.
+    #[arg(long = "max-message-size", )]
+    max_message_size: Option<usize>,
+
+    /// Directory claim-checked payloads are stored in. Large bodies are sent through it when set.
+    #[arg(long = "claim-check-dir", )]
+    claim_check_dir: Option<String>,
+
+    /// Bodies larger than this many bytes are claim-checked.
+    #[arg(long = "claim-check-threshold", default_value = "196608", )]
+    claim_check_threshold: usize,
+
+    /// JSON key file used to sign sent messages.
+    #[arg(long = "signing-key", )]
+    signing_key: Option<String>,
+
+    /// JSON file of keys whose signatures are trusted. Received messages are verified when set.
+    #[arg(long = "trusted-keys", )]
+    trusted_keys: Option<String>,
+
+    /// Accept unsigned messages when verifying. Signed ones must still be valid.
+    #[arg(long = "allow-unsigned", )]
+    allow_unsigned: bool,
+
+    /// How ids are assigned to sent messages.
+    #[arg(long = "message-id", default_value = "random", )]
+    message_ids: MessageIds,
+
+    /// Retries of failed sends. Only safe against duplicates with duplicate detection enabled on the queue.
+    #[arg(long = "send-retries", default_value = "0", )]
+    send_retries: u32,
+
+    /// SQLite outbox database. The producer adds messages to it instead of sending them.
+    #[arg(long = "outbox", )]
+    outbox: Option<String>,
+
+    /// Only list outbox rows with this status.
+    #[arg(long = "outbox-status", )]
+    outbox_status: Option<RowStatus>,
+
+    /// Outbox rows to requeue. All failed rows are requeued if not given.
+    #[arg(long = "outbox-ids", value_delimiter = ',', )]
+    outbox_ids: Vec<i64>,
+
+    /// Relay outbox rows with batch sends.
+    #[arg(long = "outbox-batch", )]
+    outbox_batch: bool,
+
+    /// SQLite database the producer spools messages to while they cannot be sent.
+    #[arg(long = "spool", )]
+    spool: Option<String>,
+
+    /// Most messages kept in the spool.
+    #[arg(long = "spool-max-messages", default_value = "10000", )]
+    spool_max_messages: usize,
+
+    /// Most bytes kept in the spool.
+    #[arg(long = "spool-max-bytes", default_value = "104857600", )]
+    spool_max_bytes: usize,
+
+    /// Seconds after which spooled messages are dropped instead of sent.
+    #[arg(long = "spool-max-age", default_value = "86400", )]
+    spool_max_age_secs: i64,
+
+    /// Where the consumer records processed message ids: `memory` or a SQLite database path.
+    #[arg(long = "inbox", )]
+    inbox: Option<String>,
+
+    /// Seconds processed message ids are remembered for.
+    #[arg(long = "inbox-retention", default_value = "86400", )]
+    inbox_retention_secs: i64,
+
+    /// Most message ids kept by the memory inbox.
+    #[arg(long = "inbox-capacity", default_value = "10000", )]
+    inbox_capacity: usize,
+
+    /// Service Bus endpoint to use instead of the namespace's, e.g. a local emulator.
+    #[arg(long = "endpoint", )]
+    endpoint: Option<String>,
+
+    /// Protocol used to talk to Service Bus.
+    #[arg(long = "transport", default_value = "rest", )]
+    transport: Transport,
+
+    /// AMQP endpoint to use instead of the namespace's, e.g. amqp://127.0.0.1:5672.
+    #[arg(long = "amqp-endpoint", )]
+    amqp_endpoint: Option<String>,
+
+    /// Messages the AMQP receiver locks ahead of being asked for.
+    #[arg(long = "prefetch", default_value = "0", )]
+    prefetch: u32,
+
+    /// AAD endpoint to use instead of login.microsoftonline.com, e.g. a local emulator.
+    #[arg(long = "oauth-endpoint", )]
+    oauth_endpoint: Option<String>,
+
+    /// Seconds the consumer waits for in-flight work after SIGINT/SIGTERM before unlocking it.
+    #[arg(long = "shutdown-grace", default_value = "30", )]
+    shutdown_grace_secs: u64,
+
+    /// Entity that dead-lettered messages are forwarded to. Defaults to the queue's <queue>/$DeadLetterQueue sub-queue.
+    #[arg(long = "dead-letter-queue", )]
+    dead_letter_queue: Option<String>,
+
+    /// Failed attempts after which a message is dead-lettered.
+    #[arg(long = "max-attempts", default_value = "5", )]
+    max_attempts: u32,
+
+    /// Error classes that are dead-lettered on the first failure.
+    #[arg(long = "dead-letter-on", value_delimiter = ',', default_value = "permanent,decode", )]
+    dead_letter_on: Vec<FailureClass>,
+
+    /// How failed messages are retried.
+    #[arg(long = "retry", default_value = "abandon", )]
+    retry: Retry,
+
+    /// First backoff delay in seconds when retrying with backoff.
+    #[arg(long = "backoff-initial", default_value = "5", )]
+    backoff_initial_secs: u64,
+
+    /// Longest backoff delay in seconds when retrying with backoff.
+    #[arg(long = "backoff-max", default_value = "300", )]
+    backoff_max_secs: u64,
 }
 
 impl CommandLineArgs {
+    fn create_transport(self: &Self) -> Result<Box<dyn QueueTransport>, Box<dyn Error>> {
+        match &self.backend {
+            Backend::ServiceBus => Ok(Box::new(self.create_sb_client()?)),
+            Backend::Local(path) => {
+                let ignored = self.service_bus_only_flags();
+                if !ignored.is_empty() {
+                    return Err(format!("{} only apply to --backend servicebus", ignored.join(", ")).into());
+                }
+                let config = BrokerConfig {
+                    lock_duration: chrono::Duration::seconds(self.lock_duration_secs),
+                    max_delivery_count: self.max_delivery_count,
+                    ..BrokerConfig::default()
+                };
+                let queue = SqliteQueue::open(path, &self.queue)?
+                    .with_config(config)
+                    .with_receive_timeout(LOCAL_RECEIVE_TIMEOUT);
+                Ok(Box::new(queue))
+            }
+        }
+    }
+
     fn create_sb_client(self: &Self) -> Result<AzureServiceBusClient, Box<dyn Error>> {
-        let aad_creds = AADCredentials::from_file(&self.credentials_file)?;
+        if self.backend != Backend::ServiceBus {
+            return Err(format!("--mode {:?} needs --backend servicebus", self.mode).into());
+        }
+        let credentials_file = self.credentials_file.as_ref().ok_or("--credentials is required")?;
+        let service_bus_namespace = self.service_bus_namespace.as_ref().ok_or("--namespace is required")?;
+        let aad_creds = AADCredentials::from_file(credentials_file)?;
 
         let http_client = reqwest::Client::new();
 
-        let aad_client = Arc::new(AADClient::new(http_client.clone(), aad_creds, SERVICE_BUS_RESOURCE, Option::None));
-        Ok(AzureServiceBusClient::new(Box::new(aad_client), http_client, &self.service_bus_namespace, &self.queue))
+        let aad_client = Arc::new(AADClient::new(http_client.clone(), aad_creds, SERVICE_BUS_RESOURCE, self.oauth_endpoint.as_deref()));
+        let dead_letter_queue = match &self.dead_letter_queue {
+            Some(dead_letter_queue) => dead_letter_queue.clone(),
+            None => format!("{}{}", self.queue, DEAD_LETTER_QUEUE_SUFFIX)
+        };
+
+        let mut sb_client = AzureServiceBusClient::new(Box::new(aad_client), http_client, service_bus_namespace, &self.queue)
+            .with_dead_letter_path(dead_letter_queue)
+            .with_size_limits(self.size_limits()?)
+            .with_message_ids(self.message_ids())
+            .with_send_retry(SendRetryPolicy { max_attempts: self.send_retries + 1, ..SendRetryPolicy::default() })
+            .with_compression(CompressionOptions {
+                algorithm: self.compression.map(CompressionAlgorithm::from),
+                threshold: self.compression_threshold,
+                ..CompressionOptions::default()
+            });
+
+        if let Some(endpoint) = &self.endpoint {
+            sb_client = sb_client.with_endpoint(endpoint);
+        }
+
+        if self.transport == Transport::Amqp {
+            let options = AmqpOptions { endpoint: self.amqp_endpoint.clone(), ..AmqpOptions::default() };
+            sb_client = sb_client.with_amqp(options.with_prefetch(self.prefetch));
+        }
+
+        if let Some(encryption_keys) = &self.encryption_keys {
+            sb_client = sb_client.with_encryption(Box::new(LocalKeyProvider::from_file(encryption_keys)?));
+        }
+
+        if let Some(claim_check_dir) = &self.claim_check_dir {
+            let store = LocalBlobStore::new(claim_check_dir)?;
+            sb_client = sb_client.with_claim_check(ClaimCheckOptions {
+                threshold: self.claim_check_threshold,
+                ..ClaimCheckOptions::new(Box::new(store))
+            });
+        }
+
+        if let Some(signing_key) = &self.signing_key {
+            sb_client = sb_client.with_signing(MessageSigner::from_file(signing_key)?);
+        }
+
+        if let Some(trusted_keys) = self.trusted_keys()? {
+            sb_client = sb_client.with_verification(trusted_keys);
+        }
+
+        Ok(sb_client)
+    }
+
+    // The flags set on the command line that only configure the Service Bus client.
+    fn service_bus_only_flags(self: &Self) -> Vec<&'static str> {
+        let flags = [
+            ("--compression", self.compression.is_some()),
+            ("--encryption-keys", self.encryption_keys.is_some()),
+            ("--signing-key", self.signing_key.is_some()),
+            ("--trusted-keys", self.trusted_keys.is_some()),
+            ("--claim-check-dir", self.claim_check_dir.is_some()),
+            ("--message-id", self.message_ids != MessageIds::Random),
+            ("--send-retries", self.send_retries != 0),
+            ("--tier", self.tier != Tier::Standard),
+            ("--max-message-size", self.max_message_size.is_some()),
+            ("--dead-letter-queue", self.dead_letter_queue.is_some()),
+            ("--transport", self.transport != Transport::Rest),
+        ];
+        flags.iter().filter(|(_, set)| *set).map(|(flag, _)| *flag).collect()
+    }
+
+    fn size_limits(self: &Self) -> Result<SizeLimits, Box<dyn Error>> {
+        match (self.tier, self.max_message_size) {
+            (_, None) => Ok(SizeLimits::for_tier(self.tier.into())),
+            (Tier::Premium, Some(max_message_size)) => Ok(SizeLimits::premium(max_message_size)?),
+            (Tier::Standard, Some(_)) => Err("--max-message-size needs --tier premium".into()),
+        }
+    }
+
+    fn message_ids(self: &Self) -> MessageIdStrategy {
+        match self.message_ids {
+            MessageIds::Service => MessageIdStrategy::Service,
+            MessageIds::Random => MessageIdStrategy::Random,
+            MessageIds::Uuid7 => MessageIdStrategy::UuidV7,
+            MessageIds::ContentHash => MessageIdStrategy::ContentHash,
+        }
+    }
+
+    fn open_outbox(self: &Self) -> Result<rusqlite::Connection, Box<dyn Error>> {
+        let path = self.outbox.as_ref().ok_or("--outbox is required")?;
+        let conn = rusqlite::Connection::open(path)?;
+        outbox::init(&conn)?;
+        Ok(conn)
+    }
+
+    fn trusted_keys(self: &Self) -> Result<Option<TrustedKeys>, Box<dyn Error>> {
+        let mut trusted_keys = match &self.trusted_keys {
+            None => return Ok(None),
+            Some(path) => TrustedKeys::from_file(path)?,
+        };
+        trusted_keys.require_signature = !self.allow_unsigned;
+        Ok(Some(trusted_keys))
+    }
+
+    fn inbox(self: &Self) -> Result<Option<Rc<dyn InboxStore>>, Box<dyn Error>> {
+        let retention = chrono::Duration::seconds(self.inbox_retention_secs);
+        match self.inbox.as_deref() {
+            None => Ok(None),
+            Some("memory") => Ok(Some(Rc::new(MemoryInbox::new(self.inbox_capacity, retention)))),
+            Some(path) => Ok(Some(Rc::new(SqliteInbox::open(path, retention)?))),
+        }
+    }
+
+    fn consumer_options(self: &Self) -> Result<ConsumerOptions, Box<dyn Error>> {
+        let retry = match self.retry {
+            Retry::Abandon => RetryStrategy::Abandon,
+            Retry::Backoff => RetryStrategy::Backoff {
+                initial: Duration::from_secs(self.backoff_initial_secs),
+                max: Duration::from_secs(self.backoff_max_secs),
+            }
+        };
+
+        Ok(ConsumerOptions {
+            shutdown_grace: Duration::from_secs(self.shutdown_grace_secs),
+            poison_policy: PoisonPolicy {
+                max_attempts: self.max_attempts,
+                dead_letter_classes: self.dead_letter_on.iter().map(|&class| class.into()).collect(),
+                retry,
+            },
+            inbox: self.inbox()?,
+            ..ConsumerOptions::default()
+        })
     }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>>{
+async fn main() -> Result<ExitCode, Box<dyn Error>>{
     let args = CommandLineArgs::parse();
-    let sb_client = args.create_sb_client()?;
 
     match args.mode {
         Mode::Consumer => {
-            consumer::run_consumer_loop(&sb_client).await?;
+            let options = args.consumer_options()?;
+            let shutdown = Shutdown::from_signals();
+            let transport = args.create_transport()?;
+            let status = consumer::run_consumer_loop(transport.as_ref(), &options, &shutdown).await?;
+            Ok(status.exit_code())
+        },
+        Mode::Producer if args.outbox.is_some() => {
+            let mut conn = args.open_outbox()?;
+            producer::run_outbox_producer(&mut conn, args.count, args.format.into())?;
+            Ok(ExitCode::SUCCESS)
+        },
+        Mode::Producer if args.spool.is_some() => {
+            let transport = args.create_transport()?;
+            let limits = SpoolLimits {
+                max_messages: args.spool_max_messages,
+                max_bytes: args.spool_max_bytes,
+                max_age: chrono::Duration::seconds(args.spool_max_age_secs),
+            };
+            let spool = StoreAndForward::open(args.spool.as_ref().unwrap(), transport.as_ref())?.with_limits(limits);
+            producer::run_spooling_producer(&spool, args.count, args.format.into()).await?;
+            Ok(ExitCode::SUCCESS)
         },
         Mode::Producer => {
-            producer::run_producer(&sb_client, args.count).await?;
+            let transport = args.create_transport()?;
+            producer::run_producer(transport.as_ref(), args.count, args.format.into()).await?;
+            Ok(ExitCode::SUCCESS)
+        },
+        Mode::Relay => {
+            let sb_client = args.create_sb_client()?;
+            let relay = OutboxRelay::new(args.open_outbox()?, &sb_client)?
+                .with_batch_send(args.outbox_batch);
+            relay.run(&Shutdown::from_signals()).await?;
+            Ok(ExitCode::SUCCESS)
+        },
+        Mode::Outbox => {
+            for row in outbox::list(&args.open_outbox()?, args.outbox_status.map(OutboxStatus::from))? {
+                println!("{:>6} {:<10} attempts={} key={} message_id={} created={} next_attempt={} error={}",
+                    row.id, format!("{:?}", row.status), row.attempts,
+                    row.ordering_key.as_deref().unwrap_or("-"), row.message_id, row.created_at,
+                    row.next_attempt_at, row.last_error.as_deref().unwrap_or("-"));
+            }
+            Ok(ExitCode::SUCCESS)
+        },
+        Mode::Requeue => {
+            let count = outbox::requeue(&args.open_outbox()?, &args.outbox_ids)?;
+            println!("Requeued {} outbox rows.", count);
+            Ok(ExitCode::SUCCESS)
+        },
+        Mode::Verify => {
+            let sb_client = args.create_sb_client()?;
+            let trusted_keys = args.trusted_keys()?.ok_or("--trusted-keys is required to verify")?;
+            match verify::run_verify(&sb_client, &trusted_keys).await? {
+                None | Some(SignatureStatus::Valid { .. }) => Ok(ExitCode::SUCCESS),
+                Some(SignatureStatus::Unsigned) if args.allow_unsigned => Ok(ExitCode::SUCCESS),
+                Some(_) => Ok(ExitCode::FAILURE),
+            }
         }
     }
-
-    Ok(())
 }
@@ -1,4 +1,16 @@
 pub mod aadclient;
+pub mod amqp;
+pub mod batching;
+pub mod chunking;
+pub mod claimcheck;
+pub mod requestreply;
 pub mod sbclient;
+pub mod sending;
 pub mod client_authentication;
+pub mod codec;
+pub mod compression;
+pub mod encryption;
+pub mod interceptor;
+pub mod limits;
+pub mod signing;
 pub mod opt_date_rfc2822_serialization;
\ No newline at end of file
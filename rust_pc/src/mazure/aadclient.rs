@@ -4,18 +4,19 @@ use std::io::BufReader;
 use std::fs::File;
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use reqwest::RequestBuilder;
 use serde_json;
 use serde::{Serialize, Deserialize};
+use tokio::sync::Mutex;
 
 use crate::mazure::client_authentication::{ClientAuthenticator, AuthenticationError};
 
 /// A library to get AAD application tokens.
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AADCredentials {
     tenant_id: String,
     client_id: String,
@@ -23,6 +24,14 @@ pub struct AADCredentials {
 }
 
 impl AADCredentials {
+    pub fn new(tenant_id: impl Into<String>, client_id: impl Into<String>, secret: impl Into<String>) -> Self {
+        AADCredentials {
+            tenant_id: tenant_id.into(),
+            client_id: client_id.into(),
+            secret: secret.into(),
+        }
+    }
+
     pub fn from_file(path: impl AsRef<Path>) -> Result<AADCredentials, Box<dyn Error>> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
@@ -107,15 +116,15 @@ impl AADClient {
             .await?;
 
         if res.status() != reqwest::StatusCode::OK {
-            return Err(AuthenticationError::AuthenticationAcquisitionError(res.status().to_string()).into())
+            return Err(AuthenticationError::AuthenticationAcquisitionError(res.status().to_string()))
         }
 
         let token_response: AADTokenResponse = res.json().await?;
-        Ok(token_response.to_token()?)
+        token_response.to_token()
     }
 
     pub async fn get_cached_token(self: &Self) -> Result<AADToken, AuthenticationError> {
-        let mut guard = self.cached_token.lock()?;
+        let mut guard = self.cached_token.lock().await;
 
         if let Some(token) = &*guard {
             if !token.is_expired() {
@@ -0,0 +1,261 @@
+pub mod codec;
+pub mod frames;
+pub mod message;
+mod connection;
+
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::mazure::amqp::connection::{AmqpConnection, ManagedMessage, Settlement};
+use crate::mazure::amqp::message::AmqpMessage;
+use crate::mazure::client_authentication::ClientAuthenticator;
+use crate::mazure::sbclient::{AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message};
+
+/// Condition Service Bus uses when settling or renewing a message whose lock is gone.
+pub static LOCK_LOST_CONDITION: &str = "com.microsoft:message-lock-lost";
+
+pub static UNAUTHORIZED_CONDITION: &str = "amqp:unauthorized-access";
+
+pub static NOT_FOUND_CONDITION: &str = "amqp:not-found";
+
+/// Condition of the rejected outcome that dead-letters a message.
+pub static DEAD_LETTER_CONDITION: &str = "com.microsoft:dead-letter";
+
+#[derive(Error, Debug, Clone)]
+pub enum AmqpError {
+    #[error("AMQP I/O error: {0}")]
+    IoError(String),
+
+    #[error("AMQP decode error: {0}")]
+    DecodeError(String),
+
+    #[error("AMQP protocol error: {0}")]
+    ProtocolError(String),
+
+    #[error("AMQP connection closed: {0}")]
+    ConnectionClosed(String),
+
+    #[error("AMQP operation timed out: {0}")]
+    Timeout(String),
+
+    #[error("{condition}: {description}")]
+    Remote { condition: String, description: String },
+}
+
+impl AmqpError {
+    pub fn remote(condition: impl Into<String>, description: impl Into<String>) -> Self {
+        AmqpError::Remote { condition: condition.into(), description: description.into() }
+    }
+}
+
+impl From<std::io::Error> for AmqpError {
+    fn from(e: std::io::Error) -> Self {
+        AmqpError::IoError(e.to_string())
+    }
+}
+
+// Remote errors map onto the HTTP statuses the REST API answers with, so callers can
+// treat both transports alike.
+impl From<AmqpError> for AzureServiceBusError {
+    fn from(e: AmqpError) -> Self {
+        match &e {
+            AmqpError::Remote { condition, .. } if condition == UNAUTHORIZED_CONDITION => AzureServiceBusError::RequestError("401".into()),
+            AmqpError::Remote { condition, .. } if condition == NOT_FOUND_CONDITION => AzureServiceBusError::RequestError("404".into()),
+            AmqpError::Remote { condition, .. } if condition == LOCK_LOST_CONDITION => AzureServiceBusError::RequestError("410".into()),
+            AmqpError::Remote { .. } => AzureServiceBusError::ServiceError(e.to_string()),
+            _ => AzureServiceBusError::CommunicationError(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AmqpOptions {
+    /// `amqps://host[:port]` or `amqp://host[:port]`. Defaults to the namespace over TLS.
+    pub endpoint: Option<String>,
+
+    /// Messages a peek-lock receiver asks for ahead of being received. Prefetched
+    /// messages are locked while they wait, so keep this below what can be processed
+    /// within the lock duration.
+    pub prefetch: u32,
+
+    /// How long a receive waits for a message before returning none.
+    pub receive_timeout: Duration,
+
+    /// How long connecting, attaching links and settling may take.
+    pub operation_timeout: Duration,
+
+    /// How often the token is put on the connection again, so it never expires there.
+    pub token_refresh_interval: Duration,
+}
+
+impl Default for AmqpOptions {
+    fn default() -> Self {
+        AmqpOptions {
+            endpoint: None,
+            prefetch: 0,
+            receive_timeout: Duration::from_secs(1),
+            operation_timeout: Duration::from_secs(30),
+            token_refresh_interval: Duration::from_secs(600),
+        }
+    }
+}
+
+impl AmqpOptions {
+    pub fn with_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn with_prefetch(mut self, prefetch: u32) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    pub fn with_receive_timeout(mut self, receive_timeout: Duration) -> Self {
+        self.receive_timeout = receive_timeout;
+        self
+    }
+}
+
+/// Carries a client's operations over one AMQP connection, opened on first use and
+/// opened again after it fails.
+pub(crate) struct AmqpTransport {
+    endpoint: String,
+    options: AmqpOptions,
+    connection: Mutex<Option<AmqpConnection>>,
+}
+
+impl AmqpTransport {
+    pub(crate) fn new(endpoint: impl Into<String>, options: AmqpOptions) -> Self {
+        AmqpTransport { endpoint: endpoint.into(), options, connection: Mutex::new(None) }
+    }
+
+    pub(crate) async fn send(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, messages: &[Message<BrokerSendProperties>]) -> Result<(), AzureServiceBusError> {
+        let payloads: Vec<Vec<u8>> = messages.iter().map(|m| AmqpMessage::from_send_message(m).encode()).collect();
+        let timeout = self.options.operation_timeout;
+
+        let mut guard = self.connect(authenticator, entity).await?;
+        let result = guard.as_mut().unwrap().send(entity, &payloads, timeout).await;
+        Ok(self.keep_if_usable(&mut guard, result)?)
+    }
+
+    pub(crate) async fn receive(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, peek_lock: bool) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        let mut guard = self.connect(authenticator, entity).await?;
+        let connection = guard.as_mut().unwrap();
+        let result = connection.receive(entity, peek_lock, self.options.prefetch, self.options.receive_timeout, self.options.operation_timeout).await;
+
+        let delivery = match self.keep_if_usable(&mut guard, result)? {
+            None => return Ok(None),
+            Some(delivery) => delivery,
+        };
+
+        let lock_token = if peek_lock { Some(delivery.lock_token()) } else { None };
+        let message = AmqpMessage::decode(&delivery.payload)?.to_received_message(lock_token);
+        Ok(Some(message))
+    }
+
+    pub(crate) async fn renew_lock(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, lock_token: &str) -> Result<DateTime<Utc>, AzureServiceBusError> {
+        let mut guard = self.connect(authenticator, entity).await?;
+        let result = guard.as_mut().unwrap().renew_lock(entity, lock_token, self.options.operation_timeout).await;
+        let expiration = self.keep_if_usable(&mut guard, result)?;
+
+        match Utc.timestamp_millis_opt(expiration).single() {
+            Some(locked_until) => Ok(locked_until),
+            None => Err(AzureServiceBusError::ConversionError(format!("Invalid lock expiration {}", expiration))),
+        }
+    }
+
+    pub(crate) async fn complete(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, lock_token: &str) -> Result<(), AzureServiceBusError> {
+        self.settle(authenticator, entity, lock_token, Settlement::Accepted).await
+    }
+
+    pub(crate) async fn abandon(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, lock_token: &str) -> Result<(), AzureServiceBusError> {
+        self.settle(authenticator, entity, lock_token, Settlement::Released).await
+    }
+
+    pub(crate) async fn dead_letter(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, lock_token: &str, reason: &str, description: &str) -> Result<(), AzureServiceBusError> {
+        let settlement = Settlement::Rejected { reason: reason.to_string(), description: description.to_string() };
+        self.settle(authenticator, entity, lock_token, settlement).await
+    }
+
+    pub(crate) async fn defer(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, lock_token: &str) -> Result<(), AzureServiceBusError> {
+        self.settle(authenticator, entity, lock_token, Settlement::Deferred).await
+    }
+
+    pub(crate) async fn peek(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, from_sequence_number: i64, count: u32) -> Result<Vec<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        let mut guard = self.connect(authenticator, entity).await?;
+        let result = guard.as_mut().unwrap().peek(entity, from_sequence_number, count, self.options.operation_timeout).await;
+        to_received_messages(self.keep_if_usable(&mut guard, result)?)
+    }
+
+    pub(crate) async fn receive_deferred(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, sequence_number: i64) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        let mut guard = self.connect(authenticator, entity).await?;
+        let result = guard.as_mut().unwrap().receive_deferred(entity, &[sequence_number], self.options.operation_timeout).await;
+        Ok(to_received_messages(self.keep_if_usable(&mut guard, result)?)?.pop())
+    }
+
+    /// Schedules the message for its scheduled enqueue time and returns its sequence number.
+    pub(crate) async fn schedule(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, message: &Message<BrokerSendProperties>) -> Result<i64, AzureServiceBusError> {
+        // Scheduled messages are identified by their id, so one is needed.
+        let mut message = message.clone();
+        let message_id = message.properties.message_id.get_or_insert_with(|| Uuid::new_v4().simple().to_string()).clone();
+        let payload = AmqpMessage::from_send_message(&message).encode();
+
+        let mut guard = self.connect(authenticator, entity).await?;
+        let result = guard.as_mut().unwrap().schedule(entity, &[(message_id, payload)], self.options.operation_timeout).await;
+        match self.keep_if_usable(&mut guard, result)?.first() {
+            Some(sequence_number) => Ok(*sequence_number),
+            None => Err(AzureServiceBusError::ConversionError("No sequence number for the scheduled message".into())),
+        }
+    }
+
+    pub(crate) async fn cancel_scheduled(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, sequence_number: i64) -> Result<(), AzureServiceBusError> {
+        let mut guard = self.connect(authenticator, entity).await?;
+        let result = guard.as_mut().unwrap().cancel_scheduled(entity, &[sequence_number], self.options.operation_timeout).await;
+        Ok(self.keep_if_usable(&mut guard, result)?)
+    }
+
+    async fn settle(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str, lock_token: &str, settlement: Settlement) -> Result<(), AzureServiceBusError> {
+        let mut guard = self.connect(authenticator, entity).await?;
+        let result = guard.as_mut().unwrap().settle(entity, lock_token, &settlement, self.options.operation_timeout).await;
+        Ok(self.keep_if_usable(&mut guard, result)?)
+    }
+
+    // Returns the open connection, authorized for the entity.
+    async fn connect(self: &Self, authenticator: &dyn ClientAuthenticator, entity: &str) -> Result<tokio::sync::MutexGuard<'_, Option<AmqpConnection>>, AzureServiceBusError> {
+        let mut guard = self.connection.lock().await;
+        if guard.as_mut().map(|c| c.is_closed()).unwrap_or(true) {
+            *guard = Some(AmqpConnection::open(&self.endpoint, self.options.operation_timeout).await?);
+        }
+
+        let connection = guard.as_mut().unwrap();
+        let audience = connection.audience(entity);
+        if connection.needs_token(&audience, self.options.token_refresh_interval) {
+            let token = authenticator.bearer_token().await?;
+            let result = connection.put_token(&audience, &token, self.options.operation_timeout).await;
+            self.keep_if_usable(&mut guard, result)?;
+        }
+        Ok(guard)
+    }
+
+    // Drops the connection after a failure of the connection itself, so the next
+    // operation opens a new one. Errors reported by the peer leave it open.
+    fn keep_if_usable<T>(self: &Self, connection: &mut Option<AmqpConnection>, result: Result<T, AmqpError>) -> Result<T, AmqpError> {
+        if let Err(e) = &result {
+            if !matches!(e, AmqpError::Remote { .. }) {
+                *connection = None;
+            }
+        }
+        result
+    }
+}
+
+fn to_received_messages(messages: Vec<ManagedMessage>) -> Result<Vec<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+    messages.into_iter()
+        .map(|m| Ok(AmqpMessage::decode(&m.payload)?.to_received_message(m.lock_token)))
+        .collect()
+}
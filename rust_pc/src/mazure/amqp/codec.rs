@@ -0,0 +1,377 @@
+use chrono::{TimeZone, Utc};
+
+use crate::mazure::amqp::AmqpError;
+
+/// An AMQP 1.0 value, covering the types Service Bus uses.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Ubyte(u8),
+    Ushort(u16),
+    Uint(u32),
+    Ulong(u64),
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Double(f64),
+    /// Milliseconds since the Unix epoch.
+    Timestamp(i64),
+    Uuid([u8; 16]),
+    Binary(Vec<u8>),
+    String(String),
+    Symbol(String),
+    List(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    /// Elements must all be of the same, non-described type.
+    Array(Vec<Value>),
+    Described(Box<Value>, Box<Value>),
+}
+
+impl Value {
+    pub fn described(code: u64, value: Value) -> Value {
+        Value::Described(Box::new(Value::Ulong(code)), Box::new(value))
+    }
+
+    pub fn symbol(s: impl Into<String>) -> Value {
+        Value::Symbol(s.into())
+    }
+
+    pub fn string(s: impl Into<String>) -> Value {
+        Value::String(s.into())
+    }
+
+    pub fn is_null(self: &Self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// The numeric descriptor and the value of a described value.
+    pub fn as_described(self: &Self) -> Option<(u64, &Value)> {
+        match self {
+            Value::Described(descriptor, value) => match descriptor.as_ref() {
+                Value::Ulong(code) => Some((*code, value)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self: &Self) -> Option<&str> {
+        match self {
+            Value::String(s) | Value::Symbol(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(self: &Self) -> Option<u64> {
+        match self {
+            Value::Ubyte(n) => Some(*n as u64),
+            Value::Ushort(n) => Some(*n as u64),
+            Value::Uint(n) => Some(*n as u64),
+            Value::Ulong(n) => Some(*n),
+            Value::Byte(n) if *n >= 0 => Some(*n as u64),
+            Value::Short(n) if *n >= 0 => Some(*n as u64),
+            Value::Int(n) if *n >= 0 => Some(*n as u64),
+            Value::Long(n) if *n >= 0 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(self: &Self) -> Option<i64> {
+        match self {
+            Value::Byte(n) => Some(*n as i64),
+            Value::Short(n) => Some(*n as i64),
+            Value::Int(n) => Some(*n as i64),
+            Value::Long(n) => Some(*n),
+            Value::Timestamp(n) => Some(*n),
+            other => other.as_u64().and_then(|n| i64::try_from(n).ok()),
+        }
+    }
+
+    pub fn as_bool(self: &Self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_binary(self: &Self) -> Option<&[u8]> {
+        match self {
+            Value::Binary(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Fields of a list, or of an empty list for null.
+    pub fn as_list(self: &Self) -> Option<&[Value]> {
+        match self {
+            Value::List(items) | Value::Array(items) => Some(items),
+            Value::Null => Some(&[]),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(self: &Self) -> Option<&[(Value, Value)]> {
+        match self {
+            Value::Map(entries) => Some(entries),
+            Value::Null => Some(&[]),
+            _ => None,
+        }
+    }
+
+    /// Looks up a map entry by its string or symbol key.
+    pub fn get(self: &Self, key: &str) -> Option<&Value> {
+        self.as_map()?.iter().find(|(k, _)| k.as_str() == Some(key)).map(|(_, v)| v)
+    }
+
+    /// Text for values Service Bus sends as application properties.
+    pub fn to_text(self: &Self) -> String {
+        match self {
+            Value::Null => String::new(),
+            Value::Bool(b) => b.to_string(),
+            Value::Double(d) => d.to_string(),
+            Value::String(s) | Value::Symbol(s) => s.clone(),
+            Value::Uuid(u) => uuid::Uuid::from_bytes(*u).to_string(),
+            Value::Timestamp(ms) => Utc.timestamp_millis_opt(*ms).single().map(|t| t.to_rfc3339()).unwrap_or_default(),
+            other => match other.as_i64() {
+                Some(n) => n.to_string(),
+                None => format!("{:?}", other),
+            },
+        }
+    }
+}
+
+/// Field `index` of a list, null when the list is shorter.
+pub fn field(fields: &[Value], index: usize) -> &Value {
+    fields.get(index).unwrap_or(&Value::Null)
+}
+
+pub fn encode(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(0x40),
+        Value::Bool(true) => out.push(0x41),
+        Value::Bool(false) => out.push(0x42),
+        Value::Uint(0) => out.push(0x43),
+        Value::Uint(n) if *n < 256 => { out.push(0x52); out.push(*n as u8); },
+        Value::Ulong(0) => out.push(0x44),
+        Value::Ulong(n) if *n < 256 => { out.push(0x53); out.push(*n as u8); },
+        Value::List(items) if items.is_empty() => out.push(0x45),
+        Value::Described(descriptor, value) => {
+            out.push(0x00);
+            encode(descriptor, out);
+            encode(value, out);
+        },
+        other => {
+            let constructor = wide_constructor(other);
+            out.push(constructor);
+            encode_body(other, out);
+        },
+    }
+}
+
+pub fn to_bytes(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode(value, &mut out);
+    out
+}
+
+// The constructor of the fixed width encoding, which array elements share.
+fn wide_constructor(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0x40,
+        Value::Bool(_) => 0x56,
+        Value::Ubyte(_) => 0x50,
+        Value::Ushort(_) => 0x60,
+        Value::Uint(_) => 0x70,
+        Value::Ulong(_) => 0x80,
+        Value::Byte(_) => 0x51,
+        Value::Short(_) => 0x61,
+        Value::Int(_) => 0x71,
+        Value::Long(_) => 0x81,
+        Value::Double(_) => 0x82,
+        Value::Timestamp(_) => 0x83,
+        Value::Uuid(_) => 0x98,
+        Value::Binary(_) => 0xb0,
+        Value::String(_) => 0xb1,
+        Value::Symbol(_) => 0xb3,
+        Value::List(_) => 0xd0,
+        Value::Map(_) => 0xd1,
+        Value::Array(_) => 0xf0,
+        Value::Described(..) => 0x00,
+    }
+}
+
+fn encode_body(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null | Value::Described(..) => {},
+        Value::Bool(b) => out.push(*b as u8),
+        Value::Ubyte(n) => out.push(*n),
+        Value::Ushort(n) => out.extend_from_slice(&n.to_be_bytes()),
+        Value::Uint(n) => out.extend_from_slice(&n.to_be_bytes()),
+        Value::Ulong(n) => out.extend_from_slice(&n.to_be_bytes()),
+        Value::Byte(n) => out.extend_from_slice(&n.to_be_bytes()),
+        Value::Short(n) => out.extend_from_slice(&n.to_be_bytes()),
+        Value::Int(n) => out.extend_from_slice(&n.to_be_bytes()),
+        Value::Long(n) | Value::Timestamp(n) => out.extend_from_slice(&n.to_be_bytes()),
+        Value::Double(d) => out.extend_from_slice(&d.to_be_bytes()),
+        Value::Uuid(u) => out.extend_from_slice(u),
+        Value::Binary(b) => {
+            out.extend_from_slice(&(b.len() as u32).to_be_bytes());
+            out.extend_from_slice(b);
+        },
+        Value::String(s) | Value::Symbol(s) => {
+            out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+            out.extend_from_slice(s.as_bytes());
+        },
+        Value::List(items) => encode_compound(items.iter(), items.len(), out),
+        Value::Map(entries) => encode_compound(entries.iter().flat_map(|(k, v)| [k, v]), entries.len() * 2, out),
+        Value::Array(items) => {
+            let mut body = Vec::new();
+            let constructor = items.first().map(wide_constructor).unwrap_or(0x40);
+            body.push(constructor);
+            for item in items {
+                encode_body(item, &mut body);
+            }
+            out.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+            out.extend_from_slice(&(items.len() as u32).to_be_bytes());
+            out.extend_from_slice(&body);
+        },
+    }
+}
+
+fn encode_compound<'a>(items: impl Iterator<Item = &'a Value>, count: usize, out: &mut Vec<u8>) {
+    let mut body = Vec::new();
+    for item in items {
+        encode(item, &mut body);
+    }
+    // The size covers the count and the items.
+    out.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+    out.extend_from_slice(&(count as u32).to_be_bytes());
+    out.extend_from_slice(&body);
+}
+
+/// Decodes one value from the front of `input`, advancing it past the value.
+pub fn decode(input: &mut &[u8]) -> Result<Value, AmqpError> {
+    let constructor = take(input, 1)?[0];
+    if constructor == 0x00 {
+        let descriptor = decode(input)?;
+        let value = decode(input)?;
+        return Ok(Value::Described(Box::new(descriptor), Box::new(value)));
+    }
+    decode_body(constructor, input)
+}
+
+pub fn from_bytes(mut input: &[u8]) -> Result<Value, AmqpError> {
+    decode(&mut input)
+}
+
+fn decode_body(constructor: u8, input: &mut &[u8]) -> Result<Value, AmqpError> {
+    let value = match constructor {
+        0x40 => Value::Null,
+        0x41 => Value::Bool(true),
+        0x42 => Value::Bool(false),
+        0x56 => Value::Bool(take(input, 1)?[0] != 0),
+        0x50 => Value::Ubyte(take(input, 1)?[0]),
+        0x60 => Value::Ushort(u16::from_be_bytes(fixed(input)?)),
+        0x70 => Value::Uint(u32::from_be_bytes(fixed(input)?)),
+        0x52 => Value::Uint(take(input, 1)?[0] as u32),
+        0x43 => Value::Uint(0),
+        0x80 => Value::Ulong(u64::from_be_bytes(fixed(input)?)),
+        0x53 => Value::Ulong(take(input, 1)?[0] as u64),
+        0x44 => Value::Ulong(0),
+        0x51 => Value::Byte(take(input, 1)?[0] as i8),
+        0x61 => Value::Short(i16::from_be_bytes(fixed(input)?)),
+        0x71 => Value::Int(i32::from_be_bytes(fixed(input)?)),
+        0x54 => Value::Int(take(input, 1)?[0] as i8 as i32),
+        0x81 => Value::Long(i64::from_be_bytes(fixed(input)?)),
+        0x55 => Value::Long(take(input, 1)?[0] as i8 as i64),
+        0x72 => Value::Double(f32::from_be_bytes(fixed(input)?) as f64),
+        0x82 => Value::Double(f64::from_be_bytes(fixed(input)?)),
+        0x83 => Value::Timestamp(i64::from_be_bytes(fixed(input)?)),
+        0x98 => Value::Uuid(fixed(input)?),
+        0xa0 | 0xb0 => {
+            let len = length(constructor, input)?;
+            Value::Binary(take(input, len)?.to_vec())
+        },
+        0xa1 | 0xb1 | 0xa3 | 0xb3 => {
+            let len = length(constructor, input)?;
+            let text = String::from_utf8(take(input, len)?.to_vec())
+                .map_err(|e| AmqpError::DecodeError(e.to_string()))?;
+            if constructor & 0x0f == 0x01 { Value::String(text) } else { Value::Symbol(text) }
+        },
+        0x45 => Value::List(vec![]),
+        0xc0 | 0xd0 => Value::List(decode_compound(constructor, input)?),
+        0xc1 | 0xd1 => {
+            let items = decode_compound(constructor, input)?;
+            if items.len() % 2 != 0 {
+                return Err(AmqpError::DecodeError("Map with an odd number of items".into()));
+            }
+            let mut entries = Vec::with_capacity(items.len() / 2);
+            let mut items = items.into_iter();
+            while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                entries.push((k, v));
+            }
+            Value::Map(entries)
+        },
+        0xe0 | 0xf0 => {
+            let (size, count) = if constructor == 0xe0 {
+                let header = take(input, 2)?;
+                (header[0] as usize, header[1] as usize)
+            } else {
+                (u32::from_be_bytes(fixed(input)?) as usize, u32::from_be_bytes(fixed(input)?) as usize)
+            };
+            let width = if constructor == 0xe0 { 1 } else { 4 };
+            let mut body = take(input, size.saturating_sub(width))?;
+            let element = take(&mut body, 1)?[0];
+            if element == 0x00 {
+                return Err(AmqpError::DecodeError("Arrays of described values are not supported".into()));
+            }
+            let mut items = Vec::with_capacity(count.min(1024));
+            for _ in 0..count {
+                items.push(decode_body(element, &mut body)?);
+            }
+            Value::Array(items)
+        },
+        other => return Err(AmqpError::DecodeError(format!("Unknown constructor 0x{:02x}", other))),
+    };
+    Ok(value)
+}
+
+fn decode_compound(constructor: u8, input: &mut &[u8]) -> Result<Vec<Value>, AmqpError> {
+    let (size, count, width) = if constructor & 0xf0 == 0xc0 {
+        let header = take(input, 2)?;
+        (header[0] as usize, header[1] as usize, 1)
+    } else {
+        (u32::from_be_bytes(fixed(input)?) as usize, u32::from_be_bytes(fixed(input)?) as usize, 4)
+    };
+
+    let mut body = take(input, size.saturating_sub(width))?;
+    let mut items = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        items.push(decode(&mut body)?);
+    }
+    Ok(items)
+}
+
+fn length(constructor: u8, input: &mut &[u8]) -> Result<usize, AmqpError> {
+    if constructor & 0xf0 == 0xa0 {
+        return Ok(take(input, 1)?[0] as usize);
+    }
+    Ok(u32::from_be_bytes(fixed(input)?) as usize)
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], AmqpError> {
+    if input.len() < n {
+        return Err(AmqpError::DecodeError("Unexpected end of data".into()));
+    }
+    let (head, rest) = input.split_at(n);
+    *input = rest;
+    Ok(head)
+}
+
+fn fixed<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], AmqpError> {
+    let mut bytes = [0u8; N];
+    bytes.copy_from_slice(take(input, N)?);
+    Ok(bytes)
+}
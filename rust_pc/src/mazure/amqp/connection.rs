@@ -0,0 +1,883 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::mazure::amqp::{AmqpError, DEAD_LETTER_CONDITION, LOCK_LOST_CONDITION, NOT_FOUND_CONDITION, UNAUTHORIZED_CONDITION};
+use crate::mazure::amqp::codec::Value;
+use crate::mazure::amqp::frames::*;
+use crate::mazure::amqp::message::{self, AmqpMessage, Body};
+use crate::mazure::sbclient::{DEAD_LETTER_DESCRIPTION_PROPERTY, DEAD_LETTER_REASON_PROPERTY};
+
+// Largest frame either side sends.
+const MAX_FRAME_SIZE: u32 = 256 * 1024;
+
+// The session window is never closed, so the peer never has to wait for it.
+const SESSION_WINDOW: u32 = i32::MAX as u32;
+
+// Credit the management reply links keep.
+const MANAGEMENT_CREDIT: u32 = 10;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Settlement {
+    Accepted,
+    Released,
+    // Moves the message to the dead-letter sub-queue.
+    Rejected { reason: String, description: String },
+    // Sets the message aside until it is received by its sequence number.
+    Deferred,
+}
+
+impl Settlement {
+    fn state(self: &Self) -> Value {
+        match self {
+            Settlement::Accepted => outcome(ACCEPTED, vec![]),
+            Settlement::Released => outcome(RELEASED, vec![]),
+            Settlement::Rejected { reason, description } => outcome(REJECTED, vec![error_value(DEAD_LETTER_CONDITION, description, vec![
+                (Value::symbol(DEAD_LETTER_REASON_PROPERTY), Value::string(reason)),
+                (Value::symbol(DEAD_LETTER_DESCRIPTION_PROPERTY), Value::string(description)),
+            ])]),
+            // Undeliverable here, which Service Bus takes as deferral.
+            Settlement::Deferred => outcome(MODIFIED, vec![Value::Bool(false), Value::Bool(true)]),
+        }
+    }
+
+    // The status the management node's update-disposition operation takes.
+    fn disposition_status(self: &Self) -> &'static str {
+        match self {
+            Settlement::Accepted => "completed",
+            Settlement::Released => "abandoned",
+            Settlement::Rejected { .. } => "suspended",
+            Settlement::Deferred => "defered",
+        }
+    }
+}
+
+/// A message returned by a management operation.
+#[derive(Debug)]
+pub(crate) struct ManagedMessage {
+    pub lock_token: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Delivery {
+    pub delivery_id: u32,
+    pub tag: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl Delivery {
+    pub fn lock_token(self: &Self) -> String {
+        message::lock_token_from_tag(&self.tag)
+    }
+}
+
+#[derive(Debug)]
+struct Link {
+    handle: u32,
+    role: bool,
+    attached: bool,
+    refused: bool,
+    detached: Option<AmqpError>,
+    // Credit granted to us on sender links, and by us on receiver links.
+    credit: u32,
+    delivery_count: u32,
+    deliveries: VecDeque<Delivery>,
+    partial: Option<Delivery>,
+}
+
+/// One AMQP connection with a single session. Frames are read by a background task and
+/// handled whenever an operation waits, so deliveries and flow arriving for other links
+/// are kept until asked for.
+pub(crate) struct AmqpConnection {
+    host: String,
+    container_id: String,
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    incoming: mpsc::UnboundedReceiver<Result<Frame, AmqpError>>,
+    tasks: Vec<JoinHandle<()>>,
+    remote_max_frame_size: u32,
+    next_outgoing_id: u32,
+    next_incoming_id: u32,
+    next_handle: u32,
+    next_delivery_id: u32,
+    next_message_id: u64,
+    links: HashMap<String, Link>,
+    remote_handles: HashMap<u32, String>,
+    // Outcomes the peer reported for deliveries we sent, and settled for ones we received.
+    sent_outcomes: HashMap<u32, Value>,
+    received_outcomes: HashMap<u32, Value>,
+    // Lock token to the receiver link and delivery id it arrived with.
+    locks: HashMap<String, (String, u32)>,
+    // Lock tokens of messages received from a management node, settled there too.
+    managed_locks: HashSet<String>,
+    // Audience to when its token was put.
+    tokens: HashMap<String, Instant>,
+    closed: Option<AmqpError>,
+}
+
+impl Drop for AmqpConnection {
+    fn drop(self: &mut Self) {
+        if self.closed.is_none() {
+            let _ = self.write(&Frame::amqp(0, Performative::new(CLOSE, vec![])));
+        }
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl AmqpConnection {
+    pub async fn open(endpoint: &str, timeout: Duration) -> Result<AmqpConnection, AmqpError> {
+        match tokio::time::timeout(timeout, AmqpConnection::connect(endpoint)).await {
+            Ok(result) => result,
+            Err(_) => Err(AmqpError::Timeout(format!("Connecting to {}", endpoint))),
+        }
+    }
+
+    async fn connect(endpoint: &str) -> Result<AmqpConnection, AmqpError> {
+        let url = reqwest::Url::parse(endpoint).map_err(|e| AmqpError::ProtocolError(format!("Invalid endpoint {}: {}", endpoint, e)))?;
+        let host = url.host_str()
+            .ok_or_else(|| AmqpError::ProtocolError(format!("No host in endpoint {}", endpoint)))?
+            .to_string();
+
+        let tcp = match url.scheme() {
+            "amqp" => TcpStream::connect((host.as_str(), url.port().unwrap_or(5672))).await?,
+            "amqps" => TcpStream::connect((host.as_str(), url.port().unwrap_or(5671))).await?,
+            other => return Err(AmqpError::ProtocolError(format!("Unsupported scheme {}", other))),
+        };
+        tcp.set_nodelay(true)?;
+
+        let mut stream: Box<dyn Stream> = if url.scheme() == "amqps" {
+            let connector = tokio_native_tls::native_tls::TlsConnector::new()
+                .map_err(|e| AmqpError::IoError(e.to_string()))?;
+            let tls = tokio_native_tls::TlsConnector::from(connector).connect(&host, tcp).await
+                .map_err(|e| AmqpError::IoError(e.to_string()))?;
+            Box::new(tls)
+        } else {
+            Box::new(tcp)
+        };
+
+        authenticate_anonymously(&mut stream, &host).await?;
+        write_header(&mut stream, &AMQP_HEADER).await?;
+        expect_header(&mut stream, &AMQP_HEADER).await?;
+
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (incoming_sender, incoming) = mpsc::unbounded_channel();
+        let (outgoing, mut outgoing_receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        let read_task = tokio::spawn(async move {
+            loop {
+                let frame = read_frame(&mut reader, MAX_FRAME_SIZE).await;
+                let failed = frame.is_err();
+                if incoming_sender.send(frame).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        let write_task = tokio::spawn(async move {
+            while let Some(bytes) = outgoing_receiver.recv().await {
+                if writer.write_all(&bytes).await.is_err() || writer.flush().await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut connection = AmqpConnection {
+            host,
+            container_id: format!("qexample-{}", Uuid::new_v4()),
+            outgoing,
+            incoming,
+            tasks: vec![read_task, write_task],
+            remote_max_frame_size: MIN_MAX_FRAME_SIZE,
+            next_outgoing_id: 0,
+            next_incoming_id: 0,
+            next_handle: 0,
+            next_delivery_id: 0,
+            next_message_id: 0,
+            links: HashMap::new(),
+            remote_handles: HashMap::new(),
+            sent_outcomes: HashMap::new(),
+            received_outcomes: HashMap::new(),
+            locks: HashMap::new(),
+            managed_locks: HashSet::new(),
+            tokens: HashMap::new(),
+            closed: None,
+        };
+        connection.begin().await?;
+        Ok(connection)
+    }
+
+    async fn begin(self: &mut Self) -> Result<(), AmqpError> {
+        self.write(&Frame::amqp(0, Performative::new(OPEN, vec![
+            Value::string(&self.container_id),
+            Value::string(&self.host),
+            Value::Uint(MAX_FRAME_SIZE),
+            Value::Ushort(0),
+        ])))?;
+        self.write(&Frame::amqp(0, Performative::new(BEGIN, vec![
+            Value::Null,
+            Value::Uint(0),
+            Value::Uint(SESSION_WINDOW),
+            Value::Uint(SESSION_WINDOW),
+        ])))?;
+
+        let open = self.expect(OPEN).await?;
+        self.remote_max_frame_size = open.field(2).as_u64()
+            .map(|size| size.clamp(MIN_MAX_FRAME_SIZE as u64, MAX_FRAME_SIZE as u64) as u32)
+            .unwrap_or(MAX_FRAME_SIZE);
+
+        // Keep the connection from idling out when the peer asks for heartbeats.
+        if let Some(idle_timeout) = open.field(4).as_u64().filter(|ms| *ms > 0) {
+            let outgoing = self.outgoing.clone();
+            let heartbeat = Frame::heartbeat().encode();
+            self.tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_millis(idle_timeout / 2));
+                loop {
+                    interval.tick().await;
+                    if outgoing.send(heartbeat.clone()).is_err() {
+                        return;
+                    }
+                }
+            }));
+        }
+
+        self.expect(BEGIN).await?;
+        Ok(())
+    }
+
+    // Waits for a connection level performative during the opening handshake.
+    async fn expect(self: &mut Self, code: u64) -> Result<Performative, AmqpError> {
+        loop {
+            let frame = match self.incoming.recv().await {
+                Some(frame) => frame?,
+                None => return Err(AmqpError::ConnectionClosed("Connection closed while opening".into())),
+            };
+            match frame.performative {
+                Some(performative) if performative.code == code => return Ok(performative),
+                Some(performative) if performative.code == CLOSE => {
+                    return Err(remote_error(performative.field(0), "Connection refused"));
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Whether the connection failed or was closed, handling whatever frames already
+    /// arrived so a connection lost while idle is noticed before it is used.
+    pub fn is_closed(self: &mut Self) -> bool {
+        while self.closed.is_none() {
+            match self.incoming.try_recv() {
+                Ok(Ok(frame)) => {
+                    if let Err(e) = self.handle(frame) {
+                        self.closed = Some(e);
+                    }
+                },
+                Ok(Err(e)) => self.closed = Some(e),
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => self.closed = Some(AmqpError::ConnectionClosed("Connection lost".into())),
+            }
+        }
+        self.closed.is_some()
+    }
+
+    /// The CBS audience of an entity.
+    pub fn audience(self: &Self, entity: &str) -> String {
+        format!("sb://{}/{}", self.host, entity)
+    }
+
+    pub fn needs_token(self: &Self, audience: &str, refresh_interval: Duration) -> bool {
+        match self.tokens.get(audience) {
+            Some(put_at) => put_at.elapsed() >= refresh_interval,
+            None => true,
+        }
+    }
+
+    /// Authorizes the connection for the audience with the claims-based security node.
+    pub async fn put_token(self: &mut Self, audience: &str, token: &str, timeout: Duration) -> Result<(), AmqpError> {
+        let expiration = chrono::Utc::now() + chrono::Duration::hours(1);
+        let properties = vec![
+            (Value::string("operation"), Value::string("put-token")),
+            (Value::string("type"), Value::string("jwt")),
+            (Value::string("name"), Value::string(audience)),
+            (Value::string("expiration"), Value::Timestamp(expiration.timestamp_millis())),
+        ];
+        self.request("$cbs", properties, Value::string(token), timeout).await?;
+        self.tokens.insert(audience.to_string(), Instant::now());
+        Ok(())
+    }
+
+    /// Sends the encoded messages and waits for the peer to accept all of them.
+    pub async fn send(self: &mut Self, entity: &str, payloads: &[Vec<u8>], timeout: Duration) -> Result<(), AmqpError> {
+        let deadline = Instant::now() + timeout;
+        let name = format!("{}-sender", entity);
+        self.attach(&name, ROLE_SENDER, SND_SETTLE_UNSETTLED, None, Some(entity), deadline).await?;
+
+        let mut delivery_ids = Vec::with_capacity(payloads.len());
+        for payload in payloads {
+            delivery_ids.push(self.transfer(&name, payload, false, deadline).await?);
+        }
+
+        let all_settled = |c: &AmqpConnection| delivery_ids.iter().all(|id| c.sent_outcomes.contains_key(id));
+        if !self.wait_until(deadline, |c| all_settled(c) || c.link_failed(&name)).await? || !all_settled(self) {
+            return Err(self.link_error(&name).unwrap_or_else(|| AmqpError::Timeout(format!("Sending to {}", entity))));
+        }
+
+        let mut result = Ok(());
+        for id in delivery_ids {
+            let state = self.sent_outcomes.remove(&id).unwrap_or(Value::Null);
+            if result.is_ok() {
+                result = outcome_result(&state);
+            }
+        }
+        result
+    }
+
+    /// Receives the next message, waiting up to the receive timeout for one. Peek-lock
+    /// receivers keep `prefetch` messages coming ahead of being asked for.
+    pub async fn receive(self: &mut Self, entity: &str, peek_lock: bool, prefetch: u32, receive_timeout: Duration, timeout: Duration) -> Result<Option<Delivery>, AmqpError> {
+        let (name, settle_mode, credit) = if peek_lock {
+            (format!("{}-receiver", entity), SND_SETTLE_UNSETTLED, prefetch.max(1))
+        } else {
+            // Settled deliveries sitting in the buffer would be lost with the connection.
+            (format!("{}-receiver-deleting", entity), SND_SETTLE_SETTLED, 1)
+        };
+        self.attach(&name, ROLE_RECEIVER, settle_mode, Some(entity), None, Instant::now() + timeout).await?;
+
+        if !self.has_delivery(&name) {
+            self.grant_credit(&name, credit, false)?;
+            let deadline = Instant::now() + receive_timeout;
+            self.wait_until(deadline, |c| c.has_delivery(&name) || c.link_failed(&name)).await?;
+
+            // Take back credit nobody waits for, unless the receiver prefetches.
+            if !self.has_delivery(&name) && !self.link_failed(&name) && (prefetch == 0 || !peek_lock) {
+                self.grant_credit(&name, 0, true)?;
+                let deadline = Instant::now() + timeout;
+                self.wait_until(deadline, |c| c.has_delivery(&name) || c.credit(&name) == 0 || c.link_failed(&name)).await?;
+            }
+        }
+
+        if let Some(error) = self.link_error(&name) {
+            return Err(error);
+        }
+
+        let delivery = self.links.get_mut(&name).and_then(|link| link.deliveries.pop_front());
+        if let (Some(delivery), true) = (&delivery, peek_lock) {
+            self.locks.insert(delivery.lock_token(), (name.clone(), delivery.delivery_id));
+        }
+        Ok(delivery)
+    }
+
+    /// Settles a message received over this connection and waits for the peer to confirm.
+    pub async fn settle(self: &mut Self, entity: &str, lock_token: &str, settlement: &Settlement, timeout: Duration) -> Result<(), AmqpError> {
+        if self.managed_locks.remove(lock_token) {
+            return self.update_disposition(entity, lock_token, settlement, timeout).await;
+        }
+
+        let (name, delivery_id) = match self.locks.remove(lock_token) {
+            Some(lock) => lock,
+            None => return Err(AmqpError::remote(LOCK_LOST_CONDITION, format!("Lock {} is not held by this connection", lock_token))),
+        };
+        if self.link_failed(&name) {
+            return Err(AmqpError::remote(LOCK_LOST_CONDITION, format!("The link holding lock {} was detached", lock_token)));
+        }
+
+        self.write(&Frame::amqp(0, Performative::new(DISPOSITION, vec![
+            Value::Bool(ROLE_RECEIVER),
+            Value::Uint(delivery_id),
+            Value::Null,
+            Value::Bool(false),
+            settlement.state(),
+        ])))?;
+
+        let deadline = Instant::now() + timeout;
+        if !self.wait_until(deadline, |c| c.received_outcomes.contains_key(&delivery_id) || c.link_failed(&name)).await? {
+            return Err(AmqpError::Timeout(format!("Settling {}", lock_token)));
+        }
+        match self.received_outcomes.remove(&delivery_id) {
+            // The peer confirms a rejection by repeating it.
+            Some(state) if state == settlement.state() => Ok(()),
+            Some(state) => outcome_result(&state),
+            None => Err(self.link_error(&name).unwrap_or_else(|| AmqpError::ConnectionClosed("Link detached".into()))),
+        }
+    }
+
+    /// Renews the lock with the entity's management node. Returns when it now expires,
+    /// in milliseconds since the epoch.
+    pub async fn renew_lock(self: &mut Self, entity: &str, lock_token: &str, timeout: Duration) -> Result<i64, AmqpError> {
+        let body = Value::Map(vec![(Value::string("lock-tokens"), Value::Array(vec![lock_token_value(lock_token)?]))]);
+
+        let response = self.management(entity, "com.microsoft:renew-lock", body, timeout).await?;
+        response.get("expirations")
+            .and_then(|expirations| expirations.as_list())
+            .and_then(|expirations| expirations.first())
+            .and_then(|expiration| expiration.as_i64())
+            .ok_or_else(|| AmqpError::ProtocolError("Renew lock response has no expiration".into()))
+    }
+
+    /// Returns up to `count` messages from the sequence number on, without locking them.
+    pub async fn peek(self: &mut Self, entity: &str, from_sequence_number: i64, count: u32, timeout: Duration) -> Result<Vec<ManagedMessage>, AmqpError> {
+        let body = Value::Map(vec![
+            (Value::string("from-sequence-number"), Value::Long(from_sequence_number)),
+            (Value::string("message-count"), Value::Int(count.min(i32::MAX as u32) as i32)),
+        ]);
+        let response = self.management(entity, "com.microsoft:peek-message", body, timeout).await?;
+        managed_messages(&response)
+    }
+
+    /// Locks and returns deferred messages by their sequence numbers.
+    pub async fn receive_deferred(self: &mut Self, entity: &str, sequence_numbers: &[i64], timeout: Duration) -> Result<Vec<ManagedMessage>, AmqpError> {
+        let body = Value::Map(vec![
+            (Value::string("sequence-numbers"), Value::Array(sequence_numbers.iter().map(|n| Value::Long(*n)).collect())),
+            // Peek-lock.
+            (Value::string("receiver-settle-mode"), Value::Uint(1)),
+        ]);
+        let response = self.management(entity, "com.microsoft:receive-by-sequence-number", body, timeout).await?;
+        let messages = managed_messages(&response)?;
+        self.managed_locks.extend(messages.iter().filter_map(|m| m.lock_token.clone()));
+        Ok(messages)
+    }
+
+    /// Sends messages to be enqueued at their scheduled enqueue time and returns their
+    /// sequence numbers.
+    pub async fn schedule(self: &mut Self, entity: &str, messages: &[(String, Vec<u8>)], timeout: Duration) -> Result<Vec<i64>, AmqpError> {
+        let messages = messages.iter()
+            .map(|(message_id, payload)| Value::Map(vec![
+                (Value::string("message-id"), Value::string(message_id)),
+                (Value::string("message"), Value::Binary(payload.clone())),
+            ]))
+            .collect();
+        let body = Value::Map(vec![(Value::string("messages"), Value::List(messages))]);
+
+        let response = self.management(entity, "com.microsoft:schedule-message", body, timeout).await?;
+        response.get("sequence-numbers")
+            .and_then(|numbers| numbers.as_list())
+            .map(|numbers| numbers.iter().filter_map(|n| n.as_i64()).collect())
+            .ok_or_else(|| AmqpError::ProtocolError("Schedule response has no sequence numbers".into()))
+    }
+
+    /// Removes scheduled messages that are not enqueued yet.
+    pub async fn cancel_scheduled(self: &mut Self, entity: &str, sequence_numbers: &[i64], timeout: Duration) -> Result<(), AmqpError> {
+        let body = Value::Map(vec![
+            (Value::string("sequence-numbers"), Value::Array(sequence_numbers.iter().map(|n| Value::Long(*n)).collect())),
+        ]);
+        self.management(entity, "com.microsoft:cancel-scheduled-message", body, timeout).await?;
+        Ok(())
+    }
+
+    // Settles a message received from the management node, which is the only place
+    // its lock is known.
+    async fn update_disposition(self: &mut Self, entity: &str, lock_token: &str, settlement: &Settlement, timeout: Duration) -> Result<(), AmqpError> {
+        let mut body = vec![
+            (Value::string("disposition-status"), Value::string(settlement.disposition_status())),
+            (Value::string("lock-tokens"), Value::Array(vec![lock_token_value(lock_token)?])),
+        ];
+        if let Settlement::Rejected { reason, description } = settlement {
+            body.push((Value::string("deadletter-reason"), Value::string(reason)));
+            body.push((Value::string("deadletter-description"), Value::string(description)));
+        }
+        self.management(entity, "com.microsoft:update-disposition", Value::Map(body), timeout).await?;
+        Ok(())
+    }
+
+    // Runs an operation on the entity's management node.
+    async fn management(self: &mut Self, entity: &str, operation: &str, body: Value, timeout: Duration) -> Result<Value, AmqpError> {
+        let properties = vec![(Value::string("operation"), Value::string(operation))];
+        self.request(&format!("{}/$management", entity), properties, body, timeout).await
+    }
+
+    // Sends a request to a management node and returns the body of the response.
+    async fn request(self: &mut Self, node: &str, properties: Vec<(Value, Value)>, body: Value, timeout: Duration) -> Result<Value, AmqpError> {
+        let deadline = Instant::now() + timeout;
+        let sender = format!("{}-requests", node);
+        let receiver = format!("{}-responses", node);
+        let reply_to = format!("{}-{}", receiver, self.container_id);
+
+        self.attach(&sender, ROLE_SENDER, SND_SETTLE_SETTLED, Some(&reply_to), Some(node), deadline).await?;
+        self.attach(&receiver, ROLE_RECEIVER, SND_SETTLE_SETTLED, Some(node), Some(&reply_to), deadline).await?;
+        if self.credit(&receiver) == 0 {
+            self.grant_credit(&receiver, MANAGEMENT_CREDIT, false)?;
+        }
+
+        let message_id = self.next_message_id;
+        self.next_message_id += 1;
+        let mut request = AmqpMessage { application_properties: properties, body: Body::Value(body), ..AmqpMessage::default() };
+        request.set_property(message::MESSAGE_ID, Value::Ulong(message_id));
+        request.set_property(message::REPLY_TO, Value::string(&reply_to));
+        self.transfer(&sender, &request.encode(), true, deadline).await?;
+
+        loop {
+            if !self.wait_until(deadline, |c| c.has_delivery(&receiver) || c.link_failed(&receiver)).await? {
+                return Err(AmqpError::Timeout(format!("Waiting for a response from {}", node)));
+            }
+            if let Some(error) = self.link_error(&receiver) {
+                return Err(error);
+            }
+
+            let delivery = self.links.get_mut(&receiver).and_then(|link| link.deliveries.pop_front()).unwrap();
+            let response = AmqpMessage::decode(&delivery.payload)?;
+            if response.property(message::CORRELATION_ID).as_u64() != Some(message_id) {
+                // A response to an earlier request that timed out.
+                continue;
+            }
+
+            let status = response.application_property("status-code").and_then(|v| v.as_i64()).unwrap_or(500);
+            let description = response.application_property("status-description").map(|v| v.to_text()).unwrap_or_default();
+            if !(200..300).contains(&status) {
+                return Err(AmqpError::remote(status_condition(status), description));
+            }
+            return match response.body {
+                Body::Value(value) => Ok(value),
+                Body::Data(_) => Ok(Value::Null),
+            };
+        }
+    }
+
+    // Attaches the link unless it already is.
+    async fn attach(self: &mut Self, name: &str, role: bool, settle_mode: u8, source: Option<&str>, target: Option<&str>, deadline: Instant) -> Result<(), AmqpError> {
+        match self.links.get(name) {
+            Some(link) if link.detached.is_none() => return Ok(()),
+            Some(_) => { self.links.remove(name); },
+            None => {},
+        }
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        let initial_delivery_count = if role == ROLE_SENDER { Value::Uint(0) } else { Value::Null };
+        self.write(&Frame::amqp(0, Performative::new(ATTACH, vec![
+            Value::string(name),
+            Value::Uint(handle),
+            Value::Bool(role),
+            Value::Ubyte(settle_mode),
+            Value::Ubyte(RCV_SETTLE_FIRST),
+            terminus(SOURCE, source),
+            terminus(TARGET, target),
+            Value::Null,
+            Value::Bool(false),
+            initial_delivery_count,
+        ])))?;
+        self.links.insert(name.to_string(), Link {
+            handle,
+            role,
+            attached: false,
+            refused: false,
+            detached: None,
+            credit: 0,
+            delivery_count: 0,
+            deliveries: VecDeque::new(),
+            partial: None,
+        });
+
+        // A refused attach is answered with an attach and then a detach with the reason.
+        let done = |c: &AmqpConnection| c.links.get(name).map(|l| (l.attached && !l.refused) || l.detached.is_some()).unwrap_or(true);
+        if !self.wait_until(deadline, done).await? {
+            return Err(AmqpError::Timeout(format!("Attaching {}", name)));
+        }
+        match self.link_error(name) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    // Sends one delivery, split over as many frames as the peer's frame size needs.
+    async fn transfer(self: &mut Self, name: &str, payload: &[u8], settled: bool, deadline: Instant) -> Result<u32, AmqpError> {
+        if !self.wait_until(deadline, |c| c.credit(name) > 0 || c.link_failed(name)).await? {
+            return Err(AmqpError::Timeout(format!("Waiting for credit on {}", name)));
+        }
+        if let Some(error) = self.link_error(name) {
+            return Err(error);
+        }
+
+        let delivery_id = self.next_delivery_id;
+        self.next_delivery_id = self.next_delivery_id.wrapping_add(1);
+        let link = self.links.get_mut(name).unwrap();
+        link.credit -= 1;
+        link.delivery_count = link.delivery_count.wrapping_add(1);
+        let handle = link.handle;
+
+        let performative = |more: bool| Performative::new(TRANSFER, vec![
+            Value::Uint(handle),
+            Value::Uint(delivery_id),
+            Value::Binary(delivery_id.to_be_bytes().to_vec()),
+            Value::Uint(0),
+            Value::Bool(settled),
+            Value::Bool(more),
+        ]);
+        let overhead = 8 + Frame::amqp(0, performative(true)).encode().len();
+        let chunk_size = (self.remote_max_frame_size as usize).saturating_sub(overhead).max(1);
+
+        let mut chunks = payload.chunks(chunk_size).peekable();
+        if chunks.peek().is_none() {
+            self.write(&Frame::amqp(0, performative(false)))?;
+        }
+        while let Some(chunk) = chunks.next() {
+            let mut frame = Frame::amqp(0, performative(chunks.peek().is_some()));
+            frame.payload = chunk.to_vec();
+            self.write(&frame)?;
+        }
+        Ok(delivery_id)
+    }
+
+    // Sets the credit of a receiver link, optionally asking the peer to use it up or
+    // give it back right away.
+    fn grant_credit(self: &mut Self, name: &str, credit: u32, drain: bool) -> Result<(), AmqpError> {
+        let link = match self.links.get_mut(name) {
+            Some(link) => link,
+            None => return Ok(()),
+        };
+        if !drain {
+            link.credit = credit;
+        }
+        let flow = Performative::new(FLOW, vec![
+            Value::Uint(self.next_incoming_id),
+            Value::Uint(SESSION_WINDOW),
+            Value::Uint(self.next_outgoing_id),
+            Value::Uint(SESSION_WINDOW),
+            Value::Uint(link.handle),
+            Value::Uint(link.delivery_count),
+            Value::Uint(if drain { link.credit } else { credit }),
+            Value::Null,
+            Value::Bool(drain),
+        ]);
+        self.write(&Frame::amqp(0, flow))
+    }
+
+    fn has_delivery(self: &Self, name: &str) -> bool {
+        self.links.get(name).map(|link| !link.deliveries.is_empty()).unwrap_or(false)
+    }
+
+    fn credit(self: &Self, name: &str) -> u32 {
+        self.links.get(name).map(|link| link.credit).unwrap_or(0)
+    }
+
+    fn link_failed(self: &Self, name: &str) -> bool {
+        self.link_error(name).is_some()
+    }
+
+    fn link_error(self: &Self, name: &str) -> Option<AmqpError> {
+        if let Some(error) = &self.closed {
+            return Some(error.clone());
+        }
+        match self.links.get(name) {
+            Some(link) => link.detached.clone(),
+            None => Some(AmqpError::ConnectionClosed(format!("Link {} is not attached", name))),
+        }
+    }
+
+    // Handles incoming frames until the condition holds. Returns false on reaching the
+    // deadline first.
+    async fn wait_until<F: Fn(&AmqpConnection) -> bool>(self: &mut Self, deadline: Instant, condition: F) -> Result<bool, AmqpError> {
+        loop {
+            if condition(self) {
+                return Ok(true);
+            }
+            if let Some(error) = &self.closed {
+                return Err(error.clone());
+            }
+
+            let frame = match tokio::time::timeout_at(deadline, self.incoming.recv()).await {
+                Err(_) => return Ok(false),
+                Ok(None) => Err(AmqpError::ConnectionClosed("Connection lost".into())),
+                Ok(Some(frame)) => frame,
+            };
+            match frame {
+                Ok(frame) => self.handle(frame)?,
+                Err(e) => self.closed = Some(e),
+            }
+        }
+    }
+
+    fn handle(self: &mut Self, frame: Frame) -> Result<(), AmqpError> {
+        let performative = match frame.performative {
+            Some(performative) => performative,
+            None => return Ok(()),
+        };
+
+        match performative.code {
+            ATTACH => {
+                let name = performative.field(0).as_str().unwrap_or("").to_string();
+                let remote_handle = performative.field(1).as_u64().unwrap_or(0) as u32;
+                if let Some(link) = self.links.get_mut(&name) {
+                    // The peer's terminus is the one on our side of the link.
+                    let terminus = if link.role == ROLE_SENDER { performative.field(6) } else { performative.field(5) };
+                    link.attached = true;
+                    link.refused = terminus.is_null();
+                    if link.role == ROLE_RECEIVER {
+                        link.delivery_count = performative.field(9).as_u64().unwrap_or(0) as u32;
+                    }
+                    self.remote_handles.insert(remote_handle, name);
+                }
+            },
+            FLOW => {
+                let link = performative.field(4).as_u64()
+                    .and_then(|handle| self.remote_handles.get(&(handle as u32)))
+                    .and_then(|name| self.links.get_mut(name));
+                if let Some(link) = link {
+                    let link_credit = performative.field(6).as_u64().unwrap_or(0) as u32;
+                    if link.role == ROLE_SENDER {
+                        let delivery_count = performative.field(5).as_u64().map(|n| n as u32).unwrap_or(0);
+                        link.credit = delivery_count.wrapping_add(link_credit).wrapping_sub(link.delivery_count);
+                    } else {
+                        link.delivery_count = performative.field(5).as_u64().map(|n| n as u32).unwrap_or(link.delivery_count);
+                        link.credit = link_credit;
+                    }
+                }
+            },
+            TRANSFER => {
+                self.next_incoming_id = self.next_incoming_id.wrapping_add(1);
+                let name = match self.remote_handles.get(&(performative.field(0).as_u64().unwrap_or(0) as u32)) {
+                    Some(name) => name.clone(),
+                    None => return Ok(()),
+                };
+                let link = match self.links.get_mut(&name) {
+                    Some(link) => link,
+                    None => return Ok(()),
+                };
+
+                let delivery = link.partial.get_or_insert_with(|| Delivery {
+                    delivery_id: performative.field(1).as_u64().unwrap_or(0) as u32,
+                    tag: performative.field(2).as_binary().unwrap_or(&[]).to_vec(),
+                    payload: vec![],
+                });
+                delivery.payload.extend_from_slice(&frame.payload);
+
+                if performative.field(5).as_bool() != Some(true) {
+                    let delivery = link.partial.take().unwrap();
+                    link.credit = link.credit.saturating_sub(1);
+                    link.delivery_count = link.delivery_count.wrapping_add(1);
+                    link.deliveries.push_back(delivery);
+                }
+            },
+            DISPOSITION => {
+                let first = performative.field(1).as_u64().unwrap_or(0) as u32;
+                let last = performative.field(2).as_u64().map(|n| n as u32).unwrap_or(first);
+                let state = performative.field(4).clone();
+                let outcomes = if performative.field(0).as_bool() == Some(ROLE_RECEIVER) {
+                    &mut self.sent_outcomes
+                } else {
+                    &mut self.received_outcomes
+                };
+                for id in first..=last {
+                    outcomes.insert(id, state.clone());
+                }
+            },
+            DETACH => {
+                let remote_handle = performative.field(0).as_u64().unwrap_or(0) as u32;
+                if let Some(name) = self.remote_handles.remove(&remote_handle) {
+                    if let Some(link) = self.links.get_mut(&name) {
+                        link.detached = Some(remote_error(performative.field(2), &format!("Link {} was detached", name)));
+                        let handle = link.handle;
+                        self.write(&Frame::amqp(0, Performative::new(DETACH, vec![Value::Uint(handle), Value::Bool(true)])))?;
+                    }
+                    self.locks.retain(|_, (link, _)| *link != name);
+                }
+            },
+            END | CLOSE => {
+                self.closed = Some(remote_error(performative.field(0), "Connection closed by the peer"));
+                if performative.code == CLOSE {
+                    let _ = self.write(&Frame::amqp(0, Performative::new(CLOSE, vec![])));
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn write(self: &mut Self, frame: &Frame) -> Result<(), AmqpError> {
+        if frame.performative.as_ref().map(|p| p.code == TRANSFER).unwrap_or(false) {
+            self.next_outgoing_id = self.next_outgoing_id.wrapping_add(1);
+        }
+        self.outgoing.send(frame.encode())
+            .map_err(|_| AmqpError::ConnectionClosed("Connection lost".into()))
+    }
+}
+
+async fn authenticate_anonymously(stream: &mut Box<dyn Stream>, host: &str) -> Result<(), AmqpError> {
+    write_header(stream, &SASL_HEADER).await?;
+    expect_header(stream, &SASL_HEADER).await?;
+
+    let mechanisms = read_frame(stream, MAX_FRAME_SIZE).await?;
+    let offered = mechanisms.performative
+        .filter(|p| p.code == SASL_MECHANISMS)
+        .map(|p| p.field(0).clone())
+        .ok_or_else(|| AmqpError::ProtocolError("Expected SASL mechanisms".into()))?;
+    let offered: Vec<&str> = match &offered {
+        Value::Symbol(mechanism) => vec![mechanism.as_str()],
+        other => other.as_list().unwrap_or(&[]).iter().filter_map(|m| m.as_str()).collect(),
+    };
+    if !offered.contains(&"ANONYMOUS") {
+        return Err(AmqpError::ProtocolError(format!("SASL ANONYMOUS is not offered, only {:?}", offered)));
+    }
+
+    write_frame(stream, &Frame::sasl(Performative::new(SASL_INIT, vec![
+        Value::symbol("ANONYMOUS"),
+        Value::Null,
+        Value::string(host),
+    ]))).await?;
+
+    let outcome = read_frame(stream, MAX_FRAME_SIZE).await?;
+    match outcome.performative.filter(|p| p.code == SASL_OUTCOME) {
+        Some(p) if p.field(0).as_u64() == Some(0) => Ok(()),
+        Some(p) => Err(AmqpError::remote(UNAUTHORIZED_CONDITION, format!("SASL outcome {:?}", p.field(0)))),
+        None => Err(AmqpError::ProtocolError("Expected a SASL outcome".into())),
+    }
+}
+
+fn lock_token_value(lock_token: &str) -> Result<Value, AmqpError> {
+    let lock_token = Uuid::parse_str(lock_token)
+        .map_err(|e| AmqpError::ProtocolError(format!("Invalid lock token {}: {}", lock_token, e)))?;
+    Ok(Value::Uuid(*lock_token.as_bytes()))
+}
+
+// The messages of a peek or receive response. A response without any has no body.
+fn managed_messages(response: &Value) -> Result<Vec<ManagedMessage>, AmqpError> {
+    let entries = response.get("messages").and_then(|messages| messages.as_list()).unwrap_or(&[]);
+    entries.iter()
+        .map(|entry| {
+            let payload = entry.get("message").and_then(|message| message.as_binary())
+                .ok_or_else(|| AmqpError::ProtocolError("Management response message has no payload".into()))?;
+            Ok(ManagedMessage {
+                lock_token: entry.get("lock-token").map(|lock_token| lock_token.to_text()),
+                payload: payload.to_vec(),
+            })
+        })
+        .collect()
+}
+
+// The result of a delivery state the peer reported.
+fn outcome_result(state: &Value) -> Result<(), AmqpError> {
+    match state.as_described() {
+        Some((ACCEPTED, _)) => Ok(()),
+        Some((RELEASED, _)) | Some((MODIFIED, _)) => Ok(()),
+        Some((REJECTED, fields)) => {
+            let error = fields.as_list().and_then(|fields| fields.first()).unwrap_or(&Value::Null);
+            Err(remote_error(error, "Delivery rejected"))
+        },
+        _ => Err(AmqpError::ProtocolError(format!("Unexpected delivery state {:?}", state))),
+    }
+}
+
+fn remote_error(error: &Value, fallback: &str) -> AmqpError {
+    match error_parts(error) {
+        Some((condition, description)) => AmqpError::Remote { condition, description },
+        None => AmqpError::ConnectionClosed(fallback.to_string()),
+    }
+}
+
+fn status_condition(status: i64) -> &'static str {
+    match status {
+        401 | 403 => UNAUTHORIZED_CONDITION,
+        404 => NOT_FOUND_CONDITION,
+        410 => LOCK_LOST_CONDITION,
+        _ => "amqp:internal-error",
+    }
+}
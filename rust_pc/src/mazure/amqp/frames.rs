@@ -0,0 +1,208 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::mazure::amqp::AmqpError;
+use crate::mazure::amqp::codec::{self, Value, field};
+
+pub const AMQP_HEADER: [u8; 8] = *b"AMQP\x00\x01\x00\x00";
+pub const SASL_HEADER: [u8; 8] = *b"AMQP\x03\x01\x00\x00";
+
+pub const FRAME_TYPE_AMQP: u8 = 0;
+pub const FRAME_TYPE_SASL: u8 = 1;
+
+/// Smallest max-frame-size a peer may announce.
+pub const MIN_MAX_FRAME_SIZE: u32 = 512;
+
+// Performatives.
+pub const OPEN: u64 = 0x10;
+pub const BEGIN: u64 = 0x11;
+pub const ATTACH: u64 = 0x12;
+pub const FLOW: u64 = 0x13;
+pub const TRANSFER: u64 = 0x14;
+pub const DISPOSITION: u64 = 0x15;
+pub const DETACH: u64 = 0x16;
+pub const END: u64 = 0x17;
+pub const CLOSE: u64 = 0x18;
+
+pub const ERROR: u64 = 0x1d;
+pub const SOURCE: u64 = 0x28;
+pub const TARGET: u64 = 0x29;
+
+// Delivery states.
+pub const ACCEPTED: u64 = 0x24;
+pub const REJECTED: u64 = 0x25;
+pub const RELEASED: u64 = 0x26;
+pub const MODIFIED: u64 = 0x27;
+
+// SASL.
+pub const SASL_MECHANISMS: u64 = 0x40;
+pub const SASL_INIT: u64 = 0x41;
+pub const SASL_OUTCOME: u64 = 0x44;
+
+// Link roles and settle modes.
+pub const ROLE_SENDER: bool = false;
+pub const ROLE_RECEIVER: bool = true;
+pub const SND_SETTLE_UNSETTLED: u8 = 0;
+pub const SND_SETTLE_SETTLED: u8 = 1;
+pub const RCV_SETTLE_FIRST: u8 = 0;
+
+/// A performative: a described list whose fields are addressed by position.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Performative {
+    pub code: u64,
+    pub fields: Vec<Value>,
+}
+
+impl Performative {
+    pub fn new(code: u64, fields: Vec<Value>) -> Self {
+        Performative { code, fields }
+    }
+
+    pub fn field(self: &Self, index: usize) -> &Value {
+        field(&self.fields, index)
+    }
+
+    pub fn to_value(self: &Self) -> Value {
+        // Trailing nulls can be left out.
+        let mut fields = self.fields.clone();
+        while fields.last().map(|f| f.is_null()).unwrap_or(false) {
+            fields.pop();
+        }
+        Value::described(self.code, Value::List(fields))
+    }
+
+    pub fn from_value(value: &Value) -> Result<Performative, AmqpError> {
+        let (code, fields) = value.as_described()
+            .ok_or_else(|| AmqpError::ProtocolError("Frame body is not a performative".into()))?;
+        let fields = fields.as_list()
+            .ok_or_else(|| AmqpError::ProtocolError(format!("Performative 0x{:02x} is not a list", code)))?;
+        Ok(Performative { code, fields: fields.to_vec() })
+    }
+}
+
+/// A frame. Frames without a performative are heartbeats.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Frame {
+    pub frame_type: u8,
+    pub channel: u16,
+    pub performative: Option<Performative>,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn amqp(channel: u16, performative: Performative) -> Self {
+        Frame { frame_type: FRAME_TYPE_AMQP, channel, performative: Some(performative), payload: vec![] }
+    }
+
+    pub fn sasl(performative: Performative) -> Self {
+        Frame { frame_type: FRAME_TYPE_SASL, channel: 0, performative: Some(performative), payload: vec![] }
+    }
+
+    pub fn heartbeat() -> Self {
+        Frame { frame_type: FRAME_TYPE_AMQP, channel: 0, performative: None, payload: vec![] }
+    }
+
+    pub fn encode(self: &Self) -> Vec<u8> {
+        let mut body = Vec::new();
+        if let Some(performative) = &self.performative {
+            codec::encode(&performative.to_value(), &mut body);
+        }
+        body.extend_from_slice(&self.payload);
+
+        let mut out = Vec::with_capacity(8 + body.len());
+        out.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+        out.push(2);
+        out.push(self.frame_type);
+        out.extend_from_slice(&self.channel.to_be_bytes());
+        out.extend_from_slice(&body);
+        out
+    }
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> Result<(), AmqpError> {
+    writer.write_all(&frame.encode()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_frame_size: u32) -> Result<Frame, AmqpError> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await?;
+
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let data_offset = header[4] as usize * 4;
+    if size > max_frame_size || (size as usize) < data_offset || data_offset < 8 {
+        return Err(AmqpError::ProtocolError(format!("Invalid frame size {}", size)));
+    }
+
+    let mut rest = vec![0u8; size as usize - 8];
+    reader.read_exact(&mut rest).await?;
+    let mut body = &rest[data_offset - 8..];
+
+    let performative = if body.is_empty() {
+        None
+    } else {
+        Some(Performative::from_value(&codec::decode(&mut body)?)?)
+    };
+
+    Ok(Frame {
+        frame_type: header[5],
+        channel: u16::from_be_bytes([header[6], header[7]]),
+        performative,
+        payload: body.to_vec(),
+    })
+}
+
+pub async fn write_header<W: AsyncWrite + Unpin>(writer: &mut W, header: &[u8; 8]) -> Result<(), AmqpError> {
+    writer.write_all(header).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn expect_header<R: AsyncRead + Unpin>(reader: &mut R, expected: &[u8; 8]) -> Result<(), AmqpError> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await?;
+    if &header != expected {
+        return Err(AmqpError::ProtocolError(format!("Unexpected protocol header {:?}", header)));
+    }
+    Ok(())
+}
+
+/// An `amqp:error` with the condition, description and info map.
+pub fn error_value(condition: &str, description: &str, info: Vec<(Value, Value)>) -> Value {
+    let info = if info.is_empty() { Value::Null } else { Value::Map(info) };
+    Value::described(ERROR, Value::List(vec![Value::symbol(condition), Value::string(description), info]))
+}
+
+/// The condition and description of an `amqp:error`, if the value is one.
+pub fn error_parts(value: &Value) -> Option<(String, String)> {
+    let (code, fields) = value.as_described()?;
+    if code != ERROR {
+        return None;
+    }
+    let fields = fields.as_list()?;
+    let condition = field(fields, 0).as_str().unwrap_or("amqp:internal-error").to_string();
+    let description = field(fields, 1).as_str().unwrap_or("").to_string();
+    Some((condition, description))
+}
+
+/// An entry of the info map of an `amqp:error`.
+pub fn error_info<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    let (_, fields) = value.as_described()?;
+    field(fields.as_list()?, 2).get(key)
+}
+
+pub fn outcome(code: u64, fields: Vec<Value>) -> Value {
+    Value::described(code, Value::List(fields))
+}
+
+/// A source or target terminus with just an address.
+pub fn terminus(code: u64, address: Option<&str>) -> Value {
+    let address = address.map(Value::string).unwrap_or(Value::Null);
+    Value::described(code, Value::List(vec![address]))
+}
+
+/// The address of a source or target terminus.
+pub fn terminus_address(value: &Value) -> Option<&str> {
+    let (_, fields) = value.as_described()?;
+    field(fields.as_list()?, 0).as_str()
+}
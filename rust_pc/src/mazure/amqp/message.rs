@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, TimeZone, Utc};
+use uuid::Uuid;
+
+use crate::mazure::amqp::AmqpError;
+use crate::mazure::amqp::codec::{self, Value, field};
+use crate::mazure::sbclient::{BrokerReceiveProperties, BrokerSendProperties, Message};
+
+// Message sections.
+pub const HEADER: u64 = 0x70;
+pub const MESSAGE_ANNOTATIONS: u64 = 0x72;
+pub const PROPERTIES: u64 = 0x73;
+pub const APPLICATION_PROPERTIES: u64 = 0x74;
+pub const DATA: u64 = 0x75;
+pub const AMQP_VALUE: u64 = 0x77;
+
+// Header fields.
+const TTL: usize = 2;
+const DELIVERY_COUNT: usize = 4;
+
+// Properties fields.
+pub const MESSAGE_ID: usize = 0;
+pub const TO: usize = 2;
+pub const SUBJECT: usize = 3;
+pub const REPLY_TO: usize = 4;
+pub const CORRELATION_ID: usize = 5;
+pub const CONTENT_TYPE: usize = 6;
+pub const GROUP_ID: usize = 10;
+pub const REPLY_TO_GROUP_ID: usize = 12;
+
+// Message annotations Service Bus keeps its broker properties in.
+pub static SCHEDULED_ENQUEUE_TIME: &str = "x-opt-scheduled-enqueue-time";
+pub static PARTITION_KEY: &str = "x-opt-partition-key";
+pub static SEQUENCE_NUMBER: &str = "x-opt-sequence-number";
+pub static ENQUEUED_TIME: &str = "x-opt-enqueued-time";
+pub static LOCKED_UNTIL: &str = "x-opt-locked-until";
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Body {
+    Data(Vec<u8>),
+    Value(Value),
+}
+
+/// The sections of an AMQP message. Sections the client does not use are dropped.
+#[derive(Clone, PartialEq, Debug)]
+pub struct AmqpMessage {
+    pub header: Vec<Value>,
+    pub message_annotations: Vec<(Value, Value)>,
+    pub properties: Vec<Value>,
+    pub application_properties: Vec<(Value, Value)>,
+    pub body: Body,
+}
+
+impl Default for AmqpMessage {
+    fn default() -> Self {
+        AmqpMessage {
+            header: vec![],
+            message_annotations: vec![],
+            properties: vec![],
+            application_properties: vec![],
+            body: Body::Data(vec![]),
+        }
+    }
+}
+
+impl AmqpMessage {
+    pub fn property(self: &Self, index: usize) -> &Value {
+        field(&self.properties, index)
+    }
+
+    pub fn set_property(self: &mut Self, index: usize, value: Value) {
+        if self.properties.len() <= index {
+            self.properties.resize(index + 1, Value::Null);
+        }
+        self.properties[index] = value;
+    }
+
+    pub fn annotation(self: &Self, key: &str) -> Option<&Value> {
+        lookup(&self.message_annotations, key)
+    }
+
+    pub fn application_property(self: &Self, key: &str) -> Option<&Value> {
+        lookup(&self.application_properties, key)
+    }
+
+    pub fn encode(self: &Self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if !self.header.is_empty() {
+            codec::encode(&Value::described(HEADER, Value::List(self.header.clone())), &mut out);
+        }
+        if !self.message_annotations.is_empty() {
+            codec::encode(&Value::described(MESSAGE_ANNOTATIONS, Value::Map(self.message_annotations.clone())), &mut out);
+        }
+        if !self.properties.is_empty() {
+            codec::encode(&Value::described(PROPERTIES, Value::List(self.properties.clone())), &mut out);
+        }
+        if !self.application_properties.is_empty() {
+            codec::encode(&Value::described(APPLICATION_PROPERTIES, Value::Map(self.application_properties.clone())), &mut out);
+        }
+        match &self.body {
+            Body::Data(data) => codec::encode(&Value::described(DATA, Value::Binary(data.clone())), &mut out),
+            Body::Value(value) => codec::encode(&Value::described(AMQP_VALUE, value.clone()), &mut out),
+        }
+        out
+    }
+
+    pub fn decode(mut input: &[u8]) -> Result<AmqpMessage, AmqpError> {
+        let mut message = AmqpMessage::default();
+        let mut data = Vec::new();
+
+        while !input.is_empty() {
+            let section = codec::decode(&mut input)?;
+            let (code, value) = section.as_described()
+                .ok_or_else(|| AmqpError::DecodeError("Message section is not described".into()))?;
+            let invalid = || AmqpError::DecodeError(format!("Invalid message section 0x{:02x}", code));
+
+            match code {
+                HEADER => message.header = value.as_list().ok_or_else(invalid)?.to_vec(),
+                MESSAGE_ANNOTATIONS => message.message_annotations = value.as_map().ok_or_else(invalid)?.to_vec(),
+                PROPERTIES => message.properties = value.as_list().ok_or_else(invalid)?.to_vec(),
+                APPLICATION_PROPERTIES => message.application_properties = value.as_map().ok_or_else(invalid)?.to_vec(),
+                // A body may be split over several data sections.
+                DATA => data.extend_from_slice(value.as_binary().ok_or_else(invalid)?),
+                AMQP_VALUE => message.body = Body::Value(value.clone()),
+                _ => {},
+            }
+        }
+
+        if let Body::Data(_) = message.body {
+            message.body = Body::Data(data);
+        }
+        Ok(message)
+    }
+
+    /// Maps the broker properties onto the message sections the way Service Bus does.
+    pub fn from_send_message(message: &Message<BrokerSendProperties>) -> AmqpMessage {
+        let p = &message.properties;
+        let mut amqp = AmqpMessage { body: Body::Data(message.content.clone()), ..AmqpMessage::default() };
+
+        if let Some(ttl) = p.time_to_live {
+            let ms = (ttl.max(0) as u64).saturating_mul(1000).min(u32::MAX as u64) as u32;
+            amqp.header = vec![Value::Bool(true), Value::Null, Value::Uint(ms)];
+        }
+
+        let text = |v: &Option<String>| v.as_ref().map(Value::string).unwrap_or(Value::Null);
+        amqp.set_property(MESSAGE_ID, text(&p.message_id));
+        amqp.set_property(TO, text(&p.to));
+        amqp.set_property(SUBJECT, text(&p.label));
+        amqp.set_property(REPLY_TO, text(&p.reply_to));
+        amqp.set_property(CORRELATION_ID, text(&p.correlation_id));
+        if !message.content_type.is_empty() {
+            amqp.set_property(CONTENT_TYPE, Value::symbol(&message.content_type));
+        }
+        amqp.set_property(GROUP_ID, text(&p.session_id));
+        amqp.set_property(REPLY_TO_GROUP_ID, text(&p.reply_to_session_id));
+
+        if let Some(scheduled) = p.scheduled_enqueue_time_utc {
+            amqp.message_annotations.push((Value::symbol(SCHEDULED_ENQUEUE_TIME), Value::Timestamp(scheduled.timestamp_millis())));
+        }
+        if let Some(partition_key) = &p.partition_key {
+            amqp.message_annotations.push((Value::symbol(PARTITION_KEY), Value::string(partition_key)));
+        }
+
+        amqp.application_properties = message.user_properties.iter()
+            .map(|(name, value)| (Value::string(name), Value::string(value)))
+            .collect();
+        amqp
+    }
+
+    /// Like `from_send_message`, adding the properties the broker sets on delivery.
+    pub fn from_received_message(message: &Message<BrokerReceiveProperties>) -> AmqpMessage {
+        let p = &message.properties;
+        let mut amqp = AmqpMessage::from_send_message(&message.to_send_message());
+        amqp.set_property(MESSAGE_ID, p.message_id.as_ref().map(Value::string).unwrap_or(Value::Null));
+
+        if amqp.header.is_empty() {
+            amqp.header = vec![Value::Bool(true)];
+        }
+        amqp.header.resize(DELIVERY_COUNT + 1, Value::Null);
+        amqp.header[DELIVERY_COUNT] = Value::Uint(p.delivery_count.unwrap_or(1).max(1) as u32 - 1);
+
+        let annotations: [(&str, Option<Value>); 4] = [
+            (SCHEDULED_ENQUEUE_TIME, p.scheduled_enqueue_time_utc.map(|t| Value::Timestamp(t.timestamp_millis()))),
+            (SEQUENCE_NUMBER, p.sequence_number.map(Value::Long)),
+            (ENQUEUED_TIME, p.enqueued_time_utc.map(|t| Value::Timestamp(t.timestamp_millis()))),
+            (LOCKED_UNTIL, p.locked_until_utc.map(|t| Value::Timestamp(t.timestamp_millis()))),
+        ];
+        for (key, value) in annotations {
+            if let Some(value) = value {
+                amqp.message_annotations.push((Value::symbol(key), value));
+            }
+        }
+        amqp
+    }
+
+    /// Reads the broker properties back out of the sections. The lock token is the
+    /// delivery tag, which the receiving link knows.
+    pub fn to_received_message(self: &Self, lock_token: Option<String>) -> Message<BrokerReceiveProperties> {
+        let text = |v: &Value| match v {
+            Value::Null => None,
+            other => Some(other.to_text()),
+        };
+        let timestamp = |key: &str| self.annotation(key).and_then(|v| v.as_i64()).and_then(from_millis);
+
+        let mut properties = BrokerReceiveProperties::new_empty();
+        properties.message_id = text(self.property(MESSAGE_ID));
+        properties.to = text(self.property(TO));
+        properties.label = text(self.property(SUBJECT));
+        properties.reply_to = text(self.property(REPLY_TO));
+        properties.correlation_id = text(self.property(CORRELATION_ID));
+        properties.session_id = text(self.property(GROUP_ID));
+        properties.reply_to_session_id = text(self.property(REPLY_TO_GROUP_ID));
+        properties.time_to_live = field(&self.header, TTL).as_u64().map(|ms| (ms / 1000) as i64);
+        properties.delivery_count = Some(field(&self.header, DELIVERY_COUNT).as_u64().unwrap_or(0) as i32 + 1);
+        properties.scheduled_enqueue_time_utc = timestamp(SCHEDULED_ENQUEUE_TIME);
+        properties.partition_key = self.annotation(PARTITION_KEY).map(|v| v.to_text());
+        properties.sequence_number = self.annotation(SEQUENCE_NUMBER).and_then(|v| v.as_i64());
+        properties.enqueued_time_utc = timestamp(ENQUEUED_TIME);
+        properties.locked_until_utc = timestamp(LOCKED_UNTIL);
+        properties.lock_token = lock_token;
+
+        let user_properties: BTreeMap<String, String> = self.application_properties.iter()
+            .filter_map(|(name, value)| name.as_str().map(|name| (name.to_string(), value.to_text())))
+            .collect();
+
+        let content = match &self.body {
+            Body::Data(data) => data.clone(),
+            Body::Value(Value::Binary(data)) => data.clone(),
+            Body::Value(value) => value.to_text().into_bytes(),
+        };
+
+        Message {
+            properties,
+            content,
+            content_type: self.property(CONTENT_TYPE).as_str().unwrap_or("").to_string(),
+            user_properties,
+        }
+    }
+}
+
+/// Service Bus lock tokens are delivery tags holding a .NET GUID.
+pub fn lock_token_from_tag(tag: &[u8]) -> String {
+    match <[u8; 16]>::try_from(tag) {
+        Ok(bytes) => Uuid::from_bytes_le(bytes).to_string(),
+        Err(_) => tag.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+pub fn tag_from_lock_token(lock_token: &str) -> Option<Vec<u8>> {
+    Uuid::parse_str(lock_token).ok().map(|uuid| uuid.to_bytes_le().to_vec())
+}
+
+pub fn from_millis(ms: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(ms).single()
+}
+
+fn lookup<'a>(entries: &'a [(Value, Value)], key: &str) -> Option<&'a Value> {
+    entries.iter().find(|(k, _)| k.as_str() == Some(key)).map(|(_, v)| v)
+}
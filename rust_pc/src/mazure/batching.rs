@@ -0,0 +1,213 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use chrono::Local;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+use crate::mazure::limits::message_size;
+use crate::mazure::sbclient::{AzureServiceBusClient, BrokerSendProperties, Message};
+use crate::mazure::sending::SendReceipt;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BatchingError {
+    #[error("Batch send failed: {0}")]
+    SendError(String),
+
+    #[error("Message cannot be batched: {0}")]
+    InvalidMessage(String),
+
+    #[error("Message is {size} bytes, more than the batch limit of {limit} bytes")]
+    MessageTooLarge { size: usize, limit: usize },
+
+    #[error("The batching sender has stopped")]
+    Closed,
+
+    #[error("This client cannot send batches: {0}")]
+    Unsupported(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct BatchingOptions {
+    /// Largest total size of the messages in a batch, counted as by `message_size`. Keep it within the tier's batch size limit.
+    pub max_batch_size: usize,
+
+    pub max_batch_count: usize,
+
+    /// How long the first message of a batch waits for others to join it.
+    pub linger: Duration,
+
+    /// Messages that can wait to be batched before `send` waits for room.
+    pub capacity: usize,
+}
+
+impl Default for BatchingOptions {
+    fn default() -> Self {
+        BatchingOptions {
+            max_batch_size: 256 * 1024,
+            max_batch_count: 100,
+            linger: Duration::from_millis(10),
+            capacity: 1000,
+        }
+    }
+}
+
+/// What a worker sent before it stopped.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct BatchingStats {
+    /// Batches the worker tried to send, including failed ones.
+    pub batches: usize,
+
+    /// Messages that were sent.
+    pub messages: usize,
+
+    /// Messages whose batch failed.
+    pub failed_messages: usize,
+}
+
+struct Pending {
+    message: Message<BrokerSendProperties>,
+    size: usize,
+    completion: oneshot::Sender<Result<SendReceipt, BatchingError>>,
+}
+
+/// Creates a sender handle and the worker that batches what it is given. The worker
+/// runs until every sender handle is dropped, sending what is left before it returns.
+/// Fails if the client cannot send batches because it encrypts or signs.
+pub fn channel(sb_client: &AzureServiceBusClient, options: BatchingOptions) -> Result<(BatchingSender, BatchingWorker<'_>), BatchingError> {
+    if !sb_client.supports_batch_send() {
+        return Err(BatchingError::Unsupported("batched messages cannot be encrypted or signed".into()));
+    }
+
+    let (queue, receiver) = mpsc::channel(options.capacity.max(1));
+    let sender = BatchingSender { queue, max_batch_size: options.max_batch_size };
+    let worker = BatchingWorker { sb_client, receiver, options };
+    Ok((sender, worker))
+}
+
+/// Hands messages to a `BatchingWorker`. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct BatchingSender {
+    queue: mpsc::Sender<Pending>,
+    max_batch_size: usize,
+}
+
+impl BatchingSender {
+    /// Queues the message for the next batch, waiting while the queue is full. The
+    /// returned completion resolves once the batch holding the message was sent.
+    ///
+    /// Batches go through `send_batch`, so bodies must be text and are sent without
+    /// their content type.
+    pub async fn send(self: &Self, message: Message<BrokerSendProperties>) -> Result<SendCompletion, BatchingError> {
+        let size = pending_size(&message)?;
+        if size > self.max_batch_size {
+            return Err(BatchingError::MessageTooLarge { size, limit: self.max_batch_size });
+        }
+
+        let (completion, receiver) = oneshot::channel();
+        self.queue.send(Pending { message, size, completion }).await
+            .map_err(|_| BatchingError::Closed)?;
+        Ok(SendCompletion { receiver })
+    }
+}
+
+/// Resolves to the message's receipt once its batch was sent, or to why it was not.
+#[derive(Debug)]
+pub struct SendCompletion {
+    receiver: oneshot::Receiver<Result<SendReceipt, BatchingError>>,
+}
+
+impl Future for SendCompletion {
+    type Output = Result<SendReceipt, BatchingError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            // The worker was dropped before sending the message.
+            Poll::Ready(Err(_)) => Poll::Ready(Err(BatchingError::Closed)),
+        }
+    }
+}
+
+/// Collects queued messages into batches and sends them. A batch is sent once it holds
+/// `max_batch_count` messages, the next message would take it past `max_batch_size`, or
+/// its first message has waited for `linger`.
+pub struct BatchingWorker<'a> {
+    sb_client: &'a AzureServiceBusClient,
+    receiver: mpsc::Receiver<Pending>,
+    options: BatchingOptions,
+}
+
+impl<'a> BatchingWorker<'a> {
+    pub async fn run(mut self: Self) -> BatchingStats {
+        let mut stats = BatchingStats::default();
+        let mut next = None;
+
+        loop {
+            let first = match next.take() {
+                Some(pending) => pending,
+                None => match self.receiver.recv().await {
+                    None => break,
+                    Some(pending) => pending,
+                },
+            };
+
+            let deadline = Instant::now() + self.options.linger;
+            let mut size = first.size;
+            let mut batch = vec![first];
+
+            while batch.len() < self.options.max_batch_count {
+                let pending = match tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+                    Ok(Some(pending)) => pending,
+                    // Closed or lingered long enough.
+                    Ok(None) | Err(_) => break,
+                };
+
+                if size + pending.size > self.options.max_batch_size {
+                    next = Some(pending);
+                    break;
+                }
+                size += pending.size;
+                batch.push(pending);
+            }
+
+            self.send(batch, &mut stats).await;
+        }
+
+        stats
+    }
+
+    async fn send(self: &Self, batch: Vec<Pending>, stats: &mut BatchingStats) {
+        let messages: Vec<Message<BrokerSendProperties>> = batch.iter().map(|pending| pending.message.clone()).collect();
+        stats.batches += 1;
+
+        match self.sb_client.send_batch(&messages).await {
+            Ok(receipts) => {
+                stats.messages += batch.len();
+                for (pending, receipt) in batch.into_iter().zip(receipts) {
+                    // The caller may have stopped waiting, that is fine.
+                    let _ = pending.completion.send(Ok(receipt));
+                }
+            },
+            Err(e) => {
+                println!("[{}] Batch of {} messages failed: {}", Local::now(), batch.len(), e);
+                stats.failed_messages += batch.len();
+                let error = BatchingError::SendError(e.to_string());
+                for pending in batch {
+                    let _ = pending.completion.send(Err(error.clone()));
+                }
+            },
+        }
+    }
+}
+
+// Bytes the message counts towards the batch limit. Batched bodies must be text.
+fn pending_size(message: &Message<BrokerSendProperties>) -> Result<usize, BatchingError> {
+    std::str::from_utf8(&message.content)
+        .map_err(|e| BatchingError::InvalidMessage(format!("Body is not text: {}", e)))?;
+    message_size(message).map_err(|e| BatchingError::InvalidMessage(e.to_string()))
+}
@@ -0,0 +1,310 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::mazure::sbclient::{AzureServiceBusClient, AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message};
+
+/// User property holding the zero based position of a chunk.
+pub static CHUNK_INDEX_PROPERTY: &str = "chunk-index";
+
+pub static CHUNK_COUNT_PROPERTY: &str = "chunk-count";
+
+/// User property holding the hex SHA-256 of the whole payload, repeated on every chunk.
+pub static CHUNK_SHA256_PROPERTY: &str = "chunk-sha256";
+
+#[derive(Error, Debug)]
+pub enum ChunkError {
+    #[error("Invalid chunk: {0}")]
+    InvalidChunk(String),
+
+    #[error("Chunk group {group} is missing chunks {missing:?}")]
+    MissingChunks { group: String, missing: Vec<u32> },
+
+    #[error("Checksum mismatch for chunk group {0}")]
+    ChecksumMismatch(String),
+}
+
+impl From<ChunkError> for AzureServiceBusError {
+    fn from(e: ChunkError) -> Self {
+        AzureServiceBusError::PayloadError(e.to_string())
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Splits the body into chunks of at most `chunk_size` bytes, all sent under the
+/// message's session id, or a new one if it has none.
+pub fn split_message(message: &Message<BrokerSendProperties>, chunk_size: usize) -> Vec<Message<BrokerSendProperties>> {
+    let mut properties = message.properties.clone();
+    let session_id = properties.session_id.get_or_insert_with(|| Uuid::new_v4().to_string()).clone();
+
+    let hash = sha256_hex(&message.content);
+    let pieces: Vec<&[u8]> = match message.content.is_empty() {
+        true => vec![&[]],
+        false => message.content.chunks(chunk_size.max(1)).collect(),
+    };
+    let count = pieces.len();
+
+    pieces.into_iter().enumerate()
+        .map(|(index, piece)| {
+            let mut chunk = Message {
+                properties: properties.clone(),
+                content: piece.to_vec(),
+                content_type: message.content_type.clone(),
+                user_properties: message.user_properties.clone(),
+            };
+            chunk.properties.session_id = Some(session_id.clone());
+            chunk.properties.message_id = message.properties.message_id.as_ref().map(|id| format!("{}-{}", id, index));
+            chunk.set_user_property(CHUNK_INDEX_PROPERTY, index.to_string());
+            chunk.set_user_property(CHUNK_COUNT_PROPERTY, count.to_string());
+            chunk.set_user_property(CHUNK_SHA256_PROPERTY, hash.clone());
+            chunk
+        })
+        .collect()
+}
+
+pub fn is_chunk<P>(message: &Message<P>) -> bool {
+    message.user_property(CHUNK_INDEX_PROPERTY).is_some()
+}
+
+/// A payload put back together from its chunks, or a message that was never chunked.
+#[derive(Debug, Clone)]
+pub struct AssembledMessage {
+    /// The payload, with the broker properties of the first chunk.
+    pub message: Message<BrokerReceiveProperties>,
+
+    /// The locked chunks, which are settled together.
+    pub chunks: Vec<BrokerReceiveProperties>,
+}
+
+#[derive(Debug)]
+pub enum ChunkOutcome {
+    /// More chunks are needed. The chunk is held, still locked.
+    Pending,
+
+    /// Another copy of a chunk that is already held. Settle it.
+    Duplicate(Message<BrokerReceiveProperties>),
+
+    Complete(AssembledMessage),
+
+    /// Every chunk arrived but the payload does not match its checksum.
+    Corrupt { error: ChunkError, chunks: Vec<Message<BrokerReceiveProperties>> },
+}
+
+struct ChunkGroup {
+    count: u32,
+    hash: String,
+    started: Instant,
+    chunks: BTreeMap<u32, Message<BrokerReceiveProperties>>,
+}
+
+impl ChunkGroup {
+    fn missing(self: &Self) -> Vec<u32> {
+        (0..self.count).filter(|i| !self.chunks.contains_key(i)).collect()
+    }
+}
+
+/// Collects chunks per session until every index has arrived.
+#[derive(Default)]
+pub struct ChunkAssembler {
+    groups: HashMap<String, ChunkGroup>,
+}
+
+impl ChunkAssembler {
+    pub fn new() -> Self {
+        ChunkAssembler::default()
+    }
+
+    pub fn add(self: &mut Self, chunk: Message<BrokerReceiveProperties>) -> Result<ChunkOutcome, ChunkError> {
+        let number = |name: &str| chunk.user_property(name)
+            .and_then(|v| v.parse::<u32>().ok())
+            .ok_or_else(|| ChunkError::InvalidChunk(format!("{} is missing or not a number", name)));
+        let index = number(CHUNK_INDEX_PROPERTY)?;
+        let count = number(CHUNK_COUNT_PROPERTY)?;
+        let hash = chunk.user_property(CHUNK_SHA256_PROPERTY)
+            .ok_or_else(|| ChunkError::InvalidChunk(format!("{} is missing", CHUNK_SHA256_PROPERTY)))?
+            .to_string();
+        let group_id = chunk.properties.session_id.clone()
+            .ok_or_else(|| ChunkError::InvalidChunk("chunk has no session id".into()))?;
+
+        if index >= count {
+            return Err(ChunkError::InvalidChunk(format!("index {} is out of range for {} chunks", index, count)));
+        }
+
+        let group = self.groups.entry(group_id.clone()).or_insert_with(|| ChunkGroup {
+            count,
+            hash: hash.clone(),
+            started: Instant::now(),
+            chunks: BTreeMap::new(),
+        });
+        if group.count != count || group.hash != hash {
+            return Err(ChunkError::InvalidChunk(format!("chunk does not match the rest of group {}", group_id)));
+        }
+
+        if let Some(held) = group.chunks.get(&index) {
+            // A redelivery of the same message means our lock on it was lost, so keep the
+            // newer lock. Anything else is a second copy.
+            if held.properties.message_id != chunk.properties.message_id {
+                return Ok(ChunkOutcome::Duplicate(chunk));
+            }
+        }
+        group.chunks.insert(index, chunk);
+
+        if group.chunks.len() < group.count as usize {
+            return Ok(ChunkOutcome::Pending);
+        }
+
+        let group = self.groups.remove(&group_id).unwrap();
+        let chunks: Vec<_> = group.chunks.into_values().collect();
+
+        let mut message = chunks[0].clone();
+        message.content = chunks.iter().flat_map(|chunk| chunk.content.iter().copied()).collect();
+        if sha256_hex(&message.content) != group.hash {
+            return Ok(ChunkOutcome::Corrupt { error: ChunkError::ChecksumMismatch(group_id), chunks });
+        }
+        let settle = chunks.into_iter().map(|chunk| chunk.properties).collect();
+
+        for name in [CHUNK_INDEX_PROPERTY, CHUNK_COUNT_PROPERTY, CHUNK_SHA256_PROPERTY] {
+            message.user_properties.remove(name);
+        }
+        Ok(ChunkOutcome::Complete(AssembledMessage { message, chunks: settle }))
+    }
+
+    /// Removes the groups that have been incomplete for longer than `timeout`, returning
+    /// the held chunks so they can be released, with the indexes that never arrived.
+    pub fn take_expired(self: &mut Self, timeout: Duration) -> Vec<(ChunkError, Vec<Message<BrokerReceiveProperties>>)> {
+        let expired: Vec<String> = self.groups.iter()
+            .filter(|(_, group)| group.started.elapsed() >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        expired.into_iter()
+            .filter_map(|id| self.groups.remove(&id).map(|group| (id, group)))
+            .map(|(group_id, group)| {
+                let error = ChunkError::MissingChunks { group: group_id, missing: group.missing() };
+                (error, group.chunks.into_values().collect())
+            })
+            .collect()
+    }
+
+    /// The chunks currently held, e.g. to renew their locks.
+    pub fn held(self: &Self) -> Vec<&BrokerReceiveProperties> {
+        self.groups.values().flat_map(|group| group.chunks.values().map(|c| &c.properties)).collect()
+    }
+}
+
+/// Receives messages, reassembling chunked ones. Chunks stay locked until the whole
+/// payload has been handled and `complete` is called, and are released again if the
+/// rest of their group does not arrive within the assembly timeout.
+pub struct ChunkedReceiver<'a> {
+    sb_client: &'a AzureServiceBusClient,
+    assembler: ChunkAssembler,
+    assembly_timeout: Duration,
+}
+
+impl<'a> ChunkedReceiver<'a> {
+    pub fn new(sb_client: &'a AzureServiceBusClient) -> Self {
+        ChunkedReceiver {
+            sb_client,
+            assembler: ChunkAssembler::new(),
+            assembly_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// How long a partial group is held. Keep it below the lock duration or renew locks.
+    pub fn with_assembly_timeout(mut self, assembly_timeout: Duration) -> Self {
+        self.assembly_timeout = assembly_timeout;
+        self
+    }
+
+    /// Returns the next whole message, or `None` once the queue has nothing more that
+    /// completes a message.
+    pub async fn receive(self: &mut Self) -> Result<Option<AssembledMessage>, AzureServiceBusError> {
+        loop {
+            self.release_expired().await?;
+
+            let raw = match self.sb_client.peek_lock_raw().await? {
+                None => return Ok(None),
+                Some(raw) => raw,
+            };
+
+            if !is_chunk(&raw) {
+                let message = self.sb_client.open_or_reject(raw).await?;
+                let chunks = vec![message.properties.clone()];
+                return Ok(Some(AssembledMessage { message, chunks }));
+            }
+
+            let verified = self.sb_client.verify_or_reject(raw).await?;
+
+            match self.assembler.add(verified.clone()) {
+                Ok(ChunkOutcome::Pending) => continue,
+                Ok(ChunkOutcome::Duplicate(duplicate)) => {
+                    println!("Discarding duplicate chunk {:?}", duplicate.properties.message_id);
+                    self.sb_client.delete_message(&duplicate.properties).await?;
+                },
+                Ok(ChunkOutcome::Complete(mut assembled)) => {
+                    assembled.message = self.sb_client.open_payload(assembled.message).await?;
+                    return Ok(Some(assembled));
+                },
+                Ok(ChunkOutcome::Corrupt { error, chunks }) => {
+                    let e: AzureServiceBusError = error.into();
+                    for chunk in &chunks {
+                        self.sb_client.reject(chunk, &e).await;
+                    }
+                    return Err(e);
+                },
+                Err(e) => {
+                    let e: AzureServiceBusError = e.into();
+                    self.sb_client.reject(&verified, &e).await;
+                    return Err(e);
+                },
+            }
+        }
+    }
+
+    /// Completes every chunk of the message.
+    pub async fn complete(self: &Self, assembled: &AssembledMessage) -> Result<(), AzureServiceBusError> {
+        for chunk in &assembled.chunks {
+            self.sb_client.delete_message(chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Releases every chunk of the message for redelivery.
+    pub async fn abandon(self: &Self, assembled: &AssembledMessage) -> Result<(), AzureServiceBusError> {
+        for chunk in &assembled.chunks {
+            self.sb_client.unlock_message(chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Renews the locks of chunks held for groups that are not complete yet.
+    pub async fn renew_locks(self: &Self) -> Result<(), AzureServiceBusError> {
+        for chunk in self.assembler.held() {
+            self.sb_client.renew_lock(chunk).await?;
+        }
+        Ok(())
+    }
+
+    async fn release_expired(self: &mut Self) -> Result<(), AzureServiceBusError> {
+        let expired = self.assembler.take_expired(self.assembly_timeout);
+        let first_error = expired.iter().map(|(error, _)| error.to_string()).next();
+
+        for (_, chunks) in expired {
+            for chunk in chunks {
+                self.sb_client.unlock_message(&chunk.properties).await?;
+            }
+        }
+
+        match first_error {
+            None => Ok(()),
+            Some(error) => Err(AzureServiceBusError::PayloadError(error)),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::mazure::sbclient::Message;
+
+/// User property holding the blob store reference of a checked payload.
+pub static CLAIM_CHECK_REFERENCE_PROPERTY: &str = "claim-check-reference";
+
+/// User property holding the hex SHA-256 of the checked payload.
+pub static CLAIM_CHECK_SHA256_PROPERTY: &str = "claim-check-sha256";
+
+pub static CLAIM_CHECK_SIZE_PROPERTY: &str = "claim-check-size";
+
+#[derive(Error, Debug)]
+pub enum ClaimCheckError {
+    #[error("Blob not found: {0}")]
+    NotFound(String),
+
+    #[error("Invalid blob reference: {0}")]
+    InvalidReference(String),
+
+    #[error("Checksum mismatch for blob {0}")]
+    ChecksumMismatch(String),
+
+    #[error("Blob store error: {0}")]
+    StoreError(String),
+}
+
+impl From<std::io::Error> for ClaimCheckError {
+    fn from(e: std::io::Error) -> Self {
+        ClaimCheckError::StoreError(e.to_string())
+    }
+}
+
+/// Stores payloads too large to send through the broker.
+#[async_trait(?Send)]
+pub trait BlobStore {
+    /// Stores the payload and returns the reference it can be fetched with.
+    async fn put(&self, data: &[u8]) -> Result<String, ClaimCheckError>;
+
+    async fn get(&self, reference: &str) -> Result<Vec<u8>, ClaimCheckError>;
+
+    /// Removes the payload. Removing a missing payload is not an error.
+    async fn delete(&self, reference: &str) -> Result<(), ClaimCheckError>;
+}
+
+/// Keeps payloads as files in a directory, e.g. a share mounted by producers and
+/// consumers alike.
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self, ClaimCheckError> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(LocalBlobStore { root })
+    }
+
+    fn path(self: &Self, reference: &str) -> Result<PathBuf, ClaimCheckError> {
+        // References come from received messages, so never let one leave the root.
+        match Uuid::parse_str(reference) {
+            Ok(id) => Ok(self.root.join(id.to_string())),
+            Err(_) => Err(ClaimCheckError::InvalidReference(reference.to_string())),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, data: &[u8]) -> Result<String, ClaimCheckError> {
+        let reference = Uuid::new_v4().to_string();
+        let path = self.path(&reference)?;
+
+        // Write under a temporary name so a reader never sees a partial blob.
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, data).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(reference)
+    }
+
+    async fn get(&self, reference: &str) -> Result<Vec<u8>, ClaimCheckError> {
+        match tokio::fs::read(self.path(reference)?).await {
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(ClaimCheckError::NotFound(reference.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, reference: &str) -> Result<(), ClaimCheckError> {
+        match tokio::fs::remove_file(self.path(reference)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct ClaimCheckOptions {
+    pub store: Box<dyn BlobStore>,
+
+    /// Bodies larger than this many bytes go to the blob store. The default leaves room
+    /// for properties under the 256 KB standard tier limit.
+    pub threshold: usize,
+}
+
+impl ClaimCheckOptions {
+    pub fn new(store: Box<dyn BlobStore>) -> Self {
+        ClaimCheckOptions { store, threshold: 192 * 1024 }
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Moves the body to the blob store if it is over the threshold, leaving a reference
+/// and checksum in the user properties. Returns whether the body was checked.
+pub async fn check_message<P>(message: &mut Message<P>, options: &ClaimCheckOptions) -> Result<bool, ClaimCheckError> {
+    if message.content.is_empty() {
+        return Ok(false);
+    }
+
+    // The body is here in full, so any reference is left over from a received message.
+    clear_claim_check(message);
+
+    if message.content.len() <= options.threshold {
+        return Ok(false);
+    }
+
+    let reference = options.store.put(&message.content).await?;
+    message.set_user_property(CLAIM_CHECK_REFERENCE_PROPERTY, reference);
+    message.set_user_property(CLAIM_CHECK_SHA256_PROPERTY, sha256_hex(&message.content));
+    message.set_user_property(CLAIM_CHECK_SIZE_PROPERTY, message.content.len().to_string());
+    message.content = Vec::new();
+    Ok(true)
+}
+
+/// Fetches a checked body and verifies it. The reference is kept on the message so the
+/// blob can be removed once the message is completed.
+pub async fn redeem_message<P>(message: &mut Message<P>, store: &dyn BlobStore) -> Result<bool, ClaimCheckError> {
+    let reference = match message.user_property(CLAIM_CHECK_REFERENCE_PROPERTY) {
+        None => return Ok(false),
+        Some(reference) => reference.to_string(),
+    };
+
+    let content = store.get(&reference).await?;
+    if message.user_property(CLAIM_CHECK_SHA256_PROPERTY) != Some(sha256_hex(&content).as_str()) {
+        return Err(ClaimCheckError::ChecksumMismatch(reference));
+    }
+
+    message.content = content;
+    message.user_properties.remove(CLAIM_CHECK_SHA256_PROPERTY);
+    message.user_properties.remove(CLAIM_CHECK_SIZE_PROPERTY);
+    Ok(true)
+}
+
+pub fn claim_check_reference<P>(message: &Message<P>) -> Option<&str> {
+    message.user_property(CLAIM_CHECK_REFERENCE_PROPERTY)
+}
+
+fn clear_claim_check<P>(message: &mut Message<P>) {
+    for name in [CLAIM_CHECK_REFERENCE_PROPERTY, CLAIM_CHECK_SHA256_PROPERTY, CLAIM_CHECK_SIZE_PROPERTY] {
+        message.user_properties.remove(name);
+    }
+}
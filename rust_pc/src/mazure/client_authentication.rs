@@ -43,4 +43,17 @@ impl<T> From<PoisonError<T>> for AuthenticationError {
 #[async_trait(?Send)]
 pub trait ClientAuthenticator {
     async fn authenticate(&self, reqbuilder: RequestBuilder) -> Result<RequestBuilder, AuthenticationError>;
-}
\ No newline at end of file
+
+    /// The bearer token `authenticate` adds, for transports other than HTTP.
+    async fn bearer_token(&self) -> Result<String, AuthenticationError> {
+        let request = self.authenticate(reqwest::Client::new().get("http://localhost/")).await?.build()?;
+        let authorization = request.headers().get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+
+        match authorization {
+            Some(token) => Ok(token.to_string()),
+            None => Err(AuthenticationError::AuthenticationAcquisitionError("The authenticator does not use bearer tokens".into())),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+pub static JSON_CONTENT_TYPE: &str = "text/json";
+
+#[cfg(feature = "msgpack")]
+pub static MESSAGE_PACK_CONTENT_TYPE: &str = "application/msgpack";
+
+#[cfg(feature = "cbor")]
+pub static CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+#[cfg(feature = "protobuf")]
+pub static PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("Unsupported content type: {0}")]
+    UnsupportedContentType(String),
+
+    #[error("Unable to encode message body: {0}")]
+    EncodeError(String),
+
+    #[error("Unable to decode message body: {0}")]
+    DecodeError(String),
+}
+
+/// Encodes and decodes message bodies of type `T`.
+///
+/// JSON is always available. MessagePack, CBOR and Protobuf are enabled with the
+/// `msgpack`, `cbor` and `protobuf` cargo features.
+pub trait Codec<T> {
+    /// Content type set on messages encoded with this codec.
+    const CONTENT_TYPE: &'static str;
+
+    fn encode(value: &T) -> Result<Vec<u8>, CodecError>;
+
+    fn decode(bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// The serde based formats, which can be picked at runtime from a content type.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Format {
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Format {
+    /// Finds the format for a received content type. Messages without a content type,
+    /// such as ones sent in a batch, are taken to be JSON.
+    pub fn from_content_type(content_type: &str) -> Result<Format, CodecError> {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+
+        match mime.as_str() {
+            "" | "text/json" | "application/json" => Ok(Format::Json),
+            m if m.ends_with("+json") => Ok(Format::Json),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Ok(Format::MessagePack),
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Ok(Format::Cbor),
+            #[cfg(feature = "protobuf")]
+            "application/x-protobuf" | "application/protobuf" => Err(CodecError::UnsupportedContentType(
+                format!("{} is not a serde format, decode it with ProtobufCodec", content_type))),
+            _ => Err(CodecError::UnsupportedContentType(content_type.to_string())),
+        }
+    }
+
+    pub fn content_type(self: &Self) -> &'static str {
+        match self {
+            Format::Json => JSON_CONTENT_TYPE,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => MESSAGE_PACK_CONTENT_TYPE,
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CBOR_CONTENT_TYPE,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self: &Self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| CodecError::EncodeError(e.to_string())),
+            // Named fields keep the payload readable by other languages.
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| CodecError::EncodeError(e.to_string())),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|e| CodecError::EncodeError(e.to_string()))?;
+                Ok(bytes)
+            },
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self: &Self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| CodecError::DecodeError(e.to_string())),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| CodecError::DecodeError(e.to_string())),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|e| CodecError::DecodeError(e.to_string())),
+        }
+    }
+}
+
+pub struct JsonCodec;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for JsonCodec {
+    const CONTENT_TYPE: &'static str = JSON_CONTENT_TYPE;
+
+    fn encode(value: &T) -> Result<Vec<u8>, CodecError> {
+        Format::Json.encode(value)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T, CodecError> {
+        Format::Json.decode(bytes)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl<T: Serialize + DeserializeOwned> Codec<T> for MessagePackCodec {
+    const CONTENT_TYPE: &'static str = MESSAGE_PACK_CONTENT_TYPE;
+
+    fn encode(value: &T) -> Result<Vec<u8>, CodecError> {
+        Format::MessagePack.encode(value)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T, CodecError> {
+        Format::MessagePack.decode(bytes)
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl<T: Serialize + DeserializeOwned> Codec<T> for CborCodec {
+    const CONTENT_TYPE: &'static str = CBOR_CONTENT_TYPE;
+
+    fn encode(value: &T) -> Result<Vec<u8>, CodecError> {
+        Format::Cbor.encode(value)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T, CodecError> {
+        Format::Cbor.decode(bytes)
+    }
+}
+
+/// Protobuf works on prost generated types rather than serde ones, so it cannot be
+/// picked from the content type by `Message::decode`. Use `Message::decode_with`.
+#[cfg(feature = "protobuf")]
+pub struct ProtobufCodec;
+
+#[cfg(feature = "protobuf")]
+impl<T: prost::Message + Default> Codec<T> for ProtobufCodec {
+    const CONTENT_TYPE: &'static str = PROTOBUF_CONTENT_TYPE;
+
+    fn encode(value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(value.encode_to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T, CodecError> {
+        T::decode(bytes).map_err(|e| CodecError::DecodeError(e.to_string()))
+    }
+}
@@ -0,0 +1,160 @@
+use std::io::{Read, Write};
+
+use thiserror::Error;
+
+use crate::mazure::sbclient::Message;
+
+/// User property recording how the body was compressed.
+pub static PAYLOAD_ENCODING_PROPERTY: &str = "payload-encoding";
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("Unsupported payload encoding: {0}")]
+    UnsupportedEncoding(String),
+
+    #[error("Decompressed payload is larger than the limit of {0} bytes")]
+    TooLarge(usize),
+
+    #[error("Unable to compress or decompress payload: {0}")]
+    IoError(String),
+}
+
+impl From<std::io::Error> for CompressionError {
+    fn from(e: std::io::Error) -> Self {
+        CompressionError::IoError(e.to_string())
+    }
+}
+
+/// Gzip is always available. Zstandard and Brotli are enabled with the `zstd` and
+/// `brotli` cargo features.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum CompressionAlgorithm {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl CompressionAlgorithm {
+    /// Name recorded in the payload encoding property, as used by `Content-Encoding`.
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            CompressionAlgorithm::Zstd => "zstd",
+            #[cfg(feature = "brotli")]
+            CompressionAlgorithm::Brotli => "br",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<CompressionAlgorithm, CompressionError> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" => Ok(CompressionAlgorithm::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(CompressionAlgorithm::Zstd),
+            #[cfg(feature = "brotli")]
+            "br" => Ok(CompressionAlgorithm::Brotli),
+            _ => Err(CompressionError::UnsupportedEncoding(name.to_string())),
+        }
+    }
+
+    pub fn compress(self: &Self, bytes: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        match self {
+            CompressionAlgorithm::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                Ok(encoder.finish()?)
+            },
+            #[cfg(feature = "zstd")]
+            CompressionAlgorithm::Zstd => Ok(zstd::stream::encode_all(bytes, 0)?),
+            #[cfg(feature = "brotli")]
+            CompressionAlgorithm::Brotli => {
+                let mut compressed = Vec::new();
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 9, 22);
+                encoder.write_all(bytes)?;
+                drop(encoder);
+                Ok(compressed)
+            },
+        }
+    }
+
+    /// Decompresses, failing as soon as the output grows past `max_size` so a small
+    /// malicious payload cannot exhaust memory.
+    pub fn decompress(self: &Self, bytes: &[u8], max_size: usize) -> Result<Vec<u8>, CompressionError> {
+        match self {
+            CompressionAlgorithm::Gzip => read_limited(flate2::read::GzDecoder::new(bytes), max_size),
+            #[cfg(feature = "zstd")]
+            CompressionAlgorithm::Zstd => read_limited(zstd::stream::Decoder::new(bytes)?, max_size),
+            #[cfg(feature = "brotli")]
+            CompressionAlgorithm::Brotli => read_limited(brotli::Decompressor::new(bytes, 4096), max_size),
+        }
+    }
+}
+
+fn read_limited(reader: impl Read, max_size: usize) -> Result<Vec<u8>, CompressionError> {
+    let mut output = Vec::new();
+    reader.take(max_size as u64 + 1).read_to_end(&mut output)?;
+
+    if output.len() > max_size {
+        return Err(CompressionError::TooLarge(max_size));
+    }
+    Ok(output)
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    /// Algorithm used when sending. Nothing is compressed if not set.
+    pub algorithm: Option<CompressionAlgorithm>,
+
+    /// Bodies smaller than this many bytes are sent as is.
+    pub threshold: usize,
+
+    /// Largest body accepted when decompressing a received message.
+    pub max_decompressed_size: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            algorithm: None,
+            threshold: 1024,
+            max_decompressed_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Compresses the body if an algorithm is configured, the body is over the threshold and
+/// compressing actually makes it smaller. Returns whether the body was compressed.
+pub fn compress_message<P>(message: &mut Message<P>, options: &CompressionOptions) -> Result<bool, CompressionError> {
+    let algorithm = match options.algorithm {
+        Some(algorithm) => algorithm,
+        None => return Ok(false),
+    };
+
+    if message.content.len() < options.threshold || message.user_property(PAYLOAD_ENCODING_PROPERTY).is_some() {
+        return Ok(false);
+    }
+
+    let compressed = algorithm.compress(&message.content)?;
+    if compressed.len() >= message.content.len() {
+        return Ok(false);
+    }
+
+    message.content = compressed;
+    message.set_user_property(PAYLOAD_ENCODING_PROPERTY, algorithm.name());
+    Ok(true)
+}
+
+/// Reverses `compress_message` if the message records a payload encoding. Returns whether
+/// the body was decompressed.
+pub fn decompress_message<P>(message: &mut Message<P>, options: &CompressionOptions) -> Result<bool, CompressionError> {
+    let algorithm = match message.user_property(PAYLOAD_ENCODING_PROPERTY) {
+        Some(name) => CompressionAlgorithm::from_name(name)?,
+        None => return Ok(false),
+    };
+
+    message.content = algorithm.decompress(&message.content, options.max_decompressed_size)?;
+    message.user_properties.remove(PAYLOAD_ENCODING_PROPERTY);
+    Ok(true)
+}
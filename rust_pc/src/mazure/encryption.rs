@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, AeadCore, OsRng, Payload};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+use crate::mazure::sbclient::Message;
+
+/// User property naming the algorithm the body is encrypted with.
+pub static ENCRYPTION_ALGORITHM_PROPERTY: &str = "encryption-algorithm";
+
+/// User property holding the id of the key-encryption key that wrapped the data key.
+pub static ENCRYPTION_KEY_ID_PROPERTY: &str = "encryption-key-id";
+
+/// User property holding the wrapped data key (base64).
+pub static ENCRYPTION_WRAPPED_KEY_PROPERTY: &str = "encryption-wrapped-key";
+
+/// User property holding the nonce the body was encrypted with (base64).
+pub static ENCRYPTION_NONCE_PROPERTY: &str = "encryption-nonce";
+
+pub static AES_256_GCM: &str = "A256GCM";
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("Unknown key-encryption key: {0}")]
+    UnknownKey(String),
+
+    #[error("Unsupported encryption algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+
+    #[error("Encrypted message is missing the {0} property")]
+    MissingProperty(String),
+
+    #[error("Invalid key material: {0}")]
+    InvalidKey(String),
+
+    #[error("Unable to encrypt or decrypt: {0}")]
+    CryptoError(String),
+
+    /// The key provider could not be reached or failed. Decryption may succeed later.
+    #[error("Key provider error: {0}")]
+    ProviderError(String),
+}
+
+impl From<aes_gcm::Error> for EncryptionError {
+    fn from(_e: aes_gcm::Error) -> Self {
+        // The aead error is deliberately opaque.
+        EncryptionError::CryptoError("authenticated encryption failed".into())
+    }
+}
+
+impl From<base64::DecodeError> for EncryptionError {
+    fn from(e: base64::DecodeError) -> Self {
+        EncryptionError::InvalidKey(e.to_string())
+    }
+}
+
+/// A data key wrapped by a key-encryption key.
+#[derive(Debug, Clone)]
+pub struct WrappedKey {
+    pub key_id: String,
+    pub wrapped: Vec<u8>,
+}
+
+/// Holds the key-encryption keys (KEKs) that protect per-message data keys.
+///
+/// Data keys are always wrapped with the current key, while any key the provider still
+/// knows can unwrap, which is what allows keys to be rotated.
+#[async_trait(?Send)]
+pub trait KeyProvider {
+    async fn wrap_key(&self, data_key: &[u8]) -> Result<WrappedKey, EncryptionError>;
+
+    async fn unwrap_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, EncryptionError>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyFile {
+    current: String,
+    keys: HashMap<String, String>,
+}
+
+/// Key-encryption keys kept in a local JSON file:
+///
+/// `{ "current": "2023-10", "keys": { "2023-09": "<base64 key>", "2023-10": "<base64 key>" } }`
+///
+/// Keys are 32 random bytes, e.g. from `openssl rand -base64 32`. To rotate, add a new
+/// key and make it current; keep the old one until its messages have drained.
+#[derive(Clone)]
+pub struct LocalKeyProvider {
+    current: String,
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl LocalKeyProvider {
+    pub fn new(current: impl Into<String>, keys: HashMap<String, Vec<u8>>) -> Result<Self, EncryptionError> {
+        let current = current.into();
+        if !keys.contains_key(&current) {
+            return Err(EncryptionError::UnknownKey(current));
+        }
+        if let Some((key_id, _)) = keys.iter().find(|(_, key)| key.len() != KEY_SIZE) {
+            return Err(EncryptionError::InvalidKey(format!("key {} is not {} bytes", key_id, KEY_SIZE)));
+        }
+
+        Ok(LocalKeyProvider { current, keys })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path)?;
+        let key_file: KeyFile = serde_json::from_reader(BufReader::new(file))?;
+
+        let mut keys = HashMap::new();
+        for (key_id, encoded) in key_file.keys {
+            keys.insert(key_id, BASE64.decode(encoded.trim())?);
+        }
+
+        Ok(LocalKeyProvider::new(key_file.current, keys)?)
+    }
+
+    /// Creates a new random key, e.g. for tests or to seed a key file.
+    pub fn generate_key() -> Vec<u8> {
+        Aes256Gcm::generate_key(OsRng).to_vec()
+    }
+
+    fn cipher(self: &Self, key_id: &str) -> Result<Aes256Gcm, EncryptionError> {
+        let key = self.keys.get(key_id).ok_or_else(|| EncryptionError::UnknownKey(key_id.to_string()))?;
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+    }
+}
+
+#[async_trait(?Send)]
+impl KeyProvider for LocalKeyProvider {
+    async fn wrap_key(&self, data_key: &[u8]) -> Result<WrappedKey, EncryptionError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload { msg: data_key, aad: self.current.as_bytes() };
+        let ciphertext = self.cipher(&self.current)?.encrypt(&nonce, payload)?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&ciphertext);
+        Ok(WrappedKey { key_id: self.current.clone(), wrapped })
+    }
+
+    async fn unwrap_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        if wrapped.len() < NONCE_SIZE {
+            return Err(EncryptionError::InvalidKey("wrapped key is too short".into()));
+        }
+
+        let (nonce, ciphertext) = wrapped.split_at(NONCE_SIZE);
+        let payload = Payload { msg: ciphertext, aad: key_id.as_bytes() };
+        Ok(self.cipher(key_id)?.decrypt(Nonce::from_slice(nonce), payload)?)
+    }
+}
+
+/// Encrypts the body with a fresh data key, which is wrapped by the provider and stored
+/// with the nonce in the user properties. The content type is bound to the ciphertext.
+pub async fn encrypt_message<P>(message: &mut Message<P>, provider: &dyn KeyProvider) -> Result<(), EncryptionError> {
+    let data_key = Aes256Gcm::generate_key(OsRng);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let payload = Payload { msg: &message.content, aad: message.content_type.as_bytes() };
+    let ciphertext = Aes256Gcm::new(&data_key).encrypt(&nonce, payload)?;
+    let wrapped = provider.wrap_key(&data_key).await?;
+
+    message.content = ciphertext;
+    message.set_user_property(ENCRYPTION_ALGORITHM_PROPERTY, AES_256_GCM);
+    message.set_user_property(ENCRYPTION_KEY_ID_PROPERTY, wrapped.key_id);
+    message.set_user_property(ENCRYPTION_WRAPPED_KEY_PROPERTY, BASE64.encode(&wrapped.wrapped));
+    message.set_user_property(ENCRYPTION_NONCE_PROPERTY, BASE64.encode(nonce));
+    Ok(())
+}
+
+pub fn is_encrypted<P>(message: &Message<P>) -> bool {
+    message.user_property(ENCRYPTION_ALGORITHM_PROPERTY).is_some()
+}
+
+/// Reverses `encrypt_message`. Returns whether the message was encrypted.
+pub async fn decrypt_message<P>(message: &mut Message<P>, provider: &dyn KeyProvider) -> Result<bool, EncryptionError> {
+    let algorithm = match message.user_property(ENCRYPTION_ALGORITHM_PROPERTY) {
+        None => return Ok(false),
+        Some(algorithm) => algorithm,
+    };
+    if algorithm != AES_256_GCM {
+        return Err(EncryptionError::UnsupportedAlgorithm(algorithm.to_string()));
+    }
+
+    let property = |name: &str| message.user_property(name)
+        .ok_or_else(|| EncryptionError::MissingProperty(name.to_string()));
+    let key_id = property(ENCRYPTION_KEY_ID_PROPERTY)?;
+    let wrapped = BASE64.decode(property(ENCRYPTION_WRAPPED_KEY_PROPERTY)?)?;
+    let nonce = BASE64.decode(property(ENCRYPTION_NONCE_PROPERTY)?)?;
+    if nonce.len() != NONCE_SIZE {
+        return Err(EncryptionError::InvalidKey("nonce has the wrong size".into()));
+    }
+
+    let data_key = provider.unwrap_key(key_id, &wrapped).await?;
+    if data_key.len() != KEY_SIZE {
+        return Err(EncryptionError::InvalidKey("data key has the wrong size".into()));
+    }
+
+    let payload = Payload { msg: &message.content, aad: message.content_type.as_bytes() };
+    let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key))
+        .decrypt(Nonce::from_slice(&nonce), payload)?;
+
+    message.content = plaintext;
+    for name in [ENCRYPTION_ALGORITHM_PROPERTY, ENCRYPTION_KEY_ID_PROPERTY, ENCRYPTION_WRAPPED_KEY_PROPERTY, ENCRYPTION_NONCE_PROPERTY] {
+        message.user_properties.remove(name);
+    }
+    Ok(true)
+}
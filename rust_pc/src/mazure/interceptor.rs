@@ -0,0 +1,22 @@
+use async_trait::async_trait;
+
+use crate::mazure::sbclient::{AzureServiceBusError, BrokerSendProperties, Message};
+
+/// Changes messages before they are sent, typically to add properties such as a tenant
+/// or trace id. Interceptors see the message as the caller built it, before ids are
+/// assigned and before it is compressed, encrypted or signed. An error aborts the send.
+/// Closures taking the message are interceptors too.
+#[async_trait(?Send)]
+pub trait SendInterceptor {
+    async fn intercept(&self, message: &mut Message<BrokerSendProperties>) -> Result<(), AzureServiceBusError>;
+}
+
+#[async_trait(?Send)]
+impl<F> SendInterceptor for F
+where
+    F: Fn(&mut Message<BrokerSendProperties>) -> Result<(), AzureServiceBusError>,
+{
+    async fn intercept(&self, message: &mut Message<BrokerSendProperties>) -> Result<(), AzureServiceBusError> {
+        self(message)
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::mazure::sbclient::{AzureServiceBusError, BatchedMessage, BrokerSendProperties, Message, encode_user_property_value};
+
+const KB: usize = 1024;
+const MB: usize = 1024 * 1024;
+
+/// Largest message size a Premium namespace can be configured for.
+pub const PREMIUM_MAX_MESSAGE_SIZE: usize = 100 * MB;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ServiceTier {
+    Standard,
+    Premium,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// Largest single message, counting the body and the property headers.
+    pub max_message_size: usize,
+
+    /// Largest total size of the messages in one batch send, each counted as by `message_size`.
+    pub max_batch_size: usize,
+}
+
+impl SizeLimits {
+    /// The tier's default limits: 256 KB on Standard, 1 MB on Premium.
+    pub fn for_tier(tier: ServiceTier) -> Self {
+        match tier {
+            ServiceTier::Standard => SizeLimits { max_message_size: 256 * KB, max_batch_size: 256 * KB },
+            ServiceTier::Premium => SizeLimits { max_message_size: MB, max_batch_size: MB },
+        }
+    }
+
+    /// Premium entities can be configured to accept messages of up to 100 MB. Batches
+    /// stay limited to 1 MB.
+    pub fn premium(max_message_size: usize) -> Result<Self, AzureServiceBusError> {
+        if max_message_size > PREMIUM_MAX_MESSAGE_SIZE {
+            return Err(AzureServiceBusError::MessageTooLarge { size: max_message_size, limit: PREMIUM_MAX_MESSAGE_SIZE });
+        }
+        Ok(SizeLimits { max_message_size, ..SizeLimits::for_tier(ServiceTier::Premium) })
+    }
+
+    pub fn check_message(self: &Self, message: &Message<BrokerSendProperties>) -> Result<usize, AzureServiceBusError> {
+        let size = message_size(message)?;
+        if size > self.max_message_size {
+            return Err(AzureServiceBusError::MessageTooLarge { size, limit: self.max_message_size });
+        }
+        Ok(size)
+    }
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        SizeLimits::for_tier(ServiceTier::Standard)
+    }
+}
+
+/// Bytes the message takes on the wire: the body, the `BrokerProperties` header and the
+/// user property headers.
+pub fn message_size(message: &Message<BrokerSendProperties>) -> Result<usize, AzureServiceBusError> {
+    wire_size(message.content.len(), &message.properties, &message.user_properties)
+}
+
+/// Bytes a batch entry counts towards the batch limit, measured as for a single message.
+pub fn batched_size<P: Serialize>(entry: &BatchedMessage<P>) -> Result<usize, AzureServiceBusError> {
+    wire_size(entry.body.len(), &entry.broker_properties, &entry.user_properties)
+}
+
+fn wire_size<P: Serialize>(body_size: usize, properties: &P, user_properties: &BTreeMap<String, String>) -> Result<usize, AzureServiceBusError> {
+    let mut size = body_size + serde_json::to_vec(properties)?.len();
+    for (name, value) in user_properties {
+        size += name.len() + encode_user_property_value(value)?.len();
+    }
+    Ok(size)
+}
+
+/// Groups batch entries into runs whose messages add up to at most `max_batch_size`.
+/// An entry too large to fit a batch on its own is an error.
+pub fn split_batch<P: Serialize>(entries: Vec<BatchedMessage<P>>, max_batch_size: usize) -> Result<Vec<Vec<BatchedMessage<P>>>, AzureServiceBusError> {
+    let mut batches = Vec::new();
+    let mut current = Vec::new();
+    let mut current_size = 0;
+
+    for entry in entries {
+        let size = batched_size(&entry)?;
+        if size > max_batch_size {
+            return Err(AzureServiceBusError::MessageTooLarge { size, limit: max_batch_size });
+        }
+
+        if current_size + size > max_batch_size {
+            batches.push(std::mem::take(&mut current));
+            current_size = 0;
+        }
+        current.push(entry);
+        current_size += size;
+    }
+
+    if !current.is_empty() {
+        batches.push(current);
+    }
+    Ok(batches)
+}
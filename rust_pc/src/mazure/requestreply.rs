@@ -0,0 +1,231 @@
+use std::future::Future;
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::mazure::codec::Format;
+use crate::mazure::sbclient::{AzureServiceBusClient, AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message};
+use crate::mazure::sending::SendReceipt;
+
+/// User property holding when the requester stops waiting for a reply, as an RFC 3339
+/// timestamp with milliseconds. `TimeToLive` and `EnqueuedTimeUtc` are only good to
+/// the second, too coarse for short timeouts.
+pub static REPLY_DEADLINE_PROPERTY: &str = "reply-deadline";
+
+/// Reason given to replies dead-lettered because they are for another requester.
+pub static UNEXPECTED_SESSION_REASON: &str = "UnexpectedSession";
+
+/// Sends requests and waits for their replies on a reply queue.
+///
+/// Requests carry the reply queue in `ReplyTo` and the requester's session id in
+/// `ReplyToSessionId`; responders answer with the request's correlation id in that
+/// session. The REST API cannot receive by session, so each requester needs a reply
+/// queue of its own. Replies in another session, e.g. for a requester that used the
+/// queue before a restart, are dead-lettered rather than locked again and again.
+/// Replies in this requester's session that do not match the outstanding request
+/// belong to requests that already timed out and are discarded.
+pub struct Requester<'a> {
+    requests: &'a AzureServiceBusClient,
+    replies: &'a AzureServiceBusClient,
+    session_id: String,
+    format: Format,
+    poll_interval: Duration,
+    late_replies: usize,
+}
+
+impl<'a> Requester<'a> {
+    /// Sends requests with `requests` and receives replies with `replies`, whose queue
+    /// becomes the `ReplyTo` of every request.
+    pub fn new(requests: &'a AzureServiceBusClient, replies: &'a AzureServiceBusClient) -> Self {
+        Requester {
+            requests,
+            replies,
+            session_id: Uuid::new_v4().to_string(),
+            format: Format::Json,
+            poll_interval: Duration::from_millis(250),
+            late_replies: 0,
+        }
+    }
+
+    /// Replies are expected in this session instead of a random one, e.g. to pick up
+    /// replies to requests sent before a restart.
+    pub fn with_session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.session_id = session_id.into();
+        self
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// How long to wait before receiving again when the reply queue is empty.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn session_id(self: &Self) -> &str {
+        &self.session_id
+    }
+
+    /// Replies discarded because their request had already timed out.
+    pub fn late_replies(self: &Self) -> usize {
+        self.late_replies
+    }
+
+    /// Sends the body as a request and decodes the reply.
+    pub async fn request<Req, Resp>(self: &mut Self, body: &Req, timeout: Duration) -> Result<Resp, AzureServiceBusError>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let message = Message::encode_as(self.format, body)?;
+        let reply = self.request_message(&message, timeout).await?;
+        Ok(reply.decode::<Resp>()?)
+    }
+
+    /// Sends the message as a request and returns the completed reply. The request
+    /// expires from the queue at the timeout unless it sets a time to live itself.
+    pub async fn request_message(self: &mut Self, message: &Message<BrokerSendProperties>, timeout: Duration) -> Result<Message<BrokerReceiveProperties>, AzureServiceBusError> {
+        let deadline = Instant::now() + timeout;
+        let deadline_utc = chrono::Duration::from_std(timeout).ok()
+            .and_then(|timeout| Utc::now().checked_add_signed(timeout))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+
+        let mut request = message.clone();
+        request.set_user_property(REPLY_DEADLINE_PROPERTY, deadline_utc.to_rfc3339_opts(SecondsFormat::Millis, true));
+        request.properties.reply_to = Some(self.replies.path().to_string());
+        request.properties.reply_to_session_id = Some(self.session_id.clone());
+        request.properties.time_to_live.get_or_insert(timeout.as_secs_f64().ceil().max(1.0) as i64);
+
+        let receipt = self.requests.send(&request).await?;
+        self.wait_for_reply(&receipt, deadline).await
+    }
+
+    async fn wait_for_reply(self: &mut Self, receipt: &SendReceipt, deadline: Instant) -> Result<Message<BrokerReceiveProperties>, AzureServiceBusError> {
+        loop {
+            match self.replies.peek_lock().await? {
+                Some(reply) if reply.properties.session_id.as_deref() != Some(self.session_id.as_str()) => {
+                    println!("Setting aside reply {:?} for session {:?}", reply.properties.correlation_id, reply.properties.session_id);
+                    let description = format!("Reply queue {} is used by session {}", self.replies.path(), self.session_id);
+                    self.replies.dead_letter(&reply, UNEXPECTED_SESSION_REASON, &description).await?;
+                },
+                Some(reply) if reply.properties.correlation_id.as_deref() == Some(receipt.correlation_id.as_str()) => {
+                    self.replies.complete_message(&reply).await?;
+                    return Ok(reply);
+                },
+                Some(reply) => {
+                    println!("Discarding late reply {:?}", reply.properties.correlation_id);
+                    self.late_replies += 1;
+                    self.replies.complete_message(&reply).await?;
+                },
+                None => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    tokio::time::sleep(self.poll_interval.min(deadline - now)).await;
+                },
+            }
+
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+
+        Err(AzureServiceBusError::ReplyTimeout(receipt.correlation_id.clone()))
+    }
+}
+
+/// Answers requests sent by a `Requester`.
+pub struct Responder<'a> {
+    sb_client: &'a AzureServiceBusClient,
+}
+
+impl<'a> Responder<'a> {
+    /// Receives requests with the client and sends replies with it too.
+    pub fn new(sb_client: &'a AzureServiceBusClient) -> Self {
+        Responder { sb_client }
+    }
+
+    /// Handles the next request, replying in the request's format. Returns false if
+    /// there was no request. Requests that cannot be decoded are dead-lettered and ones
+    /// the handler fails are released for redelivery.
+    pub async fn respond<Req, Resp, F, Fut>(self: &Self, handler: F) -> Result<bool, AzureServiceBusError>
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        F: FnOnce(Req) -> Fut,
+        Fut: Future<Output = Result<Resp, AzureServiceBusError>>,
+    {
+        let request = match self.sb_client.peek_lock().await? {
+            None => return Ok(false),
+            Some(request) => request,
+        };
+
+        let decoded = Format::from_content_type(&request.content_type)
+            .and_then(|format| Ok((format, format.decode::<Req>(&request.content)?)));
+        let (format, body) = match decoded {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self.sb_client.dead_letter(&request, "DecodeError", &e.to_string()).await?;
+                return Err(e.into());
+            },
+        };
+
+        let response = match handler(body).await {
+            Ok(response) => response,
+            Err(e) => {
+                self.sb_client.unlock_message(&request.properties).await?;
+                return Err(e);
+            },
+        };
+
+        self.reply(&request, &Message::encode_as(format, &response)?).await?;
+        self.sb_client.complete_message(&request).await?;
+        Ok(true)
+    }
+
+    /// Sends the reply to the request's `ReplyTo`. Nothing is sent if the requester has
+    /// stopped waiting, by its reply deadline or else by the request's time to live;
+    /// otherwise the reply expires when the requester stops waiting.
+    pub async fn reply(self: &Self, request: &Message<BrokerReceiveProperties>, reply: &Message<BrokerSendProperties>) -> Result<Option<SendReceipt>, AzureServiceBusError> {
+        let props = &request.properties;
+        let reply_to = props.reply_to.as_deref()
+            .ok_or_else(|| AzureServiceBusError::ConversionError("Request has no ReplyTo".into()))?;
+        let correlation_id = props.correlation_id.clone().or_else(|| props.message_id.clone())
+            .ok_or_else(|| AzureServiceBusError::ConversionError("Request has no correlation id".into()))?;
+
+        let mut reply = reply.clone();
+        if let Some(deadline) = reply_deadline(request)? {
+            let remaining = deadline - Utc::now();
+            if remaining <= chrono::Duration::zero() {
+                println!("Not replying to expired request {}", correlation_id);
+                return Ok(None);
+            }
+            // Round up so a reply sent in the last second does not expire on arrival.
+            reply.properties.time_to_live = Some((remaining.num_milliseconds() + 999) / 1000);
+        }
+        reply.properties.correlation_id = Some(correlation_id);
+        reply.properties.session_id = props.reply_to_session_id.clone();
+
+        Ok(Some(self.sb_client.send_to(reply_to, &reply).await?))
+    }
+}
+
+// When the requester stops waiting, if the request says.
+fn reply_deadline(request: &Message<BrokerReceiveProperties>) -> Result<Option<DateTime<Utc>>, AzureServiceBusError> {
+    if let Some(deadline) = request.user_property(REPLY_DEADLINE_PROPERTY) {
+        let deadline = DateTime::parse_from_rfc3339(deadline)
+            .map_err(|e| AzureServiceBusError::ConversionError(format!("Invalid {}: {}", REPLY_DEADLINE_PROPERTY, e)))?;
+        return Ok(Some(deadline.with_timezone(&Utc)));
+    }
+
+    let props = &request.properties;
+    Ok(props.enqueued_time_utc.zip(props.time_to_live).map(|(enqueued, ttl)| enqueued + chrono::Duration::seconds(ttl)))
+}
@@ -1,16 +1,52 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 use reqwest::Response;
-use reqwest::header::ToStrError;
+use reqwest::header::{HeaderMap, ToStrError};
 use serde::{Serialize, Deserialize};
 use serde::de::DeserializeOwned;
 use thiserror::Error;
 use uuid::Uuid;
 
+use crate::mazure::amqp::{AmqpOptions, AmqpTransport};
+use crate::mazure::chunking::split_message;
+use crate::mazure::claimcheck::{ClaimCheckError, ClaimCheckOptions, check_message, claim_check_reference, redeem_message};
 use crate::mazure::client_authentication::{ClientAuthenticator, AuthenticationError};
+use crate::mazure::codec::{Codec, CodecError, Format, JSON_CONTENT_TYPE};
+use crate::mazure::compression::{CompressionError, CompressionOptions, compress_message, decompress_message};
+use crate::mazure::encryption::{EncryptionError, KeyProvider, decrypt_message, encrypt_message, is_encrypted};
+use crate::mazure::interceptor::SendInterceptor;
+use crate::mazure::signing::{MessageSigner, SignatureError, TrustedKeys, sign_message, verify_message};
+use crate::mazure::limits::{SizeLimits, split_batch};
 use crate::mazure::opt_date_rfc2822_serialization;
+use crate::mazure::sending::{MessageIdStrategy, SendReceipt, SendRetryPolicy};
 
 pub static SERVICE_BUS_RESOURCE: &str = "https://servicebus.azure.net";
 
+/// User property set on dead-lettered copies with the reason they were dead-lettered.
+pub static DEAD_LETTER_REASON_PROPERTY: &str = "DeadLetterReason";
+
+/// User property set on dead-lettered copies with a longer description of the failure.
+pub static DEAD_LETTER_DESCRIPTION_PROPERTY: &str = "DeadLetterErrorDescription";
+
+/// Content type of the JSON batch send format.
+pub static BATCH_CONTENT_TYPE: &str = "application/vnd.microsoft.servicebus.json";
+
+// Response headers that are never user properties.
+static STANDARD_RESPONSE_HEADERS: &[&str] = &[
+    "brokerproperties",
+    "connection",
+    "content-encoding",
+    "content-length",
+    "content-type",
+    "date",
+    "location",
+    "server",
+    "strict-transport-security",
+    "transfer-encoding",
+    "vary",
+];
+
 #[derive(Error, Debug)]
 pub enum AzureServiceBusError {
     #[error("Unable to authenticate: {0}")]
@@ -27,6 +63,23 @@ pub enum AzureServiceBusError {
 
     #[error("Service error: {0}")]
     ServiceError(String),
+
+    #[error("Payload error: {0}")]
+    PayloadError(String),
+
+    /// The payload cannot be opened for now, e.g. its key has not been rolled out here
+    /// yet or the blob store is unreachable, and may be later.
+    #[error("Payload unavailable: {0}")]
+    PayloadUnavailable(String),
+
+    #[error("Signature error: {0}")]
+    SignatureError(String),
+
+    #[error("Message is {size} bytes, more than the limit of {limit} bytes")]
+    MessageTooLarge { size: usize, limit: usize },
+
+    #[error("No reply to request {0} before the timeout")]
+    ReplyTimeout(String),
 }
 
 impl AzureServiceBusError {
@@ -35,7 +88,7 @@ impl AzureServiceBusError {
         if sc >= 500 {
             AzureServiceBusError::ServiceError(sc.to_string())
         }
-        else if sc >= 400 && sc < 500 {
+        else if (400..500).contains(&sc) {
             AzureServiceBusError::RequestError(sc.to_string())
         }
         else {
@@ -62,6 +115,42 @@ impl From<ToStrError> for AzureServiceBusError {
     }
 }
 
+impl From<CodecError> for AzureServiceBusError {
+    fn from(e: CodecError) -> Self {
+        AzureServiceBusError::ConversionError(e.to_string())
+    }
+}
+
+impl From<CompressionError> for AzureServiceBusError {
+    fn from(e: CompressionError) -> Self {
+        AzureServiceBusError::PayloadError(e.to_string())
+    }
+}
+
+impl From<EncryptionError> for AzureServiceBusError {
+    fn from(e: EncryptionError) -> Self {
+        match e {
+            EncryptionError::UnknownKey(_) | EncryptionError::ProviderError(_) => AzureServiceBusError::PayloadUnavailable(e.to_string()),
+            _ => AzureServiceBusError::PayloadError(e.to_string()),
+        }
+    }
+}
+
+impl From<ClaimCheckError> for AzureServiceBusError {
+    fn from(e: ClaimCheckError) -> Self {
+        match e {
+            ClaimCheckError::StoreError(_) => AzureServiceBusError::PayloadUnavailable(e.to_string()),
+            _ => AzureServiceBusError::PayloadError(e.to_string()),
+        }
+    }
+}
+
+impl From<SignatureError> for AzureServiceBusError {
+    fn from(e: SignatureError) -> Self {
+        AzureServiceBusError::SignatureError(e.to_string())
+    }
+}
+
 impl From<serde_json::Error> for AzureServiceBusError {
     fn from(e: serde_json::Error) -> Self {
         AzureServiceBusError::ConversionError(e.to_string())
@@ -78,7 +167,7 @@ pub struct BrokerSendProperties {
     pub correlation_id: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(rename = "CorrelationId")]
+    #[serde(rename = "SessionId")]
     pub session_id: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -112,6 +201,11 @@ pub struct BrokerSendProperties {
     #[serde(rename = "PartitionKey")]
     pub partition_key: Option<String>,
 
+    // Send only properties
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "MessageId")]
+    pub message_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -124,7 +218,7 @@ pub struct BrokerReceiveProperties {
     pub correlation_id: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(rename = "CorrelationId")]
+    #[serde(rename = "SessionId")]
     pub session_id: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -186,7 +280,7 @@ pub struct BrokerReceiveProperties {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "SequenceNumber")]
-    pub sequence_number: Option<i32>,
+    pub sequence_number: Option<i64>,
 
     // Fields documented on BrokerProperties object, but not main page.
 
@@ -207,6 +301,7 @@ impl BrokerSendProperties {
             reply_to_session_id: None,
             session_id: None,
             to: None,
+            message_id: None,
         }
     }
     
@@ -217,7 +312,6 @@ impl BrokerSendProperties {
 
 impl BrokerReceiveProperties {
 
-    #[allow(dead_code)]
     pub fn new_empty() -> Self {
         BrokerReceiveProperties {
             correlation_id: None,
@@ -242,7 +336,7 @@ impl BrokerReceiveProperties {
     pub fn from_http_response(res: &Response) -> Result<BrokerReceiveProperties, AzureServiceBusError> {
         match res.headers().get("BrokerProperties") {
             None => {
-                return Err(AzureServiceBusError::ConversionError("BrokerProperites header not present in response.".into()));
+                Err(AzureServiceBusError::ConversionError("BrokerProperites header not present in response.".into()))
             }
             Some(props_text) => {
                 let json_text = props_text.to_str()?;
@@ -251,7 +345,6 @@ impl BrokerReceiveProperties {
         }
     }
 
-    #[allow(dead_code)]
     pub fn to_json(self: &Self) -> Result<String, AzureServiceBusError> {
         Ok(serde_json::to_string(self)?)
     }
@@ -262,12 +355,35 @@ pub struct Message<T> {
     pub properties: T,
     pub content: Vec<u8>,
     pub content_type: String,
+
+    /// Custom properties, sent as HTTP headers. Header names are case insensitive so
+    /// names are kept in lower case.
+    #[serde(default)]
+    pub user_properties: BTreeMap<String, String>,
 }
 
 impl<P> Message<P> {
     pub fn json_into<T: DeserializeOwned>(self: &Self) -> Result<T, AzureServiceBusError> {
         Ok(serde_json::from_slice(&self.content)?)
     }
+
+    /// Decodes the body with the serde format matching the content type.
+    pub fn decode<T: DeserializeOwned>(self: &Self) -> Result<T, CodecError> {
+        Format::from_content_type(&self.content_type)?.decode(&self.content)
+    }
+
+    /// Decodes the body with a specific codec regardless of the content type.
+    pub fn decode_with<C: Codec<T>, T>(self: &Self) -> Result<T, CodecError> {
+        C::decode(&self.content)
+    }
+
+    pub fn user_property(self: &Self, name: &str) -> Option<&str> {
+        self.user_properties.get(&name.to_ascii_lowercase()).map(|v| v.as_str())
+    }
+
+    pub fn set_user_property(self: &mut Self, name: &str, value: impl Into<String>) {
+        self.user_properties.insert(name.to_ascii_lowercase(), value.into());
+    }
 }
 
 impl Message<BrokerSendProperties> {
@@ -276,16 +392,116 @@ impl Message<BrokerSendProperties> {
         Ok(Message {
             properties: BrokerSendProperties::new_empty(),
             content: raw_bytes,
-            content_type: "text/json".into()
+            content_type: JSON_CONTENT_TYPE.into(),
+            user_properties: BTreeMap::new(),
+        })
+    }
+
+    pub fn encode<C: Codec<T>, T>(body: &T) -> Result<Self, CodecError> {
+        Ok(Message {
+            properties: BrokerSendProperties::new_empty(),
+            content: C::encode(body)?,
+            content_type: C::CONTENT_TYPE.into(),
+            user_properties: BTreeMap::new(),
+        })
+    }
+
+    /// Encodes the body with a serde format picked at runtime.
+    pub fn encode_as<T: Serialize>(format: Format, body: &T) -> Result<Self, CodecError> {
+        Ok(Message {
+            properties: BrokerSendProperties::new_empty(),
+            content: format.encode(body)?,
+            content_type: format.content_type().into(),
+            user_properties: BTreeMap::new(),
         })
     }
 }
 
+impl Message<BrokerReceiveProperties> {
+    /// Creates a sendable copy of a received message with the same content, user
+    /// properties and settable broker properties.
+    pub fn to_send_message(self: &Self) -> Message<BrokerSendProperties> {
+        let p = &self.properties;
+        Message {
+            properties: BrokerSendProperties {
+                correlation_id: p.correlation_id.clone(),
+                session_id: p.session_id.clone(),
+                label: p.label.clone(),
+                reply_to: p.reply_to.clone(),
+                time_to_live: p.time_to_live,
+                to: p.to.clone(),
+                scheduled_enqueue_time_utc: None,
+                reply_to_session_id: p.reply_to_session_id.clone(),
+                partition_key: p.partition_key.clone(),
+                // The copy is a new message, which duplicate detection must not drop.
+                message_id: None,
+            },
+            content: self.content.clone(),
+            content_type: self.content_type.clone(),
+            user_properties: self.user_properties.clone(),
+        }
+    }
+}
+
+/// One entry of the JSON batch send format. Batched bodies are sent as text.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchedMessage<P> {
+    #[serde(rename = "Body")]
+    pub body: String,
+
+    #[serde(rename = "BrokerProperties")]
+    pub broker_properties: P,
+
+    #[serde(rename = "UserProperties")]
+    #[serde(default)]
+    pub user_properties: BTreeMap<String, String>,
+}
+
+/// Encodes a user property value for use as a header value. Strings are sent quoted.
+pub(crate) fn encode_user_property_value(value: &str) -> Result<String, AzureServiceBusError> {
+    Ok(serde_json::to_string(value)?)
+}
+
+/// Decodes a user property header value. Strings come back quoted, anything else is
+/// taken as is.
+pub(crate) fn decode_user_property_value(text: &str) -> String {
+    match serde_json::from_str::<String>(text) {
+        Ok(v) => v,
+        Err(_) => text.to_string(),
+    }
+}
+
+fn user_properties_from_headers(headers: &HeaderMap) -> Result<BTreeMap<String, String>, AzureServiceBusError> {
+    let mut user_properties = BTreeMap::new();
+
+    for (name, value) in headers {
+        let name = name.as_str();
+        if STANDARD_RESPONSE_HEADERS.contains(&name) || name.starts_with("x-ms-") {
+            continue;
+        }
+
+        user_properties.insert(name.to_string(), decode_user_property_value(value.to_str()?));
+    }
+
+    Ok(user_properties)
+}
+
 pub struct AzureServiceBusClient {
     authenticator: Box<dyn ClientAuthenticator>,
     http_client: reqwest::Client,
-    namespace: String,
+    endpoint: String,
     path: String,
+    dead_letter_path: Option<String>,
+    compression: CompressionOptions,
+    encryption: Option<Box<dyn KeyProvider>>,
+    signer: Option<MessageSigner>,
+    trusted_keys: Option<TrustedKeys>,
+    claim_check: Option<ClaimCheckOptions>,
+    size_limits: SizeLimits,
+    message_ids: MessageIdStrategy,
+    send_retry: SendRetryPolicy,
+    interceptors: Vec<Box<dyn SendInterceptor>>,
+    amqp: Option<AmqpTransport>,
 }
 
 impl AzureServiceBusClient {
@@ -294,40 +510,199 @@ impl AzureServiceBusClient {
         Self {
             authenticator,
             http_client,
-            namespace: namespace.into(),
+            endpoint: format!("https://{}.servicebus.windows.net", urlencoding::encode(&namespace.into())),
             path: path.into(),
+            dead_letter_path: None,
+            compression: CompressionOptions::default(),
+            encryption: None,
+            signer: None,
+            trusted_keys: None,
+            claim_check: None,
+            size_limits: SizeLimits::default(),
+            message_ids: MessageIdStrategy::default(),
+            send_retry: SendRetryPolicy::none(),
+            interceptors: Vec::new(),
+            amqp: None,
         }
     }
 
-    #[allow(dead_code)]
-    pub async fn send_json<T: Serialize>(self: &Self, body: &T) -> Result<String, AzureServiceBusError> {
+    /// The queue or topic the client sends to and receives from.
+    pub fn path(self: &Self) -> &str {
+        &self.path
+    }
+
+    /// Compresses sent bodies with these options. Received bodies are decompressed
+    /// whenever they record a payload encoding, within the configured size limit.
+    pub fn with_compression(mut self, compression: CompressionOptions) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts sent bodies with a per-message data key wrapped by the key provider, and
+    /// decrypts received ones. Encrypted messages cannot be received without a provider.
+    pub fn with_encryption(mut self, key_provider: Box<dyn KeyProvider>) -> Self {
+        self.encryption = Some(key_provider);
+        self
+    }
+
+    /// Sets how ids are assigned to messages sent without one. A random id is used by
+    /// default.
+    pub fn with_message_ids(mut self, message_ids: MessageIdStrategy) -> Self {
+        self.message_ids = message_ids;
+        self
+    }
+
+    /// Retries sends that may not have reached the queue. Enable duplicate detection on
+    /// the queue so a retry of a send that did get through is dropped.
+    pub fn with_send_retry(mut self, send_retry: SendRetryPolicy) -> Self {
+        self.send_retry = send_retry;
+        self
+    }
+
+    /// Sets the size limits of the namespace's tier, which sends are checked against
+    /// before anything goes over the network. Standard tier limits are assumed otherwise.
+    pub fn with_size_limits(mut self, size_limits: SizeLimits) -> Self {
+        self.size_limits = size_limits;
+        self
+    }
+
+    /// Sends bodies over the claim-check threshold through the blob store, with only a
+    /// reference in the message, and fetches them again on receive. Use `complete_message`
+    /// so the blob is removed once the message is done with.
+    pub fn with_claim_check(mut self, claim_check: ClaimCheckOptions) -> Self {
+        self.claim_check = Some(claim_check);
+        self
+    }
+
+    /// Signs sent messages, after any compression and encryption.
+    pub fn with_signing(mut self, signer: MessageSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Verifies received messages against the trusted keys before anything else is done
+    /// with them. Messages failing verification are rejected like undecryptable ones.
+    pub fn with_verification(mut self, trusted_keys: TrustedKeys) -> Self {
+        self.trusted_keys = Some(trusted_keys);
+        self
+    }
+
+    /// Overrides the namespace endpoint, e.g. to talk to a local emulator.
+    pub fn with_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = endpoint.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Carries sends, receives and settlement over AMQP 1.0 instead of REST. Tokens from
+    /// the authenticator are put on the connection's `$cbs` node. Connects to the
+    /// namespace over TLS unless the options name an endpoint.
+    pub fn with_amqp(mut self, options: AmqpOptions) -> Self {
+        let endpoint = options.endpoint.clone()
+            .unwrap_or_else(|| self.endpoint.replacen("https://", "amqps://", 1));
+        self.amqp = Some(AmqpTransport::new(endpoint, options));
+        self
+    }
+
+    /// Whether operations go over AMQP rather than REST.
+    pub fn uses_amqp(self: &Self) -> bool {
+        self.amqp.is_some()
+    }
+
+    /// Sets the entity dead-lettered messages are forwarded to.
+    ///
+    /// The REST API has no dead-letter disposition, so dead-lettering sends a copy of the
+    /// message to this entity and then completes the original. Over AMQP messages are
+    /// dead-lettered into the entity's own dead-letter sub-queue instead.
+    pub fn with_dead_letter_path(mut self, dead_letter_path: impl Into<String>) -> Self {
+        self.dead_letter_path = Some(dead_letter_path.into());
+        self
+    }
+
+    pub async fn send_json<T: Serialize>(self: &Self, body: &T) -> Result<SendReceipt, AzureServiceBusError> {
         let msg = Message::new_json(body)?;
         self.send(&msg).await
     }
 
-    pub async fn send(self: &Self, message: &Message<BrokerSendProperties>) -> Result<String, AzureServiceBusError> {
-        let url = self.get_messages_url();
+    /// Runs the interceptor on every message sent, single, chunked or batched, after the
+    /// interceptors added before it. Messages forwarded to the dead-letter queue are not
+    /// intercepted.
+    pub fn with_send_interceptor(mut self, interceptor: Box<dyn SendInterceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
 
-        // this hacky bit is to set a correlation id to a random value if 1 wasn't specified.
-        // The input will not be modified though.
-        let correlation_id: String;
-        let props_json: String;
-        match &message.properties.correlation_id {
-            Some(_correlation_id) => {
-                correlation_id = _correlation_id.clone();
-                props_json = message.properties.to_json()?;
-            }
-            None => {
-                correlation_id = Uuid::new_v4().to_string();
-                let mut new_props = message.properties.clone();
-                new_props.correlation_id = Some(correlation_id.clone());
-                props_json = new_props.to_json()?;
+    pub async fn send(self: &Self, message: &Message<BrokerSendProperties>) -> Result<SendReceipt, AzureServiceBusError> {
+        self.send_to(&self.path, message).await
+    }
+
+    pub(crate) async fn send_to(self: &Self, path: &str, message: &Message<BrokerSendProperties>) -> Result<SendReceipt, AzureServiceBusError> {
+        let message = &self.intercept(message).await?;
+        let prepared = self.prepare_outgoing(message).await?;
+        let result = self.post_message(path, &prepared).await;
+
+        if result.is_err() && claim_check_reference(&prepared) != claim_check_reference(message) {
+            // Nothing refers to the new blob if the send failed.
+            self.delete_blob(&prepared).await;
+        }
+        result
+    }
+
+    // Sends the message exactly as given, retrying if it has an id to be deduplicated by.
+    async fn post_message(self: &Self, path: &str, message: &Message<BrokerSendProperties>) -> Result<SendReceipt, AzureServiceBusError> {
+        let (correlation_id, props) = with_correlation_id(&message.properties);
+        let message = Message { properties: props, ..message.clone() };
+        self.size_limits.check_message(&message)?;
+
+        let retryable = message.properties.message_id.is_some();
+        let attempts = self.retry_send(retryable, || self.post_once(path, &message)).await?;
+
+        Ok(SendReceipt {
+            message_id: message.properties.message_id.clone(),
+            correlation_id,
+            scheduled_enqueue_time_utc: message.properties.scheduled_enqueue_time_utc,
+            sent_at_utc: Utc::now(),
+            attempts,
+        })
+    }
+
+    // Runs the send until it succeeds, fails in a way a retry cannot fix or runs out of
+    // attempts. Returns the number of attempts.
+    async fn retry_send<F, Fut>(self: &Self, retryable: bool, send: F) -> Result<u32, AzureServiceBusError>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<(), AzureServiceBusError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match send().await {
+                Ok(()) => return Ok(attempt),
+                Err(e) if retryable && attempt < self.send_retry.max_attempts && SendRetryPolicy::is_retryable(&e) => {
+                    println!("Send attempt {} failed, retrying: {}", attempt, e);
+                    tokio::time::sleep(self.send_retry.backoff(attempt)).await;
+                    attempt += 1;
+                },
+                Err(e) => return Err(e),
             }
-        };
+        }
+    }
 
-        let res = self.authenticator.authenticate(self.http_client.post(url)).await?
+    async fn post_once(self: &Self, path: &str, message: &Message<BrokerSendProperties>) -> Result<(), AzureServiceBusError> {
+        if let Some(amqp) = &self.amqp {
+            return amqp.send(self.authenticator.as_ref(), path, std::slice::from_ref(message)).await;
+        }
+
+        let url = self.get_messages_url(path);
+        let props_json = message.properties.to_json()?;
+
+        let mut req = self.authenticator.authenticate(self.http_client.post(url)).await?
             .header("Content-Type", &message.content_type)
-            .header("BrokerProperties", &props_json)
+            .header("BrokerProperties", &props_json);
+
+        for (name, value) in &message.user_properties {
+            req = req.header(name.as_str(), encode_user_property_value(value)?);
+        }
+
+        let res = req
             .body(message.content.to_vec())
             .send()
             .await?;
@@ -336,10 +711,130 @@ impl AzureServiceBusClient {
             return Err(AzureServiceBusError::from(res.status()));
         }
 
-        Ok(correlation_id)
+        Ok(())
+    }
+
+    /// Splits the body into chunks of at most `chunk_size` bytes sent under one session
+    /// id, which is returned. Use a `ChunkedReceiver` to receive them. The body is
+    /// compressed and encrypted as a whole and each chunk is signed on its own.
+    pub async fn send_chunked(self: &Self, message: &Message<BrokerSendProperties>, chunk_size: usize) -> Result<String, AzureServiceBusError> {
+        let message = self.intercept(message).await?;
+        let payload = self.prepare_payload(&message, false).await?;
+        let chunks = split_message(&payload, chunk_size);
+        let session_id = chunks[0].properties.session_id.clone().unwrap_or_default();
+
+        for mut chunk in chunks {
+            if let Some(signer) = &self.signer {
+                sign_message(&mut chunk, signer)?;
+            }
+            self.post_message(&self.path, &chunk).await?;
+        }
+        Ok(session_id)
+    }
+
+    /// Whether `send_batch` can be used. Batched bodies are sent as text, so they cannot
+    /// be encrypted or signed.
+    pub fn supports_batch_send(self: &Self) -> bool {
+        self.encryption.is_none() && self.signer.is_none()
+    }
+
+    /// Sends several messages using the JSON batch format, split over as many requests
+    /// as the batch size limit needs. If a later request fails the earlier ones have
+    /// already been sent. Bodies must be valid UTF-8 and the content types of the messages
+    /// are not preserved. Batched bodies are never compressed or claim-checked. A client
+    /// that encrypts or signs refuses batches rather than send them in the clear.
+    pub async fn send_batch(self: &Self, messages: &[Message<BrokerSendProperties>]) -> Result<Vec<SendReceipt>, AzureServiceBusError> {
+        if !self.supports_batch_send() {
+            return Err(AzureServiceBusError::PayloadError("Batched messages cannot be encrypted or signed, send them one at a time".into()));
+        }
+
+        let mut receipts = Vec::with_capacity(messages.len());
+        let mut batch = Vec::with_capacity(messages.len());
+        for message in messages {
+            let mut message = self.intercept(message).await?;
+            self.message_ids.assign(&mut message)?;
+            let (correlation_id, props) = with_correlation_id(&message.properties);
+            let body = String::from_utf8(message.content.clone())
+                .map_err(|e| AzureServiceBusError::ConversionError(format!("Batched message body is not text: {}", e)))?;
+            self.size_limits.check_message(&Message { properties: props.clone(), ..message.clone() })?;
+
+            receipts.push(SendReceipt {
+                message_id: props.message_id.clone(),
+                correlation_id,
+                scheduled_enqueue_time_utc: props.scheduled_enqueue_time_utc,
+                sent_at_utc: Utc::now(),
+                attempts: 1,
+            });
+            batch.push(BatchedMessage {
+                body,
+                broker_properties: props,
+                user_properties: message.user_properties,
+            });
+        }
+
+        let mut sent = 0;
+        for batch in split_batch(batch, self.size_limits.max_batch_size)? {
+            let retryable = batch.iter().all(|entry| entry.broker_properties.message_id.is_some());
+            let attempts = self.retry_send(retryable, || self.post_batch_once(&batch)).await?;
+
+            for receipt in &mut receipts[sent..sent + batch.len()] {
+                receipt.sent_at_utc = Utc::now();
+                receipt.attempts = attempts;
+            }
+            sent += batch.len();
+        }
+
+        Ok(receipts)
+    }
+
+    async fn post_batch_once(self: &Self, batch: &[BatchedMessage<BrokerSendProperties>]) -> Result<(), AzureServiceBusError> {
+        if let Some(amqp) = &self.amqp {
+            let messages: Vec<Message<BrokerSendProperties>> = batch.iter()
+                .map(|entry| Message {
+                    properties: entry.broker_properties.clone(),
+                    content: entry.body.clone().into_bytes(),
+                    content_type: "".into(),
+                    user_properties: entry.user_properties.clone(),
+                })
+                .collect();
+            return amqp.send(self.authenticator.as_ref(), &self.path, &messages).await;
+        }
+
+        let url = self.get_messages_url(&self.path);
+
+        let res = self.authenticator.authenticate(self.http_client.post(url)).await?
+            .header("Content-Type", BATCH_CONTENT_TYPE)
+            .body(serde_json::to_vec(batch)?)
+            .send()
+            .await?;
+
+        if res.status() != 201 {
+            return Err(AzureServiceBusError::from(res.status()));
+        }
+        Ok(())
     }
 
     pub async fn peek_lock(self: &Self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        match self.peek_lock_raw().await? {
+            None => Ok(None),
+            Some(message) => Ok(Some(self.open_or_reject(message).await?)),
+        }
+    }
+
+    /// Returns the next message as it is on the wire without locking it, so its delivery
+    /// count is left alone. Needs AMQP.
+    pub async fn peek_raw(self: &Self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        let amqp = self.amqp_for("Peeking messages")?;
+        Ok(amqp.peek(self.authenticator.as_ref(), &self.path, 0, 1).await?.into_iter().next())
+    }
+
+    /// Locks the next message and returns it as it is on the wire, without verifying,
+    /// decrypting or decompressing it.
+    pub async fn peek_lock_raw(self: &Self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        if let Some(amqp) = &self.amqp {
+            return amqp.receive(self.authenticator.as_ref(), &self.path, true).await;
+        }
+
         let url = self.get_messages_head_url();
 
         let res = self.authenticator.authenticate(self.http_client.post(url)).await?
@@ -350,26 +845,64 @@ impl AzureServiceBusClient {
         let status = res.status();
 
         if status == 201 {
-            let content_type = match res.headers().get("Content-Type") {
-                None => "".into(),
-                Some(hv) => hv.to_str()?.into()
-            };
-
-            let properties = BrokerReceiveProperties::from_http_response(&res)?;
-            let content = res.bytes().await?.to_vec();
-            return Ok(Some(Message { properties, content, content_type }));
+            Ok(Some(message_from_response(res).await?))
         }
         else if status == 204 {
             // No messages were found.
-            return Ok(None);
+            Ok(None)
         }
         else {
-            return Err(AzureServiceBusError::from(status));
+            Err(AzureServiceBusError::from(status))
+        }
+    }
+
+    /// Receives and removes the next message in one step. The message is lost if
+    /// processing it fails.
+    pub async fn receive_and_delete(self: &Self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        let message = match self.receive_and_delete_raw().await? {
+            None => return Ok(None),
+            Some(message) => self.open_incoming(message).await?,
+        };
+
+        // The message is already gone from the queue.
+        self.delete_blob(&message).await;
+        Ok(Some(message))
+    }
+
+    async fn receive_and_delete_raw(self: &Self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        if let Some(amqp) = &self.amqp {
+            return amqp.receive(self.authenticator.as_ref(), &self.path, false).await;
+        }
+
+        let url = self.get_messages_head_url();
+
+        let res = self.authenticator.authenticate(self.http_client.delete(url)).await?
+            .header("Content-Length", 0)
+            .send()
+            .await?;
+
+        let status = res.status();
+
+        if status == 200 {
+            Ok(Some(message_from_response(res).await?))
+        }
+        else if status == 204 {
+            Ok(None)
+        }
+        else {
+            Err(AzureServiceBusError::from(status))
         }
     }
 
-    pub async fn renew_lock(self: &Self, message_properties: &BrokerReceiveProperties) -> Result<(), AzureServiceBusError> {
-        self.execute_lock_url(message_properties, reqwest::Method::POST).await
+    /// Extends the lock by the lock duration. Returns when the lock now expires if the
+    /// broker says, which it does over AMQP but not over REST.
+    pub async fn renew_lock(self: &Self, message_properties: &BrokerReceiveProperties) -> Result<Option<DateTime<Utc>>, AzureServiceBusError> {
+        if let Some(amqp) = &self.amqp {
+            let lock_token = lock_token_of(message_properties)?;
+            return Ok(Some(amqp.renew_lock(self.authenticator.as_ref(), &self.path, lock_token).await?));
+        }
+        self.execute_lock_url(message_properties, reqwest::Method::POST).await?;
+        Ok(None)
     }
 
     pub async fn unlock_message(self: &Self, message_properties: &BrokerReceiveProperties) -> Result<(), AzureServiceBusError> {
@@ -380,7 +913,232 @@ impl AzureServiceBusClient {
         self.execute_lock_url(message_properties, reqwest::Method::DELETE).await
     }
 
+    /// Completes the message like `delete_message` and then removes its claim-checked
+    /// payload, if it has one.
+    pub async fn complete_message(self: &Self, message: &Message<BrokerReceiveProperties>) -> Result<(), AzureServiceBusError> {
+        self.delete_message(&message.properties).await?;
+        self.delete_blob(message).await;
+        Ok(())
+    }
+
+    /// Moves the message to the dead-letter queue, recording why. Over AMQP it is
+    /// rejected into the entity's dead-letter sub-queue. Over REST a copy is forwarded to
+    /// the dead-letter path and the original completed.
+    pub async fn dead_letter(self: &Self, message: &Message<BrokerReceiveProperties>, reason: &str, description: &str) -> Result<(), AzureServiceBusError> {
+        self.forward_to_dead_letter(message, reason, description, true).await
+    }
+
+    /// Sets the message aside. It is not delivered again until it is received by its
+    /// sequence number with `receive_deferred`. Needs AMQP.
+    pub async fn defer_message(self: &Self, message_properties: &BrokerReceiveProperties) -> Result<(), AzureServiceBusError> {
+        let amqp = self.amqp_for("Deferring messages")?;
+        amqp.defer(self.authenticator.as_ref(), &self.path, lock_token_of(message_properties)?).await
+    }
+
+    /// Locks and returns a deferred message, which is then settled like any other.
+    /// Fails with `"404"` if there is no deferred message with the sequence number. Needs AMQP.
+    pub async fn receive_deferred(self: &Self, sequence_number: i64) -> Result<Message<BrokerReceiveProperties>, AzureServiceBusError> {
+        let amqp = self.amqp_for("Receiving deferred messages")?;
+        match amqp.receive_deferred(self.authenticator.as_ref(), &self.path, sequence_number).await? {
+            None => Err(AzureServiceBusError::RequestError("404".into())),
+            Some(message) => self.open_or_reject(message).await,
+        }
+    }
+
+    /// Returns up to `count` messages from the sequence number on without locking or
+    /// removing them, including locked, deferred and scheduled ones. Needs AMQP.
+    pub async fn peek_messages(self: &Self, from_sequence_number: i64, count: u32) -> Result<Vec<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        let amqp = self.amqp_for("Peeking messages")?;
+        let mut messages = Vec::new();
+        for message in amqp.peek(self.authenticator.as_ref(), &self.path, from_sequence_number, count).await? {
+            messages.push(self.open_incoming(message).await?);
+        }
+        Ok(messages)
+    }
+
+    /// Sends the message to be enqueued at the given time and returns its sequence number,
+    /// which `cancel_scheduled_message` takes. Needs AMQP.
+    pub async fn schedule_message(self: &Self, message: &Message<BrokerSendProperties>, enqueue_time_utc: DateTime<Utc>) -> Result<i64, AzureServiceBusError> {
+        let amqp = self.amqp_for("Scheduling messages with a sequence number")?;
+        let mut message = self.intercept(message).await?;
+        message.properties.scheduled_enqueue_time_utc = Some(enqueue_time_utc);
+        let prepared = self.prepare_outgoing(&message).await?;
+        self.size_limits.check_message(&prepared)?;
+
+        let result = amqp.schedule(self.authenticator.as_ref(), &self.path, &prepared).await;
+        if result.is_err() && claim_check_reference(&prepared) != claim_check_reference(&message) {
+            self.delete_blob(&prepared).await;
+        }
+        result
+    }
+
+    /// Removes a scheduled message before it is enqueued. Needs AMQP.
+    pub async fn cancel_scheduled_message(self: &Self, sequence_number: i64) -> Result<(), AzureServiceBusError> {
+        let amqp = self.amqp_for("Cancelling scheduled messages")?;
+        amqp.cancel_scheduled(self.authenticator.as_ref(), &self.path, sequence_number).await
+    }
+
+    // Over AMQP the message moves as received, so it keeps its claim-checked blob.
+    async fn forward_to_dead_letter(self: &Self, message: &Message<BrokerReceiveProperties>, reason: &str, description: &str, prepare: bool) -> Result<(), AzureServiceBusError> {
+        if let Some(amqp) = &self.amqp {
+            let lock_token = lock_token_of(&message.properties)?;
+            return amqp.dead_letter(self.authenticator.as_ref(), &self.path, lock_token, reason, description).await;
+        }
+
+        let dead_letter_path = match &self.dead_letter_path {
+            None => Err(AzureServiceBusError::RequestError("No dead-letter path configured.".into())),
+            Some(dead_letter_path) => Ok(dead_letter_path)
+        }?;
+
+        let mut copy = message.to_send_message();
+        if prepare {
+            copy = self.prepare_outgoing(&copy).await?;
+        }
+        copy.set_user_property(DEAD_LETTER_REASON_PROPERTY, reason);
+        copy.set_user_property(DEAD_LETTER_DESCRIPTION_PROPERTY, description);
+
+        self.post_message(dead_letter_path, &copy).await?;
+
+        // A prepared copy carries its own blob, an unprepared one shares the original's.
+        if prepare {
+            return self.complete_message(message).await;
+        }
+        self.delete_message(&message.properties).await
+    }
+
+    async fn intercept(self: &Self, message: &Message<BrokerSendProperties>) -> Result<Message<BrokerSendProperties>, AzureServiceBusError> {
+        let mut message = message.clone();
+        for interceptor in &self.interceptors {
+            interceptor.intercept(&mut message).await?;
+        }
+        Ok(message)
+    }
+
+    // Applies the send side payload transformations. Bodies are compressed before they
+    // are encrypted, as ciphertext does not compress, and signed last so the signature
+    // covers what is sent. The correlation id is assigned first so it can be signed.
+    async fn prepare_outgoing(self: &Self, message: &Message<BrokerSendProperties>) -> Result<Message<BrokerSendProperties>, AzureServiceBusError> {
+        let mut message = self.prepare_payload(message, true).await?;
+        if let Some(signer) = &self.signer {
+            sign_message(&mut message, signer)?;
+        }
+        Ok(message)
+    }
+
+    async fn prepare_payload(self: &Self, message: &Message<BrokerSendProperties>, claim_check: bool) -> Result<Message<BrokerSendProperties>, AzureServiceBusError> {
+        let mut message = message.clone();
+        self.message_ids.assign(&mut message)?;
+        message.properties = with_correlation_id(&message.properties).1;
+
+        if !is_encrypted(&message) {
+            compress_message(&mut message, &self.compression)?;
+            if let Some(key_provider) = &self.encryption {
+                encrypt_message(&mut message, key_provider.as_ref()).await?;
+            }
+            if let (Some(options), true) = (&self.claim_check, claim_check) {
+                check_message(&mut message, options).await?;
+            }
+        }
+        Ok(message)
+    }
+
+    // Reverses the send side payload transformations on a received message.
+    async fn open_incoming(self: &Self, mut message: Message<BrokerReceiveProperties>) -> Result<Message<BrokerReceiveProperties>, AzureServiceBusError> {
+        if let Some(trusted_keys) = &self.trusted_keys {
+            verify_message(&mut message, trusted_keys)?;
+        }
+        self.open_payload(message).await
+    }
+
+    // Opens a received message, rejecting it if that fails.
+    pub(crate) async fn open_or_reject(self: &Self, message: Message<BrokerReceiveProperties>) -> Result<Message<BrokerReceiveProperties>, AzureServiceBusError> {
+        let verified = self.verify_or_reject(message).await?;
+        match self.open_payload(verified.clone()).await {
+            Ok(opened) => Ok(opened),
+            Err(e) => {
+                self.reject(&verified, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) async fn verify_or_reject(self: &Self, mut message: Message<BrokerReceiveProperties>) -> Result<Message<BrokerReceiveProperties>, AzureServiceBusError> {
+        let trusted_keys = match &self.trusted_keys {
+            None => return Ok(message),
+            Some(trusted_keys) => trusted_keys,
+        };
+
+        let raw = message.clone();
+        match verify_message(&mut message, trusted_keys) {
+            Ok(_) => Ok(message),
+            Err(e) => {
+                let e: AzureServiceBusError = e.into();
+                self.reject(&raw, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    // Reverses the send side payload transformations on a verified message.
+    pub(crate) async fn open_payload(self: &Self, mut message: Message<BrokerReceiveProperties>) -> Result<Message<BrokerReceiveProperties>, AzureServiceBusError> {
+        match &self.claim_check {
+            Some(claim_check) => { redeem_message(&mut message, claim_check.store.as_ref()).await?; },
+            None if claim_check_reference(&message).is_some() => {
+                return Err(AzureServiceBusError::PayloadUnavailable("Message is claim-checked and no blob store is configured".into()));
+            },
+            None => {},
+        }
+
+        match &self.encryption {
+            Some(key_provider) => { decrypt_message(&mut message, key_provider.as_ref()).await?; },
+            None if is_encrypted(&message) => {
+                return Err(AzureServiceBusError::PayloadUnavailable("Message is encrypted and no key provider is configured".into()));
+            },
+            None => {},
+        }
+
+        decompress_message(&mut message, &self.compression)?;
+        Ok(message)
+    }
+
+    // A locked message that fails verification or whose envelope is corrupt will never
+    // be processable, so it is dead-lettered as received when possible and otherwise
+    // released. It is forwarded untouched so a tampered message is never re-signed. One
+    // whose payload is only unavailable for now is released for another attempt.
+    pub(crate) async fn reject(self: &Self, message: &Message<BrokerReceiveProperties>, error: &AzureServiceBusError) {
+        let reason = match error {
+            AzureServiceBusError::SignatureError(_) => "SignatureError",
+            _ => "PayloadError",
+        };
+
+        let can_dead_letter = self.amqp.is_some() || self.dead_letter_path.is_some();
+        let result = match error {
+            AzureServiceBusError::PayloadUnavailable(_) => self.unlock_message(&message.properties).await,
+            _ if !can_dead_letter => self.unlock_message(&message.properties).await,
+            _ => self.forward_to_dead_letter(message, reason, &error.to_string(), false).await,
+        };
+
+        if let Err(e) = result {
+            println!("Unable to reject message {:?}: {}", message.properties.message_id, e);
+        }
+    }
+
+    async fn delete_blob<P>(self: &Self, message: &Message<P>) {
+        let (claim_check, reference) = match (&self.claim_check, claim_check_reference(message)) {
+            (Some(claim_check), Some(reference)) => (claim_check, reference),
+            _ => return,
+        };
+
+        if let Err(e) = claim_check.store.delete(reference).await {
+            println!("Unable to delete claim-checked payload {}: {}", reference, e);
+        }
+    }
+
     async fn execute_lock_url(self: &Self, message_properties: &BrokerReceiveProperties, method: reqwest::Method) -> Result<(), AzureServiceBusError> {
+        if let Some(amqp) = &self.amqp {
+            return self.execute_lock_amqp(amqp, message_properties, method).await;
+        }
+
         let url = self.get_lock_url(message_properties)?;
 
         let res = self.authenticator.authenticate(self.http_client.request(method, url)).await?
@@ -396,17 +1154,36 @@ impl AzureServiceBusClient {
         Ok(())
     }
 
-    fn get_messages_url(self: &Self) -> String {
+    // Settles over the link the message arrived on, or with the management node it
+    // was received from.
+    async fn execute_lock_amqp(self: &Self, amqp: &AmqpTransport, message_properties: &BrokerReceiveProperties, method: reqwest::Method) -> Result<(), AzureServiceBusError> {
+        let lock_token = lock_token_of(message_properties)?;
+
+        let authenticator = self.authenticator.as_ref();
+        match method {
+            reqwest::Method::PUT => amqp.abandon(authenticator, &self.path, lock_token).await,
+            reqwest::Method::DELETE => amqp.complete(authenticator, &self.path, lock_token).await,
+            _ => Err(AzureServiceBusError::RequestError(format!("Unsupported lock operation {}", method))),
+        }
+    }
+
+    // Peeking, deferral and sequence numbers for scheduled messages have no REST operation.
+    fn amqp_for(self: &Self, operation: &str) -> Result<&AmqpTransport, AzureServiceBusError> {
+        self.amqp.as_ref()
+            .ok_or_else(|| AzureServiceBusError::RequestError(format!("{} needs the AMQP transport.", operation)))
+    }
+
+    fn get_messages_url(self: &Self, path: &str) -> String {
         format!(
-            "https://{}.servicebus.windows.net/{}/messages",
-            urlencoding::encode(self.namespace.as_str()),
-            urlencoding::encode(self.path.as_str()))
+            "{}/{}/messages",
+            self.endpoint,
+            urlencoding::encode(path))
     }
 
     fn get_messages_head_url(self: &Self) -> String {
         format!(
-            "https://{}.servicebus.windows.net/{}/messages/head",
-            urlencoding::encode(self.namespace.as_str()),
+            "{}/{}/messages/head",
+            self.endpoint,
             urlencoding::encode(self.path.as_str()))
     }
 
@@ -422,11 +1199,37 @@ impl AzureServiceBusClient {
         }?;
 
         Ok(format!(
-            "https://{}.servicebus.windows.net/{}/messages/{}/{}",
-            urlencoding::encode(self.namespace.as_str()),
+            "{}/{}/messages/{}/{}",
+            self.endpoint,
             urlencoding::encode(self.path.as_str()),
             urlencoding::encode(message_id.as_str()),
             urlencoding::encode(lock_token)))
     }
 
-}
\ No newline at end of file
+}
+
+fn lock_token_of(message_properties: &BrokerReceiveProperties) -> Result<&str, AzureServiceBusError> {
+    message_properties.lock_token.as_deref()
+        .ok_or_else(|| AzureServiceBusError::RequestError("No lock token found in broker properties.".into()))
+}
+
+// Sets a correlation id to a random value if one wasn't specified. The input is not modified.
+fn with_correlation_id(properties: &BrokerSendProperties) -> (String, BrokerSendProperties) {
+    let mut props = properties.clone();
+    let correlation_id = props.correlation_id
+        .get_or_insert_with(|| Uuid::new_v4().to_string())
+        .clone();
+    (correlation_id, props)
+}
+
+async fn message_from_response(res: Response) -> Result<Message<BrokerReceiveProperties>, AzureServiceBusError> {
+    let content_type = match res.headers().get("Content-Type") {
+        None => "".into(),
+        Some(hv) => hv.to_str()?.into()
+    };
+
+    let properties = BrokerReceiveProperties::from_http_response(&res)?;
+    let user_properties = user_properties_from_headers(res.headers())?;
+    let content = res.bytes().await?.to_vec();
+    Ok(Message { properties, content, content_type, user_properties })
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::mazure::sbclient::{AzureServiceBusError, BrokerSendProperties, Message};
+
+/// How a message id is assigned to messages sent without one.
+///
+/// Queues with duplicate detection drop messages repeating an id seen within the
+/// detection window, so a stable id makes a send safe to retry.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub enum MessageIdStrategy {
+    /// Leave it to the service, which assigns a random id. Sends are never retried.
+    Service,
+
+    /// A random UUID, fixed before the first attempt so retries share it.
+    #[default]
+    Random,
+
+    /// A time ordered UUIDv7.
+    UuidV7,
+
+    /// A SHA-256 of the content type, body and user properties, so sending the same
+    /// message twice is detected as a duplicate.
+    ContentHash,
+
+    /// The value of a caller set user property, e.g. an order number.
+    UserProperty(String),
+}
+
+impl MessageIdStrategy {
+    /// Sets the message id if the message does not have one already.
+    pub fn assign(self: &Self, message: &mut Message<BrokerSendProperties>) -> Result<(), AzureServiceBusError> {
+        if message.properties.message_id.is_some() {
+            return Ok(());
+        }
+
+        message.properties.message_id = match self {
+            MessageIdStrategy::Service => None,
+            MessageIdStrategy::Random => Some(uuid::Uuid::new_v4().to_string()),
+            MessageIdStrategy::UuidV7 => Some(uuid::Uuid::now_v7().to_string()),
+            MessageIdStrategy::ContentHash => Some(content_hash(message)),
+            MessageIdStrategy::UserProperty(name) => match message.user_property(name) {
+                Some(key) => Some(key.to_string()),
+                None => return Err(AzureServiceBusError::ConversionError(format!("Message id property {} is not set", name))),
+            },
+        };
+        Ok(())
+    }
+}
+
+fn content_hash(message: &Message<BrokerSendProperties>) -> String {
+    let mut hasher = Sha256::new();
+    let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_be_bytes());
+        hasher.update(bytes);
+    };
+
+    field(message.content_type.as_bytes());
+    field(&message.content);
+    for (name, value) in &message.user_properties {
+        field(name.as_bytes());
+        field(value.as_bytes());
+    }
+
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Retries of sends that failed in a way that may not have reached the queue. Only
+/// messages with a message id are retried, and only a queue with duplicate detection
+/// enabled makes that safe against the first attempt having been enqueued after all.
+#[derive(Clone, Debug)]
+pub struct SendRetryPolicy {
+    /// Attempts in total, so 1 means no retries.
+    pub max_attempts: u32,
+
+    pub initial_backoff: Duration,
+
+    pub max_backoff: Duration,
+}
+
+impl SendRetryPolicy {
+    pub fn none() -> Self {
+        SendRetryPolicy { max_attempts: 1, ..SendRetryPolicy::default() }
+    }
+
+    pub fn backoff(self: &Self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    /// Failures where the send may or may not have happened, or may succeed later.
+    pub fn is_retryable(error: &AzureServiceBusError) -> bool {
+        match error {
+            AzureServiceBusError::CommunicationError(_) => true,
+            AzureServiceBusError::ServiceError(_) => true,
+            AzureServiceBusError::RequestError(status) => status == "408" || status == "429",
+            _ => false,
+        }
+    }
+}
+
+impl Default for SendRetryPolicy {
+    fn default() -> Self {
+        SendRetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What is known about a sent message. The REST API does not return the sequence
+/// number, so the enqueue time is the scheduled time or, failing that, when the send
+/// was acknowledged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SendReceipt {
+    /// Not known when the service assigned the id.
+    pub message_id: Option<String>,
+
+    pub correlation_id: String,
+
+    pub scheduled_enqueue_time_utc: Option<DateTime<Utc>>,
+
+    pub sent_at_utc: DateTime<Utc>,
+
+    /// Attempts it took, more than 1 if the send was retried.
+    pub attempts: u32,
+}
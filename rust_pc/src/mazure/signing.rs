@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::{Signer, Verifier};
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::mazure::sbclient::{Message, DEAD_LETTER_DESCRIPTION_PROPERTY, DEAD_LETTER_REASON_PROPERTY};
+
+/// User property holding the signature (base64).
+pub static SIGNATURE_PROPERTY: &str = "signature";
+
+pub static SIGNATURE_ALGORITHM_PROPERTY: &str = "signature-algorithm";
+
+pub static SIGNATURE_KEY_ID_PROPERTY: &str = "signature-key-id";
+
+/// User property listing the broker and user properties covered by the signature.
+pub static SIGNED_PROPERTIES_PROPERTY: &str = "signed-properties";
+
+/// Broker properties signed by default. Properties the broker sets or changes on
+/// delivery cannot be signed.
+pub static DEFAULT_SIGNED_BROKER_PROPERTIES: &[&str] = &[
+    "CorrelationId",
+    "SessionId",
+    "Label",
+    "ReplyTo",
+    "To",
+    "ReplyToSessionId",
+    "PartitionKey",
+];
+
+static SIGNATURE_PROPERTIES: &[&str] = &[
+    "signature",
+    "signature-algorithm",
+    "signature-key-id",
+    "signed-properties",
+];
+
+// User properties the broker adds when it dead-letters a message, which the sender
+// cannot have signed.
+static BROKER_SET_PROPERTIES: &[&str] = &[
+    DEAD_LETTER_REASON_PROPERTY,
+    DEAD_LETTER_DESCRIPTION_PROPERTY,
+];
+
+// Versions the canonical form so it can change without old signatures verifying
+// against a different layout.
+static CANONICAL_FORM_PREFIX: &[u8] = b"qexample-signature-v1";
+
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    #[error("Message is not signed")]
+    Unsigned,
+
+    #[error("Message is signed with an untrusted key: {0}")]
+    UntrustedKey(String),
+
+    #[error("Message signature is not valid: {0}")]
+    InvalidSignature(String),
+
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+}
+
+impl From<base64::DecodeError> for SignatureError {
+    fn from(e: base64::DecodeError) -> Self {
+        SignatureError::InvalidSignature(e.to_string())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Debug)]
+pub enum SignatureAlgorithm {
+    #[serde(rename = "ed25519")]
+    Ed25519,
+    #[serde(rename = "hmac-sha256")]
+    HmacSha256,
+}
+
+impl SignatureAlgorithm {
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            SignatureAlgorithm::Ed25519 => "ed25519",
+            SignatureAlgorithm::HmacSha256 => "hmac-sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SignatureAlgorithm> {
+        match name {
+            "ed25519" => Some(SignatureAlgorithm::Ed25519),
+            "hmac-sha256" => Some(SignatureAlgorithm::HmacSha256),
+            _ => None,
+        }
+    }
+}
+
+/// How keys are stored in key files. Ed25519 keys are the 32 byte secret or public key,
+/// HMAC keys are the shared secret, all base64 encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyEntry {
+    algorithm: SignatureAlgorithm,
+    key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SigningKeyFile {
+    key_id: String,
+    #[serde(flatten)]
+    entry: KeyEntry,
+}
+
+#[derive(Clone)]
+pub enum SigningKey {
+    Ed25519(ed25519_dalek::SigningKey),
+    HmacSha256(Vec<u8>),
+}
+
+#[derive(Clone)]
+pub enum VerificationKey {
+    Ed25519(ed25519_dalek::VerifyingKey),
+    HmacSha256(Vec<u8>),
+}
+
+impl SigningKey {
+    pub fn algorithm(self: &Self) -> SignatureAlgorithm {
+        match self {
+            SigningKey::Ed25519(_) => SignatureAlgorithm::Ed25519,
+            SigningKey::HmacSha256(_) => SignatureAlgorithm::HmacSha256,
+        }
+    }
+
+    /// The key to give to consumers.
+    pub fn verification_key(self: &Self) -> VerificationKey {
+        match self {
+            SigningKey::Ed25519(key) => VerificationKey::Ed25519(key.verifying_key()),
+            SigningKey::HmacSha256(secret) => VerificationKey::HmacSha256(secret.clone()),
+        }
+    }
+
+    fn sign(self: &Self, data: &[u8]) -> Result<Vec<u8>, SignatureError> {
+        match self {
+            SigningKey::Ed25519(key) => Ok(key.sign(data).to_bytes().to_vec()),
+            SigningKey::HmacSha256(secret) => {
+                let mut mac = hmac_sha256(secret)?;
+                mac.update(data);
+                Ok(mac.finalize().into_bytes().to_vec())
+            },
+        }
+    }
+
+    fn from_entry(entry: &KeyEntry) -> Result<SigningKey, SignatureError> {
+        let key = BASE64.decode(entry.key.trim())?;
+        match entry.algorithm {
+            SignatureAlgorithm::Ed25519 => Ok(SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&key_bytes(&key)?))),
+            SignatureAlgorithm::HmacSha256 => Ok(SigningKey::HmacSha256(key)),
+        }
+    }
+}
+
+impl VerificationKey {
+    pub fn algorithm(self: &Self) -> SignatureAlgorithm {
+        match self {
+            VerificationKey::Ed25519(_) => SignatureAlgorithm::Ed25519,
+            VerificationKey::HmacSha256(_) => SignatureAlgorithm::HmacSha256,
+        }
+    }
+
+    fn verify(self: &Self, data: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+        match self {
+            VerificationKey::Ed25519(key) => {
+                let signature = ed25519_dalek::Signature::from_slice(signature)
+                    .map_err(|e| SignatureError::InvalidSignature(e.to_string()))?;
+                key.verify(data, &signature).map_err(|e| SignatureError::InvalidSignature(e.to_string()))
+            },
+            VerificationKey::HmacSha256(secret) => {
+                let mut mac = hmac_sha256(secret)?;
+                mac.update(data);
+                mac.verify_slice(signature).map_err(|_| SignatureError::InvalidSignature("HMAC mismatch".into()))
+            },
+        }
+    }
+
+    fn from_entry(entry: &KeyEntry) -> Result<VerificationKey, SignatureError> {
+        let key = BASE64.decode(entry.key.trim())?;
+        match entry.algorithm {
+            SignatureAlgorithm::Ed25519 => {
+                let key = ed25519_dalek::VerifyingKey::from_bytes(&key_bytes(&key)?)
+                    .map_err(|e| SignatureError::InvalidKey(e.to_string()))?;
+                Ok(VerificationKey::Ed25519(key))
+            },
+            SignatureAlgorithm::HmacSha256 => Ok(VerificationKey::HmacSha256(key)),
+        }
+    }
+}
+
+fn hmac_sha256(secret: &[u8]) -> Result<Hmac<Sha256>, SignatureError> {
+    Hmac::<Sha256>::new_from_slice(secret).map_err(|e| SignatureError::InvalidKey(e.to_string()))
+}
+
+fn key_bytes(key: &[u8]) -> Result<[u8; 32], SignatureError> {
+    key.try_into().map_err(|_| SignatureError::InvalidKey("Ed25519 keys are 32 bytes".into()))
+}
+
+/// Signs sent messages with one key.
+#[derive(Clone)]
+pub struct MessageSigner {
+    pub key_id: String,
+    pub key: SigningKey,
+
+    /// Broker properties covered by the signature, by their service names.
+    pub broker_properties: Vec<String>,
+}
+
+impl MessageSigner {
+    /// Signs the body, content type, default broker properties and every user property
+    /// the message has when it is sent.
+    pub fn new(key_id: impl Into<String>, key: SigningKey) -> Self {
+        MessageSigner {
+            key_id: key_id.into(),
+            key,
+            broker_properties: DEFAULT_SIGNED_BROKER_PROPERTIES.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// Reads `{ "key_id": "...", "algorithm": "ed25519", "key": "<base64>" }`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let file: SigningKeyFile = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(MessageSigner::new(file.key_id, SigningKey::from_entry(&file.entry)?))
+    }
+}
+
+/// The keys whose signatures are accepted.
+#[derive(Clone)]
+pub struct TrustedKeys {
+    pub keys: HashMap<String, VerificationKey>,
+
+    /// Rejects messages without a signature. Otherwise they are passed through unchecked.
+    pub require_signature: bool,
+}
+
+impl TrustedKeys {
+    pub fn new(keys: HashMap<String, VerificationKey>) -> Self {
+        TrustedKeys { keys, require_signature: true }
+    }
+
+    /// Reads `{ "<key id>": { "algorithm": "ed25519", "key": "<base64>" }, ... }`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let entries: HashMap<String, KeyEntry> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+        let mut keys = HashMap::new();
+        for (key_id, entry) in entries {
+            keys.insert(key_id, VerificationKey::from_entry(&entry)?);
+        }
+        Ok(TrustedKeys::new(keys))
+    }
+}
+
+/// What is known about a received message's signature. User properties of a valid
+/// message that the signature does not cover are listed as unsigned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Unsigned,
+    Valid { key_id: String, algorithm: String, signed_properties: Vec<String>, unsigned_properties: Vec<String> },
+    UntrustedKey { key_id: String },
+    Invalid { key_id: String, reason: String },
+}
+
+/// Signs the message as it will be sent, so anything applied before, such as
+/// compression or encryption, is covered.
+pub fn sign_message<P: Serialize>(message: &mut Message<P>, signer: &MessageSigner) -> Result<(), SignatureError> {
+    remove_signature(message);
+
+    let mut signed_properties: Vec<String> = signer.broker_properties.clone();
+    signed_properties.extend(message.user_properties.keys().cloned());
+
+    let data = canonical_form(message, &signed_properties)?;
+    let signature = signer.key.sign(&data)?;
+
+    message.set_user_property(SIGNATURE_ALGORITHM_PROPERTY, signer.key.algorithm().name());
+    message.set_user_property(SIGNATURE_KEY_ID_PROPERTY, signer.key_id.clone());
+    message.set_user_property(SIGNED_PROPERTIES_PROPERTY, signed_properties.join(","));
+    message.set_user_property(SIGNATURE_PROPERTY, BASE64.encode(signature));
+    Ok(())
+}
+
+/// Checks the signature without changing the message.
+pub fn inspect_signature<P: Serialize>(message: &Message<P>, trusted: &TrustedKeys) -> SignatureStatus {
+    let signature = match message.user_property(SIGNATURE_PROPERTY) {
+        None => return SignatureStatus::Unsigned,
+        Some(signature) => signature,
+    };
+    let key_id = message.user_property(SIGNATURE_KEY_ID_PROPERTY).unwrap_or("").to_string();
+    let invalid = |reason: String| SignatureStatus::Invalid { key_id: key_id.clone(), reason };
+
+    let key = match trusted.keys.get(&key_id) {
+        None => return SignatureStatus::UntrustedKey { key_id },
+        Some(key) => key,
+    };
+
+    let algorithm = message.user_property(SIGNATURE_ALGORITHM_PROPERTY).unwrap_or("");
+    if SignatureAlgorithm::from_name(algorithm) != Some(key.algorithm()) {
+        return invalid(format!("algorithm {} does not match the {} key", algorithm, key.algorithm().name()));
+    }
+
+    let signed_properties: Vec<String> = message.user_property(SIGNED_PROPERTIES_PROPERTY)
+        .unwrap_or("")
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect();
+
+    let result = BASE64.decode(signature)
+        .map_err(SignatureError::from)
+        .and_then(|signature| key.verify(&canonical_form(message, &signed_properties)?, &signature));
+
+    if let Err(e) = result {
+        return invalid(e.to_string());
+    }
+
+    let unsigned_properties = message.user_properties.keys()
+        .filter(|name| !is_covered(name, &signed_properties))
+        .cloned()
+        .collect();
+    SignatureStatus::Valid { key_id, algorithm: algorithm.to_string(), signed_properties, unsigned_properties }
+}
+
+/// Verifies the signature against the trusted keys and removes it from the message,
+/// along with any user properties it does not cover, so nothing added after signing is
+/// acted on. Returns whether the message was signed.
+pub fn verify_message<P: Serialize>(message: &mut Message<P>, trusted: &TrustedKeys) -> Result<bool, SignatureError> {
+    let unsigned_properties = match inspect_signature(message, trusted) {
+        SignatureStatus::Unsigned if trusted.require_signature => return Err(SignatureError::Unsigned),
+        SignatureStatus::Unsigned => return Ok(false),
+        SignatureStatus::UntrustedKey { key_id } => return Err(SignatureError::UntrustedKey(key_id)),
+        SignatureStatus::Invalid { reason, .. } => return Err(SignatureError::InvalidSignature(reason)),
+        SignatureStatus::Valid { unsigned_properties, .. } => unsigned_properties,
+    };
+
+    for name in unsigned_properties {
+        message.user_properties.remove(&name);
+    }
+    remove_signature(message);
+    Ok(true)
+}
+
+// Whether a user property is signed, part of the signature or set by the broker.
+fn is_covered(name: &str, signed_properties: &[String]) -> bool {
+    signed_properties.iter().any(|signed| signed.eq_ignore_ascii_case(name))
+        || SIGNATURE_PROPERTIES.contains(&name)
+        || BROKER_SET_PROPERTIES.iter().any(|property| property.eq_ignore_ascii_case(name))
+}
+
+fn remove_signature<P>(message: &mut Message<P>) {
+    for name in SIGNATURE_PROPERTIES {
+        message.user_properties.remove(*name);
+    }
+}
+
+// Length prefixed fields, so no choice of values can make two different messages
+// produce the same bytes. Missing properties are distinct from empty ones. Broker
+// properties use their PascalCase service names, user properties are lower case.
+fn canonical_form<P: Serialize>(message: &Message<P>, signed_properties: &[String]) -> Result<Vec<u8>, SignatureError> {
+    let broker_properties = serde_json::to_value(&message.properties)
+        .map_err(|e| SignatureError::InvalidSignature(e.to_string()))?;
+
+    let mut data = CANONICAL_FORM_PREFIX.to_vec();
+    push_field(&mut data, Some(message.content_type.as_bytes()));
+    push_field(&mut data, Some(&message.content));
+    push_field(&mut data, Some(signed_properties.join(",").as_bytes()));
+
+    for name in signed_properties {
+        let value = match name.chars().next() {
+            Some(c) if c.is_ascii_uppercase() => match broker_properties.get(name) {
+                None | Some(serde_json::Value::Null) => None,
+                Some(serde_json::Value::String(s)) => Some(s.clone()),
+                Some(other) => Some(other.to_string()),
+            },
+            _ => message.user_property(name).map(|v| v.to_string()),
+        };
+        push_field(&mut data, value.as_ref().map(|v| v.as_bytes()));
+    }
+
+    Ok(data)
+}
+
+fn push_field(data: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        None => data.push(0),
+        Some(bytes) => {
+            data.push(1);
+            data.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+            data.extend_from_slice(bytes);
+        },
+    }
+}
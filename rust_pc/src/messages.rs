@@ -2,12 +2,18 @@ use serde::{Serialize, Deserialize};
 
 use uuid::Uuid;
 
+use crate::typed::MessageType;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LogInfo {
     message: String,
     extra: String,
 }
 
+impl MessageType for LogInfo {
+    const TYPE_NAME: &'static str = "LogInfo";
+}
+
 impl LogInfo {
     pub fn new_random() -> Self {
         let id = Uuid::new_v4();
@@ -0,0 +1,114 @@
+use std::rc::Rc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use chrono::Local;
+
+use crate::inbox::InboxStore;
+use crate::mazure::sbclient::{BrokerReceiveProperties, Message};
+use crate::poison::ProcessingError;
+
+/// Processes a received message. Settling it is left to the caller.
+#[async_trait(?Send)]
+pub trait Processor {
+    async fn process(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), ProcessingError>;
+}
+
+/// Wraps processing with behaviour of its own. A middleware can act before and after
+/// calling `next`, hand `next` a changed message, or answer without calling it at all.
+#[async_trait(?Send)]
+pub trait Middleware {
+    async fn handle(&self, message: &Message<BrokerReceiveProperties>, next: Next<'_>) -> Result<(), ProcessingError>;
+}
+
+/// The rest of the pipeline after the middleware being called.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware + 'a>],
+    processor: &'a dyn Processor,
+}
+
+impl<'a> Next<'a> {
+    pub async fn run(self: Self, message: &Message<BrokerReceiveProperties>) -> Result<(), ProcessingError> {
+        match self.middleware.split_first() {
+            None => self.processor.process(message).await,
+            Some((first, rest)) => first.handle(message, Next { middleware: rest, processor: self.processor }).await,
+        }
+    }
+}
+
+/// A stack of middleware run around a processor. The first one added is the outermost.
+#[derive(Default)]
+pub struct Pipeline<'a> {
+    middleware: Vec<Box<dyn Middleware + 'a>>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    pub fn with(mut self: Self, middleware: impl Middleware + 'a) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    pub fn push(self: &mut Self, middleware: impl Middleware + 'a) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    pub async fn process(self: &Self, message: &Message<BrokerReceiveProperties>, processor: &dyn Processor) -> Result<(), ProcessingError> {
+        Next { middleware: &self.middleware, processor }.run(message).await
+    }
+}
+
+/// Prints each message as it is processed, with how long processing took.
+pub struct LoggingMiddleware;
+
+#[async_trait(?Send)]
+impl Middleware for LoggingMiddleware {
+    async fn handle(&self, message: &Message<BrokerReceiveProperties>, next: Next<'_>) -> Result<(), ProcessingError> {
+        let p = &message.properties;
+        println!("[{}] Processing message {:?}, label {:?}, delivery {:?}.", Local::now(), p.message_id, p.label, p.delivery_count);
+
+        let start = Instant::now();
+        let result = next.run(message).await;
+        match &result {
+            Ok(()) => println!("[{}] Processed message {:?} in {:?}.", Local::now(), p.message_id, start.elapsed()),
+            Err(e) => println!("[{}] Message {:?} failed after {:?}: {}", Local::now(), p.message_id, start.elapsed(), e),
+        }
+        result
+    }
+}
+
+/// Skips messages the inbox has recorded as processed, and records the ones processed
+/// successfully, so redeliveries are not processed twice. Messages without an id are
+/// always processed.
+pub struct DedupMiddleware {
+    inbox: Rc<dyn InboxStore>,
+}
+
+impl DedupMiddleware {
+    pub fn new(inbox: Rc<dyn InboxStore>) -> Self {
+        DedupMiddleware { inbox }
+    }
+}
+
+#[async_trait(?Send)]
+impl Middleware for DedupMiddleware {
+    async fn handle(&self, message: &Message<BrokerReceiveProperties>, next: Next<'_>) -> Result<(), ProcessingError> {
+        let message_id = match &message.properties.message_id {
+            None => return next.run(message).await,
+            Some(message_id) => message_id,
+        };
+
+        let seen = self.inbox.contains(message_id).await.map_err(|e| ProcessingError::transient(e.to_string()))?;
+        if seen {
+            println!("[{}] Message {} was already processed, skipping it.", Local::now(), message_id);
+            return Ok(());
+        }
+
+        next.run(message).await?;
+        self.inbox.record(message_id).await.map_err(|e| ProcessingError::transient(e.to_string()))
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::mazure::sbclient::{AzureServiceBusClient, AzureServiceBusError, BrokerSendProperties, Message};
+use crate::shutdown::Shutdown;
+use crate::stored_message;
+
+#[derive(Error, Debug)]
+pub enum OutboxError {
+    #[error("Outbox database error: {0}")]
+    DatabaseError(String),
+
+    #[error("Outbox row {0} is not valid: {1}")]
+    InvalidRow(i64, String),
+
+    #[error("Unable to send: {0}")]
+    SendError(String),
+}
+
+impl From<rusqlite::Error> for OutboxError {
+    fn from(e: rusqlite::Error) -> Self {
+        OutboxError::DatabaseError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for OutboxError {
+    fn from(e: serde_json::Error) -> Self {
+        OutboxError::DatabaseError(e.to_string())
+    }
+}
+
+impl From<AzureServiceBusError> for OutboxError {
+    fn from(e: AzureServiceBusError) -> Self {
+        OutboxError::SendError(e.to_string())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum OutboxStatus {
+    /// Waiting to be sent, possibly after a failed attempt.
+    Pending,
+
+    Dispatched,
+
+    /// Gave up after too many attempts. Stays put, and holds back later rows with the
+    /// same ordering key, until requeued.
+    Failed,
+}
+
+impl OutboxStatus {
+    fn as_str(self: &Self) -> &'static str {
+        match self {
+            OutboxStatus::Pending => "pending",
+            OutboxStatus::Dispatched => "dispatched",
+            OutboxStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(status: &str) -> Option<OutboxStatus> {
+        match status {
+            "pending" => Some(OutboxStatus::Pending),
+            "dispatched" => Some(OutboxStatus::Dispatched),
+            "failed" => Some(OutboxStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// An outbox row without its payload.
+#[derive(Clone, Debug)]
+pub struct OutboxRow {
+    pub id: i64,
+    pub ordering_key: Option<String>,
+    pub message_id: String,
+    pub status: OutboxStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub dispatched_at: Option<DateTime<Utc>>,
+}
+
+const ROW_COLUMNS: &str = "id, ordering_key, message_id, status, attempts, last_error, created_at, next_attempt_at, dispatched_at";
+
+impl OutboxRow {
+    fn from_row(row: &Row) -> rusqlite::Result<OutboxRow> {
+        let status: String = row.get(3)?;
+        Ok(OutboxRow {
+            id: row.get(0)?,
+            ordering_key: row.get(1)?,
+            message_id: row.get(2)?,
+            // Unknown statuses are never relayed, so treat them as stuck.
+            status: OutboxStatus::parse(&status).unwrap_or(OutboxStatus::Failed),
+            attempts: row.get(4)?,
+            last_error: row.get(5)?,
+            created_at: row.get(6)?,
+            next_attempt_at: row.get(7)?,
+            dispatched_at: row.get(8)?,
+        })
+    }
+}
+
+/// Creates the outbox table if it does not exist yet.
+pub fn init(conn: &Connection) -> Result<(), OutboxError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS outbox (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            ordering_key TEXT,
+            message_id TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            body BLOB NOT NULL,
+            properties TEXT NOT NULL,
+            user_properties TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT,
+            created_at TEXT NOT NULL,
+            next_attempt_at TEXT NOT NULL,
+            dispatched_at TEXT
+        );
+        CREATE INDEX IF NOT EXISTS outbox_status ON outbox (status, next_attempt_at);
+        CREATE INDEX IF NOT EXISTS outbox_ordering_key ON outbox (ordering_key, id);")?;
+    Ok(())
+}
+
+/// Adds a message to the outbox. Pass the application's transaction so the message is
+/// only sent if the rest of the transaction commits.
+///
+/// Messages with the same ordering key are sent in the order they were added. A message
+/// id is assigned if the message has none, so relay retries can be deduplicated.
+pub fn enqueue(conn: &Connection, message: &Message<BrokerSendProperties>, ordering_key: Option<&str>) -> Result<i64, OutboxError> {
+    let mut properties = message.properties.clone();
+    let message_id = properties.message_id.get_or_insert_with(|| Uuid::new_v4().to_string()).clone();
+    let (properties, user_properties) = stored_message::property_columns(&properties, &message.user_properties)?;
+    let now = Utc::now();
+
+    conn.execute(
+        "INSERT INTO outbox (ordering_key, message_id, content_type, body, properties, user_properties, created_at, next_attempt_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+        params![
+            ordering_key,
+            message_id,
+            message.content_type,
+            message.content,
+            properties,
+            user_properties,
+            now,
+        ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Rows in id order, optionally only those with the given status.
+pub fn list(conn: &Connection, status: Option<OutboxStatus>) -> Result<Vec<OutboxRow>, OutboxError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM outbox WHERE ?1 IS NULL OR status = ?1 ORDER BY id", ROW_COLUMNS))?;
+    let rows = stmt.query_map(params![status.map(|s| s.as_str())], OutboxRow::from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+pub fn get(conn: &Connection, id: i64) -> Result<Option<OutboxRow>, OutboxError> {
+    let row = conn.query_row(
+        &format!("SELECT {} FROM outbox WHERE id = ?1", ROW_COLUMNS),
+        params![id],
+        OutboxRow::from_row).optional()?;
+    Ok(row)
+}
+
+/// Makes failed rows pending again with a fresh attempt count, either the given ones
+/// or all of them. Returns the number of rows requeued.
+pub fn requeue(conn: &Connection, ids: &[i64]) -> Result<usize, OutboxError> {
+    let now = Utc::now();
+    if ids.is_empty() {
+        let count = conn.execute(
+            "UPDATE outbox SET status = 'pending', attempts = 0, next_attempt_at = ?1 WHERE status = 'failed'",
+            params![now])?;
+        return Ok(count);
+    }
+
+    let mut count = 0;
+    for id in ids {
+        count += conn.execute(
+            "UPDATE outbox SET status = 'pending', attempts = 0, next_attempt_at = ?1 WHERE id = ?2 AND status != 'dispatched'",
+            params![now, id])?;
+    }
+    Ok(count)
+}
+
+/// Deletes rows dispatched before the given time. Returns the number of rows deleted.
+pub fn purge_dispatched(conn: &Connection, before: DateTime<Utc>) -> Result<usize, OutboxError> {
+    let count = conn.execute(
+        "DELETE FROM outbox WHERE status = 'dispatched' AND dispatched_at < ?1",
+        params![before])?;
+    Ok(count)
+}
+
+// A due row with its message, or why the message could not be read back.
+type DueRow = (OutboxRow, Result<Message<BrokerSendProperties>, OutboxError>);
+
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct RelayStats {
+    pub dispatched: usize,
+    pub failed: usize,
+}
+
+/// Sends outbox rows and marks them dispatched.
+///
+/// Delivery is at least once: a row is marked only after the send succeeded, so a crash
+/// in between sends it again with the same message id, which the queue drops if
+/// duplicate detection is enabled. A row is not sent while an earlier row with the
+/// same ordering key is still waiting for a retry or has failed.
+pub struct OutboxRelay<'a> {
+    conn: Connection,
+    sb_client: &'a AzureServiceBusClient,
+    batch_size: usize,
+    batch_send: bool,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    poll_interval: Duration,
+}
+
+impl<'a> OutboxRelay<'a> {
+    pub fn new(conn: Connection, sb_client: &'a AzureServiceBusClient) -> Result<Self, OutboxError> {
+        init(&conn)?;
+        Ok(OutboxRelay {
+            conn,
+            sb_client,
+            batch_size: 100,
+            batch_send: false,
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            poll_interval: Duration::from_secs(1),
+        })
+    }
+
+    /// Rows picked up per round.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sends each round's rows with one batch send instead of one request per row.
+    /// Batched bodies must be text, and a failed batch counts against every row in it.
+    /// Clients that encrypt or sign cannot batch, so rows are still sent one at a time.
+    pub fn with_batch_send(mut self, batch_send: bool) -> Self {
+        self.batch_send = batch_send;
+        self
+    }
+
+    /// Failed attempts after which a row is marked failed and left for an operator.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// The delay before retrying a row doubles with each attempt starting at `initial`
+    /// and never exceeds `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// How long to wait when there is nothing to send.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn connection(self: &Self) -> &Connection {
+        &self.conn
+    }
+
+    /// Relays until shutdown is requested.
+    pub async fn run(self: &Self, shutdown: &Shutdown) -> Result<(), OutboxError> {
+        while !shutdown.is_requested() {
+            let stats = self.relay_once().await?;
+            if stats.dispatched + stats.failed > 0 {
+                println!("[{}] Outbox relayed {} rows, {} failed.", Local::now(), stats.dispatched, stats.failed);
+                continue;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(self.poll_interval) => {},
+                _ = shutdown.requested() => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the rows that are due now.
+    pub async fn relay_once(self: &Self) -> Result<RelayStats, OutboxError> {
+        let due = self.due_rows()?;
+        if self.batch_send && self.sb_client.supports_batch_send() {
+            return self.relay_batch(due).await;
+        }
+
+        let mut stats = RelayStats::default();
+        let mut blocked_keys = HashSet::new();
+        for (row, message) in due {
+            if let Some(key) = &row.ordering_key {
+                if blocked_keys.contains(key) {
+                    continue;
+                }
+            }
+
+            let sent = match message {
+                Ok(message) => self.sb_client.send(&message).await.map(|_| ()).map_err(OutboxError::from),
+                Err(e) => Err(e),
+            };
+
+            match sent {
+                Ok(()) => {
+                    self.mark_dispatched(row.id)?;
+                    stats.dispatched += 1;
+                },
+                Err(e) => {
+                    println!("Unable to relay outbox row {}: {}", row.id, e);
+                    self.record_failure(&row, &e)?;
+                    stats.failed += 1;
+                    if let Some(key) = row.ordering_key {
+                        blocked_keys.insert(key);
+                    }
+                },
+            }
+        }
+        Ok(stats)
+    }
+
+    async fn relay_batch(self: &Self, due: Vec<DueRow>) -> Result<RelayStats, OutboxError> {
+        let mut stats = RelayStats::default();
+        let mut rows = Vec::with_capacity(due.len());
+        let mut messages = Vec::with_capacity(due.len());
+        for (row, message) in due {
+            match message {
+                Ok(message) => {
+                    rows.push(row);
+                    messages.push(message);
+                },
+                // Unreadable rows cannot hold up the rest, they are retried on their own.
+                Err(e) => {
+                    self.record_failure(&row, &e)?;
+                    stats.failed += 1;
+                },
+            }
+        }
+
+        if messages.is_empty() {
+            return Ok(stats);
+        }
+
+        match self.sb_client.send_batch(&messages).await {
+            Ok(_) => {
+                for row in &rows {
+                    self.mark_dispatched(row.id)?;
+                }
+                stats.dispatched += rows.len();
+            },
+            Err(e) => {
+                println!("Unable to relay {} outbox rows: {}", rows.len(), e);
+                let e = OutboxError::from(e);
+                for row in &rows {
+                    self.record_failure(row, &e)?;
+                }
+                stats.failed += rows.len();
+            },
+        }
+        Ok(stats)
+    }
+
+    // Pending rows due now in id order, skipping ones held back by an earlier row with
+    // the same key that is not part of this round.
+    fn due_rows(self: &Self) -> Result<Vec<DueRow>, OutboxError> {
+        let now = Utc::now();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}, content_type, body, properties, user_properties FROM outbox o
+             WHERE o.status = 'pending' AND o.next_attempt_at <= ?1
+               AND NOT EXISTS (
+                 SELECT 1 FROM outbox p
+                 WHERE p.ordering_key = o.ordering_key AND p.id < o.id
+                   AND (p.status = 'failed' OR (p.status = 'pending' AND p.next_attempt_at > ?1)))
+             ORDER BY o.id LIMIT ?2", ROW_COLUMNS))?;
+
+        let rows = stmt.query_map(params![now, self.batch_size as i64], |r| {
+            let row = OutboxRow::from_row(r)?;
+            let message = stored_message::read_message(r, 9)?.map_err(|e| OutboxError::InvalidRow(row.id, e.to_string()));
+            Ok((row, message))
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn mark_dispatched(self: &Self, id: i64) -> Result<(), OutboxError> {
+        self.conn.execute(
+            "UPDATE outbox SET status = 'dispatched', attempts = attempts + 1, last_error = NULL, dispatched_at = ?1 WHERE id = ?2",
+            params![Utc::now(), id])?;
+        Ok(())
+    }
+
+    fn record_failure(self: &Self, row: &OutboxRow, error: &OutboxError) -> Result<(), OutboxError> {
+        let attempts = row.attempts + 1;
+        let status = if attempts >= self.max_attempts { OutboxStatus::Failed } else { OutboxStatus::Pending };
+        let next_attempt_at = Utc::now() + chrono::Duration::from_std(self.backoff(attempts)).unwrap_or(chrono::Duration::zero());
+
+        self.conn.execute(
+            "UPDATE outbox SET status = ?1, attempts = ?2, last_error = ?3, next_attempt_at = ?4 WHERE id = ?5",
+            params![status.as_str(), attempts, error.to_string(), next_attempt_at, row.id])?;
+        Ok(())
+    }
+
+    fn backoff(self: &Self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
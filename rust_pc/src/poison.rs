@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use crate::mazure::sbclient::{BrokerReceiveProperties, Message};
+
+/// User property holding how many processing attempts have failed so far.
+pub static RETRY_ATTEMPT_PROPERTY: &str = "retry-attempt";
+
+/// User property holding when the first processing attempt failed (RFC 3339).
+pub static RETRY_FIRST_FAILURE_PROPERTY: &str = "retry-first-failure-utc";
+
+/// User property holding the error from the most recent failed attempt.
+pub static RETRY_LAST_ERROR_PROPERTY: &str = "retry-last-error";
+
+/// Broad classes of processing failures the poison policy can act on.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ErrorClass {
+    /// Might succeed if tried again later.
+    Transient,
+
+    /// Will fail no matter how many times it is retried.
+    Permanent,
+
+    /// The payload could not be decoded.
+    Decode,
+}
+
+#[derive(Error, Debug)]
+#[error("{class:?} processing error: {message}")]
+pub struct ProcessingError {
+    pub class: ErrorClass,
+    pub message: String,
+}
+
+impl ProcessingError {
+    pub fn transient(message: impl Into<String>) -> Self {
+        ProcessingError { class: ErrorClass::Transient, message: message.into() }
+    }
+
+    pub fn permanent(message: impl Into<String>) -> Self {
+        ProcessingError { class: ErrorClass::Permanent, message: message.into() }
+    }
+
+    pub fn decode(message: impl Into<String>) -> Self {
+        ProcessingError { class: ErrorClass::Decode, message: message.into() }
+    }
+}
+
+/// What to do with a failed message that is not dead-lettered.
+#[derive(Clone, Debug)]
+pub enum RetryStrategy {
+    /// Unlock the message so it is redelivered right away.
+    Abandon,
+
+    /// Schedule a copy of the message for later and complete the original. The delay
+    /// doubles with each attempt starting at `initial` and never exceeds `max`.
+    Backoff { initial: Duration, max: Duration },
+}
+
+#[derive(Clone, Debug)]
+pub struct PoisonPolicy {
+    /// Failed attempts after which a message is dead-lettered.
+    pub max_attempts: u32,
+
+    /// Error classes that are dead-lettered on the first failure.
+    pub dead_letter_classes: Vec<ErrorClass>,
+
+    pub retry: RetryStrategy,
+}
+
+impl Default for PoisonPolicy {
+    fn default() -> Self {
+        PoisonPolicy {
+            max_attempts: 5,
+            dead_letter_classes: vec![ErrorClass::Permanent, ErrorClass::Decode],
+            retry: RetryStrategy::Abandon,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum PoisonDecision {
+    DeadLetter { reason: String, description: String },
+    Abandon,
+    Reschedule { at: DateTime<Utc> },
+}
+
+/// Retry bookkeeping that travels with a message in its user properties.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RetryMetadata {
+    pub attempt: u32,
+    pub first_failure_utc: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+impl RetryMetadata {
+    /// Reads the metadata of a received message. Attempts counted by the broker through
+    /// redelivery are added to the attempts carried over from rescheduled copies.
+    pub fn from_message(message: &Message<BrokerReceiveProperties>) -> Self {
+        let carried = message.user_property(RETRY_ATTEMPT_PROPERTY)
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(0);
+        let delivery_count = message.properties.delivery_count.unwrap_or(1).max(1) as u32;
+
+        RetryMetadata {
+            attempt: carried + delivery_count,
+            first_failure_utc: message.user_property(RETRY_FIRST_FAILURE_PROPERTY)
+                .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+                .map(|dt| dt.with_timezone(&Utc)),
+            last_error: message.user_property(RETRY_LAST_ERROR_PROPERTY).map(|v| v.to_string()),
+        }
+    }
+
+    /// Records a failure of the current attempt.
+    pub fn record_failure(self: &mut Self, error: &ProcessingError, now: DateTime<Utc>) {
+        self.first_failure_utc.get_or_insert(now);
+        self.last_error = Some(error.to_string());
+    }
+
+    pub fn write_to(self: &Self, user_properties: &mut BTreeMap<String, String>) {
+        user_properties.insert(RETRY_ATTEMPT_PROPERTY.into(), self.attempt.to_string());
+        if let Some(first_failure_utc) = &self.first_failure_utc {
+            user_properties.insert(RETRY_FIRST_FAILURE_PROPERTY.into(), first_failure_utc.to_rfc3339());
+        }
+        if let Some(last_error) = &self.last_error {
+            user_properties.insert(RETRY_LAST_ERROR_PROPERTY.into(), last_error.clone());
+        }
+    }
+}
+
+impl PoisonPolicy {
+    pub fn decide(self: &Self, retry: &RetryMetadata, error: &ProcessingError, now: DateTime<Utc>) -> PoisonDecision {
+        if self.dead_letter_classes.contains(&error.class) {
+            return PoisonDecision::DeadLetter {
+                reason: format!("{:?}Error", error.class),
+                description: error.message.clone(),
+            };
+        }
+
+        if retry.attempt >= self.max_attempts {
+            return PoisonDecision::DeadLetter {
+                reason: "MaxAttemptsExceeded".into(),
+                description: format!("Failed {} attempts, last error: {}", retry.attempt, error.message),
+            };
+        }
+
+        match &self.retry {
+            RetryStrategy::Abandon => PoisonDecision::Abandon,
+            RetryStrategy::Backoff { initial, max } => {
+                let exponent = retry.attempt.saturating_sub(1).min(30);
+                let delay = initial.saturating_mul(1 << exponent).min(*max);
+                let delay = chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::max_value());
+                // A delay past the end of time schedules the message as late as possible.
+                let at = now.checked_add_signed(delay).unwrap_or(DateTime::<Utc>::MAX_UTC);
+                PoisonDecision::Reschedule { at }
+            }
+        }
+    }
+}
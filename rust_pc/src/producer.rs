@@ -2,13 +2,20 @@ use std::error::Error;
 
 use chrono::Local;
 
-use crate::mazure::sbclient::{AzureServiceBusClient, Message};
+use crate::mazure::codec::Format;
+use crate::mazure::sbclient::Message;
 use crate::messages::LogInfo;
+use crate::outbox;
+use crate::shutdown::Shutdown;
+use crate::spool::{SendOutcome, StoreAndForward};
+use crate::transport::QueueTransport;
+use crate::typed::{MessageType, MESSAGE_TYPE_PROPERTY};
 
-pub async fn run_producer(sb_client: &AzureServiceBusClient, count: u32) -> Result<(), Box<dyn Error>> {
+pub async fn run_producer(transport: &dyn QueueTransport, count: u32, format: Format) -> Result<(), Box<dyn Error>> {
     for _ in 1..=count {
         let log_info = LogInfo::new_random();
-        let mut msg = Message::new_json(&log_info)?;
+        let mut msg = Message::encode_as(format, &log_info)?;
+        msg.set_user_property(MESSAGE_TYPE_PROPERTY, LogInfo::TYPE_NAME);
 
         // Set the message to be processed in the future.
         let eq_time = chrono::Utc::now() + chrono::Duration::seconds(15);
@@ -18,8 +25,58 @@ pub async fn run_producer(sb_client: &AzureServiceBusClient, count: u32) -> Resu
         println!("    properties: {:?}", &msg.properties);
         println!("    content: {:?}", &log_info);
 
-        sb_client.send(&msg).await?;
+        transport.send(&msg).await?;
         println!("[{}] Message sent!", Local::now());
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Like `run_producer`, but messages that cannot be sent are spooled and forwarded in
+/// the background while producing continues. The backlog is flushed at the end, and
+/// whatever is still unsent stays spooled for the next run.
+pub async fn run_spooling_producer(spool: &StoreAndForward<'_>, count: u32, format: Format) -> Result<(), Box<dyn Error>> {
+    let (done, forwarding) = Shutdown::new();
+
+    let produce = async {
+        let result = produce_spooled(spool, count, format).await;
+        done.trigger();
+        result
+    };
+    let (produced, forwarded) = tokio::join!(produce, spool.run(&forwarding));
+    produced?;
+    forwarded?;
+
+    let stats = spool.stats()?;
+    println!("[{}] Spool: {} waiting ({} bytes), {} failed, {} spooled and {} forwarded this run.",
+        Local::now(), stats.backlog, stats.backlog_bytes, stats.failed, stats.spooled, stats.forwarded);
+    Ok(())
+}
+
+async fn produce_spooled(spool: &StoreAndForward<'_>, count: u32, format: Format) -> Result<(), Box<dyn Error>> {
+    for _ in 1..=count {
+        let log_info = LogInfo::new_random();
+        let mut msg = Message::encode_as(format, &log_info)?;
+        msg.set_user_property(MESSAGE_TYPE_PROPERTY, LogInfo::TYPE_NAME);
+
+        match spool.send(&msg).await? {
+            SendOutcome::Sent(receipt) => println!("[{}] Message sent: {:?}", Local::now(), receipt.message_id),
+            SendOutcome::Spooled(id) => println!("[{}] Message spooled as row {}: {:?}", Local::now(), id, &log_info),
+        }
+    }
+    Ok(())
+}
+
+/// Adds the messages to the outbox in one transaction, for the relay to send.
+pub fn run_outbox_producer(conn: &mut rusqlite::Connection, count: u32, format: Format) -> Result<(), Box<dyn Error>> {
+    let tx = conn.transaction()?;
+    for _ in 1..=count {
+        let log_info = LogInfo::new_random();
+        let mut msg = Message::encode_as(format, &log_info)?;
+        msg.set_user_property(MESSAGE_TYPE_PROPERTY, LogInfo::TYPE_NAME);
+
+        let id = outbox::enqueue(&tx, &msg, None)?;
+        println!("[{}] Added message to the outbox as row {}: {:?}", Local::now(), id, &log_info);
+    }
+    tx.commit()?;
+    Ok(())
+}
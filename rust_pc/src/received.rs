@@ -0,0 +1,197 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use chrono::{DateTime, Local, Utc};
+
+use crate::mazure::sbclient::{AzureServiceBusError, BrokerReceiveProperties, Message};
+use crate::transport::QueueTransport;
+
+/// What happens to the lock of a `ReceivedMessage` dropped without being settled.
+#[derive(Debug, Clone, Default)]
+pub enum UnsettledAction {
+    /// Print a warning. The message is delivered again once its lock expires.
+    #[default]
+    Warn,
+
+    /// Hand the lock to `DroppedLocks` to be abandoned, so the message is delivered
+    /// again straight away.
+    Abandon(DroppedLocks),
+}
+
+/// Locks of messages dropped without being settled. Dropping cannot wait for the
+/// transport, so whoever owns these abandons them with `abandon_all`.
+#[derive(Debug, Clone, Default)]
+pub struct DroppedLocks {
+    locks: Rc<RefCell<Vec<BrokerReceiveProperties>>>,
+}
+
+impl DroppedLocks {
+    pub fn new() -> Self {
+        DroppedLocks::default()
+    }
+
+    pub fn len(self: &Self) -> usize {
+        self.locks.borrow().len()
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.locks.borrow().is_empty()
+    }
+
+    /// Abandons the locks collected so far and returns how many were abandoned. Locks
+    /// that expired meanwhile are skipped.
+    pub async fn abandon_all(self: &Self, transport: &dyn QueueTransport) -> usize {
+        let locks = std::mem::take(&mut *self.locks.borrow_mut());
+        let mut abandoned = 0;
+        for lock in locks {
+            match transport.abandon(&lock).await {
+                Ok(()) => abandoned += 1,
+                Err(e) => println!("Could not abandon dropped message {:?}: {}", lock.message_id, e),
+            }
+        }
+        abandoned
+    }
+}
+
+/// A locked message and the transport it is settled on. Settling consumes the handle,
+/// so a message cannot be settled twice, and a lock that expired by the local clock is
+/// refused with `RequestError("410")` without asking the transport.
+pub struct ReceivedMessage<'a> {
+    message: Message<BrokerReceiveProperties>,
+    transport: &'a dyn QueueTransport,
+    lock_duration: Option<chrono::Duration>,
+    guard: UnsettledGuard,
+}
+
+impl<'a> ReceivedMessage<'a> {
+    pub fn new(message: Message<BrokerReceiveProperties>, transport: &'a dyn QueueTransport) -> Self {
+        // The REST API does not say when a renewed lock expires, so renewals assume the
+        // lock is good for as long as the first one was.
+        let lock_duration = message.properties.locked_until_utc.map(|until| until - Utc::now());
+        let guard = UnsettledGuard { lock: Some(message.properties.clone()), action: UnsettledAction::Warn };
+        ReceivedMessage { message, transport, lock_duration, guard }
+    }
+
+    /// Locks and returns the next available message, or none if there is none.
+    pub async fn receive(transport: &'a dyn QueueTransport) -> Result<Option<ReceivedMessage<'a>>, AzureServiceBusError> {
+        Ok(transport.receive().await?.map(|message| ReceivedMessage::new(message, transport)))
+    }
+
+    pub fn with_unsettled_action(mut self: Self, action: UnsettledAction) -> Self {
+        self.guard.action = action;
+        self
+    }
+
+    pub fn message(self: &Self) -> &Message<BrokerReceiveProperties> {
+        &self.message
+    }
+
+    /// The transport the message is settled on.
+    pub fn transport(self: &Self) -> &'a dyn QueueTransport {
+        self.transport
+    }
+
+    pub fn locked_until_utc(self: &Self) -> Option<DateTime<Utc>> {
+        self.message.properties.locked_until_utc
+    }
+
+    pub fn is_lock_expired(self: &Self) -> bool {
+        self.locked_until_utc().is_some_and(|until| until <= Utc::now())
+    }
+
+    /// Gives up the handle without settling, the message stays locked until it is
+    /// settled some other way or the lock expires.
+    pub fn into_message(mut self: Self) -> Message<BrokerReceiveProperties> {
+        self.guard.lock = None;
+        self.message
+    }
+
+    pub async fn complete(mut self: Self) -> Result<(), AzureServiceBusError> {
+        self.settle()?;
+        self.transport.complete(&self.message).await
+    }
+
+    /// Releases the lock so the message is delivered again.
+    pub async fn abandon(mut self: Self) -> Result<(), AzureServiceBusError> {
+        self.settle()?;
+        self.transport.abandon(&self.message.properties).await
+    }
+
+    pub async fn dead_letter(mut self: Self, reason: &str, description: &str) -> Result<(), AzureServiceBusError> {
+        self.settle()?;
+        self.transport.dead_letter(&self.message, reason, description).await
+    }
+
+    /// Extends the lock and moves `locked_until_utc` along.
+    pub async fn renew_lock(self: &mut Self) -> Result<(), AzureServiceBusError> {
+        self.check_lock()?;
+        let renewed_at = Utc::now();
+        let reported = match self.transport.renew_lock(&self.message.properties).await {
+            Ok(reported) => reported,
+            Err(e) => {
+                if is_lock_lost(&e) {
+                    self.guard.lock = None;
+                }
+                return Err(e);
+            },
+        };
+
+        // The expiry the transport reports, or else an estimate from the lock duration.
+        if let Some(locked_until) = reported.or_else(|| self.lock_duration.map(|d| renewed_at + d)) {
+            self.message.properties.locked_until_utc = Some(locked_until);
+            if let Some(lock) = &mut self.guard.lock {
+                lock.locked_until_utc = Some(locked_until);
+            }
+        }
+        Ok(())
+    }
+
+    // Settling is attempted once, whatever its outcome.
+    fn settle(self: &mut Self) -> Result<(), AzureServiceBusError> {
+        let checked = self.check_lock();
+        self.guard.lock = None;
+        checked
+    }
+
+    fn check_lock(self: &mut Self) -> Result<(), AzureServiceBusError> {
+        if self.is_lock_expired() {
+            self.guard.lock = None;
+            return Err(AzureServiceBusError::RequestError("410".into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Debug for ReceivedMessage<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReceivedMessage").field("message", &self.message).finish()
+    }
+}
+
+fn is_lock_lost(error: &AzureServiceBusError) -> bool {
+    matches!(error, AzureServiceBusError::RequestError(status) if status == "410")
+}
+
+// Holds the lock until the message is settled, and deals with it if it never is.
+struct UnsettledGuard {
+    lock: Option<BrokerReceiveProperties>,
+    action: UnsettledAction,
+}
+
+impl Drop for UnsettledGuard {
+    fn drop(&mut self) {
+        let lock = match self.lock.take() {
+            None => return,
+            Some(lock) => lock,
+        };
+
+        match &self.action {
+            UnsettledAction::Warn => {
+                let locked_until = lock.locked_until_utc.map(|until| until.to_rfc3339()).unwrap_or_else(|| "it expires".into());
+                println!("[{}] Message {:?} was dropped without being settled, it stays locked until {}.", Local::now(), lock.message_id, locked_until);
+            },
+            UnsettledAction::Abandon(dropped) => dropped.locks.borrow_mut().push(lock),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use chrono::Local;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+
+use crate::consumer::handle_failure;
+use crate::mazure::sbclient::{BrokerReceiveProperties, Message};
+use crate::middleware::{Middleware, Pipeline, Processor};
+use crate::poison::{PoisonPolicy, ProcessingError, RetryMetadata};
+use crate::received::ReceivedMessage;
+use crate::shutdown::Shutdown;
+use crate::streaming::MessageStream;
+use crate::transport::QueueTransport;
+use crate::typed::{MessageType, MESSAGE_TYPE_PROPERTY};
+
+/// Reason given to messages dead-lettered because no handler takes them.
+pub static UNROUTED_REASON: &str = "UnroutedMessage";
+
+/// What a `Router` dispatches on.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RouteKey {
+    Label,
+
+    /// The `message-type` user property set by `TypedSender`.
+    MessageType,
+
+    /// The content type without parameters, in lower case.
+    ContentType,
+}
+
+/// What happens to a message no handler takes when there is no fallback handler.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnroutedAction {
+    DeadLetter,
+
+    /// Leave it for another consumer that knows the type.
+    Abandon,
+}
+
+/// How a message was dispatched.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Dispatched {
+    /// The handler for the key, or the fallback handler, processed it and it was completed.
+    Handled,
+
+    /// The handler failed or the body did not decode. It was settled by the poison policy.
+    Failed,
+
+    /// No handler took it and it was settled by the `UnroutedAction`.
+    Unrouted,
+}
+
+/// Processes one decoded payload. Closures taking the payload are handlers too.
+#[async_trait(?Send)]
+pub trait Handler<T> {
+    async fn handle(&self, body: T, message: &Message<BrokerReceiveProperties>) -> Result<(), ProcessingError>;
+}
+
+#[async_trait(?Send)]
+impl<T: 'static, F, Fut> Handler<T> for F
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), ProcessingError>>,
+{
+    async fn handle(&self, body: T, _message: &Message<BrokerReceiveProperties>) -> Result<(), ProcessingError> {
+        self(body).await
+    }
+}
+
+// A handler with the payload type erased.
+struct DecodingRoute<T, H> {
+    handler: H,
+    marker: PhantomData<fn(T)>,
+}
+
+#[async_trait(?Send)]
+impl<T: DeserializeOwned + 'static, H: Handler<T>> Processor for DecodingRoute<T, H> {
+    async fn process(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), ProcessingError> {
+        let body = message.decode::<T>().map_err(|e| ProcessingError::decode(e.to_string()))?;
+        self.handler.handle(body, message).await
+    }
+}
+
+struct FallbackRoute<'a> {
+    handler: Box<dyn Handler<Message<BrokerReceiveProperties>> + 'a>,
+}
+
+#[async_trait(?Send)]
+impl<'a> Processor for FallbackRoute<'a> {
+    async fn process(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), ProcessingError> {
+        self.handler.handle(message.clone(), message).await
+    }
+}
+
+/// Dispatches messages of several types sharing one queue to the handler registered
+/// for their label, message type or content type. Bodies are decoded with the serde
+/// format matching the content type. A message is completed once its handler succeeds,
+/// and settled by the poison policy if the handler fails or the body does not decode.
+/// Middleware added with `with_middleware` runs around every handler.
+pub struct Router<'a> {
+    transport: &'a dyn QueueTransport,
+    key: RouteKey,
+    routes: HashMap<String, Box<dyn Processor + 'a>>,
+    fallback: Option<FallbackRoute<'a>>,
+    pipeline: Pipeline<'a>,
+    unrouted: UnroutedAction,
+    poison_policy: PoisonPolicy,
+}
+
+impl<'a> Router<'a> {
+    pub fn new(transport: &'a dyn QueueTransport, key: RouteKey) -> Self {
+        Router {
+            transport,
+            key,
+            routes: HashMap::new(),
+            fallback: None,
+            pipeline: Pipeline::new(),
+            unrouted: UnroutedAction::DeadLetter,
+            poison_policy: PoisonPolicy::default(),
+        }
+    }
+
+    /// Handles messages whose key is `value`. Content types are matched in lower case.
+    pub fn with_route<T, H>(mut self: Self, value: &str, handler: H) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        H: Handler<T> + 'a,
+    {
+        let value = match self.key {
+            RouteKey::ContentType => value.to_ascii_lowercase(),
+            _ => value.to_string(),
+        };
+        self.routes.insert(value, Box::new(DecodingRoute { handler, marker: PhantomData }));
+        self
+    }
+
+    /// Handles messages keyed by the type name of `T`.
+    pub fn with_type<T, H>(self: Self, handler: H) -> Self
+    where
+        T: MessageType + DeserializeOwned + 'static,
+        H: Handler<T> + 'a,
+    {
+        self.with_route::<T, H>(T::TYPE_NAME, handler)
+    }
+
+    /// Handles messages no route takes, given the whole message.
+    pub fn with_fallback(mut self: Self, handler: impl Handler<Message<BrokerReceiveProperties>> + 'a) -> Self {
+        self.fallback = Some(FallbackRoute { handler: Box::new(handler) });
+        self
+    }
+
+    /// Wraps every handler in `middleware`. The first middleware added is the outermost.
+    pub fn with_middleware(mut self: Self, middleware: impl Middleware + 'a) -> Self {
+        self.pipeline.push(middleware);
+        self
+    }
+
+    pub fn with_unrouted_action(self: Self, unrouted: UnroutedAction) -> Self {
+        Router { unrouted, ..self }
+    }
+
+    pub fn with_poison_policy(self: Self, poison_policy: PoisonPolicy) -> Self {
+        Router { poison_policy, ..self }
+    }
+
+    /// The value of the message's routing key, if it has one.
+    pub fn key_of(self: &Self, message: &Message<BrokerReceiveProperties>) -> Option<String> {
+        match self.key {
+            RouteKey::Label => message.properties.label.clone(),
+            RouteKey::MessageType => message.user_property(MESSAGE_TYPE_PROPERTY).map(|t| t.to_string()),
+            RouteKey::ContentType => {
+                let essence = message.content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+                Some(essence).filter(|e| !e.is_empty())
+            },
+        }
+    }
+
+    pub async fn dispatch(self: &Self, received: ReceivedMessage<'_>) -> Result<Dispatched, Box<dyn Error>> {
+        let key = self.key_of(received.message());
+        let route = key.as_ref().and_then(|key| self.routes.get(key));
+
+        let result = match (route, &self.fallback) {
+            (Some(route), _) => self.pipeline.process(received.message(), route.as_ref()).await,
+            (None, Some(fallback)) => self.pipeline.process(received.message(), fallback).await,
+            (None, None) => {
+                println!("[{}] No handler for {:?} {:?}.", Local::now(), self.key, key);
+                match self.unrouted {
+                    UnroutedAction::DeadLetter => {
+                        let description = format!("No handler for {:?} {:?}", self.key, key);
+                        received.dead_letter(UNROUTED_REASON, &description).await?;
+                    },
+                    UnroutedAction::Abandon => received.abandon().await?,
+                }
+                return Ok(Dispatched::Unrouted);
+            },
+        };
+
+        match result {
+            Ok(()) => {
+                received.complete().await?;
+                Ok(Dispatched::Handled)
+            },
+            Err(error) => {
+                println!("Processing failed: {}", error);
+                let transport = received.transport();
+                let message = received.into_message();
+                let retry = RetryMetadata::from_message(&message);
+                handle_failure(transport, &self.poison_policy, &message, retry, &error).await?;
+                Ok(Dispatched::Failed)
+            },
+        }
+    }
+
+    /// Receives and dispatches up to `concurrency` messages at a time until shutdown is
+    /// requested.
+    pub async fn run(self: &Self, concurrency: usize, shutdown: &Shutdown) {
+        MessageStream::new(self.transport)
+            .take_until(shutdown.requested())
+            .for_each_concurrent(concurrency, |received| async move {
+                let dispatched = match received {
+                    Ok(received) => self.dispatch(received).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = dispatched {
+                    println!("Error dispatching: {:?}", e);
+                }
+            })
+            .await;
+    }
+}
@@ -0,0 +1,77 @@
+use chrono::Local;
+use tokio::sync::watch;
+
+/// Cooperative shutdown notification for the long running loops.
+///
+/// A `Shutdown` is cheap to clone and every clone observes the same request.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+}
+
+/// The sending half used to request a shutdown.
+#[derive(Debug)]
+pub struct ShutdownTrigger {
+    sender: watch::Sender<bool>,
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(self: &Self) {
+        // Receivers may all be gone already, that is fine.
+        let _ = self.sender.send(true);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> (ShutdownTrigger, Shutdown) {
+        let (sender, receiver) = watch::channel(false);
+        (ShutdownTrigger { sender }, Shutdown { receiver })
+    }
+
+    /// Creates a shutdown that is requested on SIGINT (Ctrl-C) or SIGTERM.
+    /// A second signal terminates the process immediately.
+    pub fn from_signals() -> Shutdown {
+        let (trigger, shutdown) = Shutdown::new();
+
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            println!("[{}] Shutdown requested, draining. Signal again to exit immediately.", Local::now());
+            trigger.trigger();
+
+            wait_for_signal().await;
+            println!("[{}] Second signal received, exiting now.", Local::now());
+            std::process::exit(130);
+        });
+
+        shutdown
+    }
+
+    pub fn is_requested(self: &Self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Completes once shutdown has been requested.
+    pub async fn requested(self: &Self) {
+        let mut receiver = self.receiver.clone();
+        if receiver.wait_for(|requested| *requested).await.is_err() {
+            // The trigger went away without firing so shutdown can never be requested.
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Unable to register SIGTERM handler.");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,346 @@
+use std::cell::Cell;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{params, Connection, Row};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::mazure::limits::message_size;
+use crate::mazure::sbclient::{AzureServiceBusError, BrokerSendProperties, Message};
+use crate::mazure::sending::{SendReceipt, SendRetryPolicy};
+use crate::shutdown::Shutdown;
+use crate::stored_message;
+use crate::transport::QueueTransport;
+
+#[derive(Error, Debug)]
+pub enum SpoolError {
+    #[error("Spool database error: {0}")]
+    DatabaseError(String),
+
+    #[error("Spool is full: {0}")]
+    Full(String),
+
+    #[error("Unable to send: {0}")]
+    SendError(String),
+}
+
+impl From<rusqlite::Error> for SpoolError {
+    fn from(e: rusqlite::Error) -> Self {
+        SpoolError::DatabaseError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for SpoolError {
+    fn from(e: serde_json::Error) -> Self {
+        SpoolError::DatabaseError(e.to_string())
+    }
+}
+
+impl From<AzureServiceBusError> for SpoolError {
+    fn from(e: AzureServiceBusError) -> Self {
+        SpoolError::SendError(e.to_string())
+    }
+}
+
+/// How much the spool holds before it refuses messages, and how long it keeps them.
+#[derive(Clone, Debug)]
+pub struct SpoolLimits {
+    pub max_messages: usize,
+
+    /// Total size of the spooled messages, counted like the service counts them.
+    pub max_bytes: usize,
+
+    /// Spooled messages older than this are dropped instead of sent.
+    pub max_age: chrono::Duration,
+}
+
+impl Default for SpoolLimits {
+    fn default() -> Self {
+        SpoolLimits {
+            max_messages: 10_000,
+            max_bytes: 100 * 1024 * 1024,
+            max_age: chrono::Duration::hours(24),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SendOutcome {
+    Sent(SendReceipt),
+
+    /// Kept in the spool to be sent later, under the given row id.
+    Spooled(i64),
+}
+
+/// Backlog and counters since the spool was opened.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct SpoolStats {
+    /// Messages waiting to be sent.
+    pub backlog: usize,
+
+    pub backlog_bytes: usize,
+
+    pub oldest_spooled_at: Option<DateTime<Utc>>,
+
+    /// Messages the service refused for good, kept in the spool for an operator.
+    pub failed: usize,
+
+    pub spooled: u64,
+
+    /// Spooled messages sent later.
+    pub forwarded: u64,
+
+    /// Spooled messages dropped for being older than `max_age`.
+    pub expired: u64,
+
+    /// Sends of spooled messages that failed and will be tried again.
+    pub failed_attempts: u64,
+}
+
+struct SpooledRow {
+    id: i64,
+    spooled_at: DateTime<Utc>,
+    message: Result<Message<BrokerSendProperties>, SpoolError>,
+}
+
+impl SpooledRow {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<SpooledRow> {
+        Ok(SpooledRow {
+            id: row.get(0)?,
+            spooled_at: row.get(5)?,
+            message: stored_message::read_message(row, 1)?.map_err(SpoolError::from),
+        })
+    }
+}
+
+/// Sends messages through a transport, keeping the ones that cannot be sent right now in
+/// a local SQLite spool and sending them later in the order they came in.
+///
+/// Once something is spooled, later messages are spooled behind it until the backlog is
+/// sent, so the queue sees messages in the order they were given. Each message gets an
+/// id before the first attempt, so with duplicate detection on the queue a send whose
+/// response was lost is not enqueued twice.
+pub struct StoreAndForward<'a> {
+    conn: Connection,
+    transport: &'a dyn QueueTransport,
+    limits: SpoolLimits,
+    retry_interval: Duration,
+    spooled: Cell<u64>,
+    forwarded: Cell<u64>,
+    expired: Cell<u64>,
+    failed_attempts: Cell<u64>,
+}
+
+impl<'a> StoreAndForward<'a> {
+    pub fn open(path: impl AsRef<Path>, transport: &'a dyn QueueTransport) -> Result<Self, SpoolError> {
+        StoreAndForward::new(Connection::open(path)?, transport)
+    }
+
+    pub fn new(conn: Connection, transport: &'a dyn QueueTransport) -> Result<Self, SpoolError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS spool (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id TEXT NOT NULL,
+                content_type TEXT NOT NULL,
+                body BLOB NOT NULL,
+                properties TEXT NOT NULL,
+                user_properties TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                last_error TEXT,
+                spooled_at TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS spool_status ON spool (status, id);")?;
+
+        Ok(StoreAndForward {
+            conn,
+            transport,
+            limits: SpoolLimits::default(),
+            retry_interval: Duration::from_secs(5),
+            spooled: Cell::new(0),
+            forwarded: Cell::new(0),
+            expired: Cell::new(0),
+            failed_attempts: Cell::new(0),
+        })
+    }
+
+    pub fn with_limits(mut self, limits: SpoolLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// How long `run` waits before trying the backlog again after a failure.
+    pub fn with_retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    pub fn connection(self: &Self) -> &Connection {
+        &self.conn
+    }
+
+    /// Sends the message, or spools it if the backlog is not empty or the send failed in
+    /// a way that may succeed later. Fails if the spool is full or the service refused
+    /// the message for good.
+    pub async fn send(self: &Self, message: &Message<BrokerSendProperties>) -> Result<SendOutcome, SpoolError> {
+        let mut message = message.clone();
+        message.properties.message_id.get_or_insert_with(|| Uuid::new_v4().to_string());
+
+        if self.backlog()? == 0 {
+            match self.transport.send(&message).await {
+                Ok(receipt) => return Ok(SendOutcome::Sent(receipt)),
+                Err(e) if is_transient(&e) => {
+                    println!("[{}] Send failed, spooling: {}", Local::now(), e);
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(SendOutcome::Spooled(self.spool(&message)?))
+    }
+
+    /// Sends the backlog in order until it is empty or a send fails. Returns the number
+    /// of messages sent.
+    pub async fn forward(self: &Self) -> Result<usize, SpoolError> {
+        let mut forwarded = 0;
+        let cutoff = Utc::now() - self.limits.max_age;
+
+        while let Some(row) = self.next_row()? {
+            if row.spooled_at < cutoff {
+                println!("[{}] Dropping spooled message {}, spooled at {}.", Local::now(), row.id, row.spooled_at);
+                self.conn.execute("DELETE FROM spool WHERE id = ?1", params![row.id])?;
+                self.expired.set(self.expired.get() + 1);
+                continue;
+            }
+
+            let message = match row.message {
+                Ok(message) => message,
+                Err(e) => {
+                    self.mark_failed(row.id, &e.to_string())?;
+                    continue;
+                }
+            };
+
+            match self.transport.send(&message).await {
+                Ok(_) => {
+                    self.conn.execute("DELETE FROM spool WHERE id = ?1", params![row.id])?;
+                    self.forwarded.set(self.forwarded.get() + 1);
+                    forwarded += 1;
+                },
+                Err(e) if is_transient(&e) => {
+                    self.failed_attempts.set(self.failed_attempts.get() + 1);
+                    self.conn.execute("UPDATE spool SET last_error = ?1 WHERE id = ?2", params![e.to_string(), row.id])?;
+                    return Ok(forwarded);
+                },
+                Err(e) => {
+                    println!("[{}] Spooled message {} was refused: {}", Local::now(), row.id, e);
+                    self.mark_failed(row.id, &e.to_string())?;
+                },
+            }
+        }
+        Ok(forwarded)
+    }
+
+    /// Forwards the backlog until shutdown is requested, then tries once more to send
+    /// what is left. Whatever still cannot be sent stays in the spool for the next run.
+    pub async fn run(self: &Self, shutdown: &Shutdown) -> Result<(), SpoolError> {
+        while !shutdown.is_requested() {
+            let forwarded = self.forward().await?;
+            if forwarded > 0 {
+                println!("[{}] Forwarded {} spooled messages.", Local::now(), forwarded);
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(self.retry_interval) => {},
+                _ = shutdown.requested() => {},
+            }
+        }
+
+        self.flush().await?;
+        Ok(())
+    }
+
+    /// Tries once to send the whole backlog. Returns the number of messages left.
+    pub async fn flush(self: &Self) -> Result<usize, SpoolError> {
+        let forwarded = self.forward().await?;
+        let left = self.backlog()?;
+        println!("[{}] Flushed spool: {} sent, {} left.", Local::now(), forwarded, left);
+        Ok(left)
+    }
+
+    pub fn stats(self: &Self) -> Result<SpoolStats, SpoolError> {
+        let (backlog, backlog_bytes, oldest_spooled_at): (i64, i64, Option<DateTime<Utc>>) = self.conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0), MIN(spooled_at) FROM spool WHERE status = 'pending'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        let failed: i64 = self.conn.query_row("SELECT COUNT(*) FROM spool WHERE status = 'failed'", [], |row| row.get(0))?;
+
+        Ok(SpoolStats {
+            backlog: backlog as usize,
+            backlog_bytes: backlog_bytes as usize,
+            oldest_spooled_at,
+            failed: failed as usize,
+            spooled: self.spooled.get(),
+            forwarded: self.forwarded.get(),
+            expired: self.expired.get(),
+            failed_attempts: self.failed_attempts.get(),
+        })
+    }
+
+    fn backlog(self: &Self) -> Result<usize, SpoolError> {
+        let count: i64 = self.conn.query_row("SELECT COUNT(*) FROM spool WHERE status = 'pending'", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    fn spool(self: &Self, message: &Message<BrokerSendProperties>) -> Result<i64, SpoolError> {
+        let size = message_size(message)?;
+        let stats = self.stats()?;
+        if stats.backlog + 1 > self.limits.max_messages {
+            return Err(SpoolError::Full(format!("{} messages spooled", stats.backlog)));
+        }
+        if stats.backlog_bytes + size > self.limits.max_bytes {
+            return Err(SpoolError::Full(format!("{} bytes spooled, the message is {} bytes", stats.backlog_bytes, size)));
+        }
+
+        let (properties, user_properties) = stored_message::property_columns(&message.properties, &message.user_properties)?;
+        self.conn.execute(
+            "INSERT INTO spool (message_id, content_type, body, properties, user_properties, size, spooled_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                message.properties.message_id,
+                message.content_type,
+                message.content,
+                properties,
+                user_properties,
+                size as i64,
+                Utc::now(),
+            ])?;
+        self.spooled.set(self.spooled.get() + 1);
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn next_row(self: &Self) -> Result<Option<SpooledRow>, SpoolError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, content_type, body, properties, user_properties, spooled_at FROM spool
+             WHERE status = 'pending' ORDER BY id LIMIT 1")?;
+        let mut rows = stmt.query_map([], SpooledRow::from_row)?;
+        Ok(rows.next().transpose()?)
+    }
+
+    fn mark_failed(self: &Self, id: i64, error: &str) -> Result<(), SpoolError> {
+        self.conn.execute("UPDATE spool SET status = 'failed', last_error = ?1 WHERE id = ?2", params![error, id])?;
+        Ok(())
+    }
+}
+
+// Failures worth spooling for: the send may succeed later, including once AAD is
+// reachable again.
+fn is_transient(error: &AzureServiceBusError) -> bool {
+    match error {
+        AzureServiceBusError::AuthenticationError(_) => true,
+        AzureServiceBusError::RequestError(status) if status == "401" => true,
+        _ => SendRetryPolicy::is_retryable(error),
+    }
+}
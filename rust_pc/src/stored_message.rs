@@ -0,0 +1,30 @@
+use std::collections::BTreeMap;
+
+use rusqlite::Row;
+
+use crate::mazure::sbclient::{BrokerSendProperties, Message};
+
+// Messages as the outbox and the spool keep them in SQLite: four columns holding the
+// content type, the body, and the broker and user properties as JSON.
+
+/// The broker and user properties columns of a message.
+pub(crate) fn property_columns(properties: &BrokerSendProperties, user_properties: &BTreeMap<String, String>) -> serde_json::Result<(String, String)> {
+    Ok((serde_json::to_string(properties)?, serde_json::to_string(user_properties)?))
+}
+
+/// Reads the message in the four columns starting at `first`. Properties that do not
+/// parse come back as the inner error, so one bad row does not fail the whole query.
+pub(crate) fn read_message(row: &Row<'_>, first: usize) -> rusqlite::Result<serde_json::Result<Message<BrokerSendProperties>>> {
+    let content_type: String = row.get(first)?;
+    let content: Vec<u8> = row.get(first + 1)?;
+    let properties: String = row.get(first + 2)?;
+    let user_properties: String = row.get(first + 3)?;
+
+    Ok(decode(content_type, content, &properties, &user_properties))
+}
+
+fn decode(content_type: String, content: Vec<u8>, properties: &str, user_properties: &str) -> serde_json::Result<Message<BrokerSendProperties>> {
+    let properties: BrokerSendProperties = serde_json::from_str(properties)?;
+    let user_properties: BTreeMap<String, String> = serde_json::from_str(user_properties)?;
+    Ok(Message { properties, content, content_type, user_properties })
+}
@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use chrono::Local;
+use futures::{Sink, Stream};
+
+use crate::mazure::sbclient::{AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message};
+use crate::mazure::sending::SendReceipt;
+use crate::received::{ReceivedMessage, UnsettledAction};
+use crate::transport::QueueTransport;
+
+type Receiving<'a> = Pin<Box<dyn Future<Output = Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError>> + 'a>>;
+type Sending<'a> = Pin<Box<dyn Future<Output = Result<SendReceipt, AzureServiceBusError>> + 'a>>;
+
+/// Receives messages with a lock for as long as it is polled. When the queue is empty
+/// it asks again every `poll_interval`, so the stream only ends when it is dropped,
+/// e.g. by `take_until(shutdown.requested())`. A failed receive is passed on as an
+/// error item and retried after `poll_interval`.
+///
+/// Nothing is received while the stream is not polled. With a prefetch of N, up to N
+/// messages are received ahead of the one being handed out. They are locked while
+/// they wait, so keep N small enough for them to be handled within the lock duration.
+///
+/// Messages dropped without being settled are dealt with by the stream's
+/// `UnsettledAction`. Locks handed to `DroppedLocks` are abandoned before the next
+/// receive and by `close`.
+pub struct MessageStream<'a> {
+    transport: &'a dyn QueueTransport,
+    prefetch: usize,
+    poll_interval: Duration,
+    unsettled: UnsettledAction,
+    buffer: VecDeque<Message<BrokerReceiveProperties>>,
+    receiving: Option<Receiving<'a>>,
+    error: Option<AzureServiceBusError>,
+}
+
+impl<'a> MessageStream<'a> {
+    pub fn new(transport: &'a dyn QueueTransport) -> Self {
+        MessageStream {
+            transport,
+            prefetch: 0,
+            poll_interval: Duration::from_secs(1),
+            unsettled: UnsettledAction::Warn,
+            buffer: VecDeque::new(),
+            receiving: None,
+            error: None,
+        }
+    }
+
+    pub fn with_prefetch(self: Self, prefetch: usize) -> Self {
+        MessageStream { prefetch, ..self }
+    }
+
+    pub fn with_poll_interval(self: Self, poll_interval: Duration) -> Self {
+        MessageStream { poll_interval, ..self }
+    }
+
+    pub fn with_unsettled_action(self: Self, unsettled: UnsettledAction) -> Self {
+        MessageStream { unsettled, ..self }
+    }
+
+    /// Waits for a receive in progress and abandons every message received ahead or
+    /// dropped unsettled, so they are delivered again straight away rather than when
+    /// their locks expire. A failure does not stop the rest from being abandoned, and
+    /// the first one is returned.
+    pub async fn close(mut self: Self) -> Result<(), AzureServiceBusError> {
+        if let UnsettledAction::Abandon(dropped) = &self.unsettled {
+            dropped.abandon_all(self.transport).await;
+        }
+
+        let mut first_error = None;
+        if let Some(receiving) = self.receiving.take() {
+            match receiving.await {
+                Ok(Some(message)) => self.buffer.push_back(message),
+                Ok(None) => {},
+                Err(e) => first_error = Some(e),
+            }
+        }
+
+        for message in self.buffer.drain(..) {
+            if let Err(e) = self.transport.abandon(&message.properties).await {
+                println!("[{}] Could not abandon prefetched message {:?}: {}", Local::now(), message.properties.message_id, e);
+                first_error.get_or_insert(e);
+            }
+        }
+
+        match first_error {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+
+    fn receive_after(self: &Self, delay: Option<Duration>) -> Receiving<'a> {
+        let transport = self.transport;
+        let unsettled = self.unsettled.clone();
+        Box::pin(async move {
+            if let UnsettledAction::Abandon(dropped) = unsettled {
+                dropped.abandon_all(transport).await;
+            }
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            transport.receive().await
+        })
+    }
+
+    // Receives until `wanted` messages are buffered or a receive is waiting.
+    fn fill(self: &mut Self, cx: &mut Context<'_>, wanted: usize) -> Result<(), AzureServiceBusError> {
+        loop {
+            if self.receiving.is_none() {
+                if self.buffer.len() >= wanted {
+                    return Ok(());
+                }
+                self.receiving = Some(self.receive_after(None));
+            }
+            let receiving = match self.receiving.as_mut() {
+                Some(receiving) => receiving,
+                None => return Ok(()),
+            };
+
+            let result = match receiving.as_mut().poll(cx) {
+                Poll::Pending => return Ok(()),
+                Poll::Ready(result) => result,
+            };
+
+            self.receiving = None;
+            match result {
+                Ok(Some(message)) => self.buffer.push_back(message),
+                Ok(None) => {
+                    self.receiving = Some(self.receive_after(Some(self.poll_interval)));
+                },
+                Err(e) => {
+                    self.receiving = Some(self.receive_after(Some(self.poll_interval)));
+                    return Err(e);
+                },
+            }
+        }
+    }
+}
+
+impl<'a> Stream for MessageStream<'a> {
+    type Item = Result<ReceivedMessage<'a>, AzureServiceBusError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some(e) = this.error.take() {
+            return Poll::Ready(Some(Err(e)));
+        }
+
+        let mut next = this.buffer.pop_front();
+        let wanted = if next.is_some() { this.prefetch } else { this.prefetch + 1 };
+        if let Err(e) = this.fill(cx, wanted) {
+            if next.is_none() {
+                return Poll::Ready(Some(Err(e)));
+            }
+            // Handed out on the next poll.
+            this.error = Some(e);
+        }
+
+        if next.is_none() {
+            next = this.buffer.pop_front();
+        }
+        match next {
+            Some(message) => {
+                let received = ReceivedMessage::new(message, this.transport).with_unsettled_action(this.unsettled.clone());
+                Poll::Ready(Some(Ok(received)))
+            },
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Sends the messages it is given one at a time and in order. A failed send is returned
+/// by the next `poll_ready`, `poll_flush` or `poll_close`, and the message is not retried
+/// beyond what the transport itself does.
+pub struct MessageSink<'a> {
+    transport: &'a dyn QueueTransport,
+    sending: Option<Sending<'a>>,
+    sent: usize,
+}
+
+impl<'a> MessageSink<'a> {
+    pub fn new(transport: &'a dyn QueueTransport) -> Self {
+        MessageSink { transport, sending: None, sent: 0 }
+    }
+
+    /// How many messages were sent so far.
+    pub fn sent(self: &Self) -> usize {
+        self.sent
+    }
+
+    fn poll_sent(self: &mut Self, cx: &mut Context<'_>) -> Poll<Result<(), AzureServiceBusError>> {
+        let sending = match self.sending.as_mut() {
+            None => return Poll::Ready(Ok(())),
+            Some(sending) => sending,
+        };
+
+        let result = match sending.as_mut().poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(result) => result,
+        };
+        self.sending = None;
+        if result.is_ok() {
+            self.sent += 1;
+        }
+        Poll::Ready(result.map(|_| ()))
+    }
+}
+
+impl<'a> Sink<Message<BrokerSendProperties>> for MessageSink<'a> {
+    type Error = AzureServiceBusError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_sent(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message<BrokerSendProperties>) -> Result<(), Self::Error> {
+        let transport = self.transport;
+        self.sending = Some(Box::pin(async move { transport.send(&message).await }));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_sent(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_sent(cx)
+    }
+}
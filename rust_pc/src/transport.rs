@@ -0,0 +1,104 @@
+pub mod broker;
+pub mod memory;
+pub mod sqlite;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::mazure::sbclient::{AzureServiceBusClient, AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message};
+use crate::mazure::sending::SendReceipt;
+
+/// The queue operations producers and consumers rely on, so they can run against
+/// Service Bus or a local queue alike.
+///
+/// Settling a message whose lock expired or was settled already fails with the
+/// `RequestError("410")` the REST API answers with, and an unknown message with `"404"`.
+#[async_trait(?Send)]
+pub trait QueueTransport {
+    async fn send(&self, message: &Message<BrokerSendProperties>) -> Result<SendReceipt, AzureServiceBusError>;
+
+    /// Locks and returns the next available message, or none if there is none.
+    async fn receive(&self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError>;
+
+    /// Extends the lock and returns when it now expires, if the transport knows.
+    async fn renew_lock(&self, properties: &BrokerReceiveProperties) -> Result<Option<DateTime<Utc>>, AzureServiceBusError>;
+
+    /// Releases the lock so the message is delivered again.
+    async fn abandon(&self, properties: &BrokerReceiveProperties) -> Result<(), AzureServiceBusError>;
+
+    async fn complete(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), AzureServiceBusError>;
+
+    /// Moves the message to the dead-letter queue, recording why.
+    async fn dead_letter(&self, message: &Message<BrokerReceiveProperties>, reason: &str, description: &str) -> Result<(), AzureServiceBusError>;
+}
+
+#[async_trait(?Send)]
+impl QueueTransport for AzureServiceBusClient {
+    async fn send(&self, message: &Message<BrokerSendProperties>) -> Result<SendReceipt, AzureServiceBusError> {
+        AzureServiceBusClient::send(self, message).await
+    }
+
+    async fn receive(&self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        self.peek_lock().await
+    }
+
+    async fn renew_lock(&self, properties: &BrokerReceiveProperties) -> Result<Option<DateTime<Utc>>, AzureServiceBusError> {
+        AzureServiceBusClient::renew_lock(self, properties).await
+    }
+
+    async fn abandon(&self, properties: &BrokerReceiveProperties) -> Result<(), AzureServiceBusError> {
+        self.unlock_message(properties).await
+    }
+
+    async fn complete(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), AzureServiceBusError> {
+        self.complete_message(message).await
+    }
+
+    async fn dead_letter(&self, message: &Message<BrokerReceiveProperties>, reason: &str, description: &str) -> Result<(), AzureServiceBusError> {
+        AzureServiceBusClient::dead_letter(self, message, reason, description).await
+    }
+}
+
+// The message as a local queue stores it, with a message id and correlation id assigned
+// if it has none. Delivery properties are set when it is received.
+pub(crate) fn enqueued_message(message: &Message<BrokerSendProperties>) -> Message<BrokerReceiveProperties> {
+    let p = &message.properties;
+    let mut properties = BrokerReceiveProperties::new_empty();
+    properties.message_id = Some(p.message_id.clone().unwrap_or_else(|| Uuid::new_v4().simple().to_string()));
+    properties.correlation_id = Some(p.correlation_id.clone().unwrap_or_else(|| Uuid::new_v4().to_string()));
+    properties.session_id = p.session_id.clone();
+    properties.label = p.label.clone();
+    properties.reply_to = p.reply_to.clone();
+    properties.time_to_live = p.time_to_live;
+    properties.to = p.to.clone();
+    properties.scheduled_enqueue_time_utc = p.scheduled_enqueue_time_utc;
+    properties.reply_to_session_id = p.reply_to_session_id.clone();
+    properties.partition_key = p.partition_key.clone();
+
+    Message {
+        properties,
+        content: message.content.clone(),
+        content_type: message.content_type.clone(),
+        user_properties: message.user_properties.clone(),
+    }
+}
+
+pub(crate) fn local_receipt(message: &Message<BrokerReceiveProperties>, sent_at_utc: DateTime<Utc>) -> SendReceipt {
+    SendReceipt {
+        message_id: message.properties.message_id.clone(),
+        correlation_id: message.properties.correlation_id.clone().unwrap_or_default(),
+        scheduled_enqueue_time_utc: message.properties.scheduled_enqueue_time_utc,
+        sent_at_utc,
+        attempts: 1,
+    }
+}
+
+// The message id and lock token settling a message takes.
+pub(crate) fn lock_of(properties: &BrokerReceiveProperties) -> Result<(&str, &str), AzureServiceBusError> {
+    let message_id = properties.message_id.as_deref()
+        .ok_or_else(|| AzureServiceBusError::RequestError("No message id found in broker properties.".into()))?;
+    let lock_token = properties.lock_token.as_deref()
+        .ok_or_else(|| AzureServiceBusError::RequestError("No lock token found in broker properties.".into()))?;
+    Ok((message_id, lock_token))
+}
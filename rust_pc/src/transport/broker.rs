@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::mazure::sbclient::{
+    BrokerReceiveProperties, Message, DEAD_LETTER_DESCRIPTION_PROPERTY, DEAD_LETTER_REASON_PROPERTY
+};
+
+/// Suffix of the dead-letter sub-queue of an entity.
+pub static DEAD_LETTER_QUEUE_SUFFIX: &str = "/$DeadLetterQueue";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum BrokerError {
+    #[error("Message not found: {0}")]
+    MessageNotFound(String),
+
+    #[error("Lock lost or expired for message: {0}")]
+    LockLost(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+    pub lock_duration: Duration,
+
+    /// Deliveries after which a message is moved to the dead-letter sub-queue.
+    pub max_delivery_count: i32,
+
+    /// Time to live for messages that do not set one.
+    pub default_time_to_live: Option<Duration>,
+
+    /// Expired messages are dead-lettered instead of dropped.
+    pub dead_letter_on_expiration: bool,
+
+    /// Messages repeating a message id seen within this window are dropped.
+    pub duplicate_detection_window: Option<Duration>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            lock_duration: Duration::seconds(60),
+            max_delivery_count: 10,
+            default_time_to_live: None,
+            dead_letter_on_expiration: false,
+            duplicate_detection_window: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct StoredMessage {
+    message: Message<BrokerReceiveProperties>,
+    visible_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    // Set aside until received by its sequence number.
+    deferred: bool,
+}
+
+impl StoredMessage {
+    fn is_locked(self: &Self, now: DateTime<Utc>) -> bool {
+        match self.message.properties.locked_until_utc {
+            Some(locked_until) => self.message.properties.lock_token.is_some() && locked_until > now,
+            None => false,
+        }
+    }
+
+    fn is_available(self: &Self, now: DateTime<Utc>) -> bool {
+        self.visible_at <= now && !self.deferred && !self.is_locked(now)
+    }
+
+    fn is_expired(self: &Self, now: DateTime<Utc>) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now && !self.is_locked(now),
+            None => false,
+        }
+    }
+}
+
+/// In-memory queues with the Service Bus semantics the client relies on: peek-lock with
+/// lock expiry, delivery counts, scheduled enqueue, time to live, deferral and dead-lettering.
+///
+/// The broker keeps its own clock which can be moved forward to expire locks and messages
+/// without waiting.
+#[derive(Debug)]
+pub struct Broker {
+    config: BrokerConfig,
+    clock_offset: Duration,
+    next_sequence_number: i64,
+    entities: HashMap<String, Vec<StoredMessage>>,
+    // Message ids accepted per entity and when, for duplicate detection.
+    seen_message_ids: HashMap<String, HashMap<String, DateTime<Utc>>>,
+}
+
+impl Broker {
+    pub fn new(config: BrokerConfig) -> Self {
+        Broker {
+            config,
+            clock_offset: Duration::zero(),
+            next_sequence_number: 1,
+            entities: HashMap::new(),
+            seen_message_ids: HashMap::new(),
+        }
+    }
+
+    pub fn config(self: &Self) -> &BrokerConfig {
+        &self.config
+    }
+
+    pub fn now(self: &Self) -> DateTime<Utc> {
+        Utc::now() + self.clock_offset
+    }
+
+    pub fn advance_clock(self: &mut Self, by: Duration) {
+        self.clock_offset = self.clock_offset + by;
+    }
+
+    /// Enqueues a message. Only the settable broker properties and the message id are
+    /// taken from the input. Returns its sequence number, or none if the message was
+    /// dropped as a duplicate.
+    pub fn send(self: &mut Self, entity: &str, mut message: Message<BrokerReceiveProperties>) -> Option<i64> {
+        let now = self.now();
+
+        if let (Some(window), Some(message_id)) = (self.config.duplicate_detection_window, &message.properties.message_id) {
+            let seen = self.seen_message_ids.entry(entity.to_string()).or_default();
+            seen.retain(|_, accepted| *accepted + window > now);
+            if seen.contains_key(message_id) {
+                return None;
+            }
+            seen.insert(message_id.clone(), now);
+        }
+
+        let sequence_number = self.next_sequence_number;
+        self.next_sequence_number += 1;
+
+        let props = &mut message.properties;
+        props.message_id.get_or_insert_with(|| Uuid::new_v4().simple().to_string());
+        props.sequence_number = Some(sequence_number);
+        props.delivery_count = Some(0);
+        props.lock_token = None;
+        props.locked_until_utc = None;
+        props.state = Some("Active".into());
+
+        let visible_at = match props.scheduled_enqueue_time_utc {
+            Some(scheduled) if scheduled > now => scheduled,
+            _ => now,
+        };
+        props.enqueued_time_utc = Some(visible_at);
+
+        let time_to_live = props.time_to_live.map(Duration::seconds).or(self.config.default_time_to_live);
+        let expires_at = time_to_live.map(|ttl| visible_at + ttl);
+
+        self.entities.entry(entity.to_string()).or_default().push(StoredMessage { message, visible_at, expires_at, deferred: false });
+        Some(sequence_number)
+    }
+
+    /// Locks and returns the next available message.
+    pub fn peek_lock(self: &mut Self, entity: &str) -> Option<Message<BrokerReceiveProperties>> {
+        let now = self.now();
+        let lock_duration = self.config.lock_duration;
+
+        let index = self.next_available(entity, now)?;
+        let stored = &mut self.entities.get_mut(entity)?[index];
+        let props = &mut stored.message.properties;
+        props.delivery_count = Some(props.delivery_count.unwrap_or(0) + 1);
+        props.lock_token = Some(Uuid::new_v4().to_string());
+        props.locked_until_utc = Some(now + lock_duration);
+
+        Some(stored.message.clone())
+    }
+
+    /// Removes and returns the next available message.
+    pub fn receive_and_delete(self: &mut Self, entity: &str) -> Option<Message<BrokerReceiveProperties>> {
+        let now = self.now();
+
+        let index = self.next_available(entity, now)?;
+        let mut message = self.entities.get_mut(entity)?.remove(index).message;
+        let props = &mut message.properties;
+        props.delivery_count = Some(props.delivery_count.unwrap_or(0) + 1);
+
+        Some(message)
+    }
+
+    /// Releases the lock so the message can be delivered again right away.
+    pub fn unlock(self: &mut Self, entity: &str, message_id: &str, lock_token: &str) -> Result<(), BrokerError> {
+        let index = self.find_locked(entity, message_id, lock_token)?;
+        let props = &mut self.entities.get_mut(entity).unwrap()[index].message.properties;
+        props.lock_token = None;
+        props.locked_until_utc = None;
+        Ok(())
+    }
+
+    pub fn renew_lock(self: &mut Self, entity: &str, message_id: &str, lock_token: &str) -> Result<DateTime<Utc>, BrokerError> {
+        let locked_until = self.now() + self.config.lock_duration;
+        let index = self.find_locked(entity, message_id, lock_token)?;
+        let props = &mut self.entities.get_mut(entity).unwrap()[index].message.properties;
+        props.locked_until_utc = Some(locked_until);
+        Ok(locked_until)
+    }
+
+    pub fn complete(self: &mut Self, entity: &str, message_id: &str, lock_token: &str) -> Result<(), BrokerError> {
+        let index = self.find_locked(entity, message_id, lock_token)?;
+        self.entities.get_mut(entity).unwrap().remove(index);
+        Ok(())
+    }
+
+    pub fn dead_letter(self: &mut Self, entity: &str, message_id: &str, lock_token: &str, reason: &str, description: &str) -> Result<(), BrokerError> {
+        let index = self.find_locked(entity, message_id, lock_token)?;
+        let stored = self.entities.get_mut(entity).unwrap().remove(index);
+        self.move_to_dead_letter(entity, stored, reason, description);
+        Ok(())
+    }
+
+    /// Sets the locked message aside until it is received by its sequence number.
+    pub fn defer(self: &mut Self, entity: &str, message_id: &str, lock_token: &str) -> Result<(), BrokerError> {
+        let index = self.find_locked(entity, message_id, lock_token)?;
+        let stored = &mut self.entities.get_mut(entity).unwrap()[index];
+        stored.deferred = true;
+        stored.message.properties.lock_token = None;
+        stored.message.properties.locked_until_utc = None;
+        stored.message.properties.state = Some("Deferred".into());
+        Ok(())
+    }
+
+    /// Locks and returns a deferred message. It stays deferred until it is settled.
+    pub fn receive_deferred(self: &mut Self, entity: &str, sequence_number: i64) -> Result<Message<BrokerReceiveProperties>, BrokerError> {
+        let now = self.now();
+        let lock_duration = self.config.lock_duration;
+        self.remove_expired(entity, now);
+
+        let stored = self.entities.get_mut(entity)
+            .and_then(|messages| messages.iter_mut().find(|stored| stored.deferred && stored.message.properties.sequence_number == Some(sequence_number)))
+            .ok_or_else(|| BrokerError::MessageNotFound(sequence_number.to_string()))?;
+        if stored.is_locked(now) {
+            return Err(BrokerError::LockLost(sequence_number.to_string()));
+        }
+
+        let props = &mut stored.message.properties;
+        props.delivery_count = Some(props.delivery_count.unwrap_or(0) + 1);
+        props.lock_token = Some(Uuid::new_v4().to_string());
+        props.locked_until_utc = Some(now + lock_duration);
+        Ok(stored.message.clone())
+    }
+
+    /// Returns up to `count` messages from the sequence number on without locking them,
+    /// including locked, deferred and scheduled ones.
+    pub fn peek(self: &mut Self, entity: &str, from_sequence_number: i64, count: usize) -> Vec<Message<BrokerReceiveProperties>> {
+        let now = self.now();
+        self.remove_expired(entity, now);
+
+        let mut messages: Vec<Message<BrokerReceiveProperties>> = self.messages(entity).into_iter()
+            .filter(|message| message.properties.sequence_number.unwrap_or(0) >= from_sequence_number)
+            .collect();
+        messages.sort_by_key(|message| message.properties.sequence_number);
+        messages.truncate(count);
+        messages
+    }
+
+    /// Removes a scheduled message that is not enqueued yet.
+    pub fn cancel_scheduled(self: &mut Self, entity: &str, sequence_number: i64) -> Result<(), BrokerError> {
+        let now = self.now();
+        let messages = self.entities.get_mut(entity)
+            .ok_or_else(|| BrokerError::MessageNotFound(sequence_number.to_string()))?;
+        let index = messages.iter()
+            .position(|stored| stored.visible_at > now && stored.message.properties.sequence_number == Some(sequence_number))
+            .ok_or_else(|| BrokerError::MessageNotFound(sequence_number.to_string()))?;
+        messages.remove(index);
+        Ok(())
+    }
+
+    /// Number of messages in the entity, including locked and scheduled ones.
+    pub fn message_count(self: &Self, entity: &str) -> usize {
+        self.entities.get(entity).map(|messages| messages.len()).unwrap_or(0)
+    }
+
+    /// A snapshot of the messages in the entity in sequence order.
+    pub fn messages(self: &Self, entity: &str) -> Vec<Message<BrokerReceiveProperties>> {
+        match self.entities.get(entity) {
+            None => vec![],
+            Some(messages) => messages.iter().map(|stored| stored.message.clone()).collect(),
+        }
+    }
+
+    // Finds the next deliverable message, expiring and dead-lettering along the way.
+    fn next_available(self: &mut Self, entity: &str, now: DateTime<Utc>) -> Option<usize> {
+        self.remove_expired(entity, now);
+        let is_dead_letter_queue = entity.ends_with(DEAD_LETTER_QUEUE_SUFFIX);
+
+        loop {
+            let messages = self.entities.get_mut(entity)?;
+            let index = messages.iter().position(|stored| stored.is_available(now))?;
+
+            let delivery_count = messages[index].message.properties.delivery_count.unwrap_or(0);
+            if is_dead_letter_queue || delivery_count < self.config.max_delivery_count {
+                return Some(index);
+            }
+
+            let stored = messages.remove(index);
+            let description = format!("Message was delivered {} times.", delivery_count);
+            self.move_to_dead_letter(entity, stored, "MaxDeliveryCountExceeded", &description);
+        }
+    }
+
+    fn remove_expired(self: &mut Self, entity: &str, now: DateTime<Utc>) {
+        if entity.ends_with(DEAD_LETTER_QUEUE_SUFFIX) {
+            return;
+        }
+
+        let expired: Vec<StoredMessage> = match self.entities.get_mut(entity) {
+            None => return,
+            Some(messages) => {
+                let (expired, remaining) = messages.drain(..).partition(|stored| stored.is_expired(now));
+                *messages = remaining;
+                expired
+            }
+        };
+
+        if self.config.dead_letter_on_expiration {
+            for stored in expired {
+                self.move_to_dead_letter(entity, stored, "TTLExpiredException", "The message expired and was dead lettered.");
+            }
+        }
+    }
+
+    fn move_to_dead_letter(self: &mut Self, entity: &str, mut stored: StoredMessage, reason: &str, description: &str) {
+        let now = self.now();
+
+        stored.message.properties.lock_token = None;
+        stored.message.properties.locked_until_utc = None;
+        stored.message.set_user_property(DEAD_LETTER_REASON_PROPERTY, reason);
+        stored.message.set_user_property(DEAD_LETTER_DESCRIPTION_PROPERTY, description);
+        stored.visible_at = now;
+        stored.expires_at = None;
+        stored.deferred = false;
+
+        let dead_letter_entity = format!("{}{}", entity, DEAD_LETTER_QUEUE_SUFFIX);
+        self.entities.entry(dead_letter_entity).or_default().push(stored);
+    }
+
+    fn find_locked(self: &Self, entity: &str, message_id: &str, lock_token: &str) -> Result<usize, BrokerError> {
+        let now = self.now();
+        let messages = self.entities.get(entity)
+            .ok_or_else(|| BrokerError::MessageNotFound(message_id.to_string()))?;
+
+        let has_id = |stored: &StoredMessage| stored.message.properties.message_id.as_deref() == Some(message_id);
+        let has_lock = |stored: &StoredMessage| stored.message.properties.lock_token.as_deref() == Some(lock_token);
+
+        match messages.iter().position(|stored| has_id(stored) && has_lock(stored)) {
+            Some(index) if messages[index].is_locked(now) => Ok(index),
+            Some(_) => Err(BrokerError::LockLost(message_id.to_string())),
+            None if messages.iter().any(has_id) => Err(BrokerError::LockLost(message_id.to_string())),
+            None => Err(BrokerError::MessageNotFound(message_id.to_string())),
+        }
+    }
+}
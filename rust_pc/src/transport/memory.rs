@@ -0,0 +1,87 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::transport::broker::{Broker, BrokerConfig, BrokerError, DEAD_LETTER_QUEUE_SUFFIX};
+use crate::mazure::sbclient::{AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message};
+use crate::mazure::sending::SendReceipt;
+use crate::transport::{QueueTransport, enqueued_message, local_receipt, lock_of};
+
+// Broker errors map onto the HTTP statuses the REST API answers with.
+impl From<BrokerError> for AzureServiceBusError {
+    fn from(e: BrokerError) -> Self {
+        match e {
+            BrokerError::MessageNotFound(_) => AzureServiceBusError::RequestError("404".into()),
+            BrokerError::LockLost(_) => AzureServiceBusError::RequestError("410".into()),
+        }
+    }
+}
+
+/// A queue on an in-memory broker, with the lock, visibility and dead-letter semantics
+/// of the emulator. Nothing survives the process.
+#[derive(Debug, Clone)]
+pub struct MemoryQueue {
+    broker: Arc<Mutex<Broker>>,
+    entity: String,
+}
+
+impl MemoryQueue {
+    pub fn new(entity: impl Into<String>, config: BrokerConfig) -> Self {
+        MemoryQueue { broker: Arc::new(Mutex::new(Broker::new(config))), entity: entity.into() }
+    }
+
+    /// Another queue on the same broker.
+    pub fn queue(self: &Self, entity: impl Into<String>) -> MemoryQueue {
+        MemoryQueue { broker: self.broker.clone(), entity: entity.into() }
+    }
+
+    pub fn dead_letter_queue(self: &Self) -> MemoryQueue {
+        self.queue(format!("{}{}", self.entity, DEAD_LETTER_QUEUE_SUFFIX))
+    }
+
+    pub fn entity(self: &Self) -> &str {
+        &self.entity
+    }
+
+    /// Runs the closure with the broker locked, e.g. to inspect it or move its clock.
+    pub fn with_broker<R>(self: &Self, f: impl FnOnce(&mut Broker) -> R) -> R {
+        let mut broker = self.broker.lock().unwrap();
+        f(&mut broker)
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueTransport for MemoryQueue {
+    async fn send(&self, message: &Message<BrokerSendProperties>) -> Result<SendReceipt, AzureServiceBusError> {
+        let message = enqueued_message(message);
+        let mut broker = self.broker.lock().unwrap();
+        let receipt = local_receipt(&message, broker.now());
+        broker.send(&self.entity, message);
+        Ok(receipt)
+    }
+
+    async fn receive(&self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        Ok(self.broker.lock().unwrap().peek_lock(&self.entity))
+    }
+
+    async fn renew_lock(&self, properties: &BrokerReceiveProperties) -> Result<Option<DateTime<Utc>>, AzureServiceBusError> {
+        let (message_id, lock_token) = lock_of(properties)?;
+        Ok(Some(self.broker.lock().unwrap().renew_lock(&self.entity, message_id, lock_token)?))
+    }
+
+    async fn abandon(&self, properties: &BrokerReceiveProperties) -> Result<(), AzureServiceBusError> {
+        let (message_id, lock_token) = lock_of(properties)?;
+        Ok(self.broker.lock().unwrap().unlock(&self.entity, message_id, lock_token)?)
+    }
+
+    async fn complete(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), AzureServiceBusError> {
+        let (message_id, lock_token) = lock_of(&message.properties)?;
+        Ok(self.broker.lock().unwrap().complete(&self.entity, message_id, lock_token)?)
+    }
+
+    async fn dead_letter(&self, message: &Message<BrokerReceiveProperties>, reason: &str, description: &str) -> Result<(), AzureServiceBusError> {
+        let (message_id, lock_token) = lock_of(&message.properties)?;
+        Ok(self.broker.lock().unwrap().dead_letter(&self.entity, message_id, lock_token, reason, description)?)
+    }
+}
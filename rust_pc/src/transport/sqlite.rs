@@ -0,0 +1,374 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+use uuid::Uuid;
+
+use crate::transport::broker::{BrokerConfig, BrokerError, DEAD_LETTER_QUEUE_SUFFIX};
+use crate::mazure::sbclient::{
+    AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message, DEAD_LETTER_DESCRIPTION_PROPERTY, DEAD_LETTER_REASON_PROPERTY
+};
+use crate::mazure::sending::SendReceipt;
+use crate::transport::{QueueTransport, enqueued_message, local_receipt, lock_of};
+
+impl From<rusqlite::Error> for AzureServiceBusError {
+    fn from(e: rusqlite::Error) -> Self {
+        AzureServiceBusError::ServiceError(format!("Local queue error: {}", e))
+    }
+}
+
+// How often a waiting receive looks for a message again.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+static ROW_COLUMNS: &str = "sequence_number, properties, content, content_type, user_properties, enqueued_at, delivery_count";
+
+// A message as stored, before it is locked for delivery.
+struct StoredRow {
+    sequence_number: i64,
+    properties: String,
+    content: Vec<u8>,
+    content_type: String,
+    user_properties: String,
+    enqueued_at: DateTime<Utc>,
+    delivery_count: i32,
+}
+
+impl StoredRow {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<StoredRow> {
+        Ok(StoredRow {
+            sequence_number: row.get(0)?,
+            properties: row.get(1)?,
+            content: row.get(2)?,
+            content_type: row.get(3)?,
+            user_properties: row.get(4)?,
+            enqueued_at: row.get(5)?,
+            delivery_count: row.get(6)?,
+        })
+    }
+}
+
+/// A durable queue in a SQLite database with the semantics of the emulator's broker:
+/// peek-lock with lock expiry, delivery counts, scheduled enqueue, time to live,
+/// duplicate detection and a dead-letter sub-queue. Queues sharing the database file
+/// share their messages, so producers and consumers in different processes can use it
+/// like a Service Bus queue.
+#[derive(Debug, Clone)]
+pub struct SqliteQueue {
+    conn: Arc<Mutex<Connection>>,
+    entity: String,
+    config: BrokerConfig,
+    receive_timeout: std::time::Duration,
+}
+
+impl SqliteQueue {
+    pub fn open(path: impl AsRef<Path>, entity: impl Into<String>) -> Result<Self, AzureServiceBusError> {
+        SqliteQueue::new(Connection::open(path)?, entity)
+    }
+
+    pub fn new(conn: Connection, entity: impl Into<String>) -> Result<Self, AzureServiceBusError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS queue_messages (
+                sequence_number INTEGER PRIMARY KEY AUTOINCREMENT,
+                entity TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                content_type TEXT NOT NULL,
+                content BLOB NOT NULL,
+                properties TEXT NOT NULL,
+                user_properties TEXT NOT NULL,
+                enqueued_at TEXT NOT NULL,
+                visible_at TEXT NOT NULL,
+                delivery_count INTEGER NOT NULL DEFAULT 0,
+                lock_token TEXT,
+                locked_until TEXT,
+                expires_at TEXT
+            );
+            CREATE INDEX IF NOT EXISTS queue_messages_entity ON queue_messages (entity, sequence_number);
+            CREATE TABLE IF NOT EXISTS queue_message_ids (
+                entity TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                accepted_at TEXT NOT NULL,
+                PRIMARY KEY (entity, message_id)
+            );")?;
+
+        Ok(SqliteQueue {
+            conn: Arc::new(Mutex::new(conn)),
+            entity: entity.into(),
+            config: BrokerConfig::default(),
+            receive_timeout: std::time::Duration::ZERO,
+        })
+    }
+
+    pub fn with_config(mut self, config: BrokerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// How long `receive` waits for a message when none is available, like a Service Bus
+    /// receive does. Without it an empty queue answers right away.
+    pub fn with_receive_timeout(mut self, receive_timeout: std::time::Duration) -> Self {
+        self.receive_timeout = receive_timeout;
+        self
+    }
+
+    /// Another queue in the same database, with the same configuration.
+    pub fn queue(self: &Self, entity: impl Into<String>) -> SqliteQueue {
+        SqliteQueue { conn: self.conn.clone(), entity: entity.into(), config: self.config.clone(), receive_timeout: self.receive_timeout }
+    }
+
+    pub fn dead_letter_queue(self: &Self) -> SqliteQueue {
+        self.queue(format!("{}{}", self.entity, DEAD_LETTER_QUEUE_SUFFIX))
+    }
+
+    pub fn entity(self: &Self) -> &str {
+        &self.entity
+    }
+
+    /// Number of messages in the queue, including locked and scheduled ones.
+    pub fn message_count(self: &Self) -> Result<usize, AzureServiceBusError> {
+        let count: i64 = self.conn.lock().unwrap()
+            .query_row("SELECT COUNT(*) FROM queue_messages WHERE entity = ?1", params![self.entity], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    // Locks the next available message, if there is one.
+    fn try_receive(self: &Self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        let now = Utc::now();
+        let mut conn = self.conn.lock().unwrap();
+        // Taking the write lock up front keeps other processes from locking the same message.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        self.remove_expired(&tx, now)?;
+
+        let row = loop {
+            let row = tx.query_row(
+                &format!("SELECT {} FROM queue_messages
+                          WHERE entity = ?1 AND visible_at <= ?2 AND (locked_until IS NULL OR locked_until <= ?2)
+                          ORDER BY sequence_number LIMIT 1", ROW_COLUMNS),
+                params![self.entity, now],
+                StoredRow::from_row).optional()?;
+
+            match row {
+                None => {
+                    tx.commit()?;
+                    return Ok(None);
+                },
+                Some(row) if !self.is_dead_letter_queue() && row.delivery_count >= self.config.max_delivery_count => {
+                    let description = format!("Message was delivered {} times.", row.delivery_count);
+                    self.move_to_dead_letter(&tx, row.sequence_number, "MaxDeliveryCountExceeded", &description, now)?;
+                },
+                Some(row) => break row,
+            }
+        };
+
+        let lock_token = Uuid::new_v4().to_string();
+        let locked_until = now + self.config.lock_duration;
+        tx.execute(
+            "UPDATE queue_messages SET delivery_count = delivery_count + 1, lock_token = ?1, locked_until = ?2 WHERE sequence_number = ?3",
+            params![lock_token, locked_until, row.sequence_number])?;
+        tx.commit()?;
+
+        let mut properties: BrokerReceiveProperties = serde_json::from_str(&row.properties)?;
+        properties.sequence_number = Some(row.sequence_number);
+        properties.enqueued_time_utc = Some(row.enqueued_at);
+        properties.delivery_count = Some(row.delivery_count + 1);
+        properties.lock_token = Some(lock_token);
+        properties.locked_until_utc = Some(locked_until);
+        properties.state = Some("Active".into());
+
+        Ok(Some(Message {
+            properties,
+            content: row.content,
+            content_type: row.content_type,
+            user_properties: serde_json::from_str(&row.user_properties)?,
+        }))
+    }
+
+    // Finds the message holding the lock. Like the broker, a message that is there under
+    // another or an expired lock has lost its lock.
+    fn find_locked(self: &Self, conn: &Connection, properties: &BrokerReceiveProperties, now: DateTime<Utc>) -> Result<i64, AzureServiceBusError> {
+        let (message_id, lock_token) = lock_of(properties)?;
+
+        let locked: Option<(i64, Option<DateTime<Utc>>)> = conn.query_row(
+            "SELECT sequence_number, locked_until FROM queue_messages WHERE entity = ?1 AND message_id = ?2 AND lock_token = ?3",
+            params![self.entity, message_id, lock_token],
+            |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+
+        match locked {
+            Some((sequence_number, Some(locked_until))) if locked_until > now => Ok(sequence_number),
+            Some(_) => Err(BrokerError::LockLost(message_id.to_string()).into()),
+            None => {
+                let count: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM queue_messages WHERE entity = ?1 AND message_id = ?2",
+                    params![self.entity, message_id],
+                    |row| row.get(0))?;
+                if count > 0 {
+                    return Err(BrokerError::LockLost(message_id.to_string()).into());
+                }
+                Err(BrokerError::MessageNotFound(message_id.to_string()).into())
+            }
+        }
+    }
+
+    fn is_dead_letter_queue(self: &Self) -> bool {
+        self.entity.ends_with(DEAD_LETTER_QUEUE_SUFFIX)
+    }
+
+    // Whether a message with the id was accepted within the duplicate detection window.
+    // Remembers the id if not.
+    fn is_duplicate(self: &Self, conn: &Connection, message_id: &str, now: DateTime<Utc>) -> Result<bool, AzureServiceBusError> {
+        let window = match self.config.duplicate_detection_window {
+            None => return Ok(false),
+            Some(window) => window,
+        };
+
+        conn.execute(
+            "DELETE FROM queue_message_ids WHERE entity = ?1 AND accepted_at <= ?2",
+            params![self.entity, now - window])?;
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO queue_message_ids (entity, message_id, accepted_at) VALUES (?1, ?2, ?3)",
+            params![self.entity, message_id, now])?;
+        Ok(inserted == 0)
+    }
+
+    // Drops expired messages that are not locked, or dead-letters them if configured to.
+    fn remove_expired(self: &Self, conn: &Connection, now: DateTime<Utc>) -> Result<(), AzureServiceBusError> {
+        if self.is_dead_letter_queue() {
+            return Ok(());
+        }
+
+        let expired = "entity = ?1 AND expires_at <= ?2 AND (locked_until IS NULL OR locked_until <= ?2)";
+        if !self.config.dead_letter_on_expiration {
+            conn.execute(&format!("DELETE FROM queue_messages WHERE {}", expired), params![self.entity, now])?;
+            return Ok(());
+        }
+
+        let mut stmt = conn.prepare(&format!("SELECT sequence_number FROM queue_messages WHERE {}", expired))?;
+        let sequence_numbers = stmt.query_map(params![self.entity, now], |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for sequence_number in sequence_numbers {
+            self.move_to_dead_letter(conn, sequence_number, "TTLExpiredException", "The message expired and was dead lettered.", now)?;
+        }
+        Ok(())
+    }
+
+    fn move_to_dead_letter(self: &Self, conn: &Connection, sequence_number: i64, reason: &str, description: &str, now: DateTime<Utc>) -> Result<(), AzureServiceBusError> {
+        let user_properties: String = conn.query_row(
+            "SELECT user_properties FROM queue_messages WHERE sequence_number = ?1",
+            params![sequence_number],
+            |row| row.get(0))?;
+        let mut user_properties: BTreeMap<String, String> = serde_json::from_str(&user_properties)?;
+        user_properties.insert(DEAD_LETTER_REASON_PROPERTY.to_ascii_lowercase(), reason.to_string());
+        user_properties.insert(DEAD_LETTER_DESCRIPTION_PROPERTY.to_ascii_lowercase(), description.to_string());
+
+        conn.execute(
+            "UPDATE queue_messages
+             SET entity = ?1, user_properties = ?2, visible_at = ?3, lock_token = NULL, locked_until = NULL, expires_at = NULL
+             WHERE sequence_number = ?4",
+            params![
+                format!("{}{}", self.entity, DEAD_LETTER_QUEUE_SUFFIX),
+                serde_json::to_string(&user_properties)?,
+                now,
+                sequence_number,
+            ])?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueTransport for SqliteQueue {
+    async fn send(&self, message: &Message<BrokerSendProperties>) -> Result<SendReceipt, AzureServiceBusError> {
+        let message = enqueued_message(message);
+        let now = Utc::now();
+        let visible_at = match message.properties.scheduled_enqueue_time_utc {
+            Some(scheduled) if scheduled > now => scheduled,
+            _ => now,
+        };
+        let time_to_live = message.properties.time_to_live.map(Duration::seconds).or(self.config.default_time_to_live);
+        let expires_at = time_to_live.map(|ttl| visible_at + ttl);
+        let message_id = message.properties.message_id.clone().unwrap_or_default();
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Like Service Bus, a duplicate is dropped without an error.
+        if self.is_duplicate(&tx, &message_id, now)? {
+            return Ok(local_receipt(&message, now));
+        }
+
+        tx.execute(
+            "INSERT INTO queue_messages (entity, message_id, content_type, content, properties, user_properties, enqueued_at, visible_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, ?8)",
+            params![
+                self.entity,
+                message_id,
+                message.content_type,
+                message.content,
+                message.properties.to_json()?,
+                serde_json::to_string(&message.user_properties)?,
+                visible_at,
+                expires_at,
+            ])?;
+        tx.commit()?;
+        Ok(local_receipt(&message, now))
+    }
+
+    async fn receive(&self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        let deadline = tokio::time::Instant::now() + self.receive_timeout;
+        loop {
+            if let Some(message) = self.try_receive()? {
+                return Ok(Some(message));
+            }
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    async fn renew_lock(&self, properties: &BrokerReceiveProperties) -> Result<Option<DateTime<Utc>>, AzureServiceBusError> {
+        let now = Utc::now();
+        let locked_until = now + self.config.lock_duration;
+        let mut conn = self.conn.lock().unwrap();
+        // The lock is checked and changed in one write transaction, so another process
+        // cannot take the message over in between.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let sequence_number = self.find_locked(&tx, properties, now)?;
+        tx.execute(
+            "UPDATE queue_messages SET locked_until = ?1 WHERE sequence_number = ?2",
+            params![locked_until, sequence_number])?;
+        tx.commit()?;
+        Ok(Some(locked_until))
+    }
+
+    async fn abandon(&self, properties: &BrokerReceiveProperties) -> Result<(), AzureServiceBusError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let sequence_number = self.find_locked(&tx, properties, Utc::now())?;
+        tx.execute(
+            "UPDATE queue_messages SET lock_token = NULL, locked_until = NULL WHERE sequence_number = ?1",
+            params![sequence_number])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn complete(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), AzureServiceBusError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let sequence_number = self.find_locked(&tx, &message.properties, Utc::now())?;
+        tx.execute("DELETE FROM queue_messages WHERE sequence_number = ?1", params![sequence_number])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn dead_letter(&self, message: &Message<BrokerReceiveProperties>, reason: &str, description: &str) -> Result<(), AzureServiceBusError> {
+        let now = Utc::now();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let sequence_number = self.find_locked(&tx, &message.properties, now)?;
+        self.move_to_dead_letter(&tx, sequence_number, reason, description, now)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+use thiserror::Error;
+
+use crate::mazure::codec::{Codec, Format, JsonCodec};
+use crate::mazure::sbclient::{AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message};
+use crate::mazure::sending::SendReceipt;
+use crate::received::ReceivedMessage;
+use crate::transport::QueueTransport;
+
+/// Names the payload type of a message, so receivers can tell payloads apart before
+/// decoding them.
+pub static MESSAGE_TYPE_PROPERTY: &str = "message-type";
+
+/// A payload type with a name that stays the same when the type is renamed or moved,
+/// recorded on the messages carrying it.
+pub trait MessageType {
+    const TYPE_NAME: &'static str;
+}
+
+#[derive(Error, Debug)]
+pub enum TypedError {
+    #[error("Transport error: {0}")]
+    TransportError(String),
+
+    #[error("Unable to encode {type_name}: {reason}")]
+    EncodeError { type_name: &'static str, reason: String },
+
+    #[error("Message {message_id:?} has content type {actual:?}, expected {expected}")]
+    ContentTypeMismatch { message_id: Option<String>, expected: &'static str, actual: String },
+
+    #[error("Message {message_id:?} has message type {actual:?}, expected {expected}")]
+    TypeMismatch { message_id: Option<String>, expected: &'static str, actual: Option<String> },
+
+    #[error("Unable to decode message {message_id:?} as {type_name}: {reason}")]
+    DecodeError { message_id: Option<String>, type_name: &'static str, reason: String },
+}
+
+impl TypedError {
+    /// Whether the message itself was at fault rather than the transport.
+    pub fn is_payload_error(self: &Self) -> bool {
+        !matches!(self, TypedError::TransportError(_))
+    }
+}
+
+impl From<AzureServiceBusError> for TypedError {
+    fn from(e: AzureServiceBusError) -> Self {
+        TypedError::TransportError(e.to_string())
+    }
+}
+
+/// Sends payloads of type `T` encoded with codec `C`.
+pub struct TypedSender<'a, T, C = JsonCodec> {
+    transport: &'a dyn QueueTransport,
+    marker: PhantomData<fn(&T, C)>,
+}
+
+impl<'a, T: MessageType, C: Codec<T>> TypedSender<'a, T, C> {
+    pub fn new(transport: &'a dyn QueueTransport) -> Self {
+        TypedSender { transport, marker: PhantomData }
+    }
+
+    /// The message `send` would send, for setting broker or user properties first.
+    pub fn message(self: &Self, body: &T) -> Result<Message<BrokerSendProperties>, TypedError> {
+        let mut message = Message::encode::<C, T>(body)
+            .map_err(|e| TypedError::EncodeError { type_name: T::TYPE_NAME, reason: e.to_string() })?;
+        message.set_user_property(MESSAGE_TYPE_PROPERTY, T::TYPE_NAME);
+        Ok(message)
+    }
+
+    pub async fn send(self: &Self, body: &T) -> Result<SendReceipt, TypedError> {
+        let message = self.message(body)?;
+        Ok(self.transport.send(&message).await?)
+    }
+}
+
+/// Receives payloads of type `T` encoded with codec `C`. A message with another content
+/// type or message type, or one that does not decode, comes back still locked as an
+/// `UndecodedMessage`, since on a shared queue it may be meant for another receiver.
+pub struct TypedReceiver<'a, T, C = JsonCodec> {
+    transport: &'a dyn QueueTransport,
+    marker: PhantomData<fn() -> (T, C)>,
+}
+
+impl<'a, T: MessageType, C: Codec<T>> TypedReceiver<'a, T, C> {
+    pub fn new(transport: &'a dyn QueueTransport) -> Self {
+        TypedReceiver { transport, marker: PhantomData }
+    }
+
+    pub async fn receive(self: &Self) -> Result<Option<Result<TypedMessage<'a, T>, UndecodedMessage<'a>>>, TypedError> {
+        match ReceivedMessage::receive(self.transport).await? {
+            None => Ok(None),
+            Some(received) => Ok(Some(self.decode(received))),
+        }
+    }
+
+    /// Checks and decodes a message received some other way, e.g. from a `MessageStream`.
+    pub fn decode(self: &Self, received: ReceivedMessage<'a>) -> Result<TypedMessage<'a, T>, UndecodedMessage<'a>> {
+        match decode_payload::<T, C>(received.message()) {
+            Ok(body) => Ok(TypedMessage { body, received }),
+            Err(error) => Err(UndecodedMessage { error, received: Box::new(received) }),
+        }
+    }
+}
+
+/// A message a `TypedReceiver` could not take as its type, left for the caller to settle.
+pub struct UndecodedMessage<'a> {
+    error: TypedError,
+    received: Box<ReceivedMessage<'a>>,
+}
+
+impl<'a> UndecodedMessage<'a> {
+    pub fn error(self: &Self) -> &TypedError {
+        &self.error
+    }
+
+    pub fn message(self: &Self) -> &Message<BrokerReceiveProperties> {
+        self.received.message()
+    }
+
+    pub fn into_parts(self: Self) -> (TypedError, ReceivedMessage<'a>) {
+        (self.error, *self.received)
+    }
+
+    /// Releases the message for other receivers.
+    pub async fn abandon(self: Self) -> Result<(), AzureServiceBusError> {
+        self.received.abandon().await
+    }
+
+    /// Dead-letters the message with reason `DecodeError` and the error as description.
+    pub async fn reject(self: Self) -> Result<(), AzureServiceBusError> {
+        self.received.dead_letter("DecodeError", &self.error.to_string()).await
+    }
+}
+
+impl<'a> fmt::Debug for UndecodedMessage<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UndecodedMessage").field("error", &self.error).field("received", &self.received).finish()
+    }
+}
+
+/// A decoded payload and the locked message it came in.
+pub struct TypedMessage<'a, T> {
+    body: T,
+    received: ReceivedMessage<'a>,
+}
+
+impl<'a, T> TypedMessage<'a, T> {
+    pub fn body(self: &Self) -> &T {
+        &self.body
+    }
+
+    pub fn message(self: &Self) -> &Message<BrokerReceiveProperties> {
+        self.received.message()
+    }
+
+    pub fn into_parts(self: Self) -> (T, ReceivedMessage<'a>) {
+        (self.body, self.received)
+    }
+
+    pub async fn complete(self: Self) -> Result<(), AzureServiceBusError> {
+        self.received.complete().await
+    }
+
+    pub async fn abandon(self: Self) -> Result<(), AzureServiceBusError> {
+        self.received.abandon().await
+    }
+
+    pub async fn dead_letter(self: Self, reason: &str, description: &str) -> Result<(), AzureServiceBusError> {
+        self.received.dead_letter(reason, description).await
+    }
+
+    pub async fn renew_lock(self: &mut Self) -> Result<(), AzureServiceBusError> {
+        self.received.renew_lock().await
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for TypedMessage<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedMessage").field("body", &self.body).field("received", &self.received).finish()
+    }
+}
+
+/// Decodes the body after checking the content type and message type. Messages without
+/// a content type, such as ones sent in a batch, are only checked by message type.
+pub fn decode_payload<T: MessageType, C: Codec<T>>(message: &Message<BrokerReceiveProperties>) -> Result<T, TypedError> {
+    let message_id = &message.properties.message_id;
+    if !message.content_type.is_empty() && !same_content_type(&message.content_type, C::CONTENT_TYPE) {
+        return Err(TypedError::ContentTypeMismatch {
+            message_id: message_id.clone(),
+            expected: C::CONTENT_TYPE,
+            actual: message.content_type.clone(),
+        });
+    }
+
+    let message_type = message.user_property(MESSAGE_TYPE_PROPERTY);
+    if message_type != Some(T::TYPE_NAME) {
+        return Err(TypedError::TypeMismatch {
+            message_id: message_id.clone(),
+            expected: T::TYPE_NAME,
+            actual: message_type.map(|t| t.to_string()),
+        });
+    }
+
+    message.decode_with::<C, T>()
+        .map_err(|e| TypedError::DecodeError { message_id: message_id.clone(), type_name: T::TYPE_NAME, reason: e.to_string() })
+}
+
+// Compares media types without parameters, taking the JSON spellings as one.
+fn same_content_type(actual: &str, expected: &str) -> bool {
+    let essence = |content_type: &str| content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if essence(actual) == essence(expected) {
+        return true;
+    }
+    matches!((Format::from_content_type(actual), Format::from_content_type(expected)), (Ok(Format::Json), Ok(Format::Json)))
+}
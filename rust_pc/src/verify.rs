@@ -0,0 +1,42 @@
+use std::error::Error;
+
+use crate::mazure::sbclient::AzureServiceBusClient;
+use crate::mazure::signing::{SignatureStatus, TrustedKeys, inspect_signature};
+
+/// Reports the signature status of the next message and leaves it on the queue.
+/// Returns `None` if the queue is empty.
+///
+/// Over AMQP the message is peeked. REST cannot peek, so there the message is locked
+/// and released again, which counts as a delivery towards the max delivery count.
+pub async fn run_verify(sb_client: &AzureServiceBusClient, trusted_keys: &TrustedKeys) -> Result<Option<SignatureStatus>, Box<dyn Error>> {
+    let peeked = if sb_client.uses_amqp() { sb_client.peek_raw().await? } else { sb_client.peek_lock_raw().await? };
+    let msg = match peeked {
+        None => {
+            println!("No message available.");
+            return Ok(None);
+        },
+        Some(msg) => msg,
+    };
+
+    let status = inspect_signature(&msg, trusted_keys);
+    if !sb_client.uses_amqp() {
+        sb_client.unlock_message(&msg.properties).await?;
+    }
+
+    println!("Message {:?}:", msg.properties.message_id);
+    println!("    properties: {:?}", &msg.properties);
+    match &status {
+        SignatureStatus::Unsigned => println!("    signature: none"),
+        SignatureStatus::Valid { key_id, algorithm, signed_properties, unsigned_properties } => {
+            println!("    signature: valid ({} key {})", algorithm, key_id);
+            println!("    signed properties: {}", signed_properties.join(", "));
+            if !unsigned_properties.is_empty() {
+                println!("    unsigned properties, ignored by consumers: {}", unsigned_properties.join(", "));
+            }
+        },
+        SignatureStatus::UntrustedKey { key_id } => println!("    signature: untrusted key {}", key_id),
+        SignatureStatus::Invalid { key_id, reason } => println!("    signature: INVALID (key {}): {}", key_id, reason),
+    }
+
+    Ok(Some(status))
+}
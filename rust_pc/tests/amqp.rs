@@ -0,0 +1,334 @@
+mod common;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::Utc;
+use qexample::emulator::Emulator;
+use qexample::mazure::amqp::AmqpOptions;
+use qexample::mazure::amqp::codec::{self, Value};
+use qexample::mazure::amqp::message::AmqpMessage;
+use qexample::mazure::sbclient::{AzureServiceBusClient, AzureServiceBusError, BrokerReceiveProperties, Message, DEAD_LETTER_DESCRIPTION_PROPERTY, DEAD_LETTER_REASON_PROPERTY};
+use qexample::mazure::signing::{SignatureStatus, TrustedKeys};
+use qexample::verify;
+
+use common::{QUEUE, client, dead_letter_queue, start_emulator};
+
+async fn start_amqp_emulator() -> Emulator {
+    let mut emulator = start_emulator().await;
+    emulator.serve_amqp("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    emulator
+}
+
+fn amqp_client(emulator: &Emulator, options: AmqpOptions) -> AzureServiceBusClient {
+    let options = options
+        .with_endpoint(emulator.amqp_endpoint().unwrap())
+        .with_receive_timeout(Duration::from_millis(200));
+    client(emulator).with_amqp(options)
+}
+
+#[test]
+fn codec_round_trips_described_values() {
+    let value = Value::described(0x73, Value::List(vec![
+        Value::string("id"),
+        Value::Null,
+        Value::Ulong(300),
+        Value::Timestamp(1_700_000_000_000),
+        Value::Map(vec![(Value::symbol("key"), Value::Int(-5))]),
+        Value::Array(vec![Value::Uuid([7; 16]), Value::Uuid([9; 16])]),
+        Value::Binary(vec![0; 300]),
+    ]));
+
+    let bytes = codec::to_bytes(&value);
+    assert_eq!(codec::from_bytes(&bytes).unwrap(), value);
+    assert!(codec::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn sequence_numbers_keep_all_64_bits() {
+    let mut properties = BrokerReceiveProperties::new_empty();
+    properties.sequence_number = Some(i64::from(i32::MAX) + 5);
+    let received = Message { properties, content: b"busy".to_vec(), content_type: "text/plain".into(), user_properties: Default::default() };
+
+    let decoded = AmqpMessage::decode(&AmqpMessage::from_received_message(&received).encode()).unwrap();
+    assert_eq!(decoded.to_received_message(None).properties.sequence_number, Some(i64::from(i32::MAX) + 5));
+}
+
+#[tokio::test]
+async fn sends_and_receives_with_broker_properties() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+
+    let mut message = Message::new_json(&"hello").unwrap();
+    message.properties.label = Some("greeting".into());
+    message.properties.session_id = Some("session".into());
+    message.properties.time_to_live = Some(120);
+    message.set_user_property("origin", "tests");
+    let receipt = sb_client.send(&message).await.unwrap();
+
+    let received = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(received.properties.message_id, receipt.message_id);
+    assert_eq!(received.properties.correlation_id.as_deref(), Some(receipt.correlation_id.as_str()));
+    assert_eq!(received.properties.label.as_deref(), Some("greeting"));
+    assert_eq!(received.properties.session_id.as_deref(), Some("session"));
+    assert_eq!(received.properties.time_to_live, Some(120));
+    assert_eq!(received.properties.delivery_count, Some(1));
+    assert!(received.properties.sequence_number.is_some());
+    assert!(received.properties.locked_until_utc.is_some());
+    assert_eq!(received.user_property("origin"), Some("tests"));
+    assert_eq!(received.content_type, message.content_type);
+    assert_eq!(received.json_into::<String>().unwrap(), "hello");
+
+    // The lock token is the broker's.
+    let stored = emulator.with_broker(|b| b.messages(QUEUE))[0].clone();
+    assert_eq!(received.properties.lock_token, stored.properties.lock_token);
+
+    sb_client.complete_message(&received).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    assert!(sb_client.peek_lock().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn abandoned_messages_are_redelivered_and_locks_renew() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    sb_client.send_json(&1).await.unwrap();
+
+    let first = sb_client.peek_lock().await.unwrap().unwrap();
+    sb_client.unlock_message(&first.properties).await.unwrap();
+
+    let second = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(second.properties.delivery_count, Some(2));
+    assert_ne!(second.properties.lock_token, first.properties.lock_token);
+
+    emulator.with_broker(|b| b.advance_clock(chrono::Duration::seconds(30)));
+    let locked_until = sb_client.renew_lock(&second.properties).await.unwrap();
+    let stored = emulator.with_broker(|b| b.messages(QUEUE))[0].clone();
+    assert_eq!(locked_until.map(|t| t.timestamp_millis()), stored.properties.locked_until_utc.map(|t| t.timestamp_millis()));
+    emulator.with_broker(|b| b.advance_clock(chrono::Duration::seconds(45)));
+    sb_client.complete_message(&second).await.unwrap();
+
+    // Settling again finds the lock gone, like the REST API's 410.
+    match sb_client.complete_message(&second).await {
+        Err(AzureServiceBusError::RequestError(status)) => assert_eq!(status, "410"),
+        other => panic!("Expected a lost lock, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn prefetch_locks_messages_ahead_of_receives() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default().with_prefetch(3));
+    let batch: Vec<_> = (0..5).map(|i| Message::new_json(&i).unwrap()).collect();
+    sb_client.send_batch(&batch).await.unwrap();
+
+    let first = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(first.json_into::<i32>().unwrap(), 0);
+    let locked = emulator.with_broker(|b| b.messages(QUEUE).iter().filter(|m| m.properties.lock_token.is_some()).count());
+    assert_eq!(locked, 3);
+
+    let mut received = vec![first];
+    while let Some(message) = sb_client.peek_lock().await.unwrap() {
+        received.push(message);
+    }
+    let bodies: Vec<i32> = received.iter().map(|m| m.json_into().unwrap()).collect();
+    assert_eq!(bodies, vec![0, 1, 2, 3, 4]);
+}
+
+#[tokio::test]
+async fn receive_and_delete_does_not_hold_credit() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    assert!(sb_client.receive_and_delete().await.unwrap().is_none());
+
+    // Had the empty receive kept its credit, this would go to the idle link and be lost.
+    client(&emulator).send_json(&"late").await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+
+    let message = sb_client.receive_and_delete().await.unwrap().unwrap();
+    assert_eq!(message.json_into::<String>().unwrap(), "late");
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn large_messages_span_several_frames() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    let body = "x".repeat(200 * 1024);
+    sb_client.send_json(&body).await.unwrap();
+
+    let received = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(received.json_into::<String>().unwrap(), body);
+}
+
+#[tokio::test]
+async fn dead_lettering_rejects_over_amqp() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    sb_client.send_json(&"poison").await.unwrap();
+
+    let message = sb_client.peek_lock().await.unwrap().unwrap();
+    sb_client.dead_letter(&message, "Poison", "Cannot be processed").await.unwrap();
+
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    let dead = emulator.with_broker(|b| b.messages(&dead_letter_queue()));
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].user_property(DEAD_LETTER_REASON_PROPERTY), Some("Poison"));
+    assert_eq!(dead[0].user_property(DEAD_LETTER_DESCRIPTION_PROPERTY), Some("Cannot be processed"));
+    // The message itself moved, not a copy.
+    assert_eq!(dead[0].properties.message_id, message.properties.message_id);
+    assert_eq!(dead[0].properties.sequence_number, message.properties.sequence_number);
+}
+
+#[tokio::test]
+async fn peeking_leaves_messages_unlocked() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    for i in 0..3 {
+        sb_client.send_json(&i).await.unwrap();
+    }
+    let locked = sb_client.peek_lock().await.unwrap().unwrap();
+
+    let peeked = sb_client.peek_messages(0, 10).await.unwrap();
+    let bodies: Vec<i32> = peeked.iter().map(|m| m.json_into().unwrap()).collect();
+    assert_eq!(bodies, vec![0, 1, 2]);
+    assert!(peeked.iter().all(|m| m.properties.lock_token.is_none()));
+
+    let from_second = peeked[1].properties.sequence_number.unwrap();
+    assert_eq!(sb_client.peek_messages(from_second, 1).await.unwrap().len(), 1);
+    assert!(sb_client.peek_messages(from_second + 10, 1).await.unwrap().is_empty());
+
+    // Only the message locked before is locked.
+    sb_client.complete_message(&locked).await.unwrap();
+    let next = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(next.json_into::<i32>().unwrap(), 1);
+    assert_eq!(next.properties.delivery_count, Some(1));
+}
+
+#[tokio::test]
+async fn deferred_messages_are_received_by_sequence_number() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    sb_client.send_json(&"later").await.unwrap();
+
+    let message = sb_client.peek_lock().await.unwrap().unwrap();
+    let sequence_number = message.properties.sequence_number.unwrap();
+    sb_client.defer_message(&message.properties).await.unwrap();
+    assert!(sb_client.peek_lock().await.unwrap().is_none());
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+
+    // Abandoning a deferred message leaves it deferred.
+    let deferred = sb_client.receive_deferred(sequence_number).await.unwrap();
+    assert_eq!(deferred.json_into::<String>().unwrap(), "later");
+    assert_eq!(deferred.properties.delivery_count, Some(2));
+    assert!(sb_client.renew_lock(&deferred.properties).await.unwrap().is_some());
+    sb_client.unlock_message(&deferred.properties).await.unwrap();
+    assert!(sb_client.peek_lock().await.unwrap().is_none());
+
+    let deferred = sb_client.receive_deferred(sequence_number).await.unwrap();
+    sb_client.complete_message(&deferred).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+
+    match sb_client.receive_deferred(sequence_number).await {
+        Err(AzureServiceBusError::RequestError(status)) => assert_eq!(status, "404"),
+        other => panic!("Expected no deferred message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn deferred_messages_can_be_dead_lettered() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    sb_client.send_json(&"stuck").await.unwrap();
+
+    let message = sb_client.peek_lock().await.unwrap().unwrap();
+    sb_client.defer_message(&message.properties).await.unwrap();
+    let deferred = sb_client.receive_deferred(message.properties.sequence_number.unwrap()).await.unwrap();
+    sb_client.dead_letter(&deferred, "Stuck", "Never got processed").await.unwrap();
+
+    let dead = emulator.with_broker(|b| b.messages(&dead_letter_queue()));
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].user_property(DEAD_LETTER_REASON_PROPERTY), Some("Stuck"));
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn scheduled_messages_can_be_cancelled() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    let enqueue_time = Utc::now() + chrono::Duration::minutes(5);
+
+    let cancelled = sb_client.schedule_message(&Message::new_json(&"cancelled").unwrap(), enqueue_time).await.unwrap();
+    let kept = sb_client.schedule_message(&Message::new_json(&"kept").unwrap(), enqueue_time).await.unwrap();
+    assert_ne!(cancelled, kept);
+    assert!(sb_client.peek_lock().await.unwrap().is_none());
+
+    sb_client.cancel_scheduled_message(cancelled).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+    match sb_client.cancel_scheduled_message(cancelled).await {
+        Err(AzureServiceBusError::RequestError(status)) => assert_eq!(status, "404"),
+        other => panic!("Expected the message to be gone, got {:?}", other),
+    }
+
+    emulator.with_broker(|b| b.advance_clock(chrono::Duration::minutes(6)));
+    let message = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(message.json_into::<String>().unwrap(), "kept");
+    assert_eq!(message.properties.sequence_number, Some(kept));
+}
+
+#[tokio::test]
+async fn management_operations_need_amqp() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    match sb_client.peek_messages(0, 1).await {
+        Err(AzureServiceBusError::RequestError(description)) => assert!(description.contains("AMQP")),
+        other => panic!("Expected peeking to be refused, got {:?}", other),
+    }
+    assert!(sb_client.cancel_scheduled_message(1).await.is_err());
+}
+
+#[tokio::test]
+async fn revoked_tokens_are_refused() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    sb_client.send_json(&1).await.unwrap();
+
+    // The authenticator keeps its cached token, which is no longer valid.
+    emulator.revoke_tokens();
+    match sb_client.send_json(&2).await {
+        Err(AzureServiceBusError::RequestError(status)) => assert_eq!(status, "401"),
+        other => panic!("Expected the token to be refused, got {:?}", other),
+    }
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+}
+
+#[tokio::test]
+async fn reconnects_after_the_connection_is_lost() {
+    let mut emulator = start_emulator().await;
+    let address = emulator.serve_amqp("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    sb_client.send_json(&1).await.unwrap();
+
+    emulator.serve_amqp(address).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    sb_client.send_json(&2).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 2);
+}
+
+#[tokio::test]
+async fn verify_peeks_over_amqp() {
+    let emulator = start_amqp_emulator().await;
+    let sb_client = amqp_client(&emulator, AmqpOptions::default());
+    sb_client.send(&Message::new_json(&"unsigned").unwrap()).await.unwrap();
+
+    for _ in 0..3 {
+        let status = verify::run_verify(&sb_client, &TrustedKeys::new(HashMap::new())).await.unwrap();
+        assert_eq!(status, Some(SignatureStatus::Unsigned));
+    }
+
+    // Peeking is not a delivery.
+    let received = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(received.properties.delivery_count, Some(1));
+}
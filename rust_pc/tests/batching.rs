@@ -0,0 +1,124 @@
+mod common;
+
+use std::time::Duration;
+
+use qexample::mazure::batching::{self, BatchingError, BatchingOptions};
+use qexample::mazure::sbclient::Message;
+use qexample::mazure::signing::{MessageSigner, SigningKey};
+
+use common::{QUEUE, client, start_emulator};
+
+#[tokio::test]
+async fn batches_by_count_and_completes_each_message() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let options = BatchingOptions { max_batch_count: 3, linger: Duration::from_secs(5), ..BatchingOptions::default() };
+    let (sender, worker) = batching::channel(&sb_client, options).unwrap();
+
+    let produce = async move {
+        let mut completions = Vec::new();
+        for i in 0..7 {
+            completions.push(sender.send(Message::new_json(&i).unwrap()).await.unwrap());
+        }
+        // Dropping the sender lets the worker send the last, partial batch right away.
+        drop(sender);
+
+        let mut message_ids = Vec::new();
+        for completion in completions {
+            message_ids.push(completion.await.unwrap().message_id.unwrap());
+        }
+        message_ids
+    };
+    let (message_ids, stats) = tokio::join!(produce, worker.run());
+
+    assert_eq!((stats.batches, stats.messages, stats.failed_messages), (3, 7, 0));
+    let queued = emulator.with_broker(|b| b.messages(QUEUE));
+    let bodies: Vec<i32> = queued.iter().map(|m| m.json_into().unwrap()).collect();
+    assert_eq!(bodies, (0..7).collect::<Vec<_>>());
+    let queued_ids: Vec<String> = queued.iter().map(|m| m.properties.message_id.clone().unwrap()).collect();
+    assert_eq!(queued_ids, message_ids);
+}
+
+#[tokio::test]
+async fn batches_by_size_and_linger() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let body = "x".repeat(300);
+    let options = BatchingOptions { max_batch_size: 1200, linger: Duration::from_millis(20), ..BatchingOptions::default() };
+    let (sender, worker) = batching::channel(&sb_client, options).unwrap();
+
+    let produce = async move {
+        let mut completions = Vec::new();
+        for _ in 0..5 {
+            completions.push(sender.send(Message::new_json(&body).unwrap()).await.unwrap());
+        }
+        for completion in completions {
+            completion.await.unwrap();
+        }
+
+        // Sent on its own once it has lingered, while the sender is still open.
+        let late = sender.send(Message::new_json(&"late").unwrap()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), late).await.unwrap().unwrap();
+    };
+    let (_, stats) = tokio::join!(produce, worker.run());
+
+    // Three entries of a little over 350 bytes fit in 1200, the fourth does not.
+    assert_eq!((stats.batches, stats.messages), (3, 6));
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 6);
+}
+
+#[tokio::test]
+async fn rejects_messages_that_cannot_be_batched() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let options = BatchingOptions { max_batch_size: 100, ..BatchingOptions::default() };
+    let (sender, _worker) = batching::channel(&sb_client, options).unwrap();
+
+    let binary = Message { content: vec![0xff, 0xfe], ..Message::new_json(&0).unwrap() };
+    assert!(matches!(sender.send(binary).await, Err(BatchingError::InvalidMessage(_))));
+
+    let large = Message::new_json(&"x".repeat(200)).unwrap();
+    assert!(matches!(sender.send(large).await, Err(BatchingError::MessageTooLarge { .. })));
+}
+
+#[tokio::test]
+async fn clients_that_sign_cannot_batch() {
+    let emulator = start_emulator().await;
+    let signer = MessageSigner::new("shared", SigningKey::HmacSha256(b"a shared secret".to_vec()));
+    let sb_client = client(&emulator).with_signing(signer);
+
+    let err = batching::channel(&sb_client, BatchingOptions::default()).err().unwrap();
+    assert!(matches!(err, BatchingError::Unsupported(_)));
+}
+
+#[tokio::test]
+async fn failed_batches_fail_every_message() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    emulator.stop().await.unwrap();
+    let (sender, worker) = batching::channel(&sb_client, BatchingOptions::default()).unwrap();
+
+    let produce = async move {
+        let first = sender.send(Message::new_json(&1).unwrap()).await.unwrap();
+        let second = sender.send(Message::new_json(&2).unwrap()).await.unwrap();
+        drop(sender);
+        (first.await, second.await)
+    };
+    let ((first, second), stats) = tokio::join!(produce, worker.run());
+
+    assert!(matches!(first, Err(BatchingError::SendError(_))));
+    assert_eq!(first, second);
+    assert_eq!((stats.batches, stats.messages, stats.failed_messages), (1, 0, 2));
+}
+
+#[tokio::test]
+async fn completions_fail_when_the_worker_is_gone() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let (sender, worker) = batching::channel(&sb_client, BatchingOptions::default()).unwrap();
+
+    let completion = sender.send(Message::new_json(&1).unwrap()).await.unwrap();
+    drop(worker);
+    assert_eq!(completion.await.unwrap_err(), BatchingError::Closed);
+    assert_eq!(sender.send(Message::new_json(&2).unwrap()).await.unwrap_err(), BatchingError::Closed);
+}
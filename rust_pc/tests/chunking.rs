@@ -0,0 +1,151 @@
+mod common;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use qexample::mazure::chunking::{ChunkedReceiver, CHUNK_INDEX_PROPERTY, split_message};
+use qexample::mazure::compression::{CompressionAlgorithm, CompressionOptions};
+use qexample::mazure::encryption::LocalKeyProvider;
+use qexample::mazure::sbclient::{AzureServiceBusError, Message, DEAD_LETTER_REASON_PROPERTY};
+use qexample::mazure::signing::{MessageSigner, SigningKey, TrustedKeys};
+
+use common::{QUEUE, client, dead_letter_queue, start_emulator};
+
+fn payload() -> Vec<String> {
+    (0..500).map(|i| format!("line {} {}", i, uuid::Uuid::new_v4())).collect()
+}
+
+#[tokio::test]
+async fn chunks_are_reassembled_and_completed_together() {
+    let emulator = start_emulator().await;
+    let keys = HashMap::from([("k1".to_string(), LocalKeyProvider::generate_key())]);
+    let signer = MessageSigner::new("s1", SigningKey::HmacSha256(b"secret".to_vec()));
+    let trusted = TrustedKeys::new(HashMap::from([("s1".to_string(), signer.key.verification_key())]));
+    let configure = |c: qexample::mazure::sbclient::AzureServiceBusClient| c
+        .with_compression(CompressionOptions { algorithm: Some(CompressionAlgorithm::Gzip), ..CompressionOptions::default() })
+        .with_encryption(Box::new(LocalKeyProvider::new("k1", keys.clone()).unwrap()));
+
+    let sender = configure(client(&emulator)).with_signing(signer);
+    let msg = Message::new_json(&payload()).unwrap();
+    let session_id = sender.send_chunked(&msg, 1024).await.unwrap();
+
+    let stored = emulator.with_broker(|b| b.messages(QUEUE));
+    assert!(stored.len() > 3);
+    assert!(stored.iter().all(|m| m.properties.session_id.as_deref() == Some(session_id.as_str())));
+
+    let sb_client = configure(client(&emulator)).with_verification(trusted);
+    let mut receiver = ChunkedReceiver::new(&sb_client);
+    let assembled = receiver.receive().await.unwrap().unwrap();
+    assert_eq!(assembled.message.content, msg.content);
+    assert_eq!(assembled.message.user_property(CHUNK_INDEX_PROPERTY), None);
+    assert_eq!(assembled.chunks.len(), stored.len());
+
+    // Nothing is settled until the payload has been handled.
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), stored.len());
+    receiver.complete(&assembled).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn abandoned_chunks_are_redelivered() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    sb_client.send_chunked(&Message::new_json(&payload()).unwrap(), 4096).await.unwrap();
+
+    let mut receiver = ChunkedReceiver::new(&sb_client);
+    let first = receiver.receive().await.unwrap().unwrap();
+    receiver.abandon(&first).await.unwrap();
+
+    let second = receiver.receive().await.unwrap().unwrap();
+    assert_eq!(second.message.content, first.message.content);
+    assert!(second.chunks.iter().all(|c| c.delivery_count == Some(2)));
+}
+
+#[tokio::test]
+async fn plain_messages_and_interleaved_groups_are_received() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    let one = split_message(&Message::new_json(&"one ".repeat(100)).unwrap(), 100);
+    let two = split_message(&Message::new_json(&"two ".repeat(100)).unwrap(), 100);
+    for (a, b) in one.iter().zip(two.iter()) {
+        sb_client.send(a).await.unwrap();
+        sb_client.send(b).await.unwrap();
+    }
+    sb_client.send(&Message::new_json(&"plain".to_string()).unwrap()).await.unwrap();
+
+    let mut receiver = ChunkedReceiver::new(&sb_client);
+    let mut bodies = Vec::new();
+    while let Some(assembled) = receiver.receive().await.unwrap() {
+        bodies.push(assembled.message.json_into::<String>().unwrap());
+        receiver.complete(&assembled).await.unwrap();
+    }
+
+    assert_eq!(bodies, vec!["one ".repeat(100), "two ".repeat(100), "plain".to_string()]);
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn duplicate_chunks_are_discarded() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    let chunks = split_message(&Message::new_json(&"dup ".repeat(100)).unwrap(), 100);
+    sb_client.send(&chunks[0]).await.unwrap();
+    sb_client.send(&chunks[0]).await.unwrap();
+    for chunk in &chunks[1..] {
+        sb_client.send(chunk).await.unwrap();
+    }
+
+    let mut receiver = ChunkedReceiver::new(&sb_client);
+    let assembled = receiver.receive().await.unwrap().unwrap();
+    assert_eq!(assembled.message.json_into::<String>().unwrap(), "dup ".repeat(100));
+    assert_eq!(assembled.chunks.len(), chunks.len());
+
+    receiver.complete(&assembled).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn missing_chunks_are_reported_and_released() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    let chunks = split_message(&Message::new_json(&"gap ".repeat(100)).unwrap(), 100);
+    for (i, chunk) in chunks.iter().enumerate() {
+        if i != 1 {
+            sb_client.send(chunk).await.unwrap();
+        }
+    }
+
+    let mut receiver = ChunkedReceiver::new(&sb_client).with_assembly_timeout(Duration::from_millis(200));
+    assert!(receiver.receive().await.unwrap().is_none());
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let err = receiver.receive().await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::PayloadError(ref e) if e.contains("[1]")));
+
+    // The held chunks are available again.
+    assert!(sb_client.peek_lock().await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn corrupted_groups_are_dead_lettered() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    let mut chunks = split_message(&Message::new_json(&"bad ".repeat(100)).unwrap(), 100);
+    chunks[2].content[0] ^= 0xff;
+    for chunk in &chunks {
+        sb_client.send(chunk).await.unwrap();
+    }
+
+    let mut receiver = ChunkedReceiver::new(&sb_client);
+    let err = receiver.receive().await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::PayloadError(_)));
+
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    let dead_lettered = emulator.with_broker(|b| b.messages(&dead_letter_queue()));
+    assert_eq!(dead_lettered.len(), chunks.len());
+    assert_eq!(dead_lettered[0].user_property(DEAD_LETTER_REASON_PROPERTY), Some("PayloadError"));
+}
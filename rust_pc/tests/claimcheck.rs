@@ -0,0 +1,153 @@
+mod common;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use qexample::consumer::{self, ConsumerOptions, DrainStatus};
+use qexample::mazure::claimcheck::{
+    BlobStore, ClaimCheckOptions, LocalBlobStore, CLAIM_CHECK_REFERENCE_PROPERTY, CLAIM_CHECK_SHA256_PROPERTY
+};
+use qexample::mazure::sbclient::{AzureServiceBusClient, AzureServiceBusError, Message, DEAD_LETTER_REASON_PROPERTY};
+use qexample::poison::{PoisonPolicy, RetryStrategy};
+use qexample::shutdown::Shutdown;
+
+use common::{QUEUE, client, dead_letter_queue, start_emulator};
+
+fn blob_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("qexample-blobs-{}", uuid::Uuid::new_v4()))
+}
+
+fn with_blobs(sb_client: AzureServiceBusClient, dir: &PathBuf) -> AzureServiceBusClient {
+    sb_client.with_claim_check(ClaimCheckOptions {
+        threshold: 1024,
+        ..ClaimCheckOptions::new(Box::new(LocalBlobStore::new(dir).unwrap()))
+    })
+}
+
+fn blob_count(dir: &PathBuf) -> usize {
+    std::fs::read_dir(dir).unwrap().count()
+}
+
+#[tokio::test]
+async fn large_bodies_travel_through_the_blob_store() {
+    let emulator = start_emulator().await;
+    let dir = blob_dir();
+    let sb_client = with_blobs(client(&emulator), &dir);
+
+    let msg = Message::new_json(&"x".repeat(10_000)).unwrap();
+    sb_client.send(&msg).await.unwrap();
+
+    let stored = &emulator.with_broker(|b| b.messages(QUEUE))[0];
+    assert!(stored.content.is_empty());
+    assert!(stored.user_property(CLAIM_CHECK_REFERENCE_PROPERTY).is_some());
+    assert_eq!(blob_count(&dir), 1);
+
+    let received = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(received.content, msg.content);
+
+    sb_client.complete_message(&received).await.unwrap();
+    assert_eq!(blob_count(&dir), 0);
+}
+
+#[tokio::test]
+async fn small_bodies_are_sent_inline() {
+    let emulator = start_emulator().await;
+    let dir = blob_dir();
+    let sb_client = with_blobs(client(&emulator), &dir);
+
+    sb_client.send(&Message::new_json(&"small".to_string()).unwrap()).await.unwrap();
+
+    let stored = &emulator.with_broker(|b| b.messages(QUEUE))[0];
+    assert_eq!(stored.user_property(CLAIM_CHECK_REFERENCE_PROPERTY), None);
+    assert_eq!(blob_count(&dir), 0);
+}
+
+#[tokio::test]
+async fn receive_and_delete_removes_the_blob() {
+    let emulator = start_emulator().await;
+    let dir = blob_dir();
+    let sb_client = with_blobs(client(&emulator), &dir);
+
+    sb_client.send(&Message::new_json(&"y".repeat(10_000)).unwrap()).await.unwrap();
+    let received = sb_client.receive_and_delete().await.unwrap().unwrap();
+
+    assert_eq!(received.json_into::<String>().unwrap().len(), 10_000);
+    assert_eq!(blob_count(&dir), 0);
+}
+
+#[tokio::test]
+async fn rescheduled_copies_replace_the_original_blob() {
+    let emulator = start_emulator().await;
+    let dir = blob_dir();
+    let sb_client = with_blobs(client(&emulator), &dir);
+
+    let log_info = serde_json::json!({ "message": "z".repeat(10_000), "extra": "padded" });
+    sb_client.send(&Message::new_json(&log_info).unwrap()).await.unwrap();
+    let original = emulator.with_broker(|b| b.messages(QUEUE))[0].user_property(CLAIM_CHECK_REFERENCE_PROPERTY).unwrap().to_string();
+
+    // The first attempt fails and is rescheduled as a new message.
+    let options = ConsumerOptions {
+        poison_policy: PoisonPolicy {
+            retry: RetryStrategy::Backoff { initial: Duration::from_secs(5), max: Duration::from_secs(60) },
+            ..PoisonPolicy::default()
+        },
+        ..ConsumerOptions::default()
+    };
+    let (_trigger, shutdown) = Shutdown::new();
+    let status = consumer::run_consumer(&sb_client, &options, &shutdown).await.unwrap();
+    assert_eq!(status, DrainStatus::Clean);
+
+    let messages = emulator.with_broker(|b| b.messages(QUEUE));
+    assert_eq!(messages.len(), 1);
+    let rescheduled = messages[0].user_property(CLAIM_CHECK_REFERENCE_PROPERTY).unwrap();
+    assert_ne!(rescheduled, original);
+    assert!(!dir.join(&original).exists());
+    assert_eq!(blob_count(&dir), 1);
+}
+
+#[tokio::test]
+async fn tampered_or_missing_blobs_are_dead_lettered() {
+    let emulator = start_emulator().await;
+    let dir = blob_dir();
+    let sb_client = with_blobs(client(&emulator), &dir);
+
+    sb_client.send(&Message::new_json(&"a".repeat(10_000)).unwrap()).await.unwrap();
+    sb_client.send(&Message::new_json(&"b".repeat(10_000)).unwrap()).await.unwrap();
+
+    let messages = emulator.with_broker(|b| b.messages(QUEUE));
+    let store = LocalBlobStore::new(&dir).unwrap();
+    let first = messages[0].user_property(CLAIM_CHECK_REFERENCE_PROPERTY).unwrap();
+    let second = messages[1].user_property(CLAIM_CHECK_REFERENCE_PROPERTY).unwrap();
+    std::fs::write(dir.join(first), b"\"tampered\"").unwrap();
+    store.delete(second).await.unwrap();
+
+    for _ in 0..2 {
+        let err = sb_client.peek_lock().await.unwrap_err();
+        assert!(matches!(err, AzureServiceBusError::PayloadError(_)));
+    }
+
+    let dead_lettered = emulator.with_broker(|b| b.messages(&dead_letter_queue()));
+    assert_eq!(dead_lettered.len(), 2);
+    assert_eq!(dead_lettered[0].user_property(DEAD_LETTER_REASON_PROPERTY), Some("PayloadError"));
+    assert!(dead_lettered[0].user_property(CLAIM_CHECK_SHA256_PROPERTY).is_some());
+}
+
+#[tokio::test]
+async fn consumers_without_the_blob_store_release_the_message() {
+    let emulator = start_emulator().await;
+    let dir = blob_dir();
+    with_blobs(client(&emulator), &dir).send(&Message::new_json(&"c".repeat(10_000)).unwrap()).await.unwrap();
+
+    let err = client(&emulator).peek_lock().await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::PayloadUnavailable(_)));
+    assert_eq!(emulator.with_broker(|b| b.message_count(&dead_letter_queue())), 0);
+
+    let received = with_blobs(client(&emulator), &dir).peek_lock().await.unwrap().unwrap();
+    assert_eq!(received.json_into::<String>().unwrap(), "c".repeat(10_000));
+}
+
+#[tokio::test]
+async fn references_cannot_escape_the_store() {
+    let store = LocalBlobStore::new(blob_dir()).unwrap();
+    assert!(store.get("../../etc/passwd").await.is_err());
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use serde::{Serialize, Deserialize};
+
+use qexample::mazure::codec::{CodecError, Format, JsonCodec};
+use qexample::mazure::sbclient::Message;
+
+use common::{client, start_emulator};
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Reading {
+    sensor: String,
+    value: f64,
+}
+
+fn reading() -> Reading {
+    Reading { sensor: "north".into(), value: 21.5 }
+}
+
+#[test]
+fn format_is_picked_from_content_type() {
+    assert_eq!(Format::from_content_type("text/json").unwrap(), Format::Json);
+    assert_eq!(Format::from_content_type("application/json; charset=utf-8").unwrap(), Format::Json);
+    assert_eq!(Format::from_content_type("application/vnd.example+json").unwrap(), Format::Json);
+    assert_eq!(Format::from_content_type("").unwrap(), Format::Json);
+    assert!(matches!(Format::from_content_type("image/png"), Err(CodecError::UnsupportedContentType(_))));
+}
+
+#[test]
+fn json_round_trip() {
+    let msg = Message::encode::<JsonCodec, _>(&reading()).unwrap();
+    assert_eq!(msg.content_type, "text/json");
+    assert_eq!(msg.decode::<Reading>().unwrap(), reading());
+    assert_eq!(msg.json_into::<Reading>().unwrap(), reading());
+}
+
+#[test]
+fn decoding_needs_only_deserialize() {
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct ReadingView {
+        sensor: String,
+    }
+
+    let msg = Message::encode_as(Format::Json, &reading()).unwrap();
+    assert_eq!(msg.decode::<ReadingView>().unwrap(), ReadingView { sensor: "north".into() });
+}
+
+#[test]
+fn decode_reports_bad_payloads() {
+    let mut msg = Message::encode::<JsonCodec, _>(&reading()).unwrap();
+    msg.content = b"{ not json".to_vec();
+    assert!(matches!(msg.decode::<Reading>(), Err(CodecError::DecodeError(_))));
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn message_pack_round_trip() {
+    use qexample::mazure::codec::MessagePackCodec;
+
+    let msg = Message::encode::<MessagePackCodec, _>(&reading()).unwrap();
+    assert_eq!(Format::from_content_type(&msg.content_type).unwrap(), Format::MessagePack);
+    assert_eq!(msg.decode::<Reading>().unwrap(), reading());
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn cbor_round_trip() {
+    use qexample::mazure::codec::CborCodec;
+
+    let msg = Message::encode::<CborCodec, _>(&reading()).unwrap();
+    assert_eq!(Format::from_content_type(&msg.content_type).unwrap(), Format::Cbor);
+    assert_eq!(msg.decode::<Reading>().unwrap(), reading());
+}
+
+#[cfg(feature = "protobuf")]
+#[test]
+fn protobuf_round_trip() {
+    use qexample::mazure::codec::ProtobufCodec;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct LogRecord {
+        #[prost(string, tag = "1")]
+        message: String,
+        #[prost(uint32, tag = "2")]
+        level: u32,
+    }
+
+    let record = LogRecord { message: "disk almost full".into(), level: 3 };
+    let msg = Message::encode::<ProtobufCodec, _>(&record).unwrap();
+    assert_eq!(msg.content_type, "application/x-protobuf");
+    assert_eq!(msg.decode_with::<ProtobufCodec, LogRecord>().unwrap(), record);
+
+    // Protobuf is not picked automatically for serde types.
+    assert!(matches!(msg.decode::<Reading>(), Err(CodecError::UnsupportedContentType(_))));
+}
+
+#[tokio::test]
+async fn content_type_survives_the_round_trip() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    for format in [Format::Json, #[cfg(feature = "msgpack")] Format::MessagePack, #[cfg(feature = "cbor")] Format::Cbor] {
+        sb_client.send(&Message::encode_as(format, &reading()).unwrap()).await.unwrap();
+
+        let received = sb_client.receive_and_delete().await.unwrap().unwrap();
+        assert_eq!(received.content_type, format.content_type());
+        assert_eq!(received.decode::<Reading>().unwrap(), reading());
+    }
+}
@@ -0,0 +1,96 @@
+// Shared setup for the emulator backed integration tests.
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+
+use qexample::emulator::{Emulator, EmulatorConfig};
+use qexample::transport::broker::{BrokerConfig, DEAD_LETTER_QUEUE_SUFFIX};
+use qexample::mazure::aadclient::{AADClient, AADCredentials};
+use qexample::mazure::sbclient::{AzureServiceBusClient, Message, SERVICE_BUS_RESOURCE};
+use qexample::received::ReceivedMessage;
+use qexample::router::{Dispatched, Router};
+use qexample::transport::QueueTransport;
+use qexample::transport::memory::MemoryQueue;
+use qexample::typed::MessageType;
+
+pub const QUEUE: &str = "testlog";
+
+pub fn dead_letter_queue() -> String {
+    format!("{}{}", QUEUE, DEAD_LETTER_QUEUE_SUFFIX)
+}
+
+pub fn credentials() -> AADCredentials {
+    AADCredentials::new("tenant", "client", "secret")
+}
+
+pub async fn start_emulator() -> Emulator {
+    let config = EmulatorConfig {
+        credentials: Some(credentials()),
+        ..EmulatorConfig::default()
+    };
+    Emulator::start("127.0.0.1:0".parse().unwrap(), config).await.unwrap()
+}
+
+pub fn aad_client(emulator: &Emulator, credentials: AADCredentials) -> AADClient {
+    AADClient::new(reqwest::Client::new(), credentials, SERVICE_BUS_RESOURCE, Some(&emulator.endpoint()))
+}
+
+pub fn client_for(emulator: &Emulator, queue: &str, credentials: AADCredentials) -> AzureServiceBusClient {
+    let aad_client = Arc::new(aad_client(emulator, credentials));
+    AzureServiceBusClient::new(Box::new(aad_client), reqwest::Client::new(), "emulated", queue)
+        .with_endpoint(emulator.endpoint())
+        .with_dead_letter_path(format!("{}{}", queue, DEAD_LETTER_QUEUE_SUFFIX))
+}
+
+pub fn client(emulator: &Emulator) -> AzureServiceBusClient {
+    client_for(emulator, QUEUE, credentials())
+}
+
+// Payload types for the typed, router and middleware tests.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Order {
+    pub id: u32,
+    pub item: String,
+}
+
+impl MessageType for Order {
+    const TYPE_NAME: &'static str = "Order";
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Invoice {
+    pub order_id: u32,
+    pub total: f64,
+}
+
+impl MessageType for Invoice {
+    const TYPE_NAME: &'static str = "Invoice";
+}
+
+pub fn order(id: u32) -> Order {
+    Order { id, item: "kettle".into() }
+}
+
+pub fn memory_queue() -> MemoryQueue {
+    MemoryQueue::new(QUEUE, BrokerConfig::default())
+}
+
+// A memory queue holding a JSON message for each body.
+pub async fn queue_with(bodies: &[i32]) -> MemoryQueue {
+    queue_locking_for(BrokerConfig::default().lock_duration, bodies).await
+}
+
+pub async fn queue_locking_for(lock_duration: chrono::Duration, bodies: &[i32]) -> MemoryQueue {
+    let queue = MemoryQueue::new(QUEUE, BrokerConfig { lock_duration, ..BrokerConfig::default() });
+    for body in bodies {
+        queue.send(&Message::new_json(body).unwrap()).await.unwrap();
+    }
+    queue
+}
+
+pub async fn dispatch_next(router: &Router<'_>, transport: &dyn QueueTransport) -> Dispatched {
+    let received = ReceivedMessage::receive(transport).await.unwrap().unwrap();
+    router.dispatch(received).await.unwrap()
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use qexample::mazure::compression::{
+    CompressionAlgorithm, CompressionError, CompressionOptions, PAYLOAD_ENCODING_PROPERTY
+};
+use qexample::mazure::sbclient::{AzureServiceBusError, Message, DEAD_LETTER_REASON_PROPERTY};
+
+use common::{QUEUE, client, dead_letter_queue, start_emulator};
+
+fn gzip() -> CompressionOptions {
+    CompressionOptions { algorithm: Some(CompressionAlgorithm::Gzip), ..CompressionOptions::default() }
+}
+
+fn log_lines(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("2023-10-13T12:00:00Z INFO request {} handled in 12ms", i)).collect()
+}
+
+#[tokio::test]
+async fn large_bodies_are_compressed_on_the_wire_and_restored_on_receive() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator).with_compression(gzip());
+
+    let msg = Message::new_json(&log_lines(200)).unwrap();
+    sb_client.send(&msg).await.unwrap();
+
+    let stored = &emulator.with_broker(|b| b.messages(QUEUE))[0];
+    assert_eq!(stored.user_property(PAYLOAD_ENCODING_PROPERTY), Some("gzip"));
+    assert!(stored.content.len() < msg.content.len() / 4);
+
+    let received = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(received.content, msg.content);
+    assert_eq!(received.user_property(PAYLOAD_ENCODING_PROPERTY), None);
+    assert_eq!(received.decode::<Vec<String>>().unwrap(), log_lines(200));
+}
+
+#[tokio::test]
+async fn small_bodies_are_sent_as_is() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator).with_compression(gzip());
+
+    sb_client.send(&Message::new_json(&log_lines(1)).unwrap()).await.unwrap();
+
+    let stored = &emulator.with_broker(|b| b.messages(QUEUE))[0];
+    assert_eq!(stored.user_property(PAYLOAD_ENCODING_PROPERTY), None);
+}
+
+#[tokio::test]
+async fn decompression_bombs_are_rejected_and_dead_lettered() {
+    let emulator = start_emulator().await;
+    let sender = client(&emulator).with_compression(gzip());
+    let receiver = client(&emulator).with_compression(CompressionOptions {
+        max_decompressed_size: 64 * 1024,
+        ..CompressionOptions::default()
+    });
+
+    // A megabyte of zeros compresses to about a kilobyte.
+    let msg = Message::new_json(&"0".repeat(1024 * 1024)).unwrap();
+    sender.send(&msg).await.unwrap();
+
+    let err = receiver.peek_lock().await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::PayloadError(_)));
+
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    let dead_lettered = emulator.with_broker(|b| b.messages(&dead_letter_queue()));
+    assert_eq!(dead_lettered[0].user_property(DEAD_LETTER_REASON_PROPERTY), Some("PayloadError"));
+    assert_eq!(dead_lettered[0].user_property(PAYLOAD_ENCODING_PROPERTY), Some("gzip"));
+}
+
+#[test]
+fn unknown_encodings_are_reported() {
+    assert!(matches!(CompressionAlgorithm::from_name("lzma"), Err(CompressionError::UnsupportedEncoding(_))));
+}
+
+#[test]
+fn every_algorithm_round_trips_within_the_limit() {
+    let body = log_lines(100).join("\n").into_bytes();
+
+    let algorithms = [
+        CompressionAlgorithm::Gzip,
+        #[cfg(feature = "zstd")]
+        CompressionAlgorithm::Zstd,
+        #[cfg(feature = "brotli")]
+        CompressionAlgorithm::Brotli,
+    ];
+
+    for algorithm in algorithms {
+        let compressed = algorithm.compress(&body).unwrap();
+        assert!(compressed.len() < body.len());
+        assert_eq!(CompressionAlgorithm::from_name(algorithm.name()).unwrap(), algorithm);
+        assert_eq!(algorithm.decompress(&compressed, body.len()).unwrap(), body);
+        assert!(matches!(algorithm.decompress(&compressed, body.len() - 1), Err(CompressionError::TooLarge(_))));
+    }
+}
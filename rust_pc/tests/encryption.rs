@@ -0,0 +1,126 @@
+mod common;
+
+use std::collections::HashMap;
+
+use qexample::mazure::compression::{CompressionAlgorithm, CompressionOptions, PAYLOAD_ENCODING_PROPERTY};
+use qexample::mazure::encryption::{ENCRYPTION_KEY_ID_PROPERTY, LocalKeyProvider};
+use qexample::mazure::sbclient::{AzureServiceBusError, Message, DEAD_LETTER_REASON_PROPERTY};
+
+use common::{QUEUE, client, dead_letter_queue, start_emulator};
+
+fn provider(current: &str, keys: &[(&str, &Vec<u8>)]) -> Box<LocalKeyProvider> {
+    let keys: HashMap<String, Vec<u8>> = keys.iter().map(|(id, key)| (id.to_string(), (*key).clone())).collect();
+    Box::new(LocalKeyProvider::new(current, keys).unwrap())
+}
+
+#[tokio::test]
+async fn bodies_are_encrypted_on_the_wire_and_decrypted_on_receive() {
+    let emulator = start_emulator().await;
+    let key = LocalKeyProvider::generate_key();
+    let sb_client = client(&emulator).with_encryption(provider("k1", &[("k1", &key)]));
+
+    let msg = Message::new_json(&"card 4111 1111 1111 1111".to_string()).unwrap();
+    sb_client.send(&msg).await.unwrap();
+
+    let stored = &emulator.with_broker(|b| b.messages(QUEUE))[0];
+    assert_eq!(stored.user_property(ENCRYPTION_KEY_ID_PROPERTY), Some("k1"));
+    assert!(!String::from_utf8_lossy(&stored.content).contains("4111"));
+
+    let received = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(received.content, msg.content);
+    assert_eq!(received.user_property(ENCRYPTION_KEY_ID_PROPERTY), None);
+}
+
+#[tokio::test]
+async fn bodies_are_compressed_before_they_are_encrypted() {
+    let emulator = start_emulator().await;
+    let key = LocalKeyProvider::generate_key();
+    let sb_client = client(&emulator)
+        .with_compression(CompressionOptions { algorithm: Some(CompressionAlgorithm::Gzip), ..CompressionOptions::default() })
+        .with_encryption(provider("k1", &[("k1", &key)]));
+
+    let msg = Message::new_json(&"log line ".repeat(1000)).unwrap();
+    sb_client.send(&msg).await.unwrap();
+
+    let stored = &emulator.with_broker(|b| b.messages(QUEUE))[0];
+    assert_eq!(stored.user_property(PAYLOAD_ENCODING_PROPERTY), Some("gzip"));
+    assert!(stored.content.len() < msg.content.len() / 4);
+
+    let received = sb_client.receive_and_delete().await.unwrap().unwrap();
+    assert_eq!(received.content, msg.content);
+}
+
+#[tokio::test]
+async fn messages_from_before_a_key_rotation_can_still_be_read() {
+    let emulator = start_emulator().await;
+    let old_key = LocalKeyProvider::generate_key();
+    let new_key = LocalKeyProvider::generate_key();
+
+    let before = client(&emulator).with_encryption(provider("old", &[("old", &old_key)]));
+    before.send(&Message::new_json(&"before".to_string()).unwrap()).await.unwrap();
+
+    let after = client(&emulator).with_encryption(provider("new", &[("old", &old_key), ("new", &new_key)]));
+    after.send(&Message::new_json(&"after".to_string()).unwrap()).await.unwrap();
+
+    let key_ids: Vec<_> = emulator.with_broker(|b| b.messages(QUEUE)).iter()
+        .map(|m| m.user_property(ENCRYPTION_KEY_ID_PROPERTY).unwrap().to_string())
+        .collect();
+    assert_eq!(key_ids, vec!["old", "new"]);
+
+    assert_eq!(after.receive_and_delete().await.unwrap().unwrap().json_into::<String>().unwrap(), "before");
+    assert_eq!(after.receive_and_delete().await.unwrap().unwrap().json_into::<String>().unwrap(), "after");
+}
+
+#[tokio::test]
+async fn messages_that_cannot_be_decrypted_are_dead_lettered() {
+    let emulator = start_emulator().await;
+    let sender = client(&emulator).with_encryption(provider("k1", &[("k1", &LocalKeyProvider::generate_key())]));
+    let wrong_key = client(&emulator).with_encryption(provider("k1", &[("k1", &LocalKeyProvider::generate_key())]));
+
+    sender.send(&Message::new_json(&"one".to_string()).unwrap()).await.unwrap();
+
+    let err = wrong_key.peek_lock().await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::PayloadError(_)));
+
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    let dead_lettered = emulator.with_broker(|b| b.messages(&dead_letter_queue()));
+    assert_eq!(dead_lettered.len(), 1);
+    assert_eq!(dead_lettered[0].user_property(DEAD_LETTER_REASON_PROPERTY), Some("PayloadError"));
+
+    // The dead-lettered copies are forwarded still encrypted, so the owner of the key can read them.
+    assert_eq!(dead_lettered[0].user_property(ENCRYPTION_KEY_ID_PROPERTY), Some("k1"));
+}
+
+#[tokio::test]
+async fn messages_under_keys_not_rolled_out_yet_are_released() {
+    let emulator = start_emulator().await;
+    let key = LocalKeyProvider::generate_key();
+    let sender = client(&emulator).with_encryption(provider("new", &[("new", &key)]));
+    let not_rotated = client(&emulator).with_encryption(provider("old", &[("old", &LocalKeyProvider::generate_key())]));
+    let no_key = client(&emulator);
+
+    sender.send(&Message::new_json(&"after rotation".to_string()).unwrap()).await.unwrap();
+
+    let err = not_rotated.peek_lock().await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::PayloadUnavailable(_)));
+    let err = no_key.peek_lock().await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::PayloadUnavailable(_)));
+    assert_eq!(emulator.with_broker(|b| b.message_count(&dead_letter_queue())), 0);
+
+    // Still there for a consumer that has the key.
+    let rotated = client(&emulator).with_encryption(provider("new", &[("old", &LocalKeyProvider::generate_key()), ("new", &key)]));
+    let received = rotated.peek_lock().await.unwrap().unwrap();
+    assert_eq!(received.json_into::<String>().unwrap(), "after rotation");
+    assert_eq!(received.properties.delivery_count, Some(3));
+}
+
+#[tokio::test]
+async fn batches_are_refused_rather_than_sent_in_the_clear() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator).with_encryption(provider("k1", &[("k1", &LocalKeyProvider::generate_key())]));
+    assert!(!sb_client.supports_batch_send());
+
+    let err = sb_client.send_batch(&[Message::new_json(&"secret".to_string()).unwrap()]).await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::PayloadError(_)));
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
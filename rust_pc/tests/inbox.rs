@@ -0,0 +1,104 @@
+mod common;
+
+use std::rc::Rc;
+
+use chrono::Duration;
+use qexample::consumer::{self, ConsumerOptions, DrainStatus};
+use qexample::inbox::{InboxStore, MemoryInbox, SqliteInbox};
+use qexample::mazure::sbclient::Message;
+use qexample::messages::LogInfo;
+use qexample::poison::RETRY_ATTEMPT_PROPERTY;
+use qexample::shutdown::Shutdown;
+
+use common::{QUEUE, client, dead_letter_queue, start_emulator};
+
+#[tokio::test]
+async fn memory_inbox_forgets_the_least_recently_recorded() {
+    let inbox = MemoryInbox::new(2, Duration::hours(1));
+    inbox.record("a").await.unwrap();
+    inbox.record("b").await.unwrap();
+    inbox.record("a").await.unwrap();
+    inbox.record("c").await.unwrap();
+
+    assert!(inbox.contains("a").await.unwrap());
+    assert!(!inbox.contains("b").await.unwrap());
+    assert!(inbox.contains("c").await.unwrap());
+    assert_eq!(inbox.len(), 2);
+
+    let expired = MemoryInbox::new(10, Duration::zero());
+    expired.record("a").await.unwrap();
+    assert!(!expired.contains("a").await.unwrap());
+}
+
+#[tokio::test]
+async fn sqlite_inbox_survives_reopening_and_purges_old_ids() {
+    let path = std::env::temp_dir().join(format!("qexample-inbox-{}.db", uuid::Uuid::new_v4()));
+
+    SqliteInbox::open(&path, Duration::hours(1)).unwrap().record("a").await.unwrap();
+    let inbox = SqliteInbox::open(&path, Duration::hours(1)).unwrap();
+    assert!(inbox.contains("a").await.unwrap());
+    assert!(!inbox.contains("b").await.unwrap());
+    assert_eq!(inbox.purge().unwrap(), 0);
+
+    let short = SqliteInbox::open(&path, Duration::zero()).unwrap();
+    assert!(!short.contains("a").await.unwrap());
+    assert_eq!(short.purge().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn consumer_completes_processed_redeliveries_without_processing() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    sb_client.send_json(&LogInfo::new_random()).await.unwrap();
+    let message_id = emulator.with_broker(|b| b.messages(QUEUE))[0].properties.message_id.clone().unwrap();
+
+    let inbox = Rc::new(MemoryInbox::new(10, Duration::hours(1)));
+    inbox.record(&message_id).await.unwrap();
+    let options = ConsumerOptions { inbox: Some(inbox), ..ConsumerOptions::default() };
+
+    // Processing would fail the first attempt and abandon the message.
+    let (_trigger, shutdown) = Shutdown::new();
+    let status = consumer::run_consumer(&sb_client, &options, &shutdown).await.unwrap();
+    assert_eq!(status, DrainStatus::Clean);
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    assert_eq!(emulator.with_broker(|b| b.message_count(&dead_letter_queue())), 0);
+}
+
+#[tokio::test]
+async fn failed_processing_is_not_recorded() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    sb_client.send_json(&LogInfo::new_random()).await.unwrap();
+    let message_id = emulator.with_broker(|b| b.messages(QUEUE))[0].properties.message_id.clone().unwrap();
+
+    let inbox = Rc::new(MemoryInbox::new(10, Duration::hours(1)));
+    let options = ConsumerOptions { inbox: Some(inbox.clone()), ..ConsumerOptions::default() };
+
+    let (_trigger, shutdown) = Shutdown::new();
+    consumer::run_consumer(&sb_client, &options, &shutdown).await.unwrap();
+    assert!(!inbox.contains(&message_id).await.unwrap());
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+}
+
+#[tokio::test]
+async fn successful_processing_is_recorded_before_completing() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    // Past its first attempt, so the consumer processes it.
+    let mut message = Message::new_json(&LogInfo::new_random()).unwrap();
+    message.set_user_property(RETRY_ATTEMPT_PROPERTY, "1");
+    sb_client.send(&message).await.unwrap();
+    let message_id = emulator.with_broker(|b| b.messages(QUEUE))[0].properties.message_id.clone().unwrap();
+
+    let inbox = Rc::new(MemoryInbox::new(10, Duration::hours(1)));
+    let options = ConsumerOptions {
+        inbox: Some(inbox.clone()),
+        processing_time: std::time::Duration::ZERO,
+        ..ConsumerOptions::default()
+    };
+
+    let (_trigger, shutdown) = Shutdown::new();
+    consumer::run_consumer(&sb_client, &options, &shutdown).await.unwrap();
+    assert!(inbox.contains(&message_id).await.unwrap());
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
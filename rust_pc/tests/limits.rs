@@ -0,0 +1,96 @@
+mod common;
+
+use qexample::mazure::compression::{CompressionAlgorithm, CompressionOptions};
+use qexample::mazure::limits::{ServiceTier, SizeLimits, batched_size, message_size, split_batch};
+use qexample::mazure::sbclient::{AzureServiceBusError, BatchedMessage, BrokerSendProperties, Message};
+
+use common::{QUEUE, client, start_emulator};
+
+fn text_of_size(size: usize) -> Message<BrokerSendProperties> {
+    Message {
+        properties: BrokerSendProperties::new_empty(),
+        content: vec![b'x'; size],
+        content_type: "text/plain".into(),
+        user_properties: Default::default(),
+    }
+}
+
+#[tokio::test]
+async fn oversized_messages_fail_before_any_network_io() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    emulator.stop().await.unwrap();
+
+    let err = sb_client.send(&text_of_size(300 * 1024)).await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::MessageTooLarge { limit, .. } if limit == 256 * 1024));
+}
+
+#[tokio::test]
+async fn property_headers_count_towards_the_limit() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    let mut msg = text_of_size(256 * 1024 - 200);
+    sb_client.send(&msg).await.unwrap();
+
+    msg.set_user_property("description", "d".repeat(200));
+    let size = message_size(&msg).unwrap();
+    assert!(size > 256 * 1024);
+    let err = sb_client.send(&msg).await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::MessageTooLarge { size: s, .. } if s > size));
+
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+}
+
+#[tokio::test]
+async fn limits_are_checked_after_compression() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator)
+        .with_compression(CompressionOptions { algorithm: Some(CompressionAlgorithm::Gzip), ..CompressionOptions::default() });
+
+    sb_client.send(&text_of_size(1024 * 1024)).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+}
+
+#[tokio::test]
+async fn premium_limits_are_configurable_up_to_100_mb() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator).with_size_limits(SizeLimits::premium(2 * 1024 * 1024).unwrap());
+
+    sb_client.send(&text_of_size(1024 * 1024)).await.unwrap();
+    assert_eq!(SizeLimits::for_tier(ServiceTier::Premium).max_message_size, 1024 * 1024);
+    assert!(matches!(SizeLimits::premium(200 * 1024 * 1024), Err(AzureServiceBusError::MessageTooLarge { .. })));
+}
+
+#[tokio::test]
+async fn large_batches_are_split_across_requests() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    let messages: Vec<_> = (0..10).map(|i| Message::new_json(&format!("{}{}", i, "y".repeat(60 * 1024))).unwrap()).collect();
+    let correlation_ids = sb_client.send_batch(&messages).await.unwrap();
+    assert_eq!(correlation_ids.len(), 10);
+
+    for i in 0..10 {
+        let msg = sb_client.receive_and_delete().await.unwrap().unwrap();
+        assert!(msg.json_into::<String>().unwrap().starts_with(&i.to_string()));
+    }
+}
+
+#[test]
+fn split_batch_keeps_each_request_within_the_limit() {
+    let entries: Vec<_> = (0..10)
+        .map(|_| BatchedMessage { body: "z".repeat(1000), broker_properties: BrokerSendProperties::new_empty(), user_properties: Default::default() })
+        .collect();
+
+    let batches = split_batch(entries.clone(), 4096).unwrap();
+    assert_eq!(batches.iter().map(|b| b.len()).sum::<usize>(), 10);
+    assert!(batches.len() >= 3);
+    assert!(batches.iter().all(|b| b.iter().map(|e| batched_size(e).unwrap()).sum::<usize>() <= 4096));
+
+    // An entry counts as much as the same message sent on its own.
+    let message = Message { content: entries[0].body.clone().into_bytes(), ..text_of_size(0) };
+    assert_eq!(batched_size(&entries[0]).unwrap(), message_size(&message).unwrap());
+
+    assert!(matches!(split_batch(entries, 500), Err(AzureServiceBusError::MessageTooLarge { .. })));
+}
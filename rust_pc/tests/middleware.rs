@@ -0,0 +1,168 @@
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use qexample::inbox::MemoryInbox;
+use qexample::mazure::sbclient::{AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message, DEAD_LETTER_REASON_PROPERTY};
+use qexample::middleware::{DedupMiddleware, LoggingMiddleware, Middleware, Next};
+use qexample::poison::ProcessingError;
+use qexample::router::{Dispatched, RouteKey, Router};
+use qexample::transport::QueueTransport;
+use qexample::typed::TypedSender;
+
+use common::{Order, QUEUE, client, dispatch_next, memory_queue, order, start_emulator};
+
+// Records when it is entered and left.
+struct Trace<'a> {
+    name: &'static str,
+    events: &'a RefCell<Vec<String>>,
+}
+
+#[async_trait(?Send)]
+impl<'a> Middleware for Trace<'a> {
+    async fn handle(&self, message: &Message<BrokerReceiveProperties>, next: Next<'_>) -> Result<(), ProcessingError> {
+        self.events.borrow_mut().push(format!("{} before", self.name));
+        let result = next.run(message).await;
+        self.events.borrow_mut().push(format!("{} after", self.name));
+        result
+    }
+}
+
+// Refuses messages without a tenant.
+struct RequireTenant;
+
+#[async_trait(?Send)]
+impl Middleware for RequireTenant {
+    async fn handle(&self, message: &Message<BrokerReceiveProperties>, next: Next<'_>) -> Result<(), ProcessingError> {
+        if message.user_property("tenant").is_none() {
+            return Err(ProcessingError::permanent("No tenant"));
+        }
+        next.run(message).await
+    }
+}
+
+// Decodes base64 bodies for the handlers after it.
+struct Base64Body;
+
+#[async_trait(?Send)]
+impl Middleware for Base64Body {
+    async fn handle(&self, message: &Message<BrokerReceiveProperties>, next: Next<'_>) -> Result<(), ProcessingError> {
+        let content = BASE64.decode(&message.content).map_err(|e| ProcessingError::decode(e.to_string()))?;
+        next.run(&Message { content, ..message.clone() }).await
+    }
+}
+
+#[tokio::test]
+async fn middleware_wraps_handlers_in_order() {
+    let queue = memory_queue();
+    TypedSender::<Order>::new(&queue).send(&order(1)).await.unwrap();
+
+    let events = &RefCell::new(Vec::new());
+    let router = Router::new(&queue, RouteKey::MessageType)
+        .with_middleware(Trace { name: "outer", events })
+        .with_middleware(LoggingMiddleware)
+        .with_middleware(Trace { name: "inner", events })
+        .with_type(|order: Order| async move { events.borrow_mut().push(format!("order {}", order.id)); Ok(()) });
+
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Handled);
+    assert_eq!(*events.borrow(), vec!["outer before", "inner before", "order 1", "inner after", "outer after"]);
+    assert_eq!(queue.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn middleware_can_refuse_messages() {
+    let queue = memory_queue();
+    TypedSender::<Order>::new(&queue).send(&order(2)).await.unwrap();
+
+    let handled = &RefCell::new(0);
+    let router = Router::new(&queue, RouteKey::MessageType)
+        .with_middleware(RequireTenant)
+        .with_type(|_: Order| async move { *handled.borrow_mut() += 1; Ok(()) });
+
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Failed);
+    assert_eq!(*handled.borrow(), 0);
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    assert_eq!(dead.user_property(DEAD_LETTER_REASON_PROPERTY), Some("PermanentError"));
+}
+
+#[tokio::test]
+async fn middleware_can_change_the_message() {
+    let queue = memory_queue();
+    let mut message = Message::new_json(&order(3)).unwrap();
+    message.content = BASE64.encode(&message.content).into_bytes();
+    message.properties.label = Some("order".into());
+    queue.send(&message).await.unwrap();
+
+    let orders = &RefCell::new(Vec::new());
+    let router = Router::new(&queue, RouteKey::Label)
+        .with_middleware(Base64Body)
+        .with_route("order", |order: Order| async move { orders.borrow_mut().push(order.id); Ok(()) });
+
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Handled);
+    assert_eq!(*orders.borrow(), vec![3]);
+}
+
+#[tokio::test]
+async fn dedup_middleware_skips_processed_messages() {
+    let queue = memory_queue();
+    let sender = TypedSender::<Order>::new(&queue);
+    for _ in 0..2 {
+        let mut message = sender.message(&order(4)).unwrap();
+        message.properties.message_id = Some("order-4".into());
+        queue.send(&message).await.unwrap();
+    }
+
+    let inbox = Rc::new(MemoryInbox::new(10, chrono::Duration::hours(1)));
+    let handled = &RefCell::new(0);
+    let router = Router::new(&queue, RouteKey::MessageType)
+        .with_middleware(DedupMiddleware::new(inbox.clone()))
+        .with_type(|_: Order| async move { *handled.borrow_mut() += 1; Ok(()) });
+
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Handled);
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Handled);
+    assert_eq!(*handled.borrow(), 1);
+    assert_eq!(inbox.len(), 1);
+    assert_eq!(queue.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn send_interceptors_enrich_messages() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator)
+        .with_send_interceptor(Box::new(|message: &mut Message<BrokerSendProperties>| {
+            message.set_user_property("tenant", "contoso");
+            message.set_user_property("trace", "first");
+            Ok(())
+        }))
+        .with_send_interceptor(Box::new(|message: &mut Message<BrokerSendProperties>| {
+            let trace = format!("{},second", message.user_property("trace").unwrap_or_default());
+            message.set_user_property("trace", trace);
+            Ok(())
+        }));
+
+    sb_client.send(&Message::new_json(&order(5)).unwrap()).await.unwrap();
+    sb_client.send_batch(&[Message::new_json(&order(6)).unwrap()]).await.unwrap();
+
+    for message in emulator.with_broker(|b| b.messages(QUEUE)) {
+        assert_eq!(message.user_property("tenant"), Some("contoso"));
+        assert_eq!(message.user_property("trace"), Some("first,second"));
+    }
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 2);
+}
+
+#[tokio::test]
+async fn failing_interceptors_abort_the_send() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator).with_send_interceptor(Box::new(|_: &mut Message<BrokerSendProperties>| {
+        Err(AzureServiceBusError::ConversionError("No tenant".into()))
+    }));
+
+    let error = sb_client.send(&Message::new_json(&order(7)).unwrap()).await.unwrap_err();
+    assert!(matches!(error, AzureServiceBusError::ConversionError(_)));
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
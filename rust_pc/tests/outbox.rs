@@ -0,0 +1,163 @@
+mod common;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use qexample::emulator::{Emulator, EmulatorConfig};
+use qexample::transport::broker::BrokerConfig;
+use qexample::mazure::encryption::{ENCRYPTION_KEY_ID_PROPERTY, LocalKeyProvider};
+use qexample::mazure::sbclient::{AzureServiceBusClient, Message};
+use qexample::outbox::{self, OutboxRelay, OutboxStatus, RelayStats};
+use rusqlite::Connection;
+
+use common::{QUEUE, client, credentials, start_emulator};
+
+fn outbox_path() -> PathBuf {
+    std::env::temp_dir().join(format!("qexample-outbox-{}.db", uuid::Uuid::new_v4()))
+}
+
+fn open(path: &PathBuf) -> Connection {
+    let conn = Connection::open(path).unwrap();
+    outbox::init(&conn).unwrap();
+    conn
+}
+
+fn relay<'a>(path: &PathBuf, sb_client: &'a AzureServiceBusClient) -> OutboxRelay<'a> {
+    OutboxRelay::new(Connection::open(path).unwrap(), sb_client).unwrap()
+        .with_backoff(Duration::ZERO, Duration::ZERO)
+}
+
+fn enqueue(conn: &Connection, body: &str, key: Option<&str>) -> i64 {
+    outbox::enqueue(conn, &Message::new_json(&body.to_string()).unwrap(), key).unwrap()
+}
+
+async fn queued_bodies(sb_client: &AzureServiceBusClient) -> Vec<String> {
+    let mut bodies = Vec::new();
+    while let Some(msg) = sb_client.receive_and_delete().await.unwrap() {
+        bodies.push(msg.json_into::<String>().unwrap());
+    }
+    bodies
+}
+
+#[tokio::test]
+async fn only_committed_rows_are_relayed() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let path = outbox_path();
+    let mut conn = open(&path);
+
+    let tx = conn.transaction().unwrap();
+    let id = enqueue(&tx, "committed", None);
+    tx.commit().unwrap();
+
+    let tx = conn.transaction().unwrap();
+    enqueue(&tx, "rolled back", None);
+    tx.rollback().unwrap();
+
+    let relay = relay(&path, &sb_client);
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats { dispatched: 1, failed: 0 });
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats::default());
+
+    let row = outbox::get(&conn, id).unwrap().unwrap();
+    assert_eq!(row.status, OutboxStatus::Dispatched);
+    let msg = sb_client.receive_and_delete().await.unwrap().unwrap();
+    assert_eq!(msg.properties.message_id.as_deref(), Some(row.message_id.as_str()));
+    assert_eq!(msg.json_into::<String>().unwrap(), "committed");
+    assert!(sb_client.receive_and_delete().await.unwrap().is_none());
+
+    assert_eq!(outbox::purge_dispatched(&conn, chrono::Utc::now()).unwrap(), 1);
+}
+
+#[tokio::test]
+async fn retries_keep_per_key_order_without_duplicates() {
+    let config = EmulatorConfig {
+        broker: BrokerConfig { duplicate_detection_window: Some(chrono::Duration::minutes(10)), ..BrokerConfig::default() },
+        credentials: Some(credentials()),
+        ..EmulatorConfig::default()
+    };
+    let emulator = Emulator::start("127.0.0.1:0".parse().unwrap(), config).await.unwrap();
+    let sb_client = client(&emulator);
+    let path = outbox_path();
+    let conn = open(&path);
+
+    enqueue(&conn, "k1", Some("k"));
+    enqueue(&conn, "k2", Some("k"));
+    enqueue(&conn, "x1", None);
+
+    // The first send reaches the queue but its response is lost.
+    emulator.lose_send_responses(1);
+    let relay = relay(&path, &sb_client);
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats { dispatched: 1, failed: 1 });
+    assert_eq!(outbox::list(&conn, Some(OutboxStatus::Pending)).unwrap().len(), 2);
+
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats { dispatched: 2, failed: 0 });
+    assert_eq!(queued_bodies(&sb_client).await, vec!["k1", "x1", "k2"]);
+}
+
+#[tokio::test]
+async fn failed_rows_hold_back_their_key_until_requeued() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let path = outbox_path();
+    let conn = open(&path);
+
+    let stuck = enqueue(&conn, "a1", Some("a"));
+    enqueue(&conn, "a2", Some("a"));
+    enqueue(&conn, "b1", Some("b"));
+
+    let stopped = start_emulator().await;
+    let unreachable = client(&stopped);
+    stopped.stop().await.unwrap();
+    let failing = relay(&path, &unreachable).with_max_attempts(1);
+    assert_eq!(failing.relay_once().await.unwrap(), RelayStats { dispatched: 0, failed: 2 });
+
+    let failed = outbox::list(&conn, Some(OutboxStatus::Failed)).unwrap();
+    assert_eq!(failed.iter().map(|r| r.id).collect::<Vec<_>>(), vec![stuck, stuck + 2]);
+    assert!(failed[0].last_error.is_some());
+
+    let relay = relay(&path, &sb_client);
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats::default());
+
+    assert_eq!(outbox::requeue(&conn, &[stuck]).unwrap(), 1);
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats { dispatched: 2, failed: 0 });
+    assert_eq!(outbox::requeue(&conn, &[]).unwrap(), 1);
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats { dispatched: 1, failed: 0 });
+    assert_eq!(queued_bodies(&sb_client).await, vec!["a1", "a2", "b1"]);
+}
+
+#[tokio::test]
+async fn batch_sends_relay_a_round_at_once() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let path = outbox_path();
+    let conn = open(&path);
+
+    for i in 0..5 {
+        enqueue(&conn, &format!("m{}", i), Some("k"));
+    }
+
+    let relay = relay(&path, &sb_client).with_batch_send(true).with_batch_size(3);
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats { dispatched: 3, failed: 0 });
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats { dispatched: 2, failed: 0 });
+    assert_eq!(queued_bodies(&sb_client).await, vec!["m0", "m1", "m2", "m3", "m4"]);
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn encrypting_clients_relay_one_row_at_a_time() {
+    let emulator = start_emulator().await;
+    let keys = HashMap::from([("k1".to_string(), LocalKeyProvider::generate_key())]);
+    let sb_client = client(&emulator).with_encryption(Box::new(LocalKeyProvider::new("k1", keys).unwrap()));
+    let path = outbox_path();
+    let conn = open(&path);
+
+    enqueue(&conn, "m0", None);
+    enqueue(&conn, "m1", None);
+
+    let relay = relay(&path, &sb_client).with_batch_send(true);
+    assert_eq!(relay.relay_once().await.unwrap(), RelayStats { dispatched: 2, failed: 0 });
+    let stored = emulator.with_broker(|b| b.messages(QUEUE));
+    assert!(stored.iter().all(|m| m.user_property(ENCRYPTION_KEY_ID_PROPERTY) == Some("k1")));
+    assert_eq!(queued_bodies(&sb_client).await, vec!["m0", "m1"]);
+}
@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use qexample::mazure::sbclient::{BrokerReceiveProperties, Message};
+use qexample::poison::{
+    ErrorClass, PoisonDecision, PoisonPolicy, ProcessingError, RetryMetadata, RetryStrategy, RETRY_ATTEMPT_PROPERTY
+};
+
+fn attempt(attempt: u32) -> RetryMetadata {
+    RetryMetadata { attempt, first_failure_utc: None, last_error: None }
+}
+
+fn backoff_policy() -> PoisonPolicy {
+    PoisonPolicy {
+        retry: RetryStrategy::Backoff { initial: Duration::from_secs(5), max: Duration::from_secs(60) },
+        max_attempts: 10,
+        ..PoisonPolicy::default()
+    }
+}
+
+fn received(user_properties: BTreeMap<String, String>, delivery_count: i32) -> Message<BrokerReceiveProperties> {
+    let mut properties = BrokerReceiveProperties::new_empty();
+    properties.delivery_count = Some(delivery_count);
+    Message { properties, content: Vec::new(), content_type: "application/json".into(), user_properties }
+}
+
+#[test]
+fn failures_are_abandoned_until_max_attempts() {
+    let policy = PoisonPolicy { max_attempts: 3, ..PoisonPolicy::default() };
+    let error = ProcessingError::transient("busy");
+
+    assert_eq!(policy.decide(&attempt(2), &error, Utc::now()), PoisonDecision::Abandon);
+    match policy.decide(&attempt(3), &error, Utc::now()) {
+        PoisonDecision::DeadLetter { reason, description } => {
+            assert_eq!(reason, "MaxAttemptsExceeded");
+            assert!(description.contains("busy"), "{}", description);
+        },
+        other => panic!("Expected a dead-letter, got {:?}", other),
+    }
+}
+
+#[test]
+fn listed_error_classes_are_dead_lettered_at_once() {
+    let policy = PoisonPolicy::default();
+
+    let decision = policy.decide(&attempt(1), &ProcessingError::permanent("no such customer"), Utc::now());
+    assert_eq!(decision, PoisonDecision::DeadLetter { reason: "PermanentError".into(), description: "no such customer".into() });
+    let decision = policy.decide(&attempt(1), &ProcessingError::decode("not json"), Utc::now());
+    assert!(matches!(decision, PoisonDecision::DeadLetter { reason, .. } if reason == "DecodeError"));
+
+    let lenient = PoisonPolicy { dead_letter_classes: vec![ErrorClass::Decode], ..PoisonPolicy::default() };
+    assert_eq!(lenient.decide(&attempt(1), &ProcessingError::permanent("no such customer"), Utc::now()), PoisonDecision::Abandon);
+}
+
+#[test]
+fn backoff_doubles_up_to_the_cap() {
+    let policy = backoff_policy();
+    let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    let error = ProcessingError::transient("busy");
+
+    let delays: Vec<i64> = (1..=6)
+        .map(|n| match policy.decide(&attempt(n), &error, now) {
+            PoisonDecision::Reschedule { at } => (at - now).num_seconds(),
+            other => panic!("Expected a reschedule, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(delays, vec![5, 10, 20, 40, 60, 60]);
+}
+
+#[test]
+fn backoff_past_the_end_of_time_is_clamped() {
+    let policy = PoisonPolicy {
+        retry: RetryStrategy::Backoff { initial: Duration::MAX, max: Duration::MAX },
+        max_attempts: 100,
+        ..PoisonPolicy::default()
+    };
+
+    let decision = policy.decide(&attempt(1), &ProcessingError::transient("busy"), Utc::now());
+    assert_eq!(decision, PoisonDecision::Reschedule { at: DateTime::<Utc>::MAX_UTC });
+}
+
+#[test]
+fn retry_metadata_survives_a_reschedule() {
+    let first_failure = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+    let error = ProcessingError::transient("busy");
+
+    // First delivery fails, and the rescheduled copy carries the bookkeeping.
+    let mut retry = RetryMetadata::from_message(&received(BTreeMap::new(), 1));
+    assert_eq!(retry, attempt(1));
+    retry.record_failure(&error, first_failure);
+    let mut user_properties = BTreeMap::new();
+    retry.write_to(&mut user_properties);
+    assert_eq!(user_properties.get(RETRY_ATTEMPT_PROPERTY).map(String::as_str), Some("1"));
+
+    // The copy is a new message, so its delivery count starts over.
+    let mut copy = RetryMetadata::from_message(&received(user_properties.clone(), 1));
+    assert_eq!(copy.attempt, 2);
+    assert_eq!(copy.first_failure_utc, Some(first_failure));
+    assert_eq!(copy.last_error, Some(error.to_string()));
+
+    // Later failures keep the first failure time.
+    copy.record_failure(&ProcessingError::transient("still busy"), first_failure + chrono::Duration::minutes(5));
+    assert_eq!(copy.first_failure_utc, Some(first_failure));
+
+    // Redeliveries of the copy add to the carried attempts.
+    assert_eq!(RetryMetadata::from_message(&received(user_properties, 3)).attempt, 4);
+}
@@ -0,0 +1,188 @@
+mod common;
+
+use qexample::consumer::{self, ConsumerOptions, DrainStatus};
+use qexample::mazure::aadclient::{AADClient, AADCredentials};
+use qexample::mazure::client_authentication::AuthenticationError;
+use qexample::mazure::codec::Format;
+use qexample::mazure::sbclient::{
+    AzureServiceBusClient, AzureServiceBusError, Message, DEAD_LETTER_REASON_PROPERTY, SERVICE_BUS_RESOURCE
+};
+use qexample::producer;
+use qexample::shutdown::Shutdown;
+
+use common::{QUEUE, aad_client, client, client_for, credentials, dead_letter_queue, start_emulator};
+
+async fn send_text(sb_client: &AzureServiceBusClient, text: &str) {
+    let msg = Message::new_json(&text.to_string()).unwrap();
+    sb_client.send(&msg).await.unwrap();
+}
+
+#[tokio::test]
+async fn empty_queue_returns_none() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    assert!(sb_client.peek_lock().await.unwrap().is_none());
+    assert!(sb_client.receive_and_delete().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn producer_messages_are_delivered_at_scheduled_time() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    producer::run_producer(&sb_client, 2, Format::Json).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 2);
+
+    // Both messages are scheduled 15 seconds out.
+    assert!(sb_client.peek_lock().await.unwrap().is_none());
+
+    emulator.with_broker(|b| b.advance_clock(chrono::Duration::seconds(16)));
+
+    let first = sb_client.peek_lock().await.unwrap().expect("first message");
+    let second = sb_client.peek_lock().await.unwrap().expect("second message");
+    assert!(first.properties.scheduled_enqueue_time_utc.is_some());
+    assert!(first.properties.sequence_number < second.properties.sequence_number);
+    assert!(first.properties.correlation_id.is_some());
+}
+
+#[tokio::test]
+async fn unlocked_message_is_redelivered_with_incremented_delivery_count() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    send_text(&sb_client, "redeliver me").await;
+
+    let first = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(first.properties.delivery_count, Some(1));
+
+    // Locked messages are not handed out again.
+    assert!(sb_client.peek_lock().await.unwrap().is_none());
+
+    sb_client.unlock_message(&first.properties).await.unwrap();
+
+    let second = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(second.properties.delivery_count, Some(2));
+    assert_eq!(second.properties.message_id, first.properties.message_id);
+    assert_ne!(second.properties.lock_token, first.properties.lock_token);
+    assert_eq!(second.json_into::<String>().unwrap(), "redeliver me");
+}
+
+#[tokio::test]
+async fn expired_lock_makes_message_available_again() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    send_text(&sb_client, "slow").await;
+
+    let first = sb_client.peek_lock().await.unwrap().unwrap();
+    emulator.with_broker(|b| b.advance_clock(chrono::Duration::seconds(61)));
+
+    let second = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(second.properties.delivery_count, Some(2));
+
+    // The first lock is gone, so settling with it fails.
+    let err = sb_client.delete_message(&first.properties).await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::RequestError(ref status) if status == "410"));
+}
+
+#[tokio::test]
+async fn renew_lock_extends_locked_until() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    send_text(&sb_client, "long running").await;
+
+    let msg = sb_client.peek_lock().await.unwrap().unwrap();
+    let locked_until = msg.properties.locked_until_utc.unwrap();
+
+    emulator.with_broker(|b| b.advance_clock(chrono::Duration::seconds(30)));
+    sb_client.renew_lock(&msg.properties).await.unwrap();
+
+    let renewed_until = emulator.with_broker(|b| b.messages(QUEUE))[0].properties.locked_until_utc.unwrap();
+    assert!(renewed_until >= locked_until + chrono::Duration::seconds(29));
+
+    // Without the renewal the lock would have expired by now.
+    emulator.with_broker(|b| b.advance_clock(chrono::Duration::seconds(45)));
+    sb_client.delete_message(&msg.properties).await.unwrap();
+}
+
+#[tokio::test]
+async fn delete_message_completes_it() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    send_text(&sb_client, "done").await;
+
+    let msg = sb_client.peek_lock().await.unwrap().unwrap();
+    sb_client.delete_message(&msg.properties).await.unwrap();
+
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    assert!(sb_client.peek_lock().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn batch_send_and_user_properties_round_trip() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    let mut first = Message::new_json(&"one".to_string()).unwrap();
+    first.set_user_property("Tenant", "contoso");
+    let second = Message::new_json(&"two".to_string()).unwrap();
+    sb_client.send_batch(&[first, second]).await.unwrap();
+
+    let msg = sb_client.receive_and_delete().await.unwrap().unwrap();
+    assert_eq!(msg.json_into::<String>().unwrap(), "one");
+    assert_eq!(msg.user_property("tenant"), Some("contoso"));
+
+    let msg = sb_client.receive_and_delete().await.unwrap().unwrap();
+    assert_eq!(msg.json_into::<String>().unwrap(), "two");
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn undecodable_message_is_dead_lettered_by_consumer() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    send_text(&sb_client, "not a LogInfo").await;
+
+    let (_trigger, shutdown) = Shutdown::new();
+    let status = consumer::run_consumer(&sb_client, &ConsumerOptions::default(), &shutdown).await.unwrap();
+    assert_eq!(status, DrainStatus::Clean);
+
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    let dead_lettered = emulator.with_broker(|b| b.messages(&dead_letter_queue()));
+    assert_eq!(dead_lettered.len(), 1);
+    assert_eq!(dead_lettered[0].user_property(DEAD_LETTER_REASON_PROPERTY), Some("DecodeError"));
+}
+
+#[tokio::test]
+async fn bad_credentials_fail_token_acquisition() {
+    let emulator = start_emulator().await;
+    let wrong = AADCredentials::new("tenant", "client", "wrong secret");
+
+    let err = aad_client(&emulator, wrong.clone()).get_token().await.unwrap_err();
+    assert!(matches!(err, AuthenticationError::AuthenticationAcquisitionError(_)));
+
+    let err = client_for(&emulator, QUEUE, wrong).peek_lock().await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::AuthenticationError(_)));
+}
+
+#[tokio::test]
+async fn unreachable_token_endpoint_is_a_communication_error() {
+    let emulator = start_emulator().await;
+    let endpoint = emulator.endpoint();
+    emulator.stop().await.unwrap();
+
+    let aad_client = AADClient::new(reqwest::Client::new(), credentials(), SERVICE_BUS_RESOURCE, Some(&endpoint));
+    let err = aad_client.get_token().await.unwrap_err();
+    assert!(matches!(err, AuthenticationError::CommunicationError(_)));
+}
+
+#[tokio::test]
+async fn revoked_token_is_rejected_by_service_bus() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    assert!(sb_client.peek_lock().await.unwrap().is_none());
+
+    // The client keeps using its cached token, which the service no longer accepts.
+    emulator.revoke_tokens();
+    let err = sb_client.peek_lock().await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::RequestError(ref status) if status == "401"));
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use qexample::mazure::sbclient::{AzureServiceBusError, Message, DEAD_LETTER_REASON_PROPERTY};
+use qexample::received::{DroppedLocks, ReceivedMessage, UnsettledAction};
+use qexample::streaming::MessageStream;
+use qexample::transport::QueueTransport;
+
+use common::{QUEUE, client, dead_letter_queue, queue_locking_for, start_emulator};
+
+fn assert_status(result: Result<(), AzureServiceBusError>, expected: &str) {
+    match result {
+        Err(AzureServiceBusError::RequestError(status)) => assert_eq!(status, expected),
+        other => panic!("Expected status {}, got {:?}", expected, other),
+    }
+}
+
+#[tokio::test]
+async fn settles_through_the_handle() {
+    let queue = queue_locking_for(chrono::Duration::seconds(60), &[1, 2, 3]).await;
+
+    let first = ReceivedMessage::receive(&queue).await.unwrap().unwrap();
+    assert_eq!(first.message().json_into::<i32>().unwrap(), 1);
+    assert!(!first.is_lock_expired());
+    first.abandon().await.unwrap();
+
+    let first = ReceivedMessage::receive(&queue).await.unwrap().unwrap();
+    assert_eq!(first.message().properties.delivery_count, Some(2));
+    first.complete().await.unwrap();
+
+    ReceivedMessage::receive(&queue).await.unwrap().unwrap().dead_letter("Poison", "Cannot be processed").await.unwrap();
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    assert_eq!(dead.user_property(DEAD_LETTER_REASON_PROPERTY), Some("Poison"));
+
+    let third = ReceivedMessage::receive(&queue).await.unwrap().unwrap().into_message();
+    assert!(ReceivedMessage::receive(&queue).await.unwrap().is_none());
+    queue.complete(&third).await.unwrap();
+}
+
+#[tokio::test]
+async fn renewing_moves_the_lock_along() {
+    let queue = queue_locking_for(chrono::Duration::milliseconds(200), &[1]).await;
+    let mut received = ReceivedMessage::receive(&queue).await.unwrap().unwrap();
+    let first_lock = received.locked_until_utc().unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    received.renew_lock().await.unwrap();
+    assert!(received.locked_until_utc().unwrap() > first_lock);
+
+    // Past the first lock but within the renewed one.
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(!received.is_lock_expired());
+    received.complete().await.unwrap();
+    assert_eq!(queue.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn expired_locks_are_refused() {
+    let queue = queue_locking_for(chrono::Duration::milliseconds(50), &[1, 2]).await;
+    let mut first = ReceivedMessage::receive(&queue).await.unwrap().unwrap();
+    let second = ReceivedMessage::receive(&queue).await.unwrap().unwrap();
+
+    tokio::time::sleep(Duration::from_millis(80)).await;
+    assert!(first.is_lock_expired());
+    assert_status(first.renew_lock().await, "410");
+    assert_status(first.complete().await, "410");
+    assert_status(second.dead_letter("Poison", "Too late").await, "410");
+
+    assert_eq!(queue.with_broker(|b| b.message_count(QUEUE)), 2);
+    assert_eq!(queue.with_broker(|b| b.message_count(&dead_letter_queue())), 0);
+}
+
+#[tokio::test]
+async fn dropped_messages_stay_locked_or_are_abandoned() {
+    let queue = queue_locking_for(chrono::Duration::seconds(60), &[1, 2]).await;
+
+    drop(ReceivedMessage::receive(&queue).await.unwrap().unwrap());
+    let dropped = DroppedLocks::new();
+    let second = ReceivedMessage::receive(&queue).await.unwrap().unwrap()
+        .with_unsettled_action(UnsettledAction::Abandon(dropped.clone()));
+    assert!(dropped.is_empty());
+    drop(second);
+    assert_eq!(dropped.len(), 1);
+    assert!(queue.receive().await.unwrap().is_none());
+
+    assert_eq!(dropped.abandon_all(&queue).await, 1);
+    let again = queue.receive().await.unwrap().unwrap();
+    assert_eq!(again.json_into::<i32>().unwrap(), 2);
+    assert_eq!(again.properties.delivery_count, Some(2));
+}
+
+#[tokio::test]
+async fn streams_abandon_dropped_messages_before_receiving() {
+    let queue = queue_locking_for(chrono::Duration::seconds(60), &[1, 2]).await;
+    let mut stream = MessageStream::new(&queue).with_unsettled_action(UnsettledAction::Abandon(DroppedLocks::new()));
+
+    let first = stream.next().await.unwrap().unwrap();
+    assert_eq!(first.message().json_into::<i32>().unwrap(), 1);
+    drop(first);
+
+    let again = stream.next().await.unwrap().unwrap();
+    assert_eq!(again.message().json_into::<i32>().unwrap(), 1);
+    assert_eq!(again.message().properties.delivery_count, Some(2));
+    again.complete().await.unwrap();
+}
+
+#[tokio::test]
+async fn tracks_the_lock_over_rest() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    sb_client.send(&Message::new_json(&1).unwrap()).await.unwrap();
+
+    let mut received = ReceivedMessage::receive(&sb_client).await.unwrap().unwrap();
+    let first_lock = received.locked_until_utc().unwrap();
+    received.renew_lock().await.unwrap();
+    assert!(received.locked_until_utc().unwrap() >= first_lock);
+    received.complete().await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use std::time::Duration;
+
+use qexample::transport::broker::DEAD_LETTER_QUEUE_SUFFIX;
+use qexample::mazure::requestreply::{Requester, Responder, REPLY_DEADLINE_PROPERTY, UNEXPECTED_SESSION_REASON};
+use qexample::mazure::sbclient::{AzureServiceBusClient, AzureServiceBusError, BrokerReceiveProperties, Message, DEAD_LETTER_REASON_PROPERTY};
+use serde::{Deserialize, Serialize};
+
+use common::{QUEUE, client, client_for, credentials, start_emulator};
+
+const REPLIES: &str = "replies";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Add {
+    a: i32,
+    b: i32,
+}
+
+async fn add(request: Add) -> Result<i32, AzureServiceBusError> {
+    Ok(request.a + request.b)
+}
+
+// Answers one request, waiting for it to arrive.
+async fn respond_once(responder: &Responder<'_>) {
+    while !responder.respond(add).await.unwrap() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+fn requester<'a>(requests: &'a AzureServiceBusClient, replies: &'a AzureServiceBusClient) -> Requester<'a> {
+    Requester::new(requests, replies).with_poll_interval(Duration::from_millis(20))
+}
+
+#[tokio::test]
+async fn requests_get_their_correlated_reply() {
+    let emulator = start_emulator().await;
+    let requests = client(&emulator);
+    let replies = client_for(&emulator, REPLIES, credentials());
+    let mut requester = requester(&requests, &replies);
+    let responder = Responder::new(&requests);
+
+    let (sum, _) = tokio::join!(
+        requester.request::<Add, i32>(&Add { a: 2, b: 3 }, Duration::from_secs(5)),
+        respond_once(&responder),
+    );
+    assert_eq!(sum.unwrap(), 5);
+
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    assert_eq!(emulator.with_broker(|b| b.message_count(REPLIES)), 0);
+}
+
+#[tokio::test]
+async fn replies_carry_the_request_correlation_and_session() {
+    let emulator = start_emulator().await;
+    let requests = client(&emulator);
+    let replies = client_for(&emulator, REPLIES, credentials());
+    let requester = requester(&requests, &replies).with_session_id("requester-1");
+
+    let mut msg = Message::new_json(&Add { a: 1, b: 1 }).unwrap();
+    msg.properties.correlation_id = Some("order-1".into());
+    msg.properties.reply_to = Some(REPLIES.into());
+    msg.properties.reply_to_session_id = Some(requester.session_id().to_string());
+    requests.send(&msg).await.unwrap();
+
+    assert!(Responder::new(&requests).respond(add).await.unwrap());
+
+    let reply = replies.receive_and_delete().await.unwrap().unwrap();
+    assert_eq!(reply.properties.correlation_id.as_deref(), Some("order-1"));
+    assert_eq!(reply.properties.session_id.as_deref(), Some("requester-1"));
+    assert_eq!(reply.json_into::<i32>().unwrap(), 2);
+}
+
+#[tokio::test]
+async fn late_replies_are_discarded() {
+    let emulator = start_emulator().await;
+    let requests = client(&emulator);
+    let replies = client_for(&emulator, REPLIES, credentials());
+    let mut requester = requester(&requests, &replies);
+    let responder = Responder::new(&requests);
+
+    let correlation_id = match requester.request::<Add, i32>(&Add { a: 1, b: 2 }, Duration::from_millis(100)).await {
+        Err(AzureServiceBusError::ReplyTimeout(correlation_id)) => correlation_id,
+        other => panic!("Expected a timeout, got {:?}", other),
+    };
+
+    // Nobody answers before the request expires from the queue.
+    emulator.with_broker(|b| b.advance_clock(chrono::Duration::seconds(2)));
+    assert!(!responder.respond(add).await.unwrap());
+
+    // A responder that took the request in time replies after the requester gave up,
+    // then a new request goes out.
+    let mut late = Message::new_json(&3).unwrap();
+    late.properties.correlation_id = Some(correlation_id);
+    late.properties.session_id = Some(requester.session_id().to_string());
+    replies.send(&late).await.unwrap();
+
+    let (sum, _) = tokio::join!(
+        requester.request::<Add, i32>(&Add { a: 4, b: 4 }, Duration::from_secs(5)),
+        respond_once(&responder),
+    );
+    assert_eq!(sum.unwrap(), 8);
+    assert_eq!(requester.late_replies(), 1);
+    assert_eq!(emulator.with_broker(|b| b.message_count(REPLIES)), 0);
+}
+
+#[tokio::test]
+async fn replies_for_other_sessions_are_set_aside() {
+    let emulator = start_emulator().await;
+    let requests = client(&emulator);
+    let replies = client_for(&emulator, REPLIES, credentials());
+    let earlier = requester(&requests, &replies);
+    let mut requester = requester(&requests, &replies);
+    let responder = Responder::new(&requests);
+
+    // A reply for a requester that used the queue before.
+    let mut reply = Message::new_json(&2).unwrap();
+    reply.properties.correlation_id = Some("earlier".into());
+    reply.properties.session_id = Some(earlier.session_id().to_string());
+    replies.send(&reply).await.unwrap();
+
+    let (sum, _) = tokio::join!(
+        requester.request::<Add, i32>(&Add { a: 5, b: 6 }, Duration::from_secs(5)),
+        respond_once(&responder),
+    );
+    assert_eq!(sum.unwrap(), 11);
+    assert_eq!(requester.late_replies(), 0);
+
+    // Locked once and dead-lettered, not held and released over and over.
+    assert_eq!(emulator.with_broker(|b| b.message_count(REPLIES)), 0);
+    let dead = emulator.with_broker(|b| b.messages(&format!("{}{}", REPLIES, DEAD_LETTER_QUEUE_SUFFIX)));
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].properties.session_id.as_deref(), Some(earlier.session_id()));
+    assert_eq!(dead[0].user_property(DEAD_LETTER_REASON_PROPERTY), Some(UNEXPECTED_SESSION_REASON));
+}
+
+#[tokio::test]
+async fn expired_requests_are_not_replied_to() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let responder = Responder::new(&sb_client);
+    let reply = Message::new_json(&0).unwrap();
+
+    let mut request = Message {
+        properties: BrokerReceiveProperties::new_empty(),
+        content: vec![],
+        content_type: "application/json".into(),
+        user_properties: Default::default(),
+    };
+    request.properties.correlation_id = Some("c1".into());
+    assert!(matches!(responder.reply(&request, &reply).await, Err(AzureServiceBusError::ConversionError(_))));
+
+    request.properties.reply_to = Some(REPLIES.into());
+    request.properties.enqueued_time_utc = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+    request.properties.time_to_live = Some(5);
+    assert!(responder.reply(&request, &reply).await.unwrap().is_none());
+
+    request.properties.time_to_live = Some(60);
+    assert!(responder.reply(&request, &reply).await.unwrap().is_some());
+    let sent = emulator.with_broker(|b| b.messages(REPLIES));
+    assert_eq!(sent.len(), 1);
+    assert!(sent[0].properties.time_to_live.unwrap() <= 50);
+
+    // The requester's deadline wins over the time to live, to the millisecond.
+    let deadline = chrono::Utc::now() - chrono::Duration::milliseconds(1);
+    request.set_user_property(REPLY_DEADLINE_PROPERTY, deadline.to_rfc3339());
+    assert!(responder.reply(&request, &reply).await.unwrap().is_none());
+
+    let deadline = chrono::Utc::now() + chrono::Duration::milliseconds(1500);
+    request.set_user_property(REPLY_DEADLINE_PROPERTY, deadline.to_rfc3339());
+    assert!(responder.reply(&request, &reply).await.unwrap().is_some());
+    let sent = emulator.with_broker(|b| b.messages(REPLIES));
+    assert_eq!(sent[1].properties.time_to_live, Some(2));
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use qexample::mazure::sbclient::{BrokerReceiveProperties, Message, DEAD_LETTER_REASON_PROPERTY};
+use qexample::poison::ProcessingError;
+use qexample::router::{Dispatched, RouteKey, Router, UnroutedAction, UNROUTED_REASON};
+use qexample::shutdown::Shutdown;
+use qexample::transport::QueueTransport;
+use qexample::transport::memory::MemoryQueue;
+use qexample::typed::TypedSender;
+
+use common::{Invoice, Order, QUEUE, client, dispatch_next, memory_queue, order, start_emulator};
+
+async fn labelled<T: Serialize>(queue: &MemoryQueue, label: &str, body: &T) {
+    let mut message = Message::new_json(body).unwrap();
+    message.properties.label = Some(label.into());
+    queue.send(&message).await.unwrap();
+}
+
+async fn dead_reason(queue: &MemoryQueue) -> String {
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    dead.user_property(DEAD_LETTER_REASON_PROPERTY).unwrap().to_string()
+}
+
+#[tokio::test]
+async fn routes_by_message_type() {
+    let queue = memory_queue();
+    TypedSender::<Order>::new(&queue).send(&order(1)).await.unwrap();
+    TypedSender::<Invoice>::new(&queue).send(&Invoice { order_id: 1, total: 9.5 }).await.unwrap();
+    TypedSender::<Order>::new(&queue).send(&order(2)).await.unwrap();
+
+    let (orders, invoices) = (&RefCell::new(Vec::new()), &RefCell::new(Vec::new()));
+    let router = Router::new(&queue, RouteKey::MessageType)
+        .with_type(|order: Order| async move { orders.borrow_mut().push(order.id); Ok(()) })
+        .with_type(|invoice: Invoice| async move { invoices.borrow_mut().push(invoice.total); Ok(()) });
+
+    for _ in 0..3 {
+        assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Handled);
+    }
+    assert_eq!(*orders.borrow(), vec![1, 2]);
+    assert_eq!(*invoices.borrow(), vec![9.5]);
+    assert_eq!(queue.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn routes_by_label_with_a_fallback() {
+    let queue = memory_queue();
+    labelled(&queue, "order", &order(3)).await;
+    labelled(&queue, "refund", &"anything").await;
+    queue.send(&Message::new_json(&"no label").unwrap()).await.unwrap();
+
+    let (orders, others) = (&RefCell::new(Vec::new()), &RefCell::new(Vec::new()));
+    let router = Router::new(&queue, RouteKey::Label)
+        .with_route("order", |order: Order| async move { orders.borrow_mut().push(order.id); Ok(()) })
+        .with_fallback(|message: Message<BrokerReceiveProperties>| async move {
+            others.borrow_mut().push(message.properties.label.clone());
+            Ok(())
+        });
+
+    for _ in 0..3 {
+        assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Handled);
+    }
+    assert_eq!(*orders.borrow(), vec![3]);
+    assert_eq!(*others.borrow(), vec![Some("refund".to_string()), None]);
+}
+
+#[tokio::test]
+async fn routes_by_content_type() {
+    let queue = memory_queue();
+    queue.send(&Message::new_json(&order(4)).unwrap()).await.unwrap();
+    let mut text = Message::new_json(&"plain").unwrap();
+    text.content_type = "Text/Plain; charset=utf-8".into();
+    queue.send(&text).await.unwrap();
+
+    let orders = &RefCell::new(Vec::new());
+    let router = Router::new(&queue, RouteKey::ContentType)
+        .with_route("TEXT/JSON", |order: Order| async move { orders.borrow_mut().push(order.id); Ok(()) });
+    assert_eq!(router.key_of(&queue.with_broker(|b| b.messages(QUEUE))[1]).as_deref(), Some("text/plain"));
+
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Handled);
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Unrouted);
+    assert_eq!(*orders.borrow(), vec![4]);
+    assert_eq!(dead_reason(&queue).await, UNROUTED_REASON);
+}
+
+#[tokio::test]
+async fn unrouted_messages_can_be_left_for_others() {
+    let queue = memory_queue();
+    labelled(&queue, "unknown", &1).await;
+
+    let router = Router::new(&queue, RouteKey::Label).with_unrouted_action(UnroutedAction::Abandon);
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Unrouted);
+
+    let again = queue.receive().await.unwrap().unwrap();
+    assert_eq!(again.properties.delivery_count, Some(2));
+}
+
+#[tokio::test]
+async fn failures_go_through_the_poison_policy() {
+    let queue = memory_queue();
+    labelled(&queue, "order", &"not an order").await;
+    labelled(&queue, "order", &order(5)).await;
+
+    let router = Router::new(&queue, RouteKey::Label)
+        .with_route("order", |_: Order| async { Err(ProcessingError::transient("Try again")) });
+
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Failed);
+    assert_eq!(dead_reason(&queue).await, "DecodeError");
+
+    // Abandoned for another attempt.
+    assert_eq!(dispatch_next(&router, &queue).await, Dispatched::Failed);
+    let again = queue.receive().await.unwrap().unwrap();
+    assert_eq!(again.json_into::<Order>().unwrap(), order(5));
+    assert_eq!(again.properties.delivery_count, Some(2));
+}
+
+#[tokio::test]
+async fn runs_until_shutdown() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    for id in 0..5 {
+        TypedSender::<Order>::new(&sb_client).send(&order(id)).await.unwrap();
+    }
+
+    let (trigger, shutdown) = Shutdown::new();
+    let (trigger, handled) = (&trigger, &RefCell::new(Vec::new()));
+    let router = Router::new(&sb_client, RouteKey::MessageType).with_type(|order: Order| async move {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        handled.borrow_mut().push(order.id);
+        if handled.borrow().len() == 5 {
+            trigger.trigger();
+        }
+        Ok(())
+    });
+    router.run(2, &shutdown).await;
+
+    let mut ids = handled.borrow().clone();
+    ids.sort();
+    assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use std::time::Duration;
+
+use qexample::emulator::{Emulator, EmulatorConfig};
+use qexample::transport::broker::BrokerConfig;
+use qexample::mazure::chunking::split_message;
+use qexample::mazure::sbclient::{AzureServiceBusError, Message};
+use qexample::mazure::sending::{MessageIdStrategy, SendRetryPolicy};
+
+use common::{QUEUE, client, credentials, start_emulator};
+
+async fn start_deduplicating_emulator() -> Emulator {
+    let config = EmulatorConfig {
+        broker: BrokerConfig { duplicate_detection_window: Some(chrono::Duration::minutes(10)), ..BrokerConfig::default() },
+        credentials: Some(credentials()),
+        ..EmulatorConfig::default()
+    };
+    Emulator::start("127.0.0.1:0".parse().unwrap(), config).await.unwrap()
+}
+
+fn fast_retry() -> SendRetryPolicy {
+    SendRetryPolicy { initial_backoff: Duration::from_millis(10), ..SendRetryPolicy::default() }
+}
+
+#[tokio::test]
+async fn message_ids_are_sent_and_returned_in_the_receipt() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+
+    let receipt = sb_client.send(&Message::new_json(&"hello".to_string()).unwrap()).await.unwrap();
+    assert_eq!(receipt.attempts, 1);
+
+    let msg = sb_client.receive_and_delete().await.unwrap().unwrap();
+    assert!(receipt.message_id.is_some());
+    assert_eq!(msg.properties.message_id, receipt.message_id);
+    assert_eq!(msg.properties.correlation_id.as_deref(), Some(receipt.correlation_id.as_str()));
+}
+
+#[tokio::test]
+async fn content_hash_ids_deduplicate_repeated_sends() {
+    let emulator = start_deduplicating_emulator().await;
+    let sb_client = client(&emulator).with_message_ids(MessageIdStrategy::ContentHash);
+
+    let msg = Message::new_json(&"same".to_string()).unwrap();
+    let first = sb_client.send(&msg).await.unwrap();
+    let second = sb_client.send(&msg).await.unwrap();
+    sb_client.send(&Message::new_json(&"other".to_string()).unwrap()).await.unwrap();
+
+    assert_eq!(first.message_id, second.message_id);
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 2);
+}
+
+#[tokio::test]
+async fn lost_responses_are_retried_without_duplicates() {
+    let emulator = start_deduplicating_emulator().await;
+    let sb_client = client(&emulator).with_send_retry(fast_retry());
+
+    emulator.lose_send_responses(1);
+    let receipt = sb_client.send(&Message::new_json(&"once".to_string()).unwrap()).await.unwrap();
+    assert_eq!(receipt.attempts, 2);
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+
+    emulator.lose_send_responses(1);
+    let messages: Vec<_> = (0..3).map(|i| Message::new_json(&i).unwrap()).collect();
+    let receipts = sb_client.send_batch(&messages).await.unwrap();
+    assert!(receipts.iter().all(|r| r.attempts == 2));
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 4);
+}
+
+#[tokio::test]
+async fn sends_without_a_message_id_are_not_retried() {
+    let emulator = start_deduplicating_emulator().await;
+    let sb_client = client(&emulator)
+        .with_message_ids(MessageIdStrategy::Service)
+        .with_send_retry(fast_retry());
+
+    emulator.lose_send_responses(1);
+    let err = sb_client.send(&Message::new_json(&"once".to_string()).unwrap()).await.unwrap_err();
+    assert!(matches!(err, AzureServiceBusError::ServiceError(_)));
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+
+    let receipt = sb_client.send(&Message::new_json(&"twice".to_string()).unwrap()).await.unwrap();
+    assert_eq!(receipt.message_id, None);
+}
+
+#[tokio::test]
+async fn caller_keys_and_uuid7_ids() {
+    let emulator = start_deduplicating_emulator().await;
+
+    let keyed = client(&emulator).with_message_ids(MessageIdStrategy::UserProperty("order-id".into()));
+    let mut msg = Message::new_json(&"order".to_string()).unwrap();
+    assert!(matches!(keyed.send(&msg).await, Err(AzureServiceBusError::ConversionError(_))));
+    msg.set_user_property("order-id", "A-17");
+    assert_eq!(keyed.send(&msg).await.unwrap().message_id.as_deref(), Some("A-17"));
+    keyed.send(&msg).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+
+    let ordered = client(&emulator).with_message_ids(MessageIdStrategy::UuidV7);
+    let first = ordered.send(&Message::new_json(&1).unwrap()).await.unwrap().message_id.unwrap();
+    let second = ordered.send(&Message::new_json(&2).unwrap()).await.unwrap().message_id.unwrap();
+    assert_eq!(uuid::Uuid::parse_str(&first).unwrap().get_version_num(), 7);
+    assert!(first < second);
+}
+
+#[tokio::test]
+async fn chunks_and_reschedules_get_their_own_ids() {
+    let emulator = start_deduplicating_emulator().await;
+    let sb_client = client(&emulator);
+
+    let mut msg = Message::new_json(&"chunk ".repeat(100)).unwrap();
+    msg.properties.message_id = Some("big".into());
+    let chunks = split_message(&msg, 100);
+    sb_client.send_chunked(&msg, 100).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), chunks.len());
+
+    let received = sb_client.receive_and_delete().await.unwrap().unwrap();
+    sb_client.send(&received.to_send_message()).await.unwrap();
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), chunks.len());
+}
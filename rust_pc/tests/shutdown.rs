@@ -0,0 +1,103 @@
+mod common;
+
+use std::process::ExitCode;
+use std::time::Duration;
+
+use qexample::consumer::{self, ConsumerOptions, DrainStatus};
+use qexample::mazure::sbclient::{BrokerSendProperties, Message};
+use qexample::messages::LogInfo;
+use qexample::poison::RETRY_ATTEMPT_PROPERTY;
+use qexample::shutdown::Shutdown;
+use qexample::transport::QueueTransport;
+use qexample::transport::sqlite::SqliteQueue;
+
+use common::{QUEUE, memory_queue};
+
+// A message past its first attempt, which the consumer fails on purpose.
+fn retried_log_info() -> Message<BrokerSendProperties> {
+    let mut message = Message::new_json(&LogInfo::new_random()).unwrap();
+    message.set_user_property(RETRY_ATTEMPT_PROPERTY, "1");
+    message
+}
+
+#[tokio::test]
+async fn processing_past_the_grace_period_is_unlocked() {
+    let queue = memory_queue();
+    queue.send(&retried_log_info()).await.unwrap();
+
+    let options = ConsumerOptions {
+        shutdown_grace: Duration::from_millis(100),
+        processing_time: Duration::from_secs(10),
+        ..ConsumerOptions::default()
+    };
+    let (trigger, shutdown) = Shutdown::new();
+    let (status, _) = tokio::join!(consumer::run_consumer(&queue, &options, &shutdown), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        trigger.trigger();
+    });
+
+    let status = status.unwrap();
+    assert_eq!(status, DrainStatus::Unlocked);
+    assert_eq!(status.exit_code(), ExitCode::from(2));
+
+    // The message was abandoned, not left locked.
+    let redelivered = queue.receive().await.unwrap().unwrap();
+    assert_eq!(redelivered.properties.delivery_count, Some(2));
+}
+
+#[tokio::test]
+async fn processing_within_the_grace_period_finishes() {
+    let queue = memory_queue();
+    queue.send(&retried_log_info()).await.unwrap();
+
+    let options = ConsumerOptions {
+        shutdown_grace: Duration::from_secs(5),
+        processing_time: Duration::from_millis(300),
+        ..ConsumerOptions::default()
+    };
+    let (trigger, shutdown) = Shutdown::new();
+    let (status, _) = tokio::join!(consumer::run_consumer(&queue, &options, &shutdown), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        trigger.trigger();
+    });
+
+    assert_eq!(status.unwrap(), DrainStatus::Clean);
+    assert!(queue.receive().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn message_arriving_during_shutdown_is_unlocked() {
+    let path = std::env::temp_dir().join(format!("qexample-shutdown-{}.db", uuid::Uuid::new_v4()));
+    let queue = SqliteQueue::open(&path, QUEUE).unwrap().with_receive_timeout(Duration::from_secs(2));
+    let sender = SqliteQueue::open(&path, QUEUE).unwrap();
+
+    let options = ConsumerOptions::default();
+    let (trigger, shutdown) = Shutdown::new();
+    let (status, _) = tokio::join!(consumer::run_consumer(&queue, &options, &shutdown), async {
+        // Shutdown comes while the receive is waiting, and a message turns up after it.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        trigger.trigger();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        sender.send(&retried_log_info()).await.unwrap();
+    });
+
+    let status = status.unwrap();
+    assert_eq!(status, DrainStatus::Unlocked);
+    assert_eq!(status.exit_code(), ExitCode::from(2));
+    let redelivered = sender.receive().await.unwrap().unwrap();
+    assert_eq!(redelivered.properties.delivery_count, Some(2));
+
+    drop((queue, sender));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn consumer_loop_stops_once_shutdown_is_requested() {
+    let queue = memory_queue();
+    let (trigger, shutdown) = Shutdown::new();
+    trigger.trigger();
+
+    let status = consumer::run_consumer_loop(&queue, &ConsumerOptions::default(), &shutdown).await.unwrap();
+    assert_eq!(status, DrainStatus::Clean);
+    assert_eq!(status.exit_code(), ExitCode::SUCCESS);
+}
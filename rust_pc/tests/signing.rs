@@ -0,0 +1,158 @@
+mod common;
+
+use std::collections::HashMap;
+
+use aes_gcm::aead::OsRng;
+use qexample::mazure::encryption::LocalKeyProvider;
+use qexample::mazure::sbclient::{AzureServiceBusError, Message, DEAD_LETTER_REASON_PROPERTY};
+use qexample::mazure::signing::{
+    MessageSigner, SigningKey, SignatureStatus, TrustedKeys, SIGNATURE_PROPERTY, inspect_signature
+};
+use qexample::verify;
+
+use common::{QUEUE, client, dead_letter_queue, start_emulator};
+
+fn ed25519_signer(key_id: &str) -> MessageSigner {
+    MessageSigner::new(key_id, SigningKey::Ed25519(ed25519_dalek::SigningKey::generate(&mut OsRng)))
+}
+
+fn trusting(signers: &[&MessageSigner]) -> TrustedKeys {
+    let keys: HashMap<_, _> = signers.iter().map(|s| (s.key_id.clone(), s.key.verification_key())).collect();
+    TrustedKeys::new(keys)
+}
+
+fn text(value: &str) -> Message<qexample::mazure::sbclient::BrokerSendProperties> {
+    let mut msg = Message::new_json(&value.to_string()).unwrap();
+    msg.properties.label = Some("greeting".into());
+    msg.set_user_property("tenant", "contoso");
+    msg
+}
+
+#[tokio::test]
+async fn signed_messages_are_verified_on_receive() {
+    let emulator = start_emulator().await;
+    let signer = ed25519_signer("producer-1");
+    let hmac = MessageSigner::new("shared", SigningKey::HmacSha256(b"a shared secret".to_vec()));
+
+    client(&emulator).with_signing(signer.clone()).send(&text("hello")).await.unwrap();
+    client(&emulator).with_signing(hmac.clone()).send(&text("hi")).await.unwrap();
+
+    let receiver = client(&emulator).with_verification(trusting(&[&signer, &hmac]));
+    for expected in ["hello", "hi"] {
+        let msg = receiver.receive_and_delete().await.unwrap().unwrap();
+        assert_eq!(msg.json_into::<String>().unwrap(), expected);
+        assert_eq!(msg.user_property("tenant"), Some("contoso"));
+        assert_eq!(msg.user_property(SIGNATURE_PROPERTY), None);
+    }
+}
+
+#[tokio::test]
+async fn signatures_cover_encrypted_bodies() {
+    let emulator = start_emulator().await;
+    let signer = ed25519_signer("producer-1");
+    let keys = HashMap::from([("k1".to_string(), LocalKeyProvider::generate_key())]);
+    let encryption = || Box::new(LocalKeyProvider::new("k1", keys.clone()).unwrap());
+
+    client(&emulator).with_encryption(encryption()).with_signing(signer.clone())
+        .send(&text("secret")).await.unwrap();
+
+    let receiver = client(&emulator).with_encryption(encryption()).with_verification(trusting(&[&signer]));
+    let msg = receiver.receive_and_delete().await.unwrap().unwrap();
+    assert_eq!(msg.json_into::<String>().unwrap(), "secret");
+}
+
+#[tokio::test]
+async fn tampered_unsigned_and_untrusted_messages_are_dead_lettered() {
+    let emulator = start_emulator().await;
+    let signer = ed25519_signer("producer-1");
+    let stranger = ed25519_signer("stranger");
+
+    client(&emulator).with_signing(signer.clone()).send(&text("tampered")).await.unwrap();
+    emulator.with_broker(|b| {
+        let mut msg = b.receive_and_delete(QUEUE).unwrap();
+        msg.properties.label = Some("changed".into());
+        b.send(QUEUE, msg);
+    });
+
+    client(&emulator).send(&text("unsigned")).await.unwrap();
+    client(&emulator).with_signing(stranger).send(&text("untrusted")).await.unwrap();
+
+    let receiver = client(&emulator).with_verification(trusting(&[&signer]));
+    for _ in 0..3 {
+        let err = receiver.peek_lock().await.unwrap_err();
+        assert!(matches!(err, AzureServiceBusError::SignatureError(_)));
+    }
+
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 0);
+    let dead_lettered = emulator.with_broker(|b| b.messages(&dead_letter_queue()));
+    assert_eq!(dead_lettered.len(), 3);
+    assert!(dead_lettered.iter().all(|m| m.user_property(DEAD_LETTER_REASON_PROPERTY) == Some("SignatureError")));
+
+    // The tampered message is forwarded as received rather than re-signed.
+    assert_eq!(dead_lettered[0].properties.label.as_deref(), Some("changed"));
+    assert!(matches!(inspect_signature(&dead_lettered[0], &trusting(&[&signer])), SignatureStatus::Invalid { .. }));
+}
+
+#[tokio::test]
+async fn properties_added_after_signing_are_stripped() {
+    let emulator = start_emulator().await;
+    let signer = ed25519_signer("producer-1");
+    client(&emulator).with_signing(signer.clone()).send(&text("hello")).await.unwrap();
+    emulator.with_broker(|b| {
+        let mut msg = b.receive_and_delete(QUEUE).unwrap();
+        msg.set_user_property("payload-encoding", "gzip");
+        msg.set_user_property("retry-attempt", "9");
+        b.send(QUEUE, msg);
+    });
+
+    let trusted = trusting(&[&signer]);
+    let raw = emulator.with_broker(|b| b.messages(QUEUE))[0].clone();
+    match inspect_signature(&raw, &trusted) {
+        SignatureStatus::Valid { unsigned_properties, .. } => assert_eq!(unsigned_properties, vec!["payload-encoding", "retry-attempt"]),
+        other => panic!("unexpected status {:?}", other),
+    }
+
+    // Had the encoding been acted on, the body would not decompress.
+    let msg = client(&emulator).with_verification(trusted).receive_and_delete().await.unwrap().unwrap();
+    assert_eq!(msg.json_into::<String>().unwrap(), "hello");
+    assert_eq!(msg.user_property("tenant"), Some("contoso"));
+    assert_eq!(msg.user_property("payload-encoding"), None);
+    assert_eq!(msg.user_property("retry-attempt"), None);
+}
+
+#[tokio::test]
+async fn unsigned_messages_can_be_allowed() {
+    let emulator = start_emulator().await;
+    client(&emulator).send(&text("unsigned")).await.unwrap();
+
+    let mut trusted = TrustedKeys::new(HashMap::new());
+    trusted.require_signature = false;
+    let msg = client(&emulator).with_verification(trusted).receive_and_delete().await.unwrap().unwrap();
+    assert_eq!(msg.json_into::<String>().unwrap(), "unsigned");
+}
+
+#[tokio::test]
+async fn verify_reports_status_and_leaves_the_message() {
+    let emulator = start_emulator().await;
+    let signer = ed25519_signer("producer-1");
+    let sb_client = client(&emulator).with_signing(signer.clone());
+    sb_client.send(&text("check me")).await.unwrap();
+
+    let status = verify::run_verify(&sb_client, &trusting(&[&signer])).await.unwrap();
+    match status {
+        Some(SignatureStatus::Valid { key_id, signed_properties, .. }) => {
+            assert_eq!(key_id, "producer-1");
+            assert!(signed_properties.contains(&"Label".to_string()));
+            assert!(signed_properties.contains(&"tenant".to_string()));
+        },
+        other => panic!("unexpected status {:?}", other),
+    }
+
+    let status = verify::run_verify(&sb_client, &trusting(&[])).await.unwrap();
+    assert_eq!(status, Some(SignatureStatus::UntrustedKey { key_id: "producer-1".into() }));
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 1);
+
+    // REST cannot peek, so each verification counted as a delivery.
+    let received = sb_client.peek_lock().await.unwrap().unwrap();
+    assert_eq!(received.properties.delivery_count, Some(3));
+}
@@ -0,0 +1,221 @@
+mod common;
+
+use std::cell::Cell;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use qexample::transport::broker::BrokerConfig;
+use qexample::mazure::codec::Format;
+use qexample::mazure::sbclient::{AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message};
+use qexample::mazure::sending::SendReceipt;
+use qexample::producer;
+use qexample::shutdown::Shutdown;
+use qexample::spool::{SendOutcome, SpoolError, SpoolLimits, StoreAndForward};
+use qexample::transport::QueueTransport;
+use qexample::transport::memory::MemoryQueue;
+
+use common::{QUEUE, client, start_emulator};
+
+// A memory queue whose sends can be made to fail.
+struct FlakyQueue {
+    queue: MemoryQueue,
+    offline: Cell<bool>,
+    refuse: Cell<bool>,
+}
+
+impl FlakyQueue {
+    fn new() -> Self {
+        FlakyQueue { queue: MemoryQueue::new(QUEUE, BrokerConfig::default()), offline: Cell::new(false), refuse: Cell::new(false) }
+    }
+
+    fn bodies(&self) -> Vec<i32> {
+        self.queue.with_broker(|b| b.messages(QUEUE)).iter().map(|m| m.json_into().unwrap()).collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueTransport for FlakyQueue {
+    async fn send(&self, message: &Message<BrokerSendProperties>) -> Result<SendReceipt, AzureServiceBusError> {
+        if self.offline.get() {
+            return Err(AzureServiceBusError::CommunicationError("offline".into()));
+        }
+        if self.refuse.get() {
+            return Err(AzureServiceBusError::RequestError("400".into()));
+        }
+        self.queue.send(message).await
+    }
+
+    async fn receive(&self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        self.queue.receive().await
+    }
+
+    async fn renew_lock(&self, properties: &BrokerReceiveProperties) -> Result<Option<DateTime<Utc>>, AzureServiceBusError> {
+        self.queue.renew_lock(properties).await
+    }
+
+    async fn abandon(&self, properties: &BrokerReceiveProperties) -> Result<(), AzureServiceBusError> {
+        self.queue.abandon(properties).await
+    }
+
+    async fn complete(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), AzureServiceBusError> {
+        self.queue.complete(message).await
+    }
+
+    async fn dead_letter(&self, message: &Message<BrokerReceiveProperties>, reason: &str, description: &str) -> Result<(), AzureServiceBusError> {
+        self.queue.dead_letter(message, reason, description).await
+    }
+}
+
+fn temp_db() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("qexample-spool-{}.db", uuid::Uuid::new_v4()))
+}
+
+fn memory_spool(transport: &dyn QueueTransport) -> StoreAndForward<'_> {
+    StoreAndForward::new(rusqlite::Connection::open_in_memory().unwrap(), transport).unwrap()
+}
+
+async fn send(spool: &StoreAndForward<'_>, body: i32) -> SendOutcome {
+    spool.send(&Message::new_json(&body).unwrap()).await.unwrap()
+}
+
+#[tokio::test]
+async fn spools_while_offline_and_forwards_in_order() {
+    let transport = FlakyQueue::new();
+    let spool = memory_spool(&transport);
+
+    assert!(matches!(send(&spool, 1).await, SendOutcome::Sent(_)));
+    transport.offline.set(true);
+    assert!(matches!(send(&spool, 2).await, SendOutcome::Spooled(_)));
+    transport.offline.set(false);
+    // Queued behind the backlog, not sent ahead of it.
+    assert!(matches!(send(&spool, 3).await, SendOutcome::Spooled(_)));
+
+    let stats = spool.stats().unwrap();
+    assert_eq!(stats.backlog, 2);
+    assert!(stats.backlog_bytes > 0);
+    assert!(stats.oldest_spooled_at.is_some());
+    assert_eq!(transport.bodies(), vec![1]);
+
+    assert_eq!(spool.forward().await.unwrap(), 2);
+    assert_eq!(transport.bodies(), vec![1, 2, 3]);
+    let stats = spool.stats().unwrap();
+    assert_eq!((stats.backlog, stats.spooled, stats.forwarded), (0, 2, 2));
+
+    assert!(matches!(send(&spool, 4).await, SendOutcome::Sent(_)));
+}
+
+#[tokio::test]
+async fn forwarding_stops_at_the_first_failure() {
+    let transport = FlakyQueue::new();
+    let spool = memory_spool(&transport);
+    transport.offline.set(true);
+    send(&spool, 1).await;
+    send(&spool, 2).await;
+
+    assert_eq!(spool.forward().await.unwrap(), 0);
+    let stats = spool.stats().unwrap();
+    assert_eq!((stats.backlog, stats.failed_attempts), (2, 1));
+}
+
+#[tokio::test]
+async fn refuses_messages_when_full() {
+    let transport = FlakyQueue::new();
+    let spool = memory_spool(&transport).with_limits(SpoolLimits { max_messages: 1, ..SpoolLimits::default() });
+    transport.offline.set(true);
+    send(&spool, 1).await;
+
+    match spool.send(&Message::new_json(&2).unwrap()).await {
+        Err(SpoolError::Full(_)) => {},
+        other => panic!("Expected a full spool, got {:?}", other),
+    }
+
+    let spool = memory_spool(&transport).with_limits(SpoolLimits { max_bytes: 10, ..SpoolLimits::default() });
+    assert!(matches!(spool.send(&Message::new_json(&"too big to spool").unwrap()).await, Err(SpoolError::Full(_))));
+}
+
+#[tokio::test]
+async fn drops_spooled_messages_past_their_age() {
+    let transport = FlakyQueue::new();
+    let spool = memory_spool(&transport).with_limits(SpoolLimits { max_age: chrono::Duration::zero(), ..SpoolLimits::default() });
+    transport.offline.set(true);
+    send(&spool, 1).await;
+    transport.offline.set(false);
+
+    assert_eq!(spool.forward().await.unwrap(), 0);
+    assert_eq!(spool.stats().unwrap().expired, 1);
+    assert!(transport.bodies().is_empty());
+}
+
+#[tokio::test]
+async fn refused_messages_do_not_hold_up_the_backlog() {
+    let transport = FlakyQueue::new();
+    let spool = memory_spool(&transport);
+
+    transport.refuse.set(true);
+    assert!(matches!(spool.send(&Message::new_json(&0).unwrap()).await, Err(SpoolError::SendError(_))));
+    assert_eq!(spool.stats().unwrap().backlog, 0);
+
+    transport.offline.set(true);
+    send(&spool, 1).await;
+    send(&spool, 2).await;
+    transport.offline.set(false);
+
+    // Both are refused once they reach the service and are set aside.
+    assert_eq!(spool.forward().await.unwrap(), 0);
+    let stats = spool.stats().unwrap();
+    assert_eq!((stats.backlog, stats.failed), (0, 2));
+
+    transport.refuse.set(false);
+    send(&spool, 3).await;
+    assert_eq!(transport.bodies(), vec![3]);
+}
+
+#[tokio::test]
+async fn forwards_in_the_background_and_flushes_on_shutdown() {
+    let transport = FlakyQueue::new();
+    let spool = memory_spool(&transport).with_retry_interval(Duration::from_millis(20));
+    transport.offline.set(true);
+    send(&spool, 1).await;
+
+    let (trigger, shutdown) = Shutdown::new();
+    let restore = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        transport.offline.set(false);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(transport.bodies(), vec![1]);
+
+        transport.offline.set(true);
+        send(&spool, 2).await;
+        transport.offline.set(false);
+        trigger.trigger();
+    };
+    let (_, ran) = tokio::join!(restore, spool.run(&shutdown));
+    ran.unwrap();
+
+    assert_eq!(transport.bodies(), vec![1, 2]);
+    assert_eq!(spool.stats().unwrap().backlog, 0);
+}
+
+#[tokio::test]
+async fn spooled_messages_survive_until_the_service_is_back() {
+    let emulator = start_emulator().await;
+    let unreachable = client(&emulator);
+    emulator.stop().await.unwrap();
+
+    let path = temp_db();
+    {
+        let spool = StoreAndForward::open(&path, &unreachable).unwrap().with_retry_interval(Duration::from_millis(10));
+        producer::run_spooling_producer(&spool, 3, Format::Json).await.unwrap();
+        assert_eq!(spool.stats().unwrap().backlog, 3);
+    }
+
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let spool = StoreAndForward::open(&path, &sb_client).unwrap();
+    assert_eq!(spool.flush().await.unwrap(), 0);
+    assert_eq!(emulator.with_broker(|b| b.message_count(QUEUE)), 3);
+
+    drop(spool);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,237 @@
+mod common;
+
+use std::cell::Cell;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{SinkExt, StreamExt};
+use qexample::mazure::sending::SendReceipt;
+use qexample::mazure::sbclient::{
+    AzureServiceBusError, BrokerReceiveProperties, BrokerSendProperties, Message, DEAD_LETTER_REASON_PROPERTY
+};
+use qexample::shutdown::Shutdown;
+use qexample::streaming::{MessageSink, MessageStream};
+use qexample::transport::QueueTransport;
+use qexample::transport::memory::MemoryQueue;
+
+use common::{QUEUE, client, dead_letter_queue, memory_queue, queue_with, start_emulator};
+
+// Messages left to receive, locking each of them.
+async fn available(queue: &MemoryQueue) -> usize {
+    let mut count = 0;
+    while queue.receive().await.unwrap().is_some() {
+        count += 1;
+    }
+    count
+}
+
+#[tokio::test]
+async fn streams_messages_in_order_and_settles_them() {
+    let queue = queue_with(&[1, 2, 3]).await;
+    let mut stream = MessageStream::new(&queue);
+
+    let mut bodies = Vec::new();
+    for _ in 0..3 {
+        let received = stream.next().await.unwrap().unwrap();
+        bodies.push(received.message().json_into::<i32>().unwrap());
+        received.complete().await.unwrap();
+    }
+    assert_eq!(bodies, vec![1, 2, 3]);
+    assert_eq!(queue.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn settlement_methods_abandon_and_dead_letter() {
+    let queue = queue_with(&[1, 2]).await;
+    let mut stream = MessageStream::new(&queue).with_poll_interval(Duration::from_millis(10));
+
+    let mut first = stream.next().await.unwrap().unwrap();
+    first.renew_lock().await.unwrap();
+    first.abandon().await.unwrap();
+    let redelivered = stream.next().await.unwrap().unwrap();
+    assert_eq!(redelivered.message().json_into::<i32>().unwrap(), 1);
+    assert_eq!(redelivered.message().properties.delivery_count, Some(2));
+    redelivered.complete().await.unwrap();
+
+    let second = stream.next().await.unwrap().unwrap();
+    second.dead_letter("Poison", "Cannot be processed").await.unwrap();
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    assert_eq!(dead.user_property(DEAD_LETTER_REASON_PROPERTY), Some("Poison"));
+}
+
+#[tokio::test]
+async fn prefetches_only_as_far_as_asked() {
+    let queue = queue_with(&[1, 2, 3, 4, 5]).await;
+    let mut stream = MessageStream::new(&queue);
+    stream.next().await.unwrap().unwrap();
+    assert_eq!(queue.with_broker(|b| b.message_count(QUEUE)), 5);
+    stream.close().await.unwrap();
+    // Only the message handed out is still locked.
+    assert_eq!(available(&queue).await, 4);
+
+    let queue = queue_with(&[1, 2, 3, 4, 5]).await;
+    let mut stream = MessageStream::new(&queue).with_prefetch(2);
+    let first = stream.next().await.unwrap().unwrap();
+    assert_eq!(first.message().json_into::<i32>().unwrap(), 1);
+    assert_eq!(available(&queue).await, 2);
+
+    // Closing hands the prefetched messages back.
+    stream.close().await.unwrap();
+    assert_eq!(available(&queue).await, 2);
+}
+
+// A memory queue whose receives and abandons can be made to fail.
+struct FailingQueue {
+    queue: MemoryQueue,
+    failing_receives: Cell<bool>,
+    failing_abandons: Cell<usize>,
+}
+
+impl FailingQueue {
+    async fn with(bodies: &[i32]) -> Self {
+        FailingQueue { queue: queue_with(bodies).await, failing_receives: Cell::new(false), failing_abandons: Cell::new(0) }
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueTransport for FailingQueue {
+    async fn send(&self, message: &Message<BrokerSendProperties>) -> Result<SendReceipt, AzureServiceBusError> {
+        self.queue.send(message).await
+    }
+
+    async fn receive(&self) -> Result<Option<Message<BrokerReceiveProperties>>, AzureServiceBusError> {
+        if self.failing_receives.get() {
+            return Err(AzureServiceBusError::CommunicationError("offline".into()));
+        }
+        self.queue.receive().await
+    }
+
+    async fn renew_lock(&self, properties: &BrokerReceiveProperties) -> Result<Option<DateTime<Utc>>, AzureServiceBusError> {
+        self.queue.renew_lock(properties).await
+    }
+
+    async fn abandon(&self, properties: &BrokerReceiveProperties) -> Result<(), AzureServiceBusError> {
+        if self.failing_abandons.get() > 0 {
+            self.failing_abandons.set(self.failing_abandons.get() - 1);
+            return Err(AzureServiceBusError::CommunicationError("offline".into()));
+        }
+        self.queue.abandon(properties).await
+    }
+
+    async fn complete(&self, message: &Message<BrokerReceiveProperties>) -> Result<(), AzureServiceBusError> {
+        self.queue.complete(message).await
+    }
+
+    async fn dead_letter(&self, message: &Message<BrokerReceiveProperties>, reason: &str, description: &str) -> Result<(), AzureServiceBusError> {
+        self.queue.dead_letter(message, reason, description).await
+    }
+}
+
+#[tokio::test]
+async fn close_abandons_prefetched_messages_when_the_pending_receive_fails() {
+    let queue = FailingQueue::with(&[1, 2]).await;
+    let mut stream = MessageStream::new(&queue).with_prefetch(2).with_poll_interval(Duration::from_millis(10));
+    // The queue is empty by now, so a receive is waiting to poll again.
+    let _first = stream.next().await.unwrap().unwrap();
+
+    queue.failing_receives.set(true);
+    assert!(matches!(stream.close().await, Err(AzureServiceBusError::CommunicationError(_))));
+    queue.failing_receives.set(false);
+    assert_eq!(available(&queue.queue).await, 1);
+}
+
+#[tokio::test]
+async fn close_keeps_abandoning_after_a_failure() {
+    let queue = FailingQueue::with(&[1, 2, 3]).await;
+    let mut stream = MessageStream::new(&queue).with_prefetch(2);
+    let _first = stream.next().await.unwrap().unwrap();
+
+    queue.failing_abandons.set(1);
+    assert!(matches!(stream.close().await, Err(AzureServiceBusError::CommunicationError(_))));
+    // One of the two prefetched messages was still abandoned.
+    assert_eq!(available(&queue.queue).await, 1);
+}
+
+#[tokio::test]
+async fn long_polls_until_a_message_arrives() {
+    let queue = memory_queue();
+    let stream = MessageStream::new(&queue).with_poll_interval(Duration::from_millis(10));
+    tokio::pin!(stream);
+
+    let send_later = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        queue.send(&Message::new_json(&7).unwrap()).await.unwrap();
+    };
+    let (_, received) = tokio::join!(send_later, stream.next());
+    assert_eq!(received.unwrap().unwrap().message().json_into::<i32>().unwrap(), 7);
+}
+
+#[tokio::test]
+async fn processes_concurrently_until_shutdown() {
+    let queue = queue_with(&(0..10).collect::<Vec<_>>()).await;
+    let (trigger, shutdown) = Shutdown::new();
+    let processed = Cell::new(0);
+    let in_flight = Cell::new(0);
+    let most_in_flight = Cell::new(0);
+
+    let stream = MessageStream::new(&queue).with_poll_interval(Duration::from_millis(10));
+    stream.take_until(shutdown.requested())
+        .for_each_concurrent(3, |received| async {
+            let received = received.unwrap();
+            in_flight.set(in_flight.get() + 1);
+            most_in_flight.set(most_in_flight.get().max(in_flight.get()));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            received.complete().await.unwrap();
+            in_flight.set(in_flight.get() - 1);
+
+            processed.set(processed.get() + 1);
+            if processed.get() == 10 {
+                trigger.trigger();
+            }
+        })
+        .await;
+
+    assert_eq!(processed.get(), 10);
+    assert_eq!(most_in_flight.get(), 3);
+    assert_eq!(queue.with_broker(|b| b.message_count(QUEUE)), 0);
+}
+
+#[tokio::test]
+async fn sink_sends_in_order_and_reports_failures() {
+    let queue = memory_queue();
+    let mut sink = MessageSink::new(&queue);
+    let messages = (1..=3).map(|n| Ok(Message::new_json(&n).unwrap()));
+    futures::stream::iter(messages).forward(&mut sink).await.unwrap();
+    assert_eq!(sink.sent(), 3);
+
+    let bodies: Vec<i32> = queue.with_broker(|b| b.messages(QUEUE)).iter().map(|m| m.json_into().unwrap()).collect();
+    assert_eq!(bodies, vec![1, 2, 3]);
+
+    let emulator = start_emulator().await;
+    let unreachable = client(&emulator);
+    emulator.stop().await.unwrap();
+    let mut sink = MessageSink::new(&unreachable);
+    assert!(sink.send(Message::new_json(&4).unwrap()).await.is_err());
+    assert_eq!(sink.sent(), 0);
+}
+
+#[tokio::test]
+async fn pipes_one_queue_into_another() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let source = queue_with(&[1, 2, 3]).await;
+
+    let relayed = MessageStream::new(&source).with_prefetch(1).take(3).then(|received| async {
+        let received = received?;
+        let doubled = received.message().json_into::<i32>()? * 2;
+        received.complete().await?;
+        Message::new_json(&doubled)
+    });
+    relayed.forward(MessageSink::new(&sb_client)).await.unwrap();
+
+    let bodies: Vec<i32> = emulator.with_broker(|b| b.messages(QUEUE)).iter().map(|m| m.json_into().unwrap()).collect();
+    assert_eq!(bodies, vec![2, 4, 6]);
+    assert_eq!(source.with_broker(|b| b.message_count(QUEUE)), 0);
+    assert!(emulator.with_broker(|b| b.messages(&dead_letter_queue())).is_empty());
+}
@@ -0,0 +1,233 @@
+mod common;
+
+use std::time::Duration;
+
+use qexample::consumer::{self, ConsumerOptions, DrainStatus};
+use qexample::transport::broker::BrokerConfig;
+use qexample::mazure::codec::Format;
+use qexample::mazure::sbclient::{AzureServiceBusError, Message, DEAD_LETTER_REASON_PROPERTY};
+use qexample::producer;
+use qexample::shutdown::Shutdown;
+use qexample::transport::QueueTransport;
+use qexample::transport::memory::MemoryQueue;
+use qexample::transport::sqlite::SqliteQueue;
+
+use common::{QUEUE, client, dead_letter_queue, start_emulator};
+
+fn temp_db() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("qexample-queue-{}.db", uuid::Uuid::new_v4()))
+}
+
+fn assert_status<T: std::fmt::Debug>(result: Result<T, AzureServiceBusError>, expected: &str) {
+    match result {
+        Err(AzureServiceBusError::RequestError(status)) => assert_eq!(status, expected),
+        other => panic!("Expected status {}, got {:?}", expected, other),
+    }
+}
+
+// The behaviour every transport shares, starting from an empty queue.
+async fn check_transport(transport: &dyn QueueTransport) {
+    assert!(transport.receive().await.unwrap().is_none());
+
+    let mut message = Message::new_json(&"first").unwrap();
+    message.properties.label = Some("greeting".into());
+    message.set_user_property("origin", "tests");
+    let receipt = transport.send(&message).await.unwrap();
+    transport.send(&Message::new_json(&"second").unwrap()).await.unwrap();
+
+    let first = transport.receive().await.unwrap().unwrap();
+    assert_eq!(first.json_into::<String>().unwrap(), "first");
+    assert_eq!(first.properties.message_id, receipt.message_id);
+    assert_eq!(first.properties.correlation_id.as_deref(), Some(receipt.correlation_id.as_str()));
+    assert_eq!(first.properties.label.as_deref(), Some("greeting"));
+    assert_eq!(first.properties.delivery_count, Some(1));
+    assert!(first.properties.locked_until_utc.is_some());
+    assert_eq!(first.user_property("origin"), Some("tests"));
+
+    // A locked message is not delivered again until it is abandoned.
+    let second = transport.receive().await.unwrap().unwrap();
+    assert_eq!(second.json_into::<String>().unwrap(), "second");
+    assert!(transport.receive().await.unwrap().is_none());
+
+    transport.renew_lock(&first.properties).await.unwrap();
+    transport.abandon(&first.properties).await.unwrap();
+    let redelivered = transport.receive().await.unwrap().unwrap();
+    assert_eq!(redelivered.properties.message_id, first.properties.message_id);
+    assert_eq!(redelivered.properties.delivery_count, Some(2));
+
+    // The abandoned lock is gone.
+    assert_status(transport.complete(&first).await, "410");
+    transport.complete(&redelivered).await.unwrap();
+    assert_status(transport.complete(&redelivered).await, "404");
+
+    transport.dead_letter(&second, "Poison", "Cannot be processed").await.unwrap();
+    assert!(transport.receive().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn service_bus_client_is_a_transport() {
+    let emulator = start_emulator().await;
+    check_transport(&client(&emulator)).await;
+
+    let dead = emulator.with_broker(|b| b.messages(&dead_letter_queue()));
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].user_property(DEAD_LETTER_REASON_PROPERTY), Some("Poison"));
+}
+
+#[tokio::test]
+async fn memory_queue_is_a_transport() {
+    let queue = MemoryQueue::new(QUEUE, BrokerConfig::default());
+    check_transport(&queue).await;
+
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    assert_eq!(dead.json_into::<String>().unwrap(), "second");
+    assert_eq!(dead.user_property(DEAD_LETTER_REASON_PROPERTY), Some("Poison"));
+}
+
+#[tokio::test]
+async fn sqlite_queue_is_a_transport() {
+    let queue = SqliteQueue::new(rusqlite::Connection::open_in_memory().unwrap(), QUEUE).unwrap();
+    check_transport(&queue).await;
+
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    assert_eq!(dead.json_into::<String>().unwrap(), "second");
+    assert_eq!(dead.user_property(DEAD_LETTER_REASON_PROPERTY), Some("Poison"));
+}
+
+#[tokio::test]
+async fn memory_queue_locks_expire() {
+    let queue = MemoryQueue::new(QUEUE, BrokerConfig::default());
+    queue.send(&Message::new_json(&1).unwrap()).await.unwrap();
+
+    let first = queue.receive().await.unwrap().unwrap();
+    queue.with_broker(|b| b.advance_clock(chrono::Duration::seconds(61)));
+    assert_status(queue.renew_lock(&first.properties).await, "410");
+
+    let second = queue.receive().await.unwrap().unwrap();
+    assert_eq!(second.properties.delivery_count, Some(2));
+}
+
+#[tokio::test]
+async fn sqlite_queue_survives_reopening_and_locks_expire() {
+    let path = temp_db();
+    {
+        let queue = SqliteQueue::open(&path, QUEUE).unwrap();
+        producer::run_producer(&queue, 2, Format::Json).await.unwrap();
+    }
+
+    let config = BrokerConfig { lock_duration: chrono::Duration::milliseconds(100), ..BrokerConfig::default() };
+    let queue = SqliteQueue::open(&path, QUEUE).unwrap().with_config(config);
+    assert_eq!(queue.message_count().unwrap(), 2);
+    // The producer schedules its messages for later.
+    assert!(queue.receive().await.unwrap().is_none());
+    queue.send(&Message::new_json(&1).unwrap()).await.unwrap();
+    queue.send(&Message::new_json(&2).unwrap()).await.unwrap();
+    // Another process sees the same messages.
+    let other = SqliteQueue::open(&path, QUEUE).unwrap();
+
+    let locked = queue.receive().await.unwrap().unwrap();
+    let next = other.receive().await.unwrap().unwrap();
+    assert_ne!(next.properties.message_id, locked.properties.message_id);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let relocked = other.receive().await.unwrap().unwrap();
+    assert_eq!(relocked.properties.message_id, locked.properties.message_id);
+    assert_eq!(relocked.properties.delivery_count, Some(2));
+    assert_status(queue.complete(&locked).await, "410");
+
+    drop((queue, other));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn empty_sqlite_queue_waits_for_messages() {
+    let path = temp_db();
+    let queue = SqliteQueue::open(&path, QUEUE).unwrap().with_receive_timeout(Duration::from_millis(300));
+
+    let started = std::time::Instant::now();
+    assert!(queue.receive().await.unwrap().is_none());
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    // A message sent while waiting is picked up before the timeout.
+    let sender = SqliteQueue::open(&path, QUEUE).unwrap();
+    let started = std::time::Instant::now();
+    let (received, _) = tokio::join!(queue.receive(), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        sender.send(&Message::new_json(&"late").unwrap()).await.unwrap();
+    });
+    assert_eq!(received.unwrap().unwrap().json_into::<String>().unwrap(), "late");
+    assert!(started.elapsed() < Duration::from_millis(300));
+
+    drop((queue, sender));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn sqlite_queue_dead_letters_after_max_deliveries() {
+    let config = BrokerConfig { max_delivery_count: 2, ..BrokerConfig::default() };
+    let queue = SqliteQueue::new(rusqlite::Connection::open_in_memory().unwrap(), QUEUE).unwrap().with_config(config);
+    queue.send(&Message::new_json(&"poison").unwrap()).await.unwrap();
+
+    for delivery in 1..=2 {
+        let message = queue.receive().await.unwrap().unwrap();
+        assert_eq!(message.properties.delivery_count, Some(delivery));
+        queue.abandon(&message.properties).await.unwrap();
+    }
+    assert!(queue.receive().await.unwrap().is_none());
+
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    assert_eq!(dead.user_property(DEAD_LETTER_REASON_PROPERTY), Some("MaxDeliveryCountExceeded"));
+}
+
+#[tokio::test]
+async fn sqlite_queue_expires_messages() {
+    let config = BrokerConfig {
+        default_time_to_live: Some(chrono::Duration::milliseconds(100)),
+        dead_letter_on_expiration: true,
+        ..BrokerConfig::default()
+    };
+    let queue = SqliteQueue::new(rusqlite::Connection::open_in_memory().unwrap(), QUEUE).unwrap().with_config(config);
+    queue.send(&Message::new_json(&"stale").unwrap()).await.unwrap();
+    let mut lasting = Message::new_json(&"fresh").unwrap();
+    lasting.properties.time_to_live = Some(60);
+    queue.send(&lasting).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let received = queue.receive().await.unwrap().unwrap();
+    assert_eq!(received.json_into::<String>().unwrap(), "fresh");
+    assert_eq!(received.properties.time_to_live, Some(60));
+
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    assert_eq!(dead.json_into::<String>().unwrap(), "stale");
+    assert_eq!(dead.user_property(DEAD_LETTER_REASON_PROPERTY), Some("TTLExpiredException"));
+}
+
+#[tokio::test]
+async fn sqlite_queue_drops_duplicates_within_the_window() {
+    let config = BrokerConfig { duplicate_detection_window: Some(chrono::Duration::minutes(10)), ..BrokerConfig::default() };
+    let queue = SqliteQueue::new(rusqlite::Connection::open_in_memory().unwrap(), QUEUE).unwrap().with_config(config);
+
+    let mut message = Message::new_json(&1).unwrap();
+    message.properties.message_id = Some("id-1".into());
+    queue.send(&message).await.unwrap();
+    queue.send(&message).await.unwrap();
+    assert_eq!(queue.message_count().unwrap(), 1);
+
+    // Other queues in the database keep their own ids.
+    queue.queue("other").send(&message).await.unwrap();
+    assert_eq!(queue.queue("other").message_count().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn consumer_runs_on_a_local_queue() {
+    let queue = MemoryQueue::new(QUEUE, BrokerConfig::default());
+    queue.send(&Message::new_json(&"not a LogInfo").unwrap()).await.unwrap();
+
+    let (_trigger, shutdown) = Shutdown::new();
+    let status = consumer::run_consumer(&queue, &ConsumerOptions::default(), &shutdown).await.unwrap();
+    assert_eq!(status, DrainStatus::Clean);
+
+    assert_eq!(queue.with_broker(|b| b.message_count(QUEUE)), 0);
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    assert_eq!(dead.user_property(DEAD_LETTER_REASON_PROPERTY), Some("DecodeError"));
+}
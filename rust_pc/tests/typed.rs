@@ -0,0 +1,151 @@
+mod common;
+
+use futures::StreamExt;
+
+use qexample::mazure::codec::{Codec, Format, JsonCodec};
+use qexample::mazure::sbclient::{Message, DEAD_LETTER_REASON_PROPERTY};
+use qexample::messages::LogInfo;
+use qexample::producer;
+use qexample::streaming::MessageStream;
+use qexample::transport::QueueTransport;
+use qexample::transport::memory::MemoryQueue;
+use qexample::typed::{MessageType, TypedError, TypedReceiver, TypedSender, MESSAGE_TYPE_PROPERTY, decode_payload};
+
+use common::{Invoice, Order, QUEUE, client, memory_queue, order, start_emulator};
+
+// Receives a message the receiver refuses, and checks it stays on the queue for others
+// until it is rejected.
+async fn expect_rejected<T: MessageType, C: Codec<T>>(queue: &MemoryQueue, receiver: &TypedReceiver<'_, T, C>) -> TypedError {
+    let undecoded = match receiver.receive().await.unwrap().unwrap() {
+        Err(undecoded) => undecoded,
+        Ok(other) => panic!("Expected a rejected message, got {:?}", other.message().properties.message_id),
+    };
+    assert!(undecoded.error().is_payload_error());
+    assert!(queue.dead_letter_queue().receive().await.unwrap().is_none());
+
+    let (error, received) = undecoded.into_parts();
+    received.abandon().await.unwrap();
+    let undecoded = receiver.receive().await.unwrap().unwrap().err().unwrap();
+    undecoded.reject().await.unwrap();
+
+    let dead = queue.dead_letter_queue().receive().await.unwrap().unwrap();
+    assert_eq!(dead.user_property(DEAD_LETTER_REASON_PROPERTY), Some("DecodeError"));
+    assert!(queue.receive().await.unwrap().is_none());
+    error
+}
+
+#[tokio::test]
+async fn typed_round_trip() {
+    let queue = memory_queue();
+    TypedSender::<Order>::new(&queue).send(&order(7)).await.unwrap();
+
+    let received = TypedReceiver::<Order>::new(&queue).receive().await.unwrap().unwrap().unwrap();
+    assert_eq!(received.body(), &order(7));
+    assert_eq!(received.message().content_type, "text/json");
+    assert_eq!(received.message().user_property(MESSAGE_TYPE_PROPERTY), Some("Order"));
+    received.complete().await.unwrap();
+
+    assert!(TypedReceiver::<Order>::new(&queue).receive().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn rejects_other_message_types() {
+    let queue = memory_queue();
+    TypedSender::<Order>::new(&queue).send(&order(7)).await.unwrap();
+
+    let error = expect_rejected(&queue, &TypedReceiver::<Invoice>::new(&queue)).await;
+    match error {
+        TypedError::TypeMismatch { expected, actual, .. } => {
+            assert_eq!(expected, "Invoice");
+            assert_eq!(actual.as_deref(), Some("Order"));
+        },
+        other => panic!("Expected a type mismatch, got {:?}", other),
+    }
+
+    // Untyped messages do not say what they carry.
+    queue.send(&Message::new_json(&order(7)).unwrap()).await.unwrap();
+    let error = expect_rejected(&queue, &TypedReceiver::<Order>::new(&queue)).await;
+    assert!(matches!(error, TypedError::TypeMismatch { actual: None, .. }));
+}
+
+#[tokio::test]
+async fn rejects_other_content_types_and_bad_bodies() {
+    let queue = memory_queue();
+    let receiver = TypedReceiver::<Order>::new(&queue);
+
+    let mut text = Message::new_json(&order(7)).unwrap();
+    text.content_type = "text/plain".into();
+    text.set_user_property(MESSAGE_TYPE_PROPERTY, "Order");
+    queue.send(&text).await.unwrap();
+    let error = expect_rejected(&queue, &receiver).await;
+    assert!(matches!(error, TypedError::ContentTypeMismatch { expected: "text/json", .. }), "{:?}", error);
+
+    let mut garbled = Message::new_json(&"not an order").unwrap();
+    garbled.content_type = "application/json; charset=utf-8".into();
+    garbled.set_user_property(MESSAGE_TYPE_PROPERTY, "Order");
+    queue.send(&garbled).await.unwrap();
+    let error = expect_rejected(&queue, &receiver).await;
+    assert!(matches!(error, TypedError::DecodeError { type_name: "Order", .. }), "{:?}", error);
+}
+
+#[tokio::test]
+async fn transport_errors_are_told_apart() {
+    let emulator = start_emulator().await;
+    let unreachable = client(&emulator);
+    emulator.stop().await.unwrap();
+
+    let error = TypedReceiver::<Order>::new(&unreachable).receive().await.unwrap_err();
+    assert!(matches!(error, TypedError::TransportError(_)));
+    assert!(!error.is_payload_error());
+    assert!(!TypedSender::<Order>::new(&unreachable).send(&order(7)).await.unwrap_err().is_payload_error());
+}
+
+#[tokio::test]
+async fn decodes_messages_from_a_stream() {
+    let emulator = start_emulator().await;
+    let sb_client = client(&emulator);
+    let sender = TypedSender::<Order>::new(&sb_client);
+    for id in 1..=3 {
+        let mut message = sender.message(&Order { id, item: "mug".into() }).unwrap();
+        message.properties.label = Some("orders".into());
+        sb_client.send(&message).await.unwrap();
+    }
+
+    let receiver = TypedReceiver::<Order>::new(&sb_client);
+    let orders = MessageStream::new(&sb_client).take(3).map(|received| receiver.decode(received.unwrap()));
+    tokio::pin!(orders);
+    let mut ids = Vec::new();
+    while let Some(order) = orders.next().await {
+        let order = order.unwrap();
+        assert_eq!(order.message().properties.label.as_deref(), Some("orders"));
+        ids.push(order.body().id);
+        order.complete().await.unwrap();
+    }
+    assert_eq!(ids, vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn producer_messages_are_typed() {
+    let queue = memory_queue();
+    producer::run_producer(&queue, 1, Format::Json).await.unwrap();
+
+    let messages = queue.with_broker(|b| b.messages(QUEUE));
+    assert!(decode_payload::<LogInfo, JsonCodec>(&messages[0]).is_ok());
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn binds_the_codec() {
+    use qexample::mazure::codec::MessagePackCodec;
+
+    let queue = memory_queue();
+    TypedSender::<Order, MessagePackCodec>::new(&queue).send(&order(7)).await.unwrap();
+    TypedSender::<Order, MessagePackCodec>::new(&queue).send(&order(7)).await.unwrap();
+
+    let received = TypedReceiver::<Order, MessagePackCodec>::new(&queue).receive().await.unwrap().unwrap().unwrap();
+    assert_eq!(received.body(), &order(7));
+    received.complete().await.unwrap();
+
+    let error = expect_rejected(&queue, &TypedReceiver::<Order>::new(&queue)).await;
+    assert!(matches!(error, TypedError::ContentTypeMismatch { expected: "text/json", .. }), "{:?}", error);
+}